@@ -0,0 +1,19 @@
+-- Redemption that produced a queue item (used to update its status on Twitch)
+ALTER TABLE queue_items ADD COLUMN reward_id TEXT;
+ALTER TABLE queue_items ADD COLUMN redemption_id TEXT;
+
+-- Side effects written in the same transaction as the queue mutation
+CREATE TABLE IF NOT EXISTS outbox (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  event_type TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  -- pending | done | failed
+  status TEXT NOT NULL DEFAULT 'pending',
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at INTEGER NOT NULL,
+  last_error TEXT,
+  created_at INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_outbox_status_next ON outbox(status, next_attempt_at);
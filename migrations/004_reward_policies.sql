@@ -0,0 +1,13 @@
+-- Policy applied when the item was enqueued (see twitch.reward_policies)
+ALTER TABLE queue_items ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue_items ADD COLUMN tags TEXT NOT NULL DEFAULT '';
+
+-- Every accepted entry, completed or not (used for one_entry_per_window)
+CREATE TABLE IF NOT EXISTS queue_entries (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT NOT NULL,
+  reward_id TEXT,
+  entered_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_queue_entries_user_time ON queue_entries(user_id, entered_at);
@@ -0,0 +1,11 @@
+-- Every access token refresh attempt (for diagnosing broken refresh tokens)
+CREATE TABLE IF NOT EXISTS token_events (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  occurred_at INTEGER NOT NULL,
+  success INTEGER NOT NULL,
+  -- NULL on success; otherwise e.g. "http_400", "http_5xx", "network", "decode"
+  error_class TEXT,
+  error_detail TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_token_events_time ON token_events(occurred_at);
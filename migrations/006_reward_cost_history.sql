@@ -0,0 +1,10 @@
+-- Observed channel point reward costs (only rows where the cost changed)
+CREATE TABLE IF NOT EXISTS reward_cost_history (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  reward_id TEXT NOT NULL,
+  cost INTEGER NOT NULL,
+  observed_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_reward_cost_history_reward_time ON reward_cost_history(reward_id, observed_at);
+CREATE INDEX IF NOT EXISTS idx_queue_entries_reward_time ON queue_entries(reward_id, entered_at);
@@ -0,0 +1,2 @@
+-- Display name exactly as received from Twitch (display_name may be sanitized)
+ALTER TABLE queue_items ADD COLUMN display_name_raw TEXT;
@@ -0,0 +1,14 @@
+-- Redemptions received while the queue is frozen (merged on thaw)
+CREATE TABLE IF NOT EXISTS pending_queue_items (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT NOT NULL UNIQUE,
+  user_login TEXT NOT NULL,
+  display_name TEXT NOT NULL,
+  display_name_raw TEXT,
+  profile_image_url TEXT NOT NULL,
+  enqueued_at INTEGER NOT NULL,
+  reward_id TEXT,
+  redemption_id TEXT,
+  priority INTEGER NOT NULL DEFAULT 0,
+  tags TEXT NOT NULL DEFAULT ''
+);
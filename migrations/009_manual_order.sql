@@ -0,0 +1,2 @@
+-- Set when an admin moves the item up (see queue.manual_order_gap_threshold)
+ALTER TABLE queue_items ADD COLUMN manually_raised INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- Reward of the queue item that was completed (see reward_policies.*.rejoin_cooldown_secs).
+-- NULL for rows recorded before this migration.
+ALTER TABLE participations ADD COLUMN reward_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_participations_user_reward_time ON participations(user_id, reward_id, completed_at);
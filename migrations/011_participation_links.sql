@@ -0,0 +1,8 @@
+-- Where each completion came from. NULL for rows recorded before this migration.
+ALTER TABLE participations ADD COLUMN queue_item_id TEXT;
+-- "queue" (completed from the queue) or "import" (POST /api/stats/import)
+ALTER TABLE participations ADD COLUMN source TEXT;
+-- stream_online_at of the stream the turn was completed in
+ALTER TABLE participations ADD COLUMN session_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_participations_session ON participations(session_id);
@@ -0,0 +1,7 @@
+-- Overlay cues (see cues.rs); a short replay buffer, trimmed on insert
+CREATE TABLE IF NOT EXISTS cues (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  kind TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
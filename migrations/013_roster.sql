@@ -0,0 +1,15 @@
+-- Canonical in-game names, matched by Twitch user id or (lowercase) login (see roster.rs)
+CREATE TABLE IF NOT EXISTS roster (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT UNIQUE,
+  user_login TEXT UNIQUE,
+  game_name TEXT NOT NULL,
+  game TEXT,
+  updated_at INTEGER NOT NULL
+);
+
+-- Redemption text, and the game name resolved from the roster (falling back to that text)
+ALTER TABLE queue_items ADD COLUMN user_input TEXT;
+ALTER TABLE queue_items ADD COLUMN game_name TEXT;
+ALTER TABLE queue_items ADD COLUMN game TEXT;
+ALTER TABLE pending_queue_items ADD COLUMN user_input TEXT;
@@ -0,0 +1,3 @@
+-- Set when "complete" was pressed with queue.complete_grace_secs; the item is removed
+-- (and its side effects run) once the grace period has elapsed
+ALTER TABLE queue_items ADD COLUMN completing_at INTEGER;
@@ -0,0 +1,2 @@
+-- Admin-only note on a queued item; never part of the overlay / public queue output
+ALTER TABLE queue_items ADD COLUMN private_note TEXT;
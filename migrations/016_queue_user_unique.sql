@@ -0,0 +1,4 @@
+-- 001 declares queue_items.user_id UNIQUE, but tables created before the migrations
+-- existed were kept as-is (CREATE TABLE IF NOT EXISTS). Enforce it for those too.
+-- db::init_pool merges duplicate rows (queue::dedupe_users) before applying this.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_queue_items_user_id ON queue_items(user_id);
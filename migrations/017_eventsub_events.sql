@@ -0,0 +1,11 @@
+-- EventSub WebSocket lifecycle (connects, disconnects, subscribe results) for reviewing gaps after a stream
+CREATE TABLE IF NOT EXISTS eventsub_events (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  occurred_at INTEGER NOT NULL,
+  -- connected / welcome / keepalive_timeout / reconnect_received / disconnected / subscribe_ok / subscribe_failed
+  kind TEXT NOT NULL,
+  -- e.g. the close code and reason, or the subscribe error
+  detail TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_eventsub_events_time ON eventsub_events(occurred_at);
@@ -0,0 +1,4 @@
+-- Set while the viewer has stepped away: the item sits after everyone present and is
+-- skipped for the front; away_return_position is where it stood among present items
+ALTER TABLE queue_items ADD COLUMN away_since INTEGER;
+ALTER TABLE queue_items ADD COLUMN away_return_position INTEGER;
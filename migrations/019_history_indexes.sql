@@ -0,0 +1,3 @@
+-- Keyset pagination (GET /api/history, /api/redemptions/recent) orders by time, then rowid
+CREATE INDEX IF NOT EXISTS idx_participations_completed_at ON participations(completed_at);
+CREATE INDEX IF NOT EXISTS idx_queue_entries_entered_at ON queue_entries(entered_at);
@@ -0,0 +1,10 @@
+-- One completed queue item is one turn: a retried or doubled complete must not record
+-- a second participation. Keep the earliest row for items already recorded twice.
+DELETE FROM participations
+WHERE queue_item_id IS NOT NULL
+  AND id NOT IN (
+    SELECT MIN(id) FROM participations WHERE queue_item_id IS NOT NULL GROUP BY queue_item_id
+  );
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_participations_queue_item ON participations(queue_item_id)
+  WHERE queue_item_id IS NOT NULL;
@@ -0,0 +1,17 @@
+-- Join redemptions dropped while enqueueing was paused, one row per user, so the
+-- broadcaster can let them in later (POST /api/pending_interest/:id/admit)
+CREATE TABLE IF NOT EXISTS pending_interest (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT NOT NULL UNIQUE,
+  user_login TEXT NOT NULL,
+  display_name TEXT NOT NULL,
+  reward_id TEXT NOT NULL,
+  -- Left unfulfilled on Twitch; the admitted item carries it so completing fulfills it
+  redemption_id TEXT,
+  user_input TEXT,
+  -- queue_paused
+  reason TEXT NOT NULL,
+  recorded_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_pending_interest_time ON pending_interest(recorded_at);
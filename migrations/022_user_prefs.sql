@@ -0,0 +1,10 @@
+-- Per-viewer notification preferences (`!queue optout` / `!queue optin` in chat).
+-- Users without a row get queue.default_notify for everything.
+CREATE TABLE IF NOT EXISTS user_prefs (
+  user_id TEXT PRIMARY KEY,
+  chat_mention INTEGER NOT NULL,
+  whisper INTEGER NOT NULL,
+  -- Named on public pages
+  public_listing INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,13 @@
+-- When the item became the active one (position 0); copied to its participation.
+ALTER TABLE queue_items ADD COLUMN turn_started_at INTEGER;
+
+ALTER TABLE participations ADD COLUMN turn_started_at INTEGER;
+-- Archive VOD of the session and the turn's offset into it. NULL when VODs are disabled
+-- or the turn could not be placed.
+ALTER TABLE participations ADD COLUMN vod_url TEXT;
+ALTER TABLE participations ADD COLUMN vod_offset_secs INTEGER;
+-- Set once the VOD lookup ran (with or without a result); rows still NULL are retried.
+ALTER TABLE participations ADD COLUMN vod_checked_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS idx_participations_vod_pending ON participations(id)
+  WHERE vod_checked_at IS NULL AND turn_started_at IS NOT NULL;
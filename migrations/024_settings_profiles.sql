@@ -0,0 +1,8 @@
+-- Named bundles of runtime settings (`PUT /api/profiles/:name`), applied together by
+-- `POST /api/profiles/:name/activate`. The last activated name is app_kv 'active_profile'.
+CREATE TABLE IF NOT EXISTS settings_profiles (
+  name TEXT PRIMARY KEY,
+  -- JSON of profiles::ProfileSettings
+  settings TEXT NOT NULL,
+  updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Scopes granted to the stored token, space-separated. Written by the authorization-code
+-- exchange and /validate only; refresh responses may omit them. NULL = not known yet.
+ALTER TABLE oauth_tokens ADD COLUMN scopes TEXT;
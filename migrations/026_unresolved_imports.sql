@@ -0,0 +1,11 @@
+-- Turns imported from another queue tool by login only (offline import). The resolve
+-- pass looks the logins up and moves the rows into participations.
+CREATE TABLE IF NOT EXISTS unresolved_imports (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_login TEXT NOT NULL,
+  completed_at INTEGER NOT NULL,
+  -- Adapter that produced the row ("csv", "streamlabs")
+  format TEXT NOT NULL,
+  imported_at INTEGER NOT NULL,
+  UNIQUE(user_login, completed_at)
+);
@@ -0,0 +1,10 @@
+-- Named helper / manager logins (`PUT /api/admins`, broadcaster only). The Basic user
+-- name picks the row; the broadcaster keeps using server.admin_password.
+CREATE TABLE IF NOT EXISTS admin_users (
+  name TEXT PRIMARY KEY,
+  role TEXT NOT NULL CHECK (role IN ('manager', 'helper')),
+  password_salt TEXT NOT NULL,
+  -- hex SHA-256 of "<salt>:<password>"
+  password_hash TEXT NOT NULL,
+  updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,5 @@
+-- When the viewer asked to join, in epoch milliseconds: Twitch's redeemed_at for
+-- redemptions, otherwise the enqueue time. Orders entries that tie on fairness, so a
+-- burst lands in redemption order. NULL (older rows) sorts before any value.
+ALTER TABLE queue_items ADD COLUMN redeemed_at_ms INTEGER;
+ALTER TABLE pending_queue_items ADD COLUMN redeemed_at_ms INTEGER;
@@ -0,0 +1,13 @@
+-- Admin API mutations (see audit.rs), exported by GET /api/events/export.ndjson
+CREATE TABLE IF NOT EXISTS audit_events (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  at INTEGER NOT NULL,
+  actor TEXT NOT NULL,
+  role TEXT NOT NULL,
+  -- access::Permission of the route: queue, settings or auth
+  kind TEXT NOT NULL,
+  method TEXT NOT NULL,
+  path TEXT NOT NULL,
+  status INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_audit_events_at ON audit_events(at);
@@ -0,0 +1,2 @@
+-- Which way a delivered chat message went out: helix | irc (NULL for other events)
+ALTER TABLE outbox ADD COLUMN transport TEXT;
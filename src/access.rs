@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use tracing::warn;
+
+use crate::{admin_users, AppState};
+
+/// Access tier of a request. Everyone is `Broadcaster` while `server.admin_password` is unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// `server.admin_password`: everything, including the Twitch login and `/api/admins`.
+    Broadcaster,
+    /// An `admin_users` login: queue and settings, but not authentication.
+    Manager,
+    /// An `admin_users` login: queue operations only.
+    Helper,
+    /// Sees `/admin` and read endpoints; mutating `/api/*` routes answer 403.
+    Viewer,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Broadcaster => "broadcaster",
+            Role::Manager => "manager",
+            Role::Helper => "helper",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    /// Roles stored in `admin_users`.
+    pub fn assignable(s: &str) -> Option<Self> {
+        match s {
+            "manager" => Some(Role::Manager),
+            "helper" => Some(Role::Helper),
+            _ => None,
+        }
+    }
+
+    pub fn allows(self, permission: Permission) -> bool {
+        match self {
+            Role::Broadcaster => true,
+            Role::Manager => permission != Permission::Auth,
+            Role::Helper => permission == Permission::Queue,
+            Role::Viewer => false,
+        }
+    }
+}
+
+/// What a route group needs; see [`permission_for`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Working the queue: add, complete, skip, reorder, pause, freeze.
+    Queue,
+    /// Everything else that changes state: profiles, rewards, roster, imports, caches.
+    Settings,
+    /// The Twitch login, overlay token and `/api/admins`.
+    Auth,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::Queue => "queue",
+            Permission::Settings => "settings",
+            Permission::Auth => "auth",
+        }
+    }
+}
+
+/// Route group of `path`. Routes not listed fall under `Settings`, so a new endpoint
+/// is manager-only until it is deliberately opened to helpers.
+pub fn permission_for(path: &str) -> Permission {
+    if path.starts_with("/auth/")
+        || path.starts_with("/api/auth/")
+        || path.starts_with("/api/overlay_token/")
+        || path == "/api/admins"
+    {
+        return Permission::Auth;
+    }
+    let queue_route = match path.strip_prefix("/api/queue") {
+        // Bulk replacement and the slot schedule are settings, not queue work.
+        Some(rest) => {
+            (rest.is_empty() || rest.starts_with('/')) && !matches!(rest, "/import" | "/slots")
+        }
+        // Putting a break into the running slot schedule is queue work.
+        None => {
+            path.starts_with("/api/pending_interest/")
+                || (path.starts_with("/api/sessions/") && path.contains("/breaks"))
+        }
+    };
+    if queue_route {
+        Permission::Queue
+    } else {
+        Permission::Settings
+    }
+}
+
+/// 403 naming the permission the caller's role lacks.
+pub fn missing_permission_response(permission: Permission, role: Role) -> Response {
+    let body = serde_json::json!({
+        "error": "missing_permission",
+        "permission": permission,
+        "role": role,
+        "message": format!("{} access is required; {} cannot do this", permission.as_str(), role.as_str()),
+    });
+    (StatusCode::FORBIDDEN, axum::Json(body)).into_response()
+}
+
+/// Reachable without credentials: the overlay page, its assets and the endpoints it reads,
+/// plus the OAuth callback (guarded by the OAuth state instead), the ingest endpoint
+/// (guarded by its request signature) and the aggregate stats (404 unless `server.public_stats`).
+fn is_public(method: &Method, path: &str) -> bool {
+    path.starts_with("/assets/")
+        || path == crate::config::AUTH_CALLBACK_PATH
+        || (*method == Method::POST && path == "/api/ingest/enqueue")
+        || (is_read(method) && path == "/api/stats/public")
+        || is_overlay(method, path)
+}
+
+/// The overlay page and the endpoints it reads; with `server.require_overlay_token` these
+/// need a valid `?token=` to skip the credential check.
+fn is_overlay(method: &Method, path: &str) -> bool {
+    path == "/obs"
+        || (matches!(*method, Method::GET | Method::HEAD)
+            && matches!(path, "/api/queue" | "/api/cues" | "/api/overlay/bootstrap"))
+}
+
+pub fn is_read(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Accepts the current or (within the overlap) previous signing key; see `overlay_token`.
+fn has_valid_overlay_token(app: &AppState, req: &Request) -> bool {
+    let Some(query) = req.uri().query() else {
+        return false;
+    };
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "token")
+        .is_some_and(|(_, token)| {
+            app.overlay_keys
+                .read()
+                .unwrap()
+                .validate(&token, crate::util::now_epoch())
+        })
+}
+
+/// Compares SHA-256 digests so the comparison time does not depend on the password.
+fn password_matches(given: &str, expected: &str) -> bool {
+    !expected.is_empty() && Sha256::digest(given.as_bytes()) == Sha256::digest(expected.as_bytes())
+}
+
+/// `(user name, password)` from an `Authorization: Basic` header.
+fn basic_credentials(headers: &axum::http::HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Role for an `Authorization: Basic` header. The shared passwords ignore the user name;
+/// any other password is checked against the `admin_users` login of that name.
+pub async fn role_from_headers(app: &AppState, headers: &axum::http::HeaderMap) -> Option<Role> {
+    let cfg = &app.settings.server;
+    if cfg.admin_password.is_empty() {
+        return Some(Role::Broadcaster);
+    }
+    let (user, password) = basic_credentials(headers)?;
+    if password_matches(&password, &cfg.admin_password) {
+        Some(Role::Broadcaster)
+    } else if password_matches(&password, &cfg.viewer_password) {
+        Some(Role::Viewer)
+    } else {
+        admin_users::verify(app.queue.db.read(), user.trim(), &password)
+            .await
+            .unwrap_or_else(|e| {
+                warn!(error = ?e, "admin_users lookup failed");
+                None
+            })
+    }
+}
+
+/// Who is acting, for logs: the Basic user name, which the password check ignores, so
+/// people sharing the admin password can still tell their changes apart.
+pub fn actor_from_headers(headers: &axum::http::HeaderMap) -> String {
+    basic_credentials(headers)
+        .map(|(user, _)| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+        .unwrap_or_else(|| "admin".to_string())
+}
+
+/// Middleware: asks for credentials (401), keeps viewers to read-only requests and other
+/// roles to the mutating route groups they hold (403). The resolved role is stored as a
+/// request extension for handlers.
+pub async fn require_role(
+    State(app): State<Arc<AppState>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let role = role_from_headers(&app, req.headers()).await;
+    let token_missing = app.settings.server.require_overlay_token
+        && is_overlay(req.method(), req.uri().path())
+        && !has_valid_overlay_token(&app, &req);
+    if !token_missing && is_public(req.method(), req.uri().path()) {
+        if let Some(role) = role {
+            req.extensions_mut().insert(role);
+        }
+        return next.run(req).await;
+    }
+    match role {
+        None => (
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                "Basic realm=\"twitch_obs_queue\", charset=\"UTF-8\"",
+            )],
+            "login required",
+        )
+            .into_response(),
+        Some(Role::Viewer) if !is_read(req.method()) => {
+            (StatusCode::FORBIDDEN, "viewer access is read-only").into_response()
+        }
+        // Backs up `AdminContext` for a mutating handler that does not take it.
+        Some(role) if !is_read(req.method()) && !role.allows(permission_for(req.uri().path())) => {
+            missing_permission_response(permission_for(req.uri().path()), role)
+        }
+        Some(role) => {
+            req.extensions_mut().insert(role);
+            next.run(req).await
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{access::Role, util};
+
+/// Longest accepted login name; the Basic user name is matched against it as is.
+const MAX_NAME_CHARS: usize = 40;
+
+/// A helper / manager login as listed by `GET /api/admins` (never the password).
+#[derive(Debug, Clone, Serialize)]
+pub struct AdminUser {
+    pub name: String,
+    pub role: Role,
+    pub updated_at: i64,
+}
+
+/// One entry of `PUT /api/admins`. `password` may be left out for a name that
+/// already exists to keep its current password.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminUserInput {
+    pub name: String,
+    pub role: Role,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct AdminUserRow {
+    name: String,
+    role: String,
+    password_salt: String,
+    password_hash: String,
+    updated_at: i64,
+}
+
+fn password_hash(salt: &str, password: &str) -> String {
+    format!(
+        "{:x}",
+        Sha256::digest(format!("{salt}:{password}").as_bytes())
+    )
+}
+
+pub async fn list(pool: &SqlitePool) -> anyhow::Result<Vec<AdminUser>> {
+    let rows = sqlx::query_as::<_, AdminUserRow>(
+        "SELECT name, role, password_salt, password_hash, updated_at FROM admin_users ORDER BY name COLLATE NOCASE ASC",
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|r| {
+            let role = Role::assignable(&r.role).ok_or_else(|| {
+                anyhow::anyhow!("admin_users.{}: unknown role {:?}", r.name, r.role)
+            })?;
+            Ok(AdminUser {
+                name: r.name,
+                role,
+                updated_at: r.updated_at,
+            })
+        })
+        .collect()
+}
+
+/// Role of `name` when `password` is its password. Unknown names and wrong passwords are `None`.
+pub async fn verify(pool: &SqlitePool, name: &str, password: &str) -> anyhow::Result<Option<Role>> {
+    let row = sqlx::query_as::<_, AdminUserRow>(
+        "SELECT name, role, password_salt, password_hash, updated_at FROM admin_users WHERE name = ?1",
+    )
+    .bind(name)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row
+        .filter(|r| password_hash(&r.password_salt, password) == r.password_hash)
+        .and_then(|r| Role::assignable(&r.role)))
+}
+
+/// Checks names and roles for [`replace`]: names are trimmed, non-empty, unique and
+/// without `:` (it ends the Basic user name); only manager / helper can be given out.
+pub fn validate(entries: Vec<AdminUserInput>) -> Result<Vec<AdminUserInput>, String> {
+    let mut out: Vec<AdminUserInput> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let name = entry.name.trim().to_string();
+        if name.is_empty() || name.chars().count() > MAX_NAME_CHARS || name.contains(':') {
+            return Err(format!(
+                "admin name {name:?} must be 1-{MAX_NAME_CHARS} characters without ':'"
+            ));
+        }
+        if !matches!(entry.role, Role::Manager | Role::Helper) {
+            return Err(format!("{name}: role must be manager or helper"));
+        }
+        if entry.password.as_deref().is_some_and(util::is_blank) {
+            return Err(format!("{name}: password must not be empty"));
+        }
+        if out.iter().any(|o| o.name == name) {
+            return Err(format!("admin name {name:?} is listed twice"));
+        }
+        out.push(AdminUserInput { name, ..entry });
+    }
+    Ok(out)
+}
+
+/// Replaces the whole list in one transaction. A name missing a password keeps the
+/// one it had; a new name without one fails with `Err(name)` and changes nothing.
+pub async fn replace(
+    pool: &SqlitePool,
+    entries: &[AdminUserInput],
+    now: i64,
+) -> anyhow::Result<Result<(), String>> {
+    let mut tx = pool.begin().await?;
+    let existing = sqlx::query_as::<_, AdminUserRow>(
+        "SELECT name, role, password_salt, password_hash, updated_at FROM admin_users",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM admin_users")
+        .execute(&mut *tx)
+        .await?;
+    for entry in entries {
+        let (salt, hash) = match (
+            &entry.password,
+            existing.iter().find(|r| r.name == entry.name),
+        ) {
+            (Some(password), _) => {
+                let salt = uuid::Uuid::new_v4().simple().to_string();
+                let hash = password_hash(&salt, password);
+                (salt, hash)
+            }
+            (None, Some(row)) => (row.password_salt.clone(), row.password_hash.clone()),
+            (None, None) => {
+                tx.rollback().await?;
+                return Ok(Err(entry.name.clone()));
+            }
+        };
+        sqlx::query(
+            "INSERT INTO admin_users (name, role, password_salt, password_hash, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(&entry.name)
+        .bind(entry.role.as_str())
+        .bind(&salt)
+        .bind(&hash)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(Ok(()))
+}
@@ -0,0 +1,490 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use uuid::Uuid;
+
+use crate::{db, queue::QueueItemDto, util};
+
+const KV_SLOT_SCHEDULE: &str = "slot_schedule";
+
+/// Fixed-length slots starting at `start_at`, with some slots kept free as breaks: the
+/// item at position N gets the Nth slot that is not a break. Stored in `app_kv`; absent
+/// means rolling queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlotSchedule {
+    /// Session id in `/api/sessions/:id/...`; survives edits of the times, so a
+    /// subscribed calendar keeps working. Empty in a profile means "keep the current one".
+    #[serde(default)]
+    pub id: String,
+    pub start_at: i64,
+    pub slot_secs: i64,
+    /// Sorted by slot, at most one per slot.
+    #[serde(default)]
+    pub breaks: Vec<SlotBreak>,
+}
+
+/// A slot nobody is scheduled into (`POST /api/sessions/:id/breaks`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotBreak {
+    pub id: String,
+    /// Slot index counted from `start_at`, breaks included.
+    pub slot: i64,
+    pub label: String,
+}
+
+impl SlotSchedule {
+    /// Slot index of queue position `position`: breaks at or before it push it back.
+    pub fn slot_of(&self, position: i64) -> i64 {
+        let mut slot = position;
+        for b in &self.breaks {
+            if b.slot <= slot {
+                slot += 1;
+            }
+        }
+        slot
+    }
+
+    pub fn slot_start(&self, position: i64) -> i64 {
+        self.start_at + self.slot_of(position) * self.slot_secs
+    }
+}
+
+/// `PUT /api/queue/slots`: only the times; id and breaks stay as they are.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct SlotTimes {
+    pub start_at: i64,
+    pub slot_secs: i64,
+}
+
+pub async fn get_schedule(pool: &SqlitePool) -> anyhow::Result<Option<SlotSchedule>> {
+    match db::get_kv(pool, KV_SLOT_SCHEDULE).await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+async fn get_schedule_conn(conn: &mut SqliteConnection) -> anyhow::Result<Option<SlotSchedule>> {
+    let json = sqlx::query_scalar::<_, String>("SELECT value FROM app_kv WHERE key = ?1")
+        .bind(KV_SLOT_SCHEDULE)
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(json.map(|j| serde_json::from_str(&j)).transpose()?)
+}
+
+/// Stores `schedule`, taking the current session id when it has none (or a new one
+/// when there is no schedule yet). Returns what was stored.
+pub async fn set_schedule(
+    conn: &mut SqliteConnection,
+    mut schedule: SlotSchedule,
+) -> anyhow::Result<SlotSchedule> {
+    if schedule.id.is_empty() {
+        schedule.id = match get_schedule_conn(conn).await? {
+            Some(current) => current.id,
+            None => Uuid::new_v4().simple().to_string(),
+        };
+    }
+    schedule.breaks.sort_by_key(|b| b.slot);
+    schedule.breaks.dedup_by_key(|b| b.slot);
+    db::set_kv(
+        &mut *conn,
+        KV_SLOT_SCHEDULE,
+        &serde_json::to_string(&schedule)?,
+    )
+    .await?;
+    Ok(schedule)
+}
+
+/// Changes the times of the current schedule, or starts one.
+pub async fn set_times(
+    conn: &mut SqliteConnection,
+    times: SlotTimes,
+) -> anyhow::Result<SlotSchedule> {
+    let breaks = get_schedule_conn(conn)
+        .await?
+        .map(|s| s.breaks)
+        .unwrap_or_default();
+    let schedule = SlotSchedule {
+        id: String::new(),
+        start_at: times.start_at,
+        slot_secs: times.slot_secs,
+        breaks,
+    };
+    set_schedule(conn, schedule).await
+}
+
+#[derive(Debug)]
+pub enum AddBreakOutcome {
+    Added(SlotBreak),
+    /// `session_id` is not the current schedule.
+    NoSession,
+    SlotTaken,
+}
+
+/// Keeps `slot` free, or with `None` the first free slot after everyone `queue_len`
+/// currently holds (a break at the end of the queue).
+pub async fn add_break(
+    conn: &mut SqliteConnection,
+    session_id: &str,
+    slot: Option<i64>,
+    queue_len: i64,
+    label: &str,
+) -> anyhow::Result<AddBreakOutcome> {
+    let Some(mut schedule) = get_schedule_conn(conn)
+        .await?
+        .filter(|s| s.id == session_id)
+    else {
+        return Ok(AddBreakOutcome::NoSession);
+    };
+    let slot = slot.unwrap_or_else(|| schedule.slot_of(queue_len));
+    if schedule.breaks.iter().any(|b| b.slot == slot) {
+        return Ok(AddBreakOutcome::SlotTaken);
+    }
+    let added = SlotBreak {
+        id: Uuid::new_v4().to_string(),
+        slot,
+        label: label.to_string(),
+    };
+    schedule.breaks.push(added.clone());
+    set_schedule(conn, schedule).await?;
+    Ok(AddBreakOutcome::Added(added))
+}
+
+/// `false` when the session or the break does not exist.
+pub async fn remove_break(
+    conn: &mut SqliteConnection,
+    session_id: &str,
+    break_id: &str,
+) -> anyhow::Result<bool> {
+    let Some(mut schedule) = get_schedule_conn(conn)
+        .await?
+        .filter(|s| s.id == session_id)
+    else {
+        return Ok(false);
+    };
+    let before = schedule.breaks.len();
+    schedule.breaks.retain(|b| b.id != break_id);
+    if schedule.breaks.len() == before {
+        return Ok(false);
+    }
+    set_schedule(conn, schedule).await?;
+    Ok(true)
+}
+
+pub async fn clear_schedule<'e, E>(executor: E) -> anyhow::Result<()>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    db::delete_kv(executor, KV_SLOT_SCHEDULE).await
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgendaSlot {
+    /// Queue item id, or the break id for a break.
+    pub id: String,
+    /// The viewer's display name, or the break's label.
+    pub display_name: String,
+    pub start_at: i64,
+    pub end_at: i64,
+    pub is_break: bool,
+}
+
+/// Scheduled items (`QueueItemDto.scheduled_at`; away items have none) and breaks, by start time.
+pub fn agenda(items: &[QueueItemDto], schedule: &SlotSchedule) -> Vec<AgendaSlot> {
+    let slot = |id: &str, display_name: &str, start_at: i64, is_break: bool| AgendaSlot {
+        id: id.to_string(),
+        display_name: display_name.to_string(),
+        start_at,
+        end_at: start_at + schedule.slot_secs,
+        is_break,
+    };
+    let mut slots: Vec<AgendaSlot> = items
+        .iter()
+        .filter_map(|item| {
+            Some(slot(
+                &item.id,
+                &item.display_name,
+                item.scheduled_at?,
+                false,
+            ))
+        })
+        .chain(schedule.breaks.iter().map(|b| {
+            slot(
+                &b.id,
+                &b.label,
+                schedule.start_at + b.slot * schedule.slot_secs,
+                true,
+            )
+        }))
+        .collect();
+    slots.sort_by_key(|s| s.start_at);
+    slots
+}
+
+/// RFC 5545 calendar with a VEVENT per viewer slot. UIDs are the queue item
+/// ids, so calendar clients update events in place when slots move.
+pub fn render_ics(slots: &[AgendaSlot], now: i64) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//twitch_obs_queue//agenda//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+    ];
+    for slot in slots.iter().filter(|s| !s.is_break) {
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}@twitch_obs_queue", slot.id));
+        lines.push(format!("DTSTAMP:{}", util::format_utc_basic(now)));
+        lines.push(format!("DTSTART:{}", util::format_utc_basic(slot.start_at)));
+        lines.push(format!("DTEND:{}", util::format_utc_basic(slot.end_at)));
+        lines.push(format!("SUMMARY:{}", ics_escape(&slot.display_name)));
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    let mut out = String::new();
+    for line in &lines {
+        out.push_str(&ics_fold(line));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// TEXT value escaping (RFC 5545 3.3.11).
+fn ics_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Folds lines longer than 75 octets without splitting a UTF-8 sequence (RFC 5545 3.1).
+fn ics_fold(line: &str) -> String {
+    const LIMIT: usize = 75;
+    let mut out = String::with_capacity(line.len() + line.len() / LIMIT * 3);
+    let mut used = 0;
+    for c in line.chars() {
+        let len = c.len_utf8();
+        if used + len > LIMIT {
+            out.push_str("\r\n ");
+            // The leading space counts towards the continuation line.
+            used = 1;
+        }
+        out.push(c);
+        used += len;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        queue,
+        testing::{self, TestApp},
+    };
+
+    const START: i64 = 1_714_590_000; // 2024-05-01T19:00:00Z
+    const SLOT: i64 = 1200;
+
+    fn schedule(break_slots: &[i64]) -> SlotSchedule {
+        SlotSchedule {
+            id: "s1".to_string(),
+            start_at: START,
+            slot_secs: SLOT,
+            breaks: break_slots
+                .iter()
+                .map(|&slot| SlotBreak {
+                    id: format!("b{slot}"),
+                    slot,
+                    label: "休憩".to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn positions_skip_break_slots() {
+        assert_eq!(
+            (0..4).map(|p| schedule(&[]).slot_of(p)).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(
+            (0..4)
+                .map(|p| schedule(&[1]).slot_of(p))
+                .collect::<Vec<_>>(),
+            vec![0, 2, 3, 4]
+        );
+        assert_eq!(
+            (0..4)
+                .map(|p| schedule(&[0, 1, 3]).slot_of(p))
+                .collect::<Vec<_>>(),
+            vec![2, 4, 5, 6]
+        );
+        assert_eq!(schedule(&[1]).slot_start(1), START + 2 * SLOT);
+    }
+
+    #[test]
+    fn ics_matches_the_fixture_byte_for_byte() {
+        let slot = |id: &str, name: &str, n: i64, is_break: bool| AgendaSlot {
+            id: id.to_string(),
+            display_name: name.to_string(),
+            start_at: START + n * SLOT,
+            end_at: START + (n + 1) * SLOT,
+            is_break,
+        };
+        let slots = [
+            slot("item-1", "Alice", 0, false),
+            slot("b1", "休憩", 1, true),
+            slot("item-2", "Bob; the \"builder\", \\ok\nline two", 2, false),
+            slot(
+                "item-3",
+                "とても長い名前の視聴者さんがここにいますよ、とても長い名前の視聴者さん",
+                3,
+                false,
+            ),
+        ];
+        let expected = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/agenda/agenda.ics"
+        ))
+        .unwrap();
+        let ics = render_ics(&slots, 1_714_580_000);
+        assert_eq!(ics.as_bytes(), expected.as_slice(), "\n{ics}");
+        assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+    }
+
+    #[test]
+    fn folding_never_splits_a_character() {
+        let line = format!("SUMMARY:{}", "😀".repeat(40));
+        let folded = ics_fold(&line);
+        let parts: Vec<&str> = folded.split("\r\n").collect();
+        assert!(parts.iter().all(|p| p.len() <= 75));
+        assert_eq!(parts.concat().replace(" 😀", "😀"), line);
+    }
+
+    async fn store(app: &TestApp, s: SlotSchedule) -> SlotSchedule {
+        let mut conn = app.queue.db.write().acquire().await.unwrap();
+        set_schedule(&mut conn, s).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn agenda_places_viewers_around_breaks() {
+        let app = TestApp::new("").await;
+        for user in ["a", "b", "c"] {
+            testing::enqueue(&app.queue, testing::new_user(user)).await;
+        }
+        let stored = store(&app, schedule(&[1])).await;
+
+        let items = queue::list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        let scheduled: Vec<Option<i64>> = items.iter().map(|i| i.scheduled_at).collect();
+        assert_eq!(
+            scheduled,
+            vec![Some(START), Some(START + 2 * SLOT), Some(START + 3 * SLOT)]
+        );
+
+        let agenda = agenda(&items, &stored);
+        let names: Vec<(&str, i64, bool)> = agenda
+            .iter()
+            .map(|s| {
+                (
+                    s.display_name.as_str(),
+                    (s.start_at - START) / SLOT,
+                    s.is_break,
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("a", 0, false),
+                ("休憩", 1, true),
+                ("b", 2, false),
+                ("c", 3, false)
+            ]
+        );
+        assert!(agenda.iter().all(|s| s.end_at == s.start_at + SLOT));
+    }
+
+    #[tokio::test]
+    async fn breaks_are_added_to_the_current_session_only() {
+        let app = TestApp::new("").await;
+        let mut conn = app.queue.db.write().acquire().await.unwrap();
+        let s = set_times(
+            &mut conn,
+            SlotTimes {
+                start_at: START,
+                slot_secs: SLOT,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!s.id.is_empty());
+
+        assert!(matches!(
+            add_break(&mut conn, "other", None, 0, "x").await.unwrap(),
+            AddBreakOutcome::NoSession
+        ));
+        // Default: the first free slot after the two queued viewers.
+        let AddBreakOutcome::Added(end) =
+            add_break(&mut conn, &s.id, None, 2, "休憩").await.unwrap()
+        else {
+            panic!("not added")
+        };
+        assert_eq!(end.slot, 2);
+        assert!(matches!(
+            add_break(&mut conn, &s.id, Some(2), 2, "x").await.unwrap(),
+            AddBreakOutcome::SlotTaken
+        ));
+        let AddBreakOutcome::Added(first) = add_break(&mut conn, &s.id, Some(0), 2, "開始前")
+            .await
+            .unwrap()
+        else {
+            panic!("not added")
+        };
+        // The next default break goes after the viewers, who now sit in slots 1 and 3.
+        let AddBreakOutcome::Added(next) =
+            add_break(&mut conn, &s.id, None, 2, "休憩").await.unwrap()
+        else {
+            panic!("not added")
+        };
+        assert_eq!(next.slot, 4);
+
+        // Editing the times keeps the session id and its breaks.
+        let edited = set_times(
+            &mut conn,
+            SlotTimes {
+                start_at: START + 60,
+                slot_secs: 900,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(edited.id, s.id);
+        assert_eq!(
+            edited.breaks.iter().map(|b| b.slot).collect::<Vec<_>>(),
+            vec![0, 2, 4]
+        );
+
+        assert!(remove_break(&mut conn, &s.id, &first.id).await.unwrap());
+        assert!(!remove_break(&mut conn, &s.id, &first.id).await.unwrap());
+        assert!(!remove_break(&mut conn, "other", &end.id).await.unwrap());
+        drop(conn);
+        let slots: Vec<i64> = get_schedule(app.queue.db.read())
+            .await
+            .unwrap()
+            .unwrap()
+            .breaks
+            .iter()
+            .map(|b| b.slot)
+            .collect();
+        assert_eq!(slots, vec![2, 4]);
+    }
+}
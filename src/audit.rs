@@ -0,0 +1,265 @@
+//! Who changed what through the admin API: one row per mutating request that got past
+//! `access::require_role`, with the acting login, its role and the response status.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use tracing::warn;
+
+use crate::{access, config::AuditConfig, util, AppState};
+
+/// Rows read per query while exporting.
+const EXPORT_BATCH: i64 = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub at: i64,
+    /// Basic-auth user name (see `access::actor_from_headers`).
+    pub actor: String,
+    pub role: String,
+    /// Route group: `queue`, `settings` or `auth`.
+    pub kind: String,
+    pub method: String,
+    pub path: String,
+    pub status: i64,
+}
+
+/// Stores one event and drops those older than `retention_days`. No-op when disabled.
+pub async fn record(
+    pool: &SqlitePool,
+    cfg: &AuditConfig,
+    event: &AuditEvent,
+) -> anyhow::Result<()> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"INSERT INTO audit_events (at, actor, role, kind, method, path, status)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+    )
+    .bind(event.at)
+    .bind(&event.actor)
+    .bind(&event.role)
+    .bind(&event.kind)
+    .bind(&event.method)
+    .bind(&event.path)
+    .bind(event.status)
+    .execute(&mut *tx)
+    .await?;
+    if cfg.retention_days > 0 {
+        sqlx::query("DELETE FROM audit_events WHERE at < ?1")
+            .bind(event.at - (cfg.retention_days * 86_400) as i64)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Middleware inside `access::require_role`: records mutating requests once answered.
+/// Bodies are not stored (they can hold passwords and tokens).
+pub async fn record_mutations(
+    State(app): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let method = req.method().clone();
+    let role = req.extensions().get::<access::Role>().copied();
+    let (Some(role), false) = (role, access::is_read(&method)) else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path().to_string();
+    let actor = access::actor_from_headers(req.headers());
+    let res = next.run(req).await;
+
+    let event = AuditEvent {
+        id: 0,
+        at: util::now_epoch(),
+        actor,
+        role: role.as_str().to_string(),
+        kind: access::permission_for(&path).as_str().to_string(),
+        method: method.to_string(),
+        path,
+        status: i64::from(res.status().as_u16()),
+    };
+    if let Err(e) = record(app.queue.db.write(), &app.settings.audit, &event).await {
+        warn!(error = ?e, path = %event.path, "failed to record audit event");
+    }
+    res
+}
+
+/// Every stored event as newline-delimited JSON, oldest first. Filters: `at >= since` and
+/// `kind`. Reads in batches keyed by id, so the response streams without holding the
+/// whole log in memory.
+pub fn export_ndjson(
+    pool: SqlitePool,
+    since: Option<i64>,
+    kind: Option<String>,
+) -> impl futures_util::Stream<Item = std::io::Result<String>> {
+    futures_util::stream::try_unfold(Some(0_i64), move |after| {
+        let pool = pool.clone();
+        let kind = kind.clone();
+        async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+            let rows = sqlx::query_as::<_, AuditEvent>(
+                r#"SELECT id, at, actor, role, kind, method, path, status FROM audit_events
+                   WHERE id > ?1 AND (?2 IS NULL OR at >= ?2) AND (?3 IS NULL OR kind = ?3)
+                   ORDER BY id ASC
+                   LIMIT ?4"#,
+            )
+            .bind(after)
+            .bind(since)
+            .bind(&kind)
+            .bind(EXPORT_BATCH)
+            .fetch_all(&pool)
+            .await
+            .map_err(std::io::Error::other)?;
+
+            if rows.is_empty() {
+                return Ok(None);
+            }
+            let next = (rows.len() as i64 == EXPORT_BATCH).then(|| rows[rows.len() - 1].id);
+            let mut chunk = String::new();
+            for r in &rows {
+                chunk.push_str(&serde_json::to_string(r).map_err(std::io::Error::other)?);
+                chunk.push('\n');
+            }
+            Ok(Some((chunk, next)))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::testing::{self, TestApp};
+
+    async fn events(app: &TestApp, since: Option<i64>, kind: Option<&str>) -> Vec<AuditEvent> {
+        let chunks: Vec<String> =
+            export_ndjson(app.queue.db.read().clone(), since, kind.map(str::to_string))
+                .try_collect()
+                .await
+                .unwrap();
+        chunks
+            .concat()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn event(at: i64, kind: &str) -> AuditEvent {
+        AuditEvent {
+            id: 0,
+            at,
+            actor: "admin".to_string(),
+            role: "broadcaster".to_string(),
+            kind: kind.to_string(),
+            method: "POST".to_string(),
+            path: "/api/queue/freeze".to_string(),
+            status: 200,
+        }
+    }
+
+    #[tokio::test]
+    async fn mutating_requests_are_recorded_with_actor_and_role() {
+        let app = TestApp::new("").await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let client = reqwest::Client::new();
+
+        client
+            .get(format!("{base}/api/queue"))
+            .send()
+            .await
+            .unwrap();
+        let res = client
+            .post(format!("{base}/api/queue/freeze"))
+            .basic_auth("alice", Some("ignored"))
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        client
+            .post(format!("{base}/api/queue/missing/delete"))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+
+        let got: Vec<_> = events(&app, None, None)
+            .await
+            .into_iter()
+            .map(|e| (e.actor, e.role, e.kind, e.method, e.path, e.status))
+            .collect();
+        assert_eq!(got.len(), 2, "reads are not recorded: {got:?}");
+        assert_eq!(
+            got[0],
+            (
+                "alice".to_string(),
+                "broadcaster".to_string(),
+                "queue".to_string(),
+                "POST".to_string(),
+                "/api/queue/freeze".to_string(),
+                200
+            )
+        );
+        assert_eq!(
+            (got[1].0.as_str(), got[1].4.as_str()),
+            ("admin", "/api/queue/missing/delete")
+        );
+        assert!(got[1].5 >= 400);
+    }
+
+    #[tokio::test]
+    async fn disabled_audit_records_nothing() {
+        let app = TestApp::new("[audit]\nenabled = false\n").await;
+        record(
+            app.queue.db.write(),
+            &app.settings.audit,
+            &event(util::now_epoch(), "queue"),
+        )
+        .await
+        .unwrap();
+        assert!(events(&app, None, None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn old_events_are_dropped_and_the_export_filters_and_pages() {
+        let app = TestApp::new("[audit]\nretention_days = 1\n").await;
+        let now = util::now_epoch();
+        record(
+            app.queue.db.write(),
+            &app.settings.audit,
+            &event(now - 2 * 86_400, "queue"),
+        )
+        .await
+        .unwrap();
+        for i in 0..1_200 {
+            let kind = if i % 3 == 0 { "settings" } else { "queue" };
+            record(
+                app.queue.db.write(),
+                &app.settings.audit,
+                &event(now - 1_200 + i, kind),
+            )
+            .await
+            .unwrap();
+        }
+
+        let all = events(&app, None, None).await;
+        assert_eq!(all.len(), 1_200);
+        assert!(all.windows(2).all(|w| w[0].id < w[1].id));
+        assert_eq!(events(&app, None, Some("settings")).await.len(), 400);
+        assert_eq!(events(&app, Some(now - 100), Some("queue")).await.len(), 67);
+    }
+}
@@ -0,0 +1,85 @@
+//! One-shot subcommands run instead of the server:
+//!
+//! ```text
+//! twitch_obs_queue import --format csv|streamlabs --file PATH [--dry-run] [--offline]
+//! twitch_obs_queue resolve [--dry-run]
+//! ```
+//!
+//! They use the same `CONFIG` and database as the server and print a JSON report.
+
+use anyhow::Context;
+
+use crate::{history_import, AppState};
+
+#[derive(Debug)]
+pub enum Command {
+    /// See [`history_import::import_history`].
+    Import {
+        format: String,
+        file: String,
+        opts: history_import::ImportOptions,
+    },
+    /// See [`history_import::resolve_pending`].
+    Resolve { dry_run: bool },
+}
+
+fn usage() -> String {
+    format!(
+        "usage:\n  twitch_obs_queue import --format {} --file PATH [--dry-run] [--offline]\n  twitch_obs_queue resolve [--dry-run]",
+        history_import::format_names().join("|")
+    )
+}
+
+/// `None` without arguments (run the server).
+pub fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Option<Command>> {
+    let Some(sub) = args.next() else {
+        return Ok(None);
+    };
+    let mut format = None;
+    let mut file = None;
+    let mut dry_run = false;
+    let mut offline = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = args.next(),
+            "--file" => file = args.next(),
+            "--dry-run" => dry_run = true,
+            "--offline" => offline = true,
+            other => anyhow::bail!("unknown argument {other:?}\n{}", usage()),
+        }
+    }
+    match sub.as_str() {
+        "import" => {
+            let (Some(format), Some(file)) = (format, file) else {
+                anyhow::bail!("import needs --format and --file\n{}", usage());
+            };
+            if history_import::adapter(&format).is_none() {
+                anyhow::bail!("unknown format {format:?}\n{}", usage());
+            }
+            let opts = history_import::ImportOptions { dry_run, offline };
+            Ok(Some(Command::Import { format, file, opts }))
+        }
+        "resolve" if format.is_none() && file.is_none() && !offline => {
+            Ok(Some(Command::Resolve { dry_run }))
+        }
+        _ => anyhow::bail!("{}", usage()),
+    }
+}
+
+pub async fn run(state: &AppState, cmd: Command) -> anyhow::Result<()> {
+    let report = match cmd {
+        Command::Import { format, file, opts } => {
+            let adapter = history_import::adapter(&format).context("unknown format")?;
+            let input =
+                std::fs::read_to_string(&file).with_context(|| format!("failed to read {file}"))?;
+            serde_json::to_string_pretty(
+                &history_import::import_history(state, adapter, &input, opts).await?,
+            )?
+        }
+        Command::Resolve { dry_run } => {
+            serde_json::to_string_pretty(&history_import::resolve_pending(state, dry_run).await?)?
+        }
+    };
+    println!("{report}");
+    Ok(())
+}
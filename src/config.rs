@@ -1,6 +1,15 @@
-use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-#[derive(Debug, Clone, Deserialize)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub server: ServerConfig,
@@ -8,18 +17,252 @@ pub struct Config {
     pub twitch: TwitchConfig,
     #[serde(default)]
     pub queue: QueueConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub overlay: OverlayConfig,
+    #[serde(default)]
+    pub ingest: IngestConfig,
+    #[serde(default)]
+    pub chat: ChatConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    /// Dotted keys set in the config file (see `diagnostics`); filled by [`Config::load`].
+    #[serde(skip)]
+    pub file_keys: std::collections::BTreeSet<String>,
+}
+
+/// The loaded config plus the switches that last until restart; cheap to clone and
+/// shared by every capability in `AppState`. Derefs to [`Config`].
+#[derive(Debug, Clone)]
+pub struct Settings {
+    config: Arc<Config>,
+    /// Ignore viewers' notification preferences (`POST /api/prefs/override`).
+    prefs_overridden: Arc<AtomicBool>,
+}
+
+impl Settings {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config: Arc::new(config),
+            prefs_overridden: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn prefs_overridden(&self) -> bool {
+        self.prefs_overridden.load(Ordering::Relaxed)
+    }
+
+    pub fn set_prefs_overridden(&self, overridden: bool) {
+        self.prefs_overridden.store(overridden, Ordering::Relaxed);
+    }
+}
+
+impl Deref for Settings {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        &self.config
+    }
 }
 
 impl Config {
     pub fn load(path: &str) -> anyhow::Result<Self> {
         let bytes = std::fs::read(path)?;
-        let s = std::str::from_utf8(&bytes)?;
-        let cfg: Config = toml::from_str(s)?;
+        Self::parse(std::str::from_utf8(&bytes)?)
+    }
+
+    /// [`Config::load`] without the file: parses, normalizes and validates `s`.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut cfg: Config = toml::from_str(s)?;
+        let file_table: toml::Table = toml::from_str(s)?;
+        cfg.file_keys = crate::diagnostics::leaf_values(&serde_json::to_value(file_table)?)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        // The legacy key keeps its value so diagnostics show what the file said.
+        let legacy_id = cfg.twitch.target_reward_id.clone();
+        if !legacy_id.trim().is_empty()
+            && !cfg
+                .twitch
+                .target_reward_ids
+                .iter()
+                .any(|id| id.trim() == legacy_id.trim())
+        {
+            cfg.twitch.target_reward_ids.push(legacy_id);
+            cfg.file_keys.insert("twitch.target_reward_ids".to_string());
+        }
+        if let (None, Some(legacy_size)) = (cfg.queue.max_queue_size, cfg.queue.max_size) {
+            cfg.queue.max_queue_size = Some(legacy_size).filter(|&n| n > 0);
+            cfg.file_keys.insert("queue.max_queue_size".to_string());
+        }
+        if cfg.twitch.normalize_redirect_url {
+            cfg.twitch.redirect_url = normalize_redirect_url(&cfg.twitch.redirect_url);
+        }
+        cfg.queue.aging_priority_cap = cfg.max_reward_priority();
+        cfg.validate()?;
         Ok(cfg)
     }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.server.viewer_password.is_empty() {
+            if self.server.admin_password.is_empty() {
+                anyhow::bail!(
+                    "server.viewer_password needs server.admin_password to be set as well"
+                );
+            }
+            if self.server.viewer_password == self.server.admin_password {
+                anyhow::bail!("server.viewer_password must differ from server.admin_password");
+            }
+        }
+        if self.server.require_overlay_token && self.server.admin_password.is_empty() {
+            // Without a password every request is already an admin one.
+            anyhow::bail!(
+                "server.require_overlay_token needs server.admin_password to be set as well"
+            );
+        }
+
+        let mut chat_commands: Vec<String> = [
+            self.twitch.chat_join_command.as_str(),
+            self.chat.leave_command.as_str(),
+            self.chat.position_command.as_str(),
+        ]
+        .iter()
+        .map(|c| c.trim().to_ascii_lowercase())
+        .filter(|c| !c.is_empty())
+        .collect();
+        let configured = chat_commands.len();
+        chat_commands.sort_unstable();
+        chat_commands.dedup();
+        if chat_commands.len() != configured {
+            anyhow::bail!("twitch.chat_join_command, chat.leave_command and chat.position_command must differ");
+        }
+
+        let needed = self.required_subscription_count();
+        let limit = self.twitch.max_eventsub_subscriptions;
+        if needed > limit {
+            anyhow::bail!(
+                "twitch.target_reward_ids + cancel_reward_id + away_reward_id (+ chat commands) need {needed} EventSub subscriptions, \
+                 but twitch.max_eventsub_subscriptions is {limit}. Reduce the number of reward IDs."
+            );
+        }
+
+        let mut unknown: Vec<&str> = self
+            .twitch
+            .reward_policies
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| !self.twitch.target_reward_ids.iter().any(|t| t.trim() == *k))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort_unstable();
+            anyhow::bail!(
+                "twitch.reward_policies has reward IDs that are not in twitch.target_reward_ids: {}",
+                unknown.join(", ")
+            );
+        }
+
+        let tiers = &self.queue.tiers;
+        // Names become CSS classes (`tier-<name>`) on the overlay.
+        let css_safe = |n: &str| {
+            !n.is_empty()
+                && n.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        if let Some(t) = tiers.iter().find(|t| !css_safe(&t.name)) {
+            anyhow::bail!(
+                "queue.tiers name {:?} must be non-empty and use only A-Z, a-z, 0-9, - and _",
+                t.name
+            );
+        }
+        if tiers
+            .windows(2)
+            .any(|w| w[0].min_participations >= w[1].min_participations)
+        {
+            anyhow::bail!("queue.tiers must be ordered by strictly increasing min_participations");
+        }
+        // Seen nonces live in processed_messages; they must outlive the accepted timestamps.
+        if !self.ingest.secret.is_empty()
+            && self.queue.processed_message_ttl_secs < 2 * self.ingest.max_clock_skew_secs
+        {
+            anyhow::bail!("queue.processed_message_ttl_secs must be at least twice ingest.max_clock_skew_secs");
+        }
+        if self.queue.processed_message_cleanup_interval_secs == 0 {
+            anyhow::bail!("queue.processed_message_cleanup_interval_secs must be positive");
+        }
+        if self.queue.max_queue_size == Some(0) {
+            anyhow::bail!("queue.max_queue_size must be positive; leave it out for no limit");
+        }
+        if self.queue.aging_interval_secs > 0 && self.queue.aging_increment <= 0 {
+            anyhow::bail!(
+                "queue.aging_increment must be positive when queue.aging_interval_secs is set"
+            );
+        }
+
+        let mut unknown_cues: Vec<&str> = self
+            .overlay
+            .cues
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| !crate::cues::CueKind::ALL.iter().any(|c| c.as_str() == *k))
+            .collect();
+        if !unknown_cues.is_empty() {
+            unknown_cues.sort_unstable();
+            anyhow::bail!(
+                "overlay.cues has unknown cue names: {}",
+                unknown_cues.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Highest priority any reward (or the global policy) grants.
+    pub fn max_reward_priority(&self) -> i64 {
+        self.twitch
+            .reward_policies
+            .values()
+            .filter_map(|o| o.priority)
+            .fold(self.queue.default_policy().priority, i64::max)
+    }
+
+    /// Effective queue policy for a redemption of `reward_id`:
+    /// the reward's overrides, falling back to the global `[queue]` values.
+    pub fn policy_for(&self, reward_id: Option<&str>) -> QueuePolicy {
+        let base = self.queue.default_policy();
+        match reward_id.and_then(|id| self.twitch.reward_policies.get(id)) {
+            Some(o) => o.apply_to(base),
+            None => base,
+        }
+    }
+
+    /// Whether a chat command is in use (joining, `chat.leave_command`,
+    /// `chat.position_command`), which subscribes to channel.chat.message and needs the
+    /// `user:read:chat` scope. `!queue optout` / `optin` work whenever this holds.
+    pub fn reads_chat(&self) -> bool {
+        (self.twitch.chat_join_command().is_some() && self.queue.accepts(EnqueueSource::Chat))
+            || self.chat.leave_command().is_some()
+            || self.chat.position_command().is_some()
+    }
+
+    /// [`TwitchConfig::required_subscription_count`] plus channel.chat.message. Without
+    /// `"redemption"` in `queue.enqueue_sources` no reward is subscribed to.
+    pub fn required_subscription_count(&self) -> usize {
+        let twitch = if self.queue.accepts(EnqueueSource::Redemption) {
+            self.twitch.required_subscription_count()
+        } else {
+            // stream.online and channel.raid only.
+            1 + usize::from(self.twitch.raid_pause_secs > 0)
+        };
+        twitch + usize::from(self.reads_chat())
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     #[serde(default = "default_bind")]
     pub bind: String,
@@ -27,6 +270,44 @@ pub struct ServerConfig {
     pub static_dir: String,
     #[serde(default = "default_db_path")]
     pub db_path: String,
+    /// Connections in the read-only pool used by `/api/queue` and `/api/status`.
+    /// 0 sends reads through the single write connection as well.
+    #[serde(default = "default_read_pool_max_connections")]
+    pub read_pool_max_connections: u32,
+    /// How many `<db_path>.pre-migrate-*` backups to keep.
+    #[serde(default = "default_db_backup_keep")]
+    pub db_backup_keep: usize,
+    /// Repeated errors in the reconnect / token refresh paths are logged at most
+    /// once per this many seconds (with a count of suppressed repeats).
+    #[serde(default = "default_log_throttle_secs")]
+    pub log_throttle_secs: u64,
+    /// Queue mutations slower than this log a warning with a per-phase breakdown. 0 disables.
+    #[serde(default = "default_slow_mutation_warn_ms")]
+    pub slow_mutation_warn_ms: u64,
+    /// Largest request body accepted on `/api` routes; bigger bodies get a 413.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Body limit for the bulk import endpoints (`/api/queue/import`, `/api/stats/import`).
+    #[serde(default = "default_max_import_body_bytes")]
+    pub max_import_body_bytes: usize,
+    /// HTTP Basic auth password for `/admin`, `/auth/*` and `/api/*` (the overlay stays open).
+    /// Empty disables authentication.
+    #[serde(default)]
+    pub admin_password: String,
+    /// Second password with read-only access: mutating `/api/*` routes answer 403.
+    /// Requires `admin_password`.
+    #[serde(default)]
+    pub viewer_password: String,
+    /// The overlay (`/obs`, and the `/api/queue` / `/api/cues` polls) needs a signed
+    /// `?token=` (see `overlay_token`) or admin / viewer credentials.
+    #[serde(default)]
+    pub require_overlay_token: bool,
+    /// How long overlay URLs signed with the previous key keep working after a rotation.
+    #[serde(default = "default_overlay_token_overlap_secs")]
+    pub overlay_token_overlap_secs: u64,
+    /// Serve `GET /api/stats/public` (aggregates only) without credentials; 404 when off.
+    #[serde(default)]
+    pub public_stats: bool,
 }
 
 impl Default for ServerConfig {
@@ -35,6 +316,17 @@ impl Default for ServerConfig {
             bind: default_bind(),
             static_dir: default_static_dir(),
             db_path: default_db_path(),
+            read_pool_max_connections: default_read_pool_max_connections(),
+            db_backup_keep: default_db_backup_keep(),
+            log_throttle_secs: default_log_throttle_secs(),
+            slow_mutation_warn_ms: default_slow_mutation_warn_ms(),
+            max_body_bytes: default_max_body_bytes(),
+            max_import_body_bytes: default_max_import_body_bytes(),
+            admin_password: String::new(),
+            viewer_password: String::new(),
+            require_overlay_token: false,
+            overlay_token_overlap_secs: default_overlay_token_overlap_secs(),
+            public_stats: false,
         }
     }
 }
@@ -51,7 +343,56 @@ fn default_db_path() -> String {
     "data/app.db".to_string()
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_read_pool_max_connections() -> u32 {
+    4
+}
+
+fn default_db_backup_keep() -> usize {
+    5
+}
+
+fn default_max_concurrent_helix_requests() -> usize {
+    4
+}
+
+fn default_max_reward_title_len() -> usize {
+    60
+}
+
+fn default_manual_order_gap_threshold() -> i64 {
+    2
+}
+
+fn default_log_throttle_secs() -> u64 {
+    60
+}
+
+fn default_enqueue_sources() -> Vec<EnqueueSource> {
+    vec![
+        EnqueueSource::Redemption,
+        EnqueueSource::Manual,
+        EnqueueSource::Chat,
+        EnqueueSource::External,
+    ]
+}
+
+fn default_overlay_token_overlap_secs() -> u64 {
+    60 * 60
+}
+
+fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_import_body_bytes() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_slow_mutation_warn_ms() -> u64 {
+    250
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TwitchConfig {
     #[serde(default)]
     pub client_id: String,
@@ -64,14 +405,269 @@ pub struct TwitchConfig {
     #[serde(default)]
     pub target_reward_ids: Vec<String>,
 
-    /// If empty, cancel reward handling is disabled.
+    /// Single join reward, from before `target_reward_ids`; appended to it on load.
+    /// Read `target_reward_ids` instead.
+    #[serde(default)]
+    pub target_reward_id: String,
+
+    /// Redeeming this removes the user's item (see `cancel_reward_behavior`); it gets its
+    /// own EventSub subscription. From a user who is not queued it does nothing (see
+    /// `refund_unmatched_cancel`). If empty, cancel reward handling is disabled.
     #[serde(default)]
     pub cancel_reward_id: String,
 
+    /// Redeeming this toggles the viewer's item between away and back (see
+    /// `queue::set_away`). If empty, the away reward is disabled.
+    #[serde(default)]
+    pub away_reward_id: String,
+
+    /// Helix requests in flight at once across profile lookups, reward reads and
+    /// subscription management, so bursts stay under Twitch's rate limit.
+    #[serde(default = "default_max_concurrent_helix_requests")]
+    pub max_concurrent_helix_requests: usize,
+
+    /// What redeeming `cancel_reward_id` does to the user's queued item.
+    #[serde(default)]
+    pub cancel_reward_behavior: CancelRewardBehavior,
+
+    /// Refund (cancel on Twitch) a cancel redemption from a user who is not queued.
+    /// Requires `update_redemption_status`.
+    #[serde(default)]
+    pub refund_unmatched_cancel: bool,
+
+    /// Subscribe to `channel.raid` and pause enqueueing for this many seconds after the
+    /// channel is raided, so a flood of new viewers does not fill the queue at once. 0 disables.
+    #[serde(default)]
+    pub raid_pause_secs: u64,
+
+    /// Chat command (e.g. `!join`) that enqueues the viewer like a join redemption,
+    /// with the global queue policy. Needs the `user:read:chat` scope, one more EventSub
+    /// subscription and `"chat"` in `queue.enqueue_sources`. If empty, chat joins are disabled.
+    #[serde(default)]
+    pub chat_join_command: String,
+
     /// Cache TTL for user profiles (profile image URL) in seconds.
     /// Set 0 to always fetch from Helix.
     #[serde(default = "default_user_cache_ttl_secs")]
     pub user_cache_ttl_secs: u64,
+
+    /// Hosts a `profile_image_url` may point to (https only). Other URLs are dropped and
+    /// the overlay shows the placeholder avatar. Empty allows any https host.
+    #[serde(default = "default_profile_image_hosts")]
+    pub profile_image_hosts: Vec<String>,
+
+    /// Consecutive Helix profile lookup failures (within `profile_breaker_window_secs`)
+    /// that stop further lookups for `profile_breaker_cooldown_secs`. While stopped,
+    /// enqueues use the cached image or an empty placeholder. 0 disables the breaker.
+    #[serde(default = "default_profile_breaker_failures")]
+    pub profile_breaker_failures: u32,
+    #[serde(default = "default_profile_breaker_window_secs")]
+    pub profile_breaker_window_secs: u64,
+    #[serde(default = "default_profile_breaker_cooldown_secs")]
+    pub profile_breaker_cooldown_secs: u64,
+    /// A profile lookup on the enqueue path is abandoned (and counted as a breaker
+    /// failure) after this many milliseconds. 0 leaves only `http.timeout_secs`.
+    #[serde(default = "default_profile_lookup_timeout_ms")]
+    pub profile_lookup_timeout_ms: u64,
+
+    /// Strip trailing slashes from `redirect_url` (e.g. `.../auth/callback/`) at load time.
+    /// The URL registered on the Twitch console must then match the normalized form.
+    #[serde(default)]
+    pub normalize_redirect_url: bool,
+
+    /// Upper bound on EventSub subscriptions this app will create (one per reward ID).
+    /// Twitch allows 300 enabled subscriptions per WebSocket session.
+    #[serde(default = "default_max_eventsub_subscriptions")]
+    pub max_eventsub_subscriptions: usize,
+
+    /// Mark redemptions FULFILLED / CANCELED on Twitch when items are completed / canceled.
+    /// Needs the `channel:manage:redemptions` scope and only works for rewards created by this client_id.
+    #[serde(default)]
+    pub update_redemption_status: bool,
+
+    /// Keep redemption status updates pending until `POST /api/redemptions/flush`
+    /// (e.g. at stream end) instead of sending them one by one.
+    #[serde(default)]
+    pub defer_redemption_updates: bool,
+
+    /// Per-reward overrides of the `[queue]` policy, keyed by reward ID.
+    #[serde(default)]
+    pub reward_policies: HashMap<String, RewardPolicyOverride>,
+
+    /// Badge text shown on the overlay per reward ID (e.g. "🎮 Game").
+    #[serde(default)]
+    pub reward_labels: HashMap<String, String>,
+
+    /// Prompt text pushed to each target reward on Twitch, rendered from its effective
+    /// policy (see `reward_prompt::render`). Empty disables the sync.
+    /// Needs the `channel:manage:redemptions` scope and rewards created by this client_id.
+    #[serde(default)]
+    pub reward_prompt_template: String,
+
+    /// When the queue takes entries, as free text for `{open_hours}` in
+    /// `reward_prompt_template` (e.g. "20:00-23:00"). Empty renders as "随時".
+    #[serde(default)]
+    pub reward_prompt_open_hours: String,
+
+    /// Pause the join rewards on Twitch while the queue is paused, so viewers cannot
+    /// spend points on them. Needs the `channel:manage:redemptions` scope and rewards
+    /// created by this client_id. Updates go through the outbox and are retried there.
+    #[serde(default)]
+    pub pause_rewards_with_queue: bool,
+
+    /// Reward titles longer than this (in characters) are cut with `…` in logs and
+    /// API responses. 0 disables truncation.
+    #[serde(default = "default_max_reward_title_len")]
+    pub max_reward_title_len: usize,
+
+    /// What happens to the previous channel's data when a login as a different
+    /// account is confirmed (`POST /api/auth/confirm_switch`).
+    #[serde(default)]
+    pub on_broadcaster_switch: BroadcasterSwitchData,
+}
+
+/// Ways an item can enter the queue (`queue.enqueue_sources`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnqueueSource {
+    /// Channel point redemptions via EventSub.
+    Redemption,
+    /// The admin API (`POST /api/queue/add`, `POST /api/queue/import`).
+    Manual,
+    /// Chat commands.
+    Chat,
+    /// Signed requests from other tools (`POST /api/ingest/enqueue`).
+    External,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OverlayConfig {
+    /// Cue name (e.g. `user_joined`) to enabled. Cues not listed are enabled.
+    #[serde(default)]
+    pub cues: HashMap<String, bool>,
+}
+
+impl OverlayConfig {
+    pub fn cue_enabled(&self, kind: &str) -> bool {
+        self.cues.get(kind).copied().unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelRewardBehavior {
+    /// Remove the item, refunding its join redemption.
+    #[default]
+    Remove,
+    /// Keep the item but move it to the last position, so a mistaken cancel
+    /// does not cost a join that cannot be redeemed again.
+    MoveToBack,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcasterSwitchData {
+    /// Copy the DB next to it (`<db_path>.broadcaster-<id>-<ts>`), then wipe.
+    #[default]
+    Archive,
+    Wipe,
+    Keep,
+}
+
+/// How automatic placement orders users with the same priority and participation count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueTiebreak {
+    /// After everyone already waiting with the same count.
+    #[default]
+    Insertion,
+    /// Ahead of equal-count users who played more recently; never played sorts first.
+    OldestLastCompletion,
+}
+
+/// Queue rules applied to one enqueue.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct QueuePolicy {
+    /// Reject if the user completed a turn less than this many seconds ago. 0 disables.
+    pub cooldown_secs: u64,
+    /// Reject once the user has this many completed turns in the window. 0 disables.
+    pub max_participations_per_window: u32,
+    /// Reject if the user already entered (completed or not) within the window.
+    pub one_entry_per_window: bool,
+    /// Per-reward cooldown measured from the user's last completed turn from the same
+    /// reward. Replaces `cooldown_secs` when set; 0 disables.
+    pub rejoin_cooldown_secs: Option<u64>,
+    /// Higher priority is placed ahead of lower priority regardless of participation count.
+    pub priority: i64,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RewardPolicyOverride {
+    #[serde(default)]
+    pub cooldown_secs: Option<u64>,
+    #[serde(default)]
+    pub rejoin_cooldown_secs: Option<u64>,
+    #[serde(default)]
+    pub max_participations_per_window: Option<u32>,
+    #[serde(default)]
+    pub one_entry_per_window: Option<bool>,
+    #[serde(default)]
+    pub priority: Option<i64>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+impl RewardPolicyOverride {
+    fn apply_to(&self, base: QueuePolicy) -> QueuePolicy {
+        QueuePolicy {
+            cooldown_secs: self.cooldown_secs.unwrap_or(base.cooldown_secs),
+            rejoin_cooldown_secs: self.rejoin_cooldown_secs.or(base.rejoin_cooldown_secs),
+            max_participations_per_window: self
+                .max_participations_per_window
+                .unwrap_or(base.max_participations_per_window),
+            one_entry_per_window: self
+                .one_entry_per_window
+                .unwrap_or(base.one_entry_per_window),
+            priority: self.priority.unwrap_or(base.priority),
+            tags: self.tags.clone().unwrap_or(base.tags),
+        }
+    }
+}
+
+impl TwitchConfig {
+    /// Whether a feature that writes to Twitch rewards / redemptions is enabled.
+    pub fn needs_manage_scope(&self) -> bool {
+        self.update_redemption_status
+            || !self.reward_prompt_template.trim().is_empty()
+            || self.pause_rewards_with_queue
+    }
+
+    /// `chat_join_command`, trimmed, or `None` when chat joins are disabled.
+    pub fn chat_join_command(&self) -> Option<&str> {
+        Some(self.chat_join_command.trim()).filter(|c| !c.is_empty())
+    }
+
+    /// Number of subscriptions needed for the configured rewards
+    /// (unique, non-blank join IDs plus the cancel ID if set), plus stream.online
+    /// and channel.raid when `raid_pause_secs` is set. channel.chat.message is
+    /// counted by [`Config::required_subscription_count`].
+    pub fn required_subscription_count(&self) -> usize {
+        let mut ids: Vec<&str> = self
+            .target_reward_ids
+            .iter()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        let cancel = self.cancel_reward_id.trim();
+        let cancel_extra = usize::from(!cancel.is_empty() && !ids.contains(&cancel));
+        let away = self.away_reward_id.trim();
+        let away_extra = usize::from(!away.is_empty() && away != cancel && !ids.contains(&away));
+        ids.len() + cancel_extra + away_extra + 1 + usize::from(self.raid_pause_secs > 0)
+    }
 }
 
 impl Default for TwitchConfig {
@@ -80,9 +676,32 @@ impl Default for TwitchConfig {
             client_id: String::new(),
             client_secret: String::new(),
             redirect_url: default_redirect_url(),
+            normalize_redirect_url: false,
             target_reward_ids: Vec::new(),
+            target_reward_id: String::new(),
             cancel_reward_id: String::new(),
+            away_reward_id: String::new(),
+            max_concurrent_helix_requests: default_max_concurrent_helix_requests(),
+            cancel_reward_behavior: CancelRewardBehavior::default(),
+            refund_unmatched_cancel: false,
+            raid_pause_secs: 0,
+            chat_join_command: String::new(),
             user_cache_ttl_secs: default_user_cache_ttl_secs(),
+            profile_image_hosts: default_profile_image_hosts(),
+            profile_breaker_failures: default_profile_breaker_failures(),
+            profile_breaker_window_secs: default_profile_breaker_window_secs(),
+            profile_breaker_cooldown_secs: default_profile_breaker_cooldown_secs(),
+            profile_lookup_timeout_ms: default_profile_lookup_timeout_ms(),
+            max_eventsub_subscriptions: default_max_eventsub_subscriptions(),
+            update_redemption_status: false,
+            defer_redemption_updates: false,
+            reward_policies: HashMap::new(),
+            reward_labels: HashMap::new(),
+            reward_prompt_template: String::new(),
+            reward_prompt_open_hours: String::new(),
+            pause_rewards_with_queue: false,
+            max_reward_title_len: default_max_reward_title_len(),
+            on_broadcaster_switch: BroadcasterSwitchData::default(),
         }
     }
 }
@@ -91,24 +710,239 @@ fn default_redirect_url() -> String {
     "http://localhost:3000/auth/callback".to_string()
 }
 
+/// Path of the OAuth callback route served by this app.
+pub const AUTH_CALLBACK_PATH: &str = "/auth/callback";
+
+fn normalize_redirect_url(raw: &str) -> String {
+    let trimmed = raw.trim();
+    match url::Url::parse(trimmed) {
+        Ok(mut u) if u.path().len() > 1 && u.path().ends_with('/') => {
+            let path = u.path().trim_end_matches('/').to_string();
+            u.set_path(&path);
+            u.to_string()
+        }
+        _ => trimmed.to_string(),
+    }
+}
+
+fn default_profile_image_hosts() -> Vec<String> {
+    vec!["static-cdn.jtvnw.net".to_string()]
+}
+
 fn default_user_cache_ttl_secs() -> u64 {
     24 * 60 * 60
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_profile_breaker_failures() -> u32 {
+    5
+}
+
+fn default_profile_breaker_window_secs() -> u64 {
+    60
+}
+
+fn default_profile_breaker_cooldown_secs() -> u64 {
+    120
+}
+
+fn default_profile_lookup_timeout_ms() -> u64 {
+    2_000
+}
+
+fn default_max_eventsub_subscriptions() -> usize {
+    300
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueueConfig {
     #[serde(default = "default_participation_window_secs")]
     pub participation_window_secs: u64,
 
+    /// Enabled entry points. Without "redemption" no reward is subscribed to, so the tool
+    /// works before any reward is set up; chat commands and raids still come in over EventSub.
+    #[serde(default = "default_enqueue_sources")]
+    pub enqueue_sources: Vec<EnqueueSource>,
+
+    /// New entries are turned away (`EnqueueOutcome::QueueFull`) once this many viewers
+    /// are waiting, held entries of a freeze included. Unset = unlimited.
+    #[serde(default)]
+    pub max_queue_size: Option<usize>,
+
+    /// Cap from before `max_queue_size`, with 0 for unlimited; moved to it on load unless
+    /// `max_queue_size` is set. Read `max_queue_size` instead.
+    #[serde(default)]
+    pub max_size: Option<usize>,
+
     #[serde(default = "default_processed_message_ttl_secs")]
     pub processed_message_ttl_secs: u64,
+
+    /// How often expired `processed_messages` rows are swept.
+    #[serde(default = "default_processed_message_cleanup_interval_secs")]
+    pub processed_message_cleanup_interval_secs: u64,
+
+    /// Sweep right away once this many ids were recorded since the last sweep
+    /// (bursts such as raids). 0 leaves only the interval.
+    #[serde(default = "default_processed_message_cleanup_threshold")]
+    pub processed_message_cleanup_threshold: u64,
+
+    /// Average length of one turn, used for `estimated_start_at`. 0 disables the estimate.
+    #[serde(default)]
+    pub seconds_per_item: u64,
+
+    /// If no stream.online has been seen yet, items older than this many hours
+    /// count as "from a previous session". 0 disables the fallback.
+    #[serde(default = "default_previous_session_fallback_hours")]
+    pub previous_session_fallback_hours: u64,
+
+    /// When two queued items share a display name, append the login to both.
+    #[serde(default = "default_true")]
+    pub disambiguate_duplicate_names: bool,
+
+    /// Global policy; each can be overridden per reward in `twitch.reward_policies`.
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    #[serde(default)]
+    pub max_participations_per_window: u32,
+    #[serde(default)]
+    pub one_entry_per_window: bool,
+
+    /// Whenever someone else takes position 0 (moved up, moved to the top, or a new entry
+    /// placed first), whoever held it is completed.
+    #[serde(default)]
+    pub complete_on_advance: bool,
+
+    /// Completing an item only marks it for this many seconds; it can be restored with
+    /// `abort_complete` until then, and the participation record and redemption update
+    /// happen when the grace period ends. 0 completes immediately.
+    #[serde(default)]
+    pub complete_grace_secs: u64,
+
+    /// Pause enqueueing while the OBS overlay has not polled for this many seconds. 0 disables.
+    #[serde(default)]
+    pub overlay_heartbeat_timeout_secs: u64,
+
+    /// Join redemptions dropped while enqueueing is paused are listed for one-click
+    /// admission (`GET /api/pending_interest`) for this long. 0 does not record them.
+    #[serde(default = "default_pending_interest_ttl_secs")]
+    pub pending_interest_ttl_secs: u64,
+
+    /// Notification preferences of viewers who never used `!queue optout` / `!queue optin`
+    /// (see `prefs`).
+    #[serde(default = "default_true")]
+    pub default_notify: bool,
+
+    /// Strip control / bidi-override characters from display names before storing them.
+    /// The raw name is kept in `queue_items.display_name_raw` either way.
+    #[serde(default = "default_true")]
+    pub sanitize_display_names: bool,
+
+    /// Automatic placement keeps new items below the deepest item an admin moved up,
+    /// unless that item has at least this many more recent participations than the
+    /// newcomer. 0 disables the rule.
+    #[serde(default = "default_manual_order_gap_threshold")]
+    pub manual_order_gap_threshold: i64,
+
+    /// Order among users with equal priority and recent participation count.
+    #[serde(default)]
+    pub tiebreak: QueueTiebreak,
+
+    /// Target for the per-stream unique participant counter (on-stream goal). 0 = no goal.
+    #[serde(default)]
+    pub unique_participants_goal: u64,
+
+    /// Labels for `recent_participation_count` (e.g. new / occasional / regular), shown as
+    /// `tier` on queue items. An item gets the last tier whose minimum it reaches.
+    #[serde(default = "default_tiers")]
+    pub tiers: Vec<QueueTier>,
+
+    /// Every this many seconds of waiting adds `aging_increment` to an item's effective
+    /// priority, up to the highest reward priority. 0 disables aging.
+    #[serde(default)]
+    pub aging_interval_secs: u64,
+    #[serde(default = "default_aging_increment")]
+    pub aging_increment: i64,
+    /// Upper bound for aged priorities; set from [`Config::max_reward_priority`] on load.
+    #[serde(skip)]
+    pub aging_priority_cap: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueTier {
+    pub name: String,
+    /// Lowest `recent_participation_count` in this tier.
+    pub min_participations: i64,
+}
+
+fn default_aging_increment() -> i64 {
+    1
+}
+
+fn default_tiers() -> Vec<QueueTier> {
+    [("new", 0), ("occasional", 1), ("regular", 3)]
+        .into_iter()
+        .map(|(name, min_participations)| QueueTier {
+            name: name.to_string(),
+            min_participations,
+        })
+        .collect()
+}
+
+impl QueueConfig {
+    pub fn accepts(&self, source: EnqueueSource) -> bool {
+        self.enqueue_sources.contains(&source)
+    }
+
+    /// Tier for a participation count; `None` below the first tier or with no tiers.
+    pub fn tier_for(&self, recent_participation_count: i64) -> Option<&str> {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|t| recent_participation_count >= t.min_participations)
+            .map(|t| t.name.as_str())
+    }
+
+    pub fn default_policy(&self) -> QueuePolicy {
+        QueuePolicy {
+            cooldown_secs: self.cooldown_secs,
+            rejoin_cooldown_secs: None,
+            max_participations_per_window: self.max_participations_per_window,
+            one_entry_per_window: self.one_entry_per_window,
+            priority: 0,
+            tags: Vec::new(),
+        }
+    }
 }
 
 impl Default for QueueConfig {
     fn default() -> Self {
         Self {
             participation_window_secs: default_participation_window_secs(),
+            enqueue_sources: default_enqueue_sources(),
+            max_queue_size: None,
+            max_size: None,
             processed_message_ttl_secs: default_processed_message_ttl_secs(),
+            processed_message_cleanup_interval_secs:
+                default_processed_message_cleanup_interval_secs(),
+            processed_message_cleanup_threshold: default_processed_message_cleanup_threshold(),
+            seconds_per_item: 0,
+            previous_session_fallback_hours: default_previous_session_fallback_hours(),
+            disambiguate_duplicate_names: true,
+            cooldown_secs: 0,
+            max_participations_per_window: 0,
+            one_entry_per_window: false,
+            complete_on_advance: false,
+            complete_grace_secs: 0,
+            overlay_heartbeat_timeout_secs: 0,
+            pending_interest_ttl_secs: default_pending_interest_ttl_secs(),
+            default_notify: true,
+            sanitize_display_names: true,
+            manual_order_gap_threshold: default_manual_order_gap_threshold(),
+            tiebreak: QueueTiebreak::default(),
+            unique_participants_goal: 0,
+            tiers: default_tiers(),
+            aging_interval_secs: 0,
+            aging_increment: default_aging_increment(),
+            aging_priority_cap: 0,
         }
     }
 }
@@ -120,3 +954,379 @@ fn default_participation_window_secs() -> u64 {
 fn default_processed_message_ttl_secs() -> u64 {
     24 * 60 * 60
 }
+
+fn default_processed_message_cleanup_interval_secs() -> u64 {
+    10 * 60
+}
+
+fn default_processed_message_cleanup_threshold() -> u64 {
+    5000
+}
+
+fn default_pending_interest_ttl_secs() -> u64 {
+    2 * 60 * 60
+}
+
+fn default_previous_session_fallback_hours() -> u64 {
+    12
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxConfig {
+    /// How often the dispatcher looks for pending side effects.
+    #[serde(default = "default_outbox_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+
+    /// Give up (status = failed) after this many attempts.
+    #[serde(default = "default_outbox_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: default_outbox_poll_interval_secs(),
+            max_attempts: default_outbox_max_attempts(),
+        }
+    }
+}
+
+fn default_outbox_poll_interval_secs() -> u64 {
+    2
+}
+
+fn default_outbox_max_attempts() -> u32 {
+    8
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertsConfig {
+    /// Discord-compatible webhook URL. Empty disables alerts.
+    #[serde(default)]
+    pub webhook_url: String,
+
+    /// Alert once after this many consecutive token refresh failures. 0 disables.
+    #[serde(default = "default_token_failure_threshold")]
+    pub token_failure_threshold: u32,
+
+    /// Sent to `webhook_url` when a redemption or chat join enters the queue; same
+    /// placeholders as `chat.join_announce`. Empty disables.
+    #[serde(default)]
+    pub join_message: String,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            webhook_url: String::new(),
+            token_failure_threshold: default_token_failure_threshold(),
+            join_message: String::new(),
+        }
+    }
+}
+
+fn default_token_failure_threshold() -> u32 {
+    3
+}
+
+/// Log of admin API mutations (`audit`), exported by `GET /api/events/export.ndjson`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Events older than this are dropped. 0 keeps them forever.
+    #[serde(default = "default_audit_retention_days")]
+    pub retention_days: u64,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: default_audit_retention_days(),
+        }
+    }
+}
+
+fn default_audit_retention_days() -> u64 {
+    90
+}
+
+/// Inbound enqueue requests from other tools (`POST /api/ingest/enqueue`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestConfig {
+    /// Shared HMAC-SHA256 key for request signatures. Empty disables the endpoint.
+    #[serde(default)]
+    pub secret: String,
+
+    /// Reject requests whose `timestamp` is further than this from the server clock.
+    #[serde(default = "default_ingest_max_clock_skew_secs")]
+    pub max_clock_skew_secs: u64,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            max_clock_skew_secs: default_ingest_max_clock_skew_secs(),
+        }
+    }
+}
+
+fn default_ingest_max_clock_skew_secs() -> u64 {
+    5 * 60
+}
+
+/// Chat commands other than joining. They are read from the channel.chat.message
+/// subscription, which exists while any chat command is set (see [`Config::reads_chat`]).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatConfig {
+    /// Removes the sender's own item, like the cancel reward but without one. Needs the
+    /// `user:read:chat` scope. Empty (the default) disables.
+    #[serde(default)]
+    pub leave_command: String,
+
+    /// Repeats of `leave_command` from the same user within this many seconds are ignored.
+    #[serde(default = "default_chat_leave_cooldown_secs")]
+    pub leave_cooldown_secs: u64,
+
+    /// Replies in chat with the sender's place in the queue. Needs the `user:read:chat`
+    /// and `user:write:chat` scopes. Empty (the default) disables.
+    #[serde(default)]
+    pub position_command: String,
+
+    /// Reply to `position_command` for a queued viewer. Placeholders: `{user}` (display
+    /// name), `{position}` (1-based), `{ahead}` (people in front), `{total}` (queue length).
+    #[serde(default = "default_chat_position_reply")]
+    pub position_reply: String,
+
+    /// Reply to `position_command` for a viewer who is not queued. Placeholders: `{user}`, `{total}`.
+    #[serde(default = "default_chat_position_not_queued_reply")]
+    pub position_not_queued_reply: String,
+
+    /// Repeats of `position_command` from the same user within this many seconds are ignored.
+    #[serde(default = "default_chat_position_cooldown_secs")]
+    pub position_cooldown_secs: u64,
+
+    /// Posted in chat when a redemption or chat join enters the queue. Placeholders:
+    /// `{user}`, `{position}` (1-based), `{ahead}`, `{total}`, `{wait_min}`, `{priority}`.
+    /// Needs `user:write:chat`. Empty disables.
+    #[serde(default)]
+    pub join_announce: String,
+
+    /// Whispered to the viewer when their redemption or chat join enters the queue; same
+    /// placeholders as `join_announce`. Needs `user:manage:whispers`. Empty disables.
+    #[serde(default)]
+    pub join_whisper: String,
+
+    /// Posted in chat when first-come-first-served mode starts (`POST /api/queue/ffa`).
+    /// `{limit}` becomes e.g. "10人・10分間". Needs `user:write:chat`. Empty disables.
+    #[serde(default)]
+    pub ffa_announce: String,
+
+    /// Resend over Twitch IRC when Helix answers a chat message with a 5xx. Adds the
+    /// `chat:edit` scope; checked at startup.
+    #[serde(default)]
+    pub irc_fallback: bool,
+}
+
+impl ChatConfig {
+    /// `leave_command`, trimmed, or `None` when disabled.
+    pub fn leave_command(&self) -> Option<&str> {
+        Some(self.leave_command.trim()).filter(|c| !c.is_empty())
+    }
+
+    /// `position_command`, trimmed, or `None` when disabled.
+    pub fn position_command(&self) -> Option<&str> {
+        Some(self.position_command.trim()).filter(|c| !c.is_empty())
+    }
+
+    /// A chat command or announcement posts to chat.
+    pub fn needs_write_scope(&self) -> bool {
+        !self.position_command.trim().is_empty()
+            || !self.join_announce.trim().is_empty()
+            || !self.ffa_announce.trim().is_empty()
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            leave_command: String::new(),
+            leave_cooldown_secs: default_chat_leave_cooldown_secs(),
+            position_command: String::new(),
+            position_reply: default_chat_position_reply(),
+            position_not_queued_reply: default_chat_position_not_queued_reply(),
+            position_cooldown_secs: default_chat_position_cooldown_secs(),
+            join_announce: String::new(),
+            join_whisper: String::new(),
+            ffa_announce: String::new(),
+            irc_fallback: false,
+        }
+    }
+}
+
+fn default_chat_leave_cooldown_secs() -> u64 {
+    5
+}
+
+fn default_chat_position_reply() -> String {
+    "{user} さんは {position} 番目です（前に {ahead} 人）".to_string()
+}
+
+fn default_chat_position_not_queued_reply() -> String {
+    "{user} さんは並んでいません（いま {total} 人待ち）".to_string()
+}
+
+fn default_chat_position_cooldown_secs() -> u64 {
+    10
+}
+
+/// Outbound HTTP client settings (Twitch API, OAuth, alert webhooks).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpConfig {
+    /// Proxy for all outbound requests, e.g. `http://proxy.example:8080`. Empty = direct.
+    #[serde(default)]
+    pub proxy_url: String,
+
+    /// Headers added to every outbound request.
+    #[serde(default)]
+    pub extra_headers: HashMap<String, String>,
+
+    /// Whole-request limit for every outbound request. 0 = no limit.
+    #[serde(default = "default_http_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Limit for establishing the connection. 0 = no limit.
+    #[serde(default = "default_http_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            proxy_url: String::new(),
+            extra_headers: HashMap::new(),
+            timeout_secs: default_http_timeout_secs(),
+            connect_timeout_secs: default_http_connect_timeout_secs(),
+        }
+    }
+}
+
+fn default_http_timeout_secs() -> u64 {
+    10
+}
+
+fn default_http_connect_timeout_secs() -> u64 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY_CONFIG: &str = r#"
+[queue]
+cooldown_secs = 600
+max_participations_per_window = 2
+one_entry_per_window = true
+
+[twitch]
+target_reward_ids = ["cheap", "priority", "tagged"]
+
+[twitch.reward_policies.priority]
+one_entry_per_window = false
+max_participations_per_window = 0
+priority = 10
+
+[twitch.reward_policies.tagged]
+cooldown_secs = 0
+rejoin_cooldown_secs = 3600
+tags = ["vip"]
+"#;
+
+    #[test]
+    fn reward_policies_inherit_unset_fields_from_queue() {
+        let cfg = Config::parse(POLICY_CONFIG).unwrap();
+        let global = QueuePolicy {
+            cooldown_secs: 600,
+            max_participations_per_window: 2,
+            one_entry_per_window: true,
+            rejoin_cooldown_secs: None,
+            priority: 0,
+            tags: vec![],
+        };
+        let cases = [
+            (None, global.clone()),
+            (Some("cheap"), global.clone()),
+            (Some("not-a-target"), global.clone()),
+            (
+                Some("priority"),
+                QueuePolicy {
+                    one_entry_per_window: false,
+                    max_participations_per_window: 0,
+                    priority: 10,
+                    ..global.clone()
+                },
+            ),
+            (
+                Some("tagged"),
+                QueuePolicy {
+                    cooldown_secs: 0,
+                    rejoin_cooldown_secs: Some(3600),
+                    tags: vec!["vip".to_string()],
+                    ..global.clone()
+                },
+            ),
+        ];
+        for (reward_id, expected) in cases {
+            assert_eq!(cfg.policy_for(reward_id), expected, "reward {reward_id:?}");
+        }
+        assert_eq!(cfg.max_reward_priority(), 10);
+    }
+
+    #[test]
+    fn legacy_max_size_still_caps_the_queue() {
+        let cap = |toml: &str| Config::parse(toml).unwrap().queue.max_queue_size;
+        assert_eq!(cap("[queue]\nmax_size = 30\n"), Some(30));
+        assert_eq!(cap("[queue]\nmax_size = 0\n"), None, "0 meant unlimited");
+        assert_eq!(
+            cap("[queue]\nmax_size = 30\nmax_queue_size = 10\n"),
+            Some(10)
+        );
+        assert_eq!(cap(""), None);
+        assert!(Config::parse("[queue]\nmax_queue_size = 0\n").is_err());
+    }
+
+    #[test]
+    fn override_wins_even_when_it_sets_the_default_value() {
+        let cfg = Config::parse(
+            "[queue]\ncooldown_secs = 600\n[twitch]\ntarget_reward_ids = [\"r\"]\n[twitch.reward_policies.r]\ncooldown_secs = 0\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.policy_for(Some("r")).cooldown_secs, 0);
+        assert_eq!(cfg.policy_for(None).cooldown_secs, 600);
+    }
+
+    #[test]
+    fn reward_policies_must_reference_target_rewards() {
+        let err = Config::parse(
+            "[twitch]\ntarget_reward_ids = [\"a\"]\n[twitch.reward_policies.b]\npriority = 1\n[twitch.reward_policies.c]\npriority = 2\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("not in twitch.target_reward_ids: b, c"),
+            "{err}"
+        );
+
+        // Surrounding whitespace in the target list still matches.
+        assert!(Config::parse(
+            "[twitch]\ntarget_reward_ids = [\" a \"]\n[twitch.reward_policies.a]\npriority = 1\n"
+        )
+        .is_ok());
+    }
+}
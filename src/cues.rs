@@ -0,0 +1,322 @@
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::config::OverlayConfig;
+
+/// Cues kept for replay; clients that fall further behind just miss the oldest.
+const REPLAY_BUFFER_LEN: i64 = 200;
+
+/// Named moments the overlay can react to (e.g. play a sound). Recorded in the
+/// same transaction as the mutation, so each logical event yields exactly one cue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CueKind {
+    UserJoined,
+    /// A different user is now at position 0.
+    UserUpNext,
+    /// The queue was thawed or resumed after a pause.
+    QueueOpened,
+}
+
+impl CueKind {
+    pub const ALL: [CueKind; 3] = [
+        CueKind::UserJoined,
+        CueKind::UserUpNext,
+        CueKind::QueueOpened,
+    ];
+
+    /// Key in `overlay.cues`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CueKind::UserJoined => "user_joined",
+            CueKind::UserUpNext => "user_up_next",
+            CueKind::QueueOpened => "queue_opened",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CuePayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile_image_url: Option<String>,
+}
+
+impl CuePayload {
+    pub fn user(display_name: &str, profile_image_url: &str) -> Self {
+        Self {
+            display_name: Some(display_name.to_string()),
+            profile_image_url: Some(profile_image_url.to_string()),
+        }
+    }
+}
+
+pub async fn emit_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    kind: CueKind,
+    payload: &CuePayload,
+    now: i64,
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO cues (kind, payload, created_at) VALUES (?1, ?2, ?3)")
+        .bind(kind.as_str())
+        .bind(serde_json::to_string(payload)?)
+        .bind(now)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM cues WHERE id <= (SELECT MAX(id) FROM cues) - ?1")
+        .bind(REPLAY_BUFFER_LEN)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, FromRow)]
+struct CueRow {
+    id: i64,
+    kind: String,
+    payload: String,
+    created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CueDto {
+    /// Increasing; clients pass the last one they handled as `after` and dedupe on it.
+    pub id: i64,
+    /// `cue:<kind>`, e.g. `cue:user_joined`.
+    pub name: String,
+    pub payload: serde_json::Value,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CuesDto {
+    /// Newest cue id (including disabled ones); resume from here. Below the `after` a
+    /// client sent, the history was reset (e.g. a restored database) and it should resume
+    /// from this id instead.
+    pub last_id: i64,
+    pub cues: Vec<CueDto>,
+}
+
+/// Enabled cues newer than `after`. Without `after` only `last_id` is returned, so a
+/// freshly loaded overlay does not replay old sounds.
+pub async fn list_after(
+    pool: &SqlitePool,
+    overlay: &OverlayConfig,
+    after: Option<i64>,
+) -> anyhow::Result<CuesDto> {
+    let last_id = sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(id) FROM cues")
+        .fetch_one(pool)
+        .await?
+        .unwrap_or(0);
+    let Some(after) = after else {
+        return Ok(CuesDto {
+            last_id,
+            cues: Vec::new(),
+        });
+    };
+
+    let rows = sqlx::query_as::<_, CueRow>(
+        "SELECT id, kind, payload, created_at FROM cues WHERE id > ?1 ORDER BY id ASC",
+    )
+    .bind(after)
+    .fetch_all(pool)
+    .await?;
+
+    let cues = rows
+        .into_iter()
+        .filter(|r| overlay.cue_enabled(&r.kind))
+        .map(|r| CueDto {
+            id: r.id,
+            name: format!("cue:{}", r.kind),
+            payload: serde_json::from_str(&r.payload).unwrap_or(serde_json::Value::Null),
+            created_at: r.created_at,
+        })
+        .collect();
+    Ok(CuesDto { last_id, cues })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        queue::{self, QueueService},
+        testing,
+    };
+
+    /// Every cue recorded after `after`, enabled or not, as `(name, display name)`.
+    async fn cues_after(service: &QueueService, after: i64) -> Vec<(String, Option<String>)> {
+        let all = crate::config::OverlayConfig::default();
+        list_after(service.db.read(), &all, Some(after))
+            .await
+            .unwrap()
+            .cues
+            .into_iter()
+            .map(|c| {
+                (
+                    c.name,
+                    c.payload["display_name"].as_str().map(str::to_string),
+                )
+            })
+            .collect()
+    }
+
+    async fn last_id(service: &QueueService) -> i64 {
+        list_after(service.db.read(), &service.settings.overlay, None)
+            .await
+            .unwrap()
+            .last_id
+    }
+
+    fn cue(name: &str, who: &str) -> (String, Option<String>) {
+        (format!("cue:{name}"), Some(who.to_string()))
+    }
+
+    #[tokio::test]
+    async fn joins_and_head_changes_each_emit_one_cue() {
+        let service = testing::queue_service("").await;
+        let a = testing::enqueue(&service, testing::new_user("a")).await;
+        assert_eq!(
+            cues_after(&service, 0).await,
+            [cue("user_joined", "a"), cue("user_up_next", "a")]
+        );
+
+        let mark = last_id(&service).await;
+        let b = testing::enqueue(&service, testing::new_user("b")).await;
+        assert_eq!(cues_after(&service, mark).await, [cue("user_joined", "b")]);
+
+        let mark = last_id(&service).await;
+        queue::move_to_top(service.db.write(), &service.timings, &b, false)
+            .await
+            .unwrap();
+        assert_eq!(cues_after(&service, mark).await, [cue("user_up_next", "b")]);
+
+        let mark = last_id(&service).await;
+        queue::delete_item(
+            service.db.write(),
+            &service.timings,
+            &b,
+            queue::DeleteMode::Completed,
+        )
+        .await
+        .unwrap();
+        assert_eq!(cues_after(&service, mark).await, [cue("user_up_next", "a")]);
+
+        let mark = last_id(&service).await;
+        queue::delete_item(
+            service.db.write(),
+            &service.timings,
+            &a,
+            queue::DeleteMode::Canceled,
+        )
+        .await
+        .unwrap();
+        assert_eq!(cues_after(&service, mark).await, []);
+    }
+
+    #[tokio::test]
+    async fn thaw_emits_queue_opened_and_the_held_joins() {
+        let service = testing::queue_service("").await;
+        queue::freeze(service.db.write()).await.unwrap();
+        let held = service
+            .enqueue(testing::new_user("a"), &queue::JoinNotices::default())
+            .await
+            .unwrap();
+        assert!(matches!(held, queue::EnqueueOutcome::Pending { .. }));
+        assert_eq!(last_id(&service).await, 0);
+
+        queue::thaw(service.db.write(), &service.settings.queue)
+            .await
+            .unwrap();
+        assert_eq!(
+            cues_after(&service, 0).await,
+            [
+                cue("user_joined", "a"),
+                ("cue:queue_opened".to_string(), None),
+                cue("user_up_next", "a")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resuming_a_paused_queue_emits_queue_opened_once() {
+        let service = testing::queue_service("").await;
+        queue::set_paused(service.db.write(), false, true)
+            .await
+            .unwrap();
+        assert_eq!(last_id(&service).await, 0);
+
+        queue::set_paused(service.db.write(), false, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            cues_after(&service, 0).await,
+            [("cue:queue_opened".to_string(), None)]
+        );
+
+        // Resuming a queue that is not paused opens nothing.
+        let mark = last_id(&service).await;
+        queue::set_paused(service.db.write(), false, false)
+            .await
+            .unwrap();
+        assert_eq!(cues_after(&service, mark).await, []);
+    }
+
+    #[tokio::test]
+    async fn reads_emit_nothing() {
+        let service = testing::queue_service("").await;
+        testing::enqueue(&service, testing::new_user("a")).await;
+        let mark = last_id(&service).await;
+
+        queue::list_queue(service.db.read(), &service.timings, &service.settings)
+            .await
+            .unwrap();
+        queue::list_queue_admin(service.db.read(), &service.timings, &service.settings)
+            .await
+            .unwrap();
+        let cfg = &service.settings.queue;
+        queue::explain_enqueue(
+            service.db.write(),
+            &service.timings,
+            cfg,
+            &cfg.default_policy(),
+            testing::new_user("b"),
+        )
+        .await
+        .unwrap();
+        queue::freeze_state(service.db.read()).await.unwrap();
+        list_after(service.db.read(), &service.settings.overlay, Some(0))
+            .await
+            .unwrap();
+
+        assert_eq!(last_id(&service).await, mark);
+    }
+
+    #[tokio::test]
+    async fn polls_resume_after_the_last_id_and_skip_disabled_cues() {
+        let service = testing::queue_service("[overlay.cues]\nuser_joined = false\n").await;
+        assert!(
+            list_after(service.db.read(), &service.settings.overlay, None)
+                .await
+                .unwrap()
+                .cues
+                .is_empty()
+        );
+        testing::enqueue(&service, testing::new_user("a")).await;
+
+        let first = list_after(service.db.read(), &service.settings.overlay, Some(0))
+            .await
+            .unwrap();
+        let names: Vec<_> = first.cues.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["cue:user_up_next"]);
+        // The disabled join still advances last_id, so a replay from it yields nothing twice.
+        assert_eq!(first.last_id, 2);
+        let replay = list_after(
+            service.db.read(),
+            &service.settings.overlay,
+            Some(first.last_id),
+        )
+        .await
+        .unwrap();
+        assert!(replay.cues.is_empty());
+    }
+}
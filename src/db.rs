@@ -1,17 +1,27 @@
 use std::path::Path;
 
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
     FromRow, SqlitePool,
 };
+use tracing::{info, warn};
 
 use crate::util;
 
-#[derive(Debug, Clone)]
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Adds the unique index on queue_items.user_id; duplicates are merged before it runs.
+const UNIQUE_QUEUE_USER_MIGRATION: i64 = 16;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct OAuthToken {
     pub access_token: String,
     pub refresh_token: String,
     pub expires_at: i64,
+    /// Granted scopes. `None` leaves the stored list as it is (refreshes), and reads as
+    /// `None` until an exchange or /validate reported them.
+    pub scopes: Option<Vec<String>>,
 }
 
 #[derive(Debug, FromRow)]
@@ -19,9 +29,77 @@ struct OAuthTokenRow {
     access_token: String,
     refresh_token: String,
     expires_at: i64,
+    scopes: Option<String>,
+}
+
+/// The two connection pools. Mutations go through `write()`, a single connection, so
+/// writers queue up in the app instead of contending for SQLite's write lock (BUSY);
+/// polling reads go through `read()` so they never wait behind a writer.
+#[derive(Debug, Clone)]
+pub struct Db {
+    read: SqlitePool,
+    write: SqlitePool,
+}
+
+impl Db {
+    /// Opens both pools; `read_connections == 0` sends reads through the write connection.
+    pub async fn open(
+        db_path: &str,
+        backup_keep: usize,
+        read_connections: u32,
+    ) -> anyhow::Result<Self> {
+        let write = init_pool(db_path, backup_keep).await?;
+        let read = if read_connections > 0 {
+            init_read_pool(db_path, read_connections).await?
+        } else {
+            write.clone()
+        };
+        Ok(Self { read, write })
+    }
+
+    /// A migrated database that lives as long as the returned handle, on one
+    /// connection for reads and writes.
+    #[cfg(test)]
+    pub async fn in_memory() -> anyhow::Result<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(<SqliteConnectOptions as std::str::FromStr>::from_str(
+                "sqlite::memory:",
+            )?)
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(Self {
+            read: pool.clone(),
+            write: pool,
+        })
+    }
+
+    /// Read-only pool for queries that change nothing.
+    pub fn read(&self) -> &SqlitePool {
+        &self.read
+    }
+
+    /// The single-connection pool every mutation uses.
+    pub fn write(&self) -> &SqlitePool {
+        &self.write
+    }
+}
+
+/// Opens the DB and applies migrations. Before applying new migrations to an existing
+/// DB, a copy is saved next to it (keeping the newest `backup_keep` copies) so an older
+/// binary can still be used with the backup.
+async fn init_pool(db_path: &str, backup_keep: usize) -> anyhow::Result<SqlitePool> {
+    init_pool_with(db_path, backup_keep, &MIGRATOR).await
 }
 
-pub async fn init_pool(db_path: &str) -> anyhow::Result<SqlitePool> {
+/// [`init_pool`] with the given migrations; tests run it with made-up ones.
+async fn init_pool_with(
+    db_path: &str,
+    backup_keep: usize,
+    migrator: &Migrator,
+) -> anyhow::Result<SqlitePool> {
     if let Some(parent) = Path::new(db_path).parent() {
         if !parent.as_os_str().is_empty() {
             std::fs::create_dir_all(parent)?;
@@ -34,22 +112,163 @@ pub async fn init_pool(db_path: &str) -> anyhow::Result<SqlitePool> {
     //     .connect(&url)
     //     .await?;
 
+    // WAL lets the read-only pool (see init_read_pool) read while a write is in progress.
     let options = SqliteConnectOptions::new()
         .filename(db_path)
-        .create_if_missing(true);
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal);
     let pool = SqlitePoolOptions::new()
-        .max_connections(5)
+        .max_connections(1)
         .connect_with(options)
         .await?;
 
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    let applied = applied_migration_versions(&pool).await?;
+    let known_latest = migrator.iter().map(|m| m.version).max().unwrap_or(0);
+
+    if let Some(&newest) = applied.iter().max() {
+        if newest > known_latest {
+            anyhow::bail!(
+                "database schema (version {newest}) is newer than this binary (knows up to version {known_latest}). \
+                 Use a newer release, or restore a {db_path}.pre-migrate-* backup."
+            );
+        }
+    }
+
+    let pending: Vec<i64> = migrator
+        .iter()
+        .map(|m| m.version)
+        .filter(|v| !applied.contains(v))
+        .collect();
+    // A database from before the migrations has no _sqlx_migrations table but does have
+    // queue data, so it is backed up too; only a brand-new file is not.
+    if !pending.is_empty() && (!applied.is_empty() || table_exists(&pool, "queue_items").await?) {
+        let from_version = applied.iter().max().copied().unwrap_or(0);
+        backup_before_migrate(db_path, from_version, backup_keep)?;
+    }
+
+    // queue_items without the constraint only exists in databases that predate it, including
+    // ones created before the migrations (no _sqlx_migrations table at all).
+    if pending.contains(&UNIQUE_QUEUE_USER_MIGRATION) && table_exists(&pool, "queue_items").await? {
+        // Bring the schema up to just before the constraint so dedupe_users sees every column.
+        let before = Migrator {
+            migrations: migrator
+                .iter()
+                .filter(|m| m.version < UNIQUE_QUEUE_USER_MIGRATION)
+                .cloned()
+                .collect(),
+            ignore_missing: migrator.ignore_missing,
+            locking: migrator.locking,
+        };
+        before.run(&pool).await?;
+        let report = crate::queue::dedupe_users(&pool).await.map_err(|e| {
+            anyhow::anyhow!(
+                "could not merge duplicate queue entries before adding the user_id constraint: {e:#}. \
+                 The database was left at the previous schema."
+            )
+        })?;
+        if !report.merged.is_empty() {
+            warn!(merged = ?report.merged, "merged duplicate queue entries before migrating");
+        }
+    }
+
+    migrator.run(&pool).await?;
+
+    Ok(pool)
+}
+
+async fn table_exists(pool: &SqlitePool, name: &str) -> anyhow::Result<bool> {
+    let count = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+    )
+    .bind(name)
+    .fetch_one(pool)
+    .await?;
+    Ok(count > 0)
+}
+
+async fn applied_migration_versions(pool: &SqlitePool) -> anyhow::Result<Vec<i64>> {
+    if !table_exists(pool, "_sqlx_migrations").await? {
+        return Ok(Vec::new());
+    }
 
+    let versions =
+        sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success = 1")
+            .fetch_all(pool)
+            .await?;
+    Ok(versions)
+}
+
+/// Copies the DB file (and its -wal / -shm files) to `<db_path>.pre-migrate-<version>-<timestamp>`.
+fn backup_before_migrate(db_path: &str, from_version: i64, keep: usize) -> anyhow::Result<()> {
+    let backup = format!("{db_path}.pre-migrate-{from_version}-{}", util::now_epoch());
+    std::fs::copy(db_path, &backup)?;
+    for suffix in ["-wal", "-shm"] {
+        let src = format!("{db_path}{suffix}");
+        if Path::new(&src).exists() {
+            std::fs::copy(&src, format!("{backup}{suffix}"))?;
+        }
+    }
+    info!(backup = %backup, "backed up database before applying migrations");
+
+    if let Err(e) = prune_pre_migrate_backups(db_path, keep) {
+        warn!(error = ?e, "failed to prune old pre-migrate backups");
+    }
+    Ok(())
+}
+
+fn prune_pre_migrate_backups(db_path: &str, keep: usize) -> anyhow::Result<()> {
+    let path = Path::new(db_path);
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.pre-migrate-",
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+    );
+
+    // Main backup files only; their -wal / -shm companions are removed alongside.
+    let mut backups: Vec<(std::time::SystemTime, std::path::PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(&prefix) && !name.ends_with("-wal") && !name.ends_with("-shm") {
+            backups.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    backups.sort_by_key(|b| std::cmp::Reverse(b.0));
+
+    for (_, old) in backups.into_iter().skip(keep.max(1)) {
+        std::fs::remove_file(&old)?;
+        for suffix in ["-wal", "-shm"] {
+            let companion = format!("{}{suffix}", old.display());
+            if Path::new(&companion).exists() {
+                std::fs::remove_file(companion)?;
+            }
+        }
+        info!(backup = %old.display(), "removed old pre-migrate backup");
+    }
+    Ok(())
+}
+
+/// Separate read-only pool for high-frequency polling (overlay, status), so readers
+/// never hold a connection the write path needs. Call after `init_pool` (migrations).
+async fn init_read_pool(db_path: &str, max_connections: u32) -> anyhow::Result<SqlitePool> {
+    let options = SqliteConnectOptions::new()
+        .filename(db_path)
+        .read_only(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(max_connections)
+        .connect_with(options)
+        .await?;
     Ok(pool)
 }
 
 pub async fn get_oauth_token(pool: &SqlitePool) -> anyhow::Result<Option<OAuthToken>> {
     let row = sqlx::query_as::<_, OAuthTokenRow>(
-        r#"SELECT access_token, refresh_token, expires_at
+        r#"SELECT access_token, refresh_token, expires_at, scopes
            FROM oauth_tokens
            WHERE id = 1"#,
     )
@@ -60,27 +279,41 @@ pub async fn get_oauth_token(pool: &SqlitePool) -> anyhow::Result<Option<OAuthTo
         access_token: r.access_token,
         refresh_token: r.refresh_token,
         expires_at: r.expires_at,
+        scopes: r
+            .scopes
+            .map(|s| s.split_whitespace().map(str::to_string).collect()),
     }))
 }
 
 pub async fn upsert_oauth_token(pool: &SqlitePool, token: &OAuthToken) -> anyhow::Result<()> {
     sqlx::query(
-        r#"INSERT INTO oauth_tokens (id, access_token, refresh_token, expires_at)
-           VALUES (1, ?1, ?2, ?3)
+        r#"INSERT INTO oauth_tokens (id, access_token, refresh_token, expires_at, scopes)
+           VALUES (1, ?1, ?2, ?3, ?4)
            ON CONFLICT(id) DO UPDATE SET
              access_token = excluded.access_token,
              refresh_token = excluded.refresh_token,
-             expires_at = excluded.expires_at"#,
+             expires_at = excluded.expires_at,
+             scopes = COALESCE(excluded.scopes, oauth_tokens.scopes)"#,
     )
     .bind(&token.access_token)
     .bind(&token.refresh_token)
     .bind(token.expires_at)
+    .bind(token.scopes.as_ref().map(|s| s.join(" ")))
     .execute(pool)
     .await?;
 
     Ok(())
 }
 
+/// Scopes confirmed by /validate for the stored token.
+pub async fn set_oauth_scopes(pool: &SqlitePool, scopes: &[String]) -> anyhow::Result<()> {
+    sqlx::query("UPDATE oauth_tokens SET scopes = ?1 WHERE id = 1")
+        .bind(scopes.join(" "))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
 pub async fn delete_oauth_token(pool: &SqlitePool) -> anyhow::Result<()> {
     sqlx::query("DELETE FROM oauth_tokens WHERE id = 1")
         .execute(pool)
@@ -106,7 +339,10 @@ pub async fn get_kv(pool: &SqlitePool, key: &str) -> anyhow::Result<Option<Strin
     Ok(row.map(|r| r.value))
 }
 
-pub async fn set_kv(pool: &SqlitePool, key: &str, value: &str) -> anyhow::Result<()> {
+pub async fn set_kv<'e, E>(executor: E, key: &str, value: &str) -> anyhow::Result<()>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
     sqlx::query(
         r#"INSERT INTO app_kv (key, value)
            VALUES (?1, ?2)
@@ -115,19 +351,25 @@ pub async fn set_kv(pool: &SqlitePool, key: &str, value: &str) -> anyhow::Result
     )
     .bind(key)
     .bind(value)
-    .execute(pool)
+    .execute(executor)
     .await?;
 
     Ok(())
 }
 
-#[derive(Debug, FromRow)]
-struct MessageRow {
-    message_id: String,
+pub async fn delete_kv<'e, E>(executor: E, key: &str) -> anyhow::Result<()>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    sqlx::query("DELETE FROM app_kv WHERE key = ?1")
+        .bind(key)
+        .execute(executor)
+        .await?;
+    Ok(())
 }
 
 pub async fn is_processed_message(pool: &SqlitePool, message_id: &str) -> anyhow::Result<bool> {
-    let row = sqlx::query_as::<_, MessageRow>(
+    let row = sqlx::query_scalar::<_, String>(
         r#"SELECT message_id
            FROM processed_messages
            WHERE message_id = ?1"#,
@@ -155,18 +397,50 @@ pub async fn mark_processed_message(
     Ok(())
 }
 
-pub async fn cleanup_processed_messages(pool: &SqlitePool, cutoff: i64) -> anyhow::Result<u64> {
+/// Marks `message_id` as processed; false if it already was (a replay).
+pub async fn claim_processed_message(
+    pool: &SqlitePool,
+    message_id: &str,
+    received_at: i64,
+) -> anyhow::Result<bool> {
+    let result = sqlx::query(
+        r#"INSERT OR IGNORE INTO processed_messages (message_id, received_at)
+           VALUES (?1, ?2)"#,
+    )
+    .bind(message_id)
+    .bind(received_at)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Deletes at most `limit` rows received before `cutoff`, so one call holds the write
+/// lock only briefly (see `sweep`).
+pub async fn cleanup_processed_messages(
+    pool: &SqlitePool,
+    cutoff: i64,
+    limit: u32,
+) -> anyhow::Result<u64> {
     let result = sqlx::query(
         r#"DELETE FROM processed_messages
-           WHERE received_at < ?1"#,
+           WHERE rowid IN (SELECT rowid FROM processed_messages WHERE received_at < ?1 LIMIT ?2)"#,
     )
     .bind(cutoff)
+    .bind(limit)
     .execute(pool)
     .await?;
 
     Ok(result.rows_affected())
 }
 
+pub async fn count_processed_messages(pool: &SqlitePool) -> anyhow::Result<i64> {
+    Ok(
+        sqlx::query_scalar("SELECT COUNT(*) FROM processed_messages")
+            .fetch_one(pool)
+            .await?,
+    )
+}
+
 pub async fn get_broadcaster_id(pool: &SqlitePool) -> anyhow::Result<Option<String>> {
     get_kv(pool, "broadcaster_id").await
 }
@@ -183,6 +457,246 @@ pub async fn set_broadcaster_login(pool: &SqlitePool, login: &str) -> anyhow::Re
     set_kv(pool, "broadcaster_login", login).await
 }
 
+/// Epoch second of the last stream.online notification.
+pub async fn get_stream_online_at(pool: &SqlitePool) -> anyhow::Result<Option<i64>> {
+    Ok(get_kv(pool, "stream_online_at")
+        .await?
+        .and_then(|v| v.parse().ok()))
+}
+
+pub async fn set_stream_online_at(pool: &SqlitePool, at: i64) -> anyhow::Result<()> {
+    set_kv(pool, "stream_online_at", &at.to_string()).await
+}
+
+/// Id of the current stream session (its `stream_online_at`), recorded on participations.
+pub async fn current_session_id<'e, E>(executor: E) -> anyhow::Result<Option<String>>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let id =
+        sqlx::query_scalar::<_, String>("SELECT value FROM app_kv WHERE key = 'stream_online_at'")
+            .fetch_optional(executor)
+            .await?;
+    Ok(id)
+}
+
+// --- Redemptions without a join reward --------------------------------------
+
+/// Redemptions dropped because no join reward is configured, with the most recent
+/// reward so the admin page can suggest it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct UnconfiguredRedemptions {
+    pub count: u64,
+    pub last_reward_id: String,
+    pub last_reward_title: String,
+    pub last_at: i64,
+}
+
+pub async fn get_unconfigured_redemptions(
+    pool: &SqlitePool,
+) -> anyhow::Result<Option<UnconfiguredRedemptions>> {
+    match get_kv(pool, "unconfigured_redemptions").await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn record_unconfigured_redemption(
+    pool: &SqlitePool,
+    reward_id: &str,
+    reward_title: &str,
+    at: i64,
+) -> anyhow::Result<()> {
+    let count = get_unconfigured_redemptions(pool)
+        .await?
+        .map_or(0, |s| s.count)
+        + 1;
+    let seen = UnconfiguredRedemptions {
+        count,
+        last_reward_id: reward_id.to_string(),
+        last_reward_title: reward_title.to_string(),
+        last_at: at,
+    };
+    set_kv(
+        pool,
+        "unconfigured_redemptions",
+        &serde_json::to_string(&seen)?,
+    )
+    .await
+}
+
+// --- Participations ----------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticipationSource {
+    /// A queue item was completed.
+    Queue,
+    /// Imported history from another tool.
+    Import,
+}
+
+impl ParticipationSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Queue => "queue",
+            Self::Import => "import",
+        }
+    }
+}
+
+/// A completed turn with everything known about where it came from.
+#[derive(Debug, Clone)]
+pub struct FullParticipation {
+    pub user_id: String,
+    pub completed_at: i64,
+    pub queue_item_id: Option<String>,
+    pub reward_id: Option<String>,
+    pub source: ParticipationSource,
+    pub session_id: Option<String>,
+    /// When the item reached position 0; `None` for imports and items from before it was tracked.
+    pub turn_started_at: Option<i64>,
+}
+
+/// Returns false if a participation for the same `queue_item_id` already exists
+/// (one queue item is one turn, however often its complete is delivered).
+pub async fn insert_participation<'e, E>(executor: E, p: &FullParticipation) -> anyhow::Result<bool>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let result = sqlx::query(
+        r#"INSERT INTO participations (user_id, completed_at, queue_item_id, reward_id, source, session_id, turn_started_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+           ON CONFLICT DO NOTHING"#,
+    )
+    .bind(&p.user_id)
+    .bind(p.completed_at)
+    .bind(&p.queue_item_id)
+    .bind(&p.reward_id)
+    .bind(p.source.as_str())
+    .bind(&p.session_id)
+    .bind(p.turn_started_at)
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// --- Broadcaster account switch ----------------------------------------------
+
+/// A login as a different Twitch account than the stored broadcaster, waiting for
+/// `POST /api/auth/confirm_switch` (or `cancel_switch`).
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PendingBroadcasterSwitch {
+    pub broadcaster_id: String,
+    pub broadcaster_login: String,
+    pub detected_at: i64,
+}
+
+/// Shown on the status endpoint after a switch was confirmed.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BroadcasterSwitchNotice {
+    pub previous_broadcaster_id: String,
+    pub previous_broadcaster_login: Option<String>,
+    /// "archived", "wiped" or "kept".
+    pub data: String,
+    pub archive_path: Option<String>,
+    pub switched_at: i64,
+}
+
+pub async fn get_pending_broadcaster_switch(
+    pool: &SqlitePool,
+) -> anyhow::Result<Option<PendingBroadcasterSwitch>> {
+    match get_kv(pool, "pending_broadcaster_switch").await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn set_pending_broadcaster_switch(
+    pool: &SqlitePool,
+    pending: &PendingBroadcasterSwitch,
+) -> anyhow::Result<()> {
+    set_kv(
+        pool,
+        "pending_broadcaster_switch",
+        &serde_json::to_string(pending)?,
+    )
+    .await
+}
+
+/// Clears the pending switch together with the token held for it.
+pub async fn clear_pending_broadcaster_switch(pool: &SqlitePool) -> anyhow::Result<()> {
+    delete_kv(pool, "pending_oauth_token").await?;
+    delete_kv(pool, "pending_broadcaster_switch").await
+}
+
+/// Token of the login behind a pending switch. It replaces `oauth_tokens` only when
+/// the switch is confirmed, so until then the current channel keeps its login.
+pub async fn get_pending_oauth_token(pool: &SqlitePool) -> anyhow::Result<Option<OAuthToken>> {
+    match get_kv(pool, "pending_oauth_token").await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn set_pending_oauth_token(pool: &SqlitePool, token: &OAuthToken) -> anyhow::Result<()> {
+    set_kv(pool, "pending_oauth_token", &serde_json::to_string(token)?).await
+}
+
+pub async fn get_broadcaster_switch_notice(
+    pool: &SqlitePool,
+) -> anyhow::Result<Option<BroadcasterSwitchNotice>> {
+    match get_kv(pool, "broadcaster_switch_notice").await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn set_broadcaster_switch_notice(
+    pool: &SqlitePool,
+    notice: &BroadcasterSwitchNotice,
+) -> anyhow::Result<()> {
+    set_kv(
+        pool,
+        "broadcaster_switch_notice",
+        &serde_json::to_string(notice)?,
+    )
+    .await
+}
+
+/// Writes a consistent copy of the whole DB to `path` (`VACUUM INTO`).
+pub async fn archive_database(pool: &SqlitePool, path: &str) -> anyhow::Result<()> {
+    sqlx::query("VACUUM INTO ?1")
+        .bind(path)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Removes data that belongs to one channel: the queue, participation history,
+/// cached profiles, reward stats and unsent redemption updates.
+/// OAuth, processed message ids and token history are kept.
+pub async fn wipe_channel_data(pool: &SqlitePool) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    for sql in [
+        "DELETE FROM queue_items",
+        "DELETE FROM pending_queue_items",
+        "DELETE FROM participations",
+        "DELETE FROM queue_entries",
+        "DELETE FROM user_cache",
+        "DELETE FROM reward_cost_history",
+        "DELETE FROM cues",
+        "DELETE FROM roster",
+        "DELETE FROM outbox WHERE event_type = 'redemption_status' AND status != 'done'",
+        r#"DELETE FROM app_kv
+           WHERE key IN ('stream_online_at', 'queue_frozen_at', 'queue_freeze_members', 'unconfigured_redemptions')
+              OR key LIKE 'reward_prompt:%'"#,
+    ] {
+        sqlx::query(sql).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
 /// Convenience: returns true if we have a token and it looks non-expired.
 pub async fn has_validish_token(pool: &SqlitePool) -> anyhow::Result<bool> {
     let Some(t) = get_oauth_token(pool).await? else {
@@ -193,7 +707,7 @@ pub async fn has_validish_token(pool: &SqlitePool) -> anyhow::Result<bool> {
 
 // --- Twitch user cache ------------------------------------------------------
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct CachedUserProfile {
     pub user_id: String,
     pub user_login: String,
@@ -233,6 +747,31 @@ pub async fn get_cached_user_profile(
     }))
 }
 
+/// Most recently updated entry for a login (logins can move between accounts).
+pub async fn get_cached_user_profile_by_login(
+    pool: &SqlitePool,
+    user_login: &str,
+) -> anyhow::Result<Option<CachedUserProfile>> {
+    let row = sqlx::query_as::<_, CachedUserProfileRow>(
+        r#"SELECT user_id, user_login, display_name, profile_image_url, updated_at
+           FROM user_cache
+           WHERE user_login = ?1
+           ORDER BY updated_at DESC
+           LIMIT 1"#,
+    )
+    .bind(user_login.to_lowercase())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|r| CachedUserProfile {
+        user_id: r.user_id,
+        user_login: r.user_login,
+        display_name: r.display_name,
+        profile_image_url: r.profile_image_url,
+        updated_at: r.updated_at,
+    }))
+}
+
 pub async fn upsert_cached_user_profile(
     pool: &SqlitePool,
     profile: &CachedUserProfile,
@@ -256,3 +795,461 @@ pub async fn upsert_cached_user_profile(
 
     Ok(())
 }
+
+/// Most recently updated first.
+pub async fn list_cached_user_profiles(
+    pool: &SqlitePool,
+    limit: i64,
+    offset: i64,
+) -> anyhow::Result<Vec<CachedUserProfile>> {
+    let rows = sqlx::query_as::<_, CachedUserProfileRow>(
+        r#"SELECT user_id, user_login, display_name, profile_image_url, updated_at
+           FROM user_cache
+           ORDER BY updated_at DESC, user_id ASC
+           LIMIT ?1 OFFSET ?2"#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| CachedUserProfile {
+            user_id: r.user_id,
+            user_login: r.user_login,
+            display_name: r.display_name,
+            profile_image_url: r.profile_image_url,
+            updated_at: r.updated_at,
+        })
+        .collect())
+}
+
+pub async fn count_cached_user_profiles(pool: &SqlitePool) -> anyhow::Result<i64> {
+    let n = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM user_cache")
+        .fetch_one(pool)
+        .await?;
+    Ok(n)
+}
+
+/// Deletes one profile, or all of them when `user_id` is `None`. Returns rows removed.
+pub async fn delete_cached_user_profiles(
+    pool: &SqlitePool,
+    user_id: Option<&str>,
+) -> anyhow::Result<u64> {
+    let result = sqlx::query("DELETE FROM user_cache WHERE ?1 IS NULL OR user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// --- Token refresh history ---------------------------------------------------
+
+#[derive(Debug, Clone, serde::Serialize, FromRow)]
+pub struct TokenEvent {
+    pub occurred_at: i64,
+    pub success: bool,
+    pub error_class: Option<String>,
+    pub error_detail: Option<String>,
+}
+
+pub async fn insert_token_event(pool: &SqlitePool, ev: &TokenEvent) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"INSERT INTO token_events (occurred_at, success, error_class, error_detail)
+           VALUES (?1, ?2, ?3, ?4)"#,
+    )
+    .bind(ev.occurred_at)
+    .bind(ev.success)
+    .bind(&ev.error_class)
+    .bind(&ev.error_detail)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Most recent first.
+pub async fn list_token_events(pool: &SqlitePool, limit: i64) -> anyhow::Result<Vec<TokenEvent>> {
+    let rows = sqlx::query_as::<_, TokenEvent>(
+        r#"SELECT occurred_at, success, error_class, error_detail
+           FROM token_events
+           ORDER BY id DESC
+           LIMIT ?1"#,
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn cleanup_token_events(pool: &SqlitePool, cutoff: i64) -> anyhow::Result<u64> {
+    let result = sqlx::query("DELETE FROM token_events WHERE occurred_at < ?1")
+        .bind(cutoff)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// --- EventSub connection history ---------------------------------------------
+
+#[derive(Debug, Clone, serde::Serialize, FromRow)]
+pub struct EventSubEvent {
+    pub occurred_at: i64,
+    pub kind: String,
+    pub detail: Option<String>,
+}
+
+pub async fn insert_eventsub_event(pool: &SqlitePool, ev: &EventSubEvent) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO eventsub_events (occurred_at, kind, detail) VALUES (?1, ?2, ?3)")
+        .bind(ev.occurred_at)
+        .bind(&ev.kind)
+        .bind(&ev.detail)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Events in `[from, to)`, oldest first, preceded by the last event before `from` (if any)
+/// so the connection state at `from` is known.
+pub async fn list_eventsub_events(
+    pool: &SqlitePool,
+    from: i64,
+    to: i64,
+) -> anyhow::Result<Vec<EventSubEvent>> {
+    let rows = sqlx::query_as::<_, EventSubEvent>(
+        r#"SELECT occurred_at, kind, detail FROM (
+             SELECT id, occurred_at, kind, detail
+             FROM eventsub_events
+             WHERE occurred_at < ?1
+             ORDER BY occurred_at DESC, id DESC
+             LIMIT 1
+           )
+           UNION ALL
+           SELECT occurred_at, kind, detail FROM (
+             SELECT id, occurred_at, kind, detail
+             FROM eventsub_events
+             WHERE occurred_at >= ?1 AND occurred_at < ?2
+             ORDER BY occurred_at ASC, id ASC
+           )"#,
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn cleanup_eventsub_events(pool: &SqlitePool, cutoff: i64) -> anyhow::Result<u64> {
+    let result = sqlx::query("DELETE FROM eventsub_events WHERE occurred_at < ?1")
+        .bind(cutoff)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use sqlx::migrate::{Migration, MigrationType};
+
+    use super::*;
+    use crate::{
+        queue,
+        testing::{self, TestApp},
+    };
+
+    /// Migrations `1..=latest`, each creating a table `step_<version>`.
+    fn migrations_up_to(latest: i64) -> Migrator {
+        Migrator {
+            migrations: (1..=latest)
+                .map(|v| {
+                    let sql = format!("CREATE TABLE step_{v} (id INTEGER PRIMARY KEY)");
+                    Migration::new(
+                        v,
+                        format!("step {v}").into(),
+                        MigrationType::Simple,
+                        sql.into(),
+                    )
+                })
+                .collect(),
+            ignore_missing: false,
+            locking: true,
+        }
+    }
+
+    async fn schema_version(path: &str) -> i64 {
+        let pool =
+            SqlitePool::connect_with(SqliteConnectOptions::new().filename(path).read_only(true))
+                .await
+                .unwrap();
+        let version = sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        version
+    }
+
+    #[tokio::test]
+    async fn migrating_backs_up_the_previous_schema_and_an_older_binary_refuses_the_result() {
+        let path = testing::TempPath::new();
+        let dir = Path::new(path.as_str()).parent().unwrap();
+        let prefix = format!(
+            "{}.pre-migrate-",
+            Path::new(path.as_str())
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        let backups = || -> Vec<String> {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .filter(|n| n.starts_with(&prefix) && !n.ends_with("-wal") && !n.ends_with("-shm"))
+                .collect()
+        };
+
+        // A new database has nothing to back up.
+        init_pool_with(path.as_str(), 5, &migrations_up_to(1))
+            .await
+            .unwrap()
+            .close()
+            .await;
+        assert!(backups().is_empty());
+
+        init_pool_with(path.as_str(), 5, &migrations_up_to(2))
+            .await
+            .unwrap()
+            .close()
+            .await;
+        let made = backups();
+        assert_eq!(made.len(), 1, "{made:?}");
+        let timestamp = made[0]
+            .strip_prefix(&format!("{prefix}1-"))
+            .expect("named after the previous version");
+        assert!(timestamp.parse::<i64>().is_ok(), "{timestamp}");
+        let backup = dir.join(&made[0]);
+        assert_eq!(schema_version(backup.to_str().unwrap()).await, 1);
+        assert_eq!(schema_version(path.as_str()).await, 2);
+
+        let err = init_pool_with(path.as_str(), 5, &migrations_up_to(1))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with(
+                "database schema (version 2) is newer than this binary (knows up to version 1)"
+            ),
+            "{err}"
+        );
+        assert!(err.contains(".pre-migrate-* backup"), "{err}");
+        assert_eq!(
+            schema_version(path.as_str()).await,
+            2,
+            "a refused open changes nothing"
+        );
+
+        for name in made {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(dir.join(format!("{name}{suffix}")));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicates_in_a_pre_migrations_database_are_merged_before_the_unique_index() {
+        let path = testing::TempPath::new();
+        let fixture = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/db/pre_migrations_duplicates.sql"
+        ))
+        .unwrap();
+        let options = SqliteConnectOptions::new()
+            .filename(path.as_str())
+            .create_if_missing(true);
+        let legacy = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::raw_sql(&fixture).execute(&legacy).await.unwrap();
+        legacy.close().await;
+
+        let db = Db::open(path.as_str(), 0, 1).await.unwrap();
+        let backup_prefix = format!("{}.pre-migrate-0-", path.as_str());
+        let backups: Vec<_> = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_str().unwrap().starts_with(&backup_prefix))
+            .collect();
+        let main = backups.iter().filter(|p| {
+            !p.to_str().unwrap().ends_with("-wal") && !p.to_str().unwrap().ends_with("-shm")
+        });
+        assert_eq!(
+            main.count(),
+            1,
+            "the pre-migrations database is backed up first"
+        );
+        for backup in backups {
+            std::fs::remove_file(backup).unwrap();
+        }
+
+        let rows: Vec<(String, String, i64, i64)> = sqlx::query_as(
+            "SELECT id, user_id, enqueued_at, position FROM queue_items ORDER BY position",
+        )
+        .fetch_all(db.read())
+        .await
+        .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("item-a1".to_string(), "100".to_string(), 1700000010, 0),
+                ("item-b".to_string(), "200".to_string(), 1700000020, 1),
+                ("item-c".to_string(), "300".to_string(), 1700000030, 2),
+            ],
+            "the first item is kept with the earliest enqueued_at; positions close up"
+        );
+
+        let dup = sqlx::query(
+            "INSERT INTO queue_items (id, user_id, user_login, display_name, profile_image_url, enqueued_at, position)
+             VALUES ('again', '100', 'alice', 'Alice', '', 0, 3)",
+        )
+        .execute(db.write())
+        .await;
+        assert!(dup.is_err(), "the unique index is in place afterwards");
+    }
+
+    #[tokio::test]
+    async fn mutations_go_through_while_every_read_connection_is_busy() {
+        let (db, path) = testing::temp_db(2).await;
+        let app = TestApp::with_db("", db, path).await;
+        let held = [
+            app.queue.db.read().acquire().await.unwrap(),
+            app.queue.db.read().acquire().await.unwrap(),
+        ];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), app.queue.db.read().acquire())
+                .await
+                .is_err()
+        );
+
+        let mutations = async {
+            let a = testing::enqueue(&app.queue, testing::new_user("a")).await;
+            let b = testing::enqueue(&app.queue, testing::new_user("b")).await;
+            queue::move_to_top(app.queue.db.write(), &app.queue.timings, &b, false)
+                .await
+                .unwrap();
+            queue::delete_item(
+                app.queue.db.write(),
+                &app.queue.timings,
+                &a,
+                queue::DeleteMode::Completed,
+            )
+            .await
+            .unwrap();
+            set_stream_online_at(app.queue.db.write(), 1_700_000_000)
+                .await
+                .unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(5), mutations)
+            .await
+            .expect("writes waited on the read pool");
+
+        drop(held);
+        let users: Vec<String> = sqlx::query_scalar("SELECT user_id FROM queue_items")
+            .fetch_all(app.queue.db.read())
+            .await
+            .unwrap();
+        assert_eq!(users, ["b"]);
+    }
+
+    /// Concurrent manual adds against overlay-style polling, once on one shared pool of
+    /// five writable connections (the layout before `Db`) and once on `Db`'s split pools.
+    /// `cargo test -- --ignored pool_contention_benchmark --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn pool_contention_benchmark() {
+        const WRITES: usize = 150;
+        const READS: usize = 300;
+
+        async fn run(label: &str, read: SqlitePool, write: SqlitePool, app: &TestApp) {
+            let cfg = Arc::new(app.settings.clone());
+            let timings = Arc::new(crate::timing::Timings::default());
+            let started = std::time::Instant::now();
+            let mut tasks = Vec::new();
+            for i in 0..WRITES.max(READS) {
+                if i < WRITES {
+                    let (pool, cfg, timings) =
+                        (write.clone(), Arc::clone(&cfg), Arc::clone(&timings));
+                    let user = testing::new_user(&format!("{label}{i}"));
+                    tasks.push(tokio::spawn(async move {
+                        let t = std::time::Instant::now();
+                        let policy = cfg.queue.default_policy();
+                        let ok = queue::enqueue_user(
+                            &pool,
+                            &timings,
+                            &cfg.queue,
+                            &policy,
+                            user,
+                            &queue::JoinNotices::default(),
+                        )
+                        .await
+                        .is_ok();
+                        (true, ok, t.elapsed())
+                    }));
+                }
+                if i < READS {
+                    let (pool, cfg, timings) =
+                        (read.clone(), Arc::clone(&cfg), Arc::clone(&timings));
+                    tasks.push(tokio::spawn(async move {
+                        let t = std::time::Instant::now();
+                        let ok = queue::list_queue(&pool, &timings, &cfg).await.is_ok();
+                        (false, ok, t.elapsed())
+                    }));
+                }
+            }
+            let mut write_ms = Vec::new();
+            let (mut write_errors, mut read_errors) = (0, 0);
+            for task in tasks {
+                let (is_write, ok, elapsed) = task.await.unwrap();
+                match (is_write, ok) {
+                    (true, true) => write_ms.push(elapsed.as_secs_f64() * 1000.0),
+                    (true, false) => write_errors += 1,
+                    (false, false) => read_errors += 1,
+                    (false, true) => {}
+                }
+            }
+            write_ms.sort_by(f64::total_cmp);
+            let p = |q: f64| {
+                write_ms
+                    .get(((write_ms.len() as f64 - 1.0) * q) as usize)
+                    .copied()
+                    .unwrap_or(0.0)
+            };
+            println!(
+                "{label:<7} total {:>7.1?}  writes ok {:>3} failed {write_errors:>3}  reads failed {read_errors:>3}  write p50 {:>7.1}ms p99 {:>7.1}ms",
+                started.elapsed(),
+                write_ms.len(),
+                p(0.5),
+                p(0.99),
+            );
+        }
+
+        let (db, path) = testing::temp_db(4).await;
+        let app = TestApp::with_db("[queue]\nmax_participations_per_window = 0\n", db, path).await;
+        let options = SqliteConnectOptions::new()
+            .filename(app.path.as_str())
+            .journal_mode(SqliteJournalMode::Wal);
+        let shared = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await
+            .unwrap();
+        run("shared", shared.clone(), shared, &app).await;
+        run(
+            "split",
+            app.queue.db.read().clone(),
+            app.queue.db.write().clone(),
+            &app,
+        )
+        .await;
+    }
+}
@@ -0,0 +1,371 @@
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+/// Longest waits and newest outbox failures listed in full; the rest are only counted.
+const NOTABLE_LIMIT: i64 = 10;
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct KindCount {
+    pub kind: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueDigest {
+    /// Accepted entries, from any source.
+    pub entered: i64,
+    pub completed: i64,
+    /// Redemption status updates queued for Twitch (completions and cancels/refunds).
+    pub redemptions_fulfilled: i64,
+    pub redemptions_canceled: i64,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct LongWait {
+    pub user_id: String,
+    pub user_login: Option<String>,
+    pub display_name: Option<String>,
+    pub completed_at: i64,
+    pub waited_secs: i64,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct OutboxFailure {
+    pub id: i64,
+    pub event_type: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub updated_at: i64,
+}
+
+/// What happened in `[since, until)` (`GET /api/digest`), for an admin tab that was
+/// asleep. Every field is always present; empty ranges give zeros and empty lists.
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestDto {
+    pub since: i64,
+    /// Server time the digest was computed at; pass it as the next `since`.
+    pub until: i64,
+    pub queue: QueueDigest,
+    /// EventSub lifecycle steps by kind (`connected`, `disconnected`, ...), sorted by kind.
+    pub eventsub: Vec<KindCount>,
+    pub wait_threshold_secs: i64,
+    /// Turns completed in the range after waiting at least `wait_threshold_secs`, longest first.
+    pub long_waits: Vec<LongWait>,
+    pub long_waits_total: i64,
+    /// Outbox entries that gave up in the range, newest first.
+    pub outbox_failures: Vec<OutboxFailure>,
+    pub outbox_failures_total: i64,
+    /// Newest overlay cue id; `/api/cues?after=` resumes from here.
+    pub cue_last_id: i64,
+}
+
+pub async fn digest(
+    pool: &SqlitePool,
+    since: i64,
+    until: i64,
+    wait_threshold_secs: i64,
+) -> anyhow::Result<DigestDto> {
+    let entered = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM queue_entries WHERE entered_at >= ?1 AND entered_at < ?2",
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_one(pool)
+    .await?;
+    let completed = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM participations WHERE source = 'queue' AND completed_at >= ?1 AND completed_at < ?2",
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_one(pool)
+    .await?;
+
+    let mut queue = QueueDigest {
+        entered,
+        completed,
+        ..Default::default()
+    };
+    let statuses = sqlx::query_as::<_, KindCount>(
+        r#"SELECT COALESCE(json_extract(payload, '$.status'), '') AS kind, COUNT(*) AS count
+           FROM outbox
+           WHERE event_type = 'redemption_status' AND created_at >= ?1 AND created_at < ?2
+           GROUP BY kind"#,
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_all(pool)
+    .await?;
+    for s in statuses {
+        match s.kind.as_str() {
+            "FULFILLED" => queue.redemptions_fulfilled = s.count,
+            "CANCELED" => queue.redemptions_canceled = s.count,
+            _ => {}
+        }
+    }
+
+    let eventsub = sqlx::query_as::<_, KindCount>(
+        r#"SELECT kind, COUNT(*) AS count
+           FROM eventsub_events
+           WHERE occurred_at >= ?1 AND occurred_at < ?2
+           GROUP BY kind
+           ORDER BY kind"#,
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_all(pool)
+    .await?;
+
+    // Wait = completion minus the user's latest entry before it, as in stats::public_stats.
+    let waits = r#"SELECT p.user_id, p.completed_at,
+                          p.completed_at - (
+                            SELECT MAX(e.entered_at) FROM queue_entries e
+                            WHERE e.user_id = p.user_id AND e.entered_at <= p.completed_at
+                          ) AS waited_secs
+                   FROM participations p
+                   WHERE p.source = 'queue' AND p.completed_at >= ?1 AND p.completed_at < ?2"#;
+    let long_waits_total = sqlx::query_scalar::<_, i64>(&format!(
+        "SELECT COUNT(*) FROM ({waits}) WHERE waited_secs >= ?3"
+    ))
+    .bind(since)
+    .bind(until)
+    .bind(wait_threshold_secs)
+    .fetch_one(pool)
+    .await?;
+    let long_waits = sqlx::query_as::<_, LongWait>(&format!(
+        r#"SELECT w.user_id, c.user_login, c.display_name, w.completed_at, w.waited_secs
+           FROM ({waits}) w
+           LEFT JOIN user_cache c ON c.user_id = w.user_id
+           WHERE w.waited_secs >= ?3
+           ORDER BY w.waited_secs DESC, w.completed_at DESC
+           LIMIT ?4"#
+    ))
+    .bind(since)
+    .bind(until)
+    .bind(wait_threshold_secs)
+    .bind(NOTABLE_LIMIT)
+    .fetch_all(pool)
+    .await?;
+
+    let outbox_failures_total = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM outbox WHERE status = 'failed' AND updated_at >= ?1 AND updated_at < ?2",
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_one(pool)
+    .await?;
+    let outbox_failures = sqlx::query_as::<_, OutboxFailure>(
+        r#"SELECT id, event_type, attempts, last_error, updated_at
+           FROM outbox
+           WHERE status = 'failed' AND updated_at >= ?1 AND updated_at < ?2
+           ORDER BY updated_at DESC, id DESC
+           LIMIT ?3"#,
+    )
+    .bind(since)
+    .bind(until)
+    .bind(NOTABLE_LIMIT)
+    .fetch_all(pool)
+    .await?;
+
+    let cue_last_id = sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(id) FROM cues")
+        .fetch_one(pool)
+        .await?
+        .unwrap_or(0);
+
+    Ok(DigestDto {
+        since,
+        until,
+        queue,
+        eventsub,
+        wait_threshold_secs,
+        long_waits,
+        long_waits_total,
+        outbox_failures,
+        outbox_failures_total,
+        cue_last_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{self, FullParticipation, ParticipationSource},
+        testing,
+    };
+
+    const SINCE: i64 = 1_000;
+    const UNTIL: i64 = 2_000;
+
+    async fn exec(pool: &SqlitePool, sql: &str) {
+        sqlx::query(sql).execute(pool).await.unwrap();
+    }
+
+    async fn complete(
+        pool: &SqlitePool,
+        user_id: &str,
+        completed_at: i64,
+        source: ParticipationSource,
+    ) {
+        let p = FullParticipation {
+            user_id: user_id.to_string(),
+            completed_at,
+            queue_item_id: None,
+            reward_id: None,
+            source,
+            session_id: None,
+            turn_started_at: None,
+        };
+        db::insert_participation(pool, &p).await.unwrap();
+    }
+
+    /// Events inside `[SINCE, UNTIL)` and on both sides of it.
+    async fn seed(pool: &SqlitePool) {
+        exec(
+            pool,
+            "INSERT INTO queue_entries (user_id, entered_at) VALUES
+               ('u1', 900), ('u1', 1100), ('u2', 1200), ('u3', 1999), ('u4', 2000)",
+        )
+        .await;
+        complete(pool, "u1", 1900, ParticipationSource::Queue).await;
+        complete(pool, "u2", 1300, ParticipationSource::Queue).await;
+        complete(pool, "u3", 1500, ParticipationSource::Import).await;
+        complete(pool, "u0", 999, ParticipationSource::Queue).await;
+        let profile = db::CachedUserProfile {
+            user_id: "u1".to_string(),
+            user_login: "alice".to_string(),
+            display_name: "Alice".to_string(),
+            profile_image_url: String::new(),
+            updated_at: 0,
+        };
+        db::upsert_cached_user_profile(pool, &profile)
+            .await
+            .unwrap();
+        exec(
+            pool,
+            r#"INSERT INTO outbox (event_type, payload, status, attempts, next_attempt_at, last_error, created_at, updated_at) VALUES
+                 ('redemption_status', '{"status":"FULFILLED"}', 'done', 1, 0, NULL, 1500, 1500),
+                 ('redemption_status', '{"status":"FULFILLED"}', 'failed', 5, 0, 'HTTP 500', 1400, 1700),
+                 ('redemption_status', '{"status":"CANCELED"}', 'done', 1, 0, NULL, 1600, 1600),
+                 ('redemption_status', '{"status":"FULFILLED"}', 'done', 1, 0, NULL, 2500, 2500),
+                 ('chat_message', '{}', 'failed', 5, 0, 'HTTP 401', 500, 500)"#,
+        )
+        .await;
+        for (occurred_at, kind) in [
+            (1100, "disconnected"),
+            (1150, "connected"),
+            (1160, "connected"),
+            (3000, "welcome"),
+        ] {
+            let ev = db::EventSubEvent {
+                occurred_at,
+                kind: kind.to_string(),
+                detail: None,
+            };
+            db::insert_eventsub_event(pool, &ev).await.unwrap();
+        }
+        exec(pool, "INSERT INTO cues (kind, payload, created_at) VALUES ('queue_opened', '{}', 100), ('queue_opened', '{}', 5000)").await;
+    }
+
+    #[tokio::test]
+    async fn counts_only_what_happened_in_the_range() {
+        let service = testing::queue_service("").await;
+        let pool = service.db.write();
+        seed(pool).await;
+
+        let d = digest(pool, SINCE, UNTIL, 500).await.unwrap();
+        assert_eq!(
+            (d.since, d.until, d.wait_threshold_secs),
+            (SINCE, UNTIL, 500)
+        );
+        let q = &d.queue;
+        assert_eq!(
+            (
+                q.entered,
+                q.completed,
+                q.redemptions_fulfilled,
+                q.redemptions_canceled
+            ),
+            (3, 2, 2, 1)
+        );
+        let eventsub: Vec<(&str, i64)> = d
+            .eventsub
+            .iter()
+            .map(|k| (k.kind.as_str(), k.count))
+            .collect();
+        assert_eq!(eventsub, [("connected", 2), ("disconnected", 1)]);
+
+        // u1 waited from the 1100 entry (not the one at 900), u2 only 100 seconds.
+        assert_eq!(d.long_waits_total, 1);
+        let w = &d.long_waits[0];
+        assert_eq!(
+            (
+                w.user_id.as_str(),
+                w.user_login.as_deref(),
+                w.display_name.as_deref(),
+                w.completed_at,
+                w.waited_secs
+            ),
+            ("u1", Some("alice"), Some("Alice"), 1900, 800)
+        );
+        assert_eq!(
+            digest(pool, SINCE, UNTIL, 0)
+                .await
+                .unwrap()
+                .long_waits_total,
+            2
+        );
+
+        assert_eq!(d.outbox_failures_total, 1);
+        assert_eq!(
+            (
+                d.outbox_failures[0].event_type.as_str(),
+                d.outbox_failures[0].last_error.as_deref()
+            ),
+            ("redemption_status", Some("HTTP 500"))
+        );
+        // Not limited to the range: the client resumes cues from here.
+        assert_eq!(d.cue_last_id, 2);
+    }
+
+    #[tokio::test]
+    async fn an_empty_range_gives_zeros_and_empty_lists() {
+        let service = testing::queue_service("").await;
+        let pool = service.db.write();
+        let fresh = digest(pool, SINCE, UNTIL, 0).await.unwrap();
+        assert_eq!(fresh.cue_last_id, 0);
+
+        seed(pool).await;
+        for (since, until) in [(1_500, 1_500), (10_000, 20_000)] {
+            let d = digest(pool, since, until, 0).await.unwrap();
+            let q = &d.queue;
+            assert_eq!(
+                (
+                    q.entered,
+                    q.completed,
+                    q.redemptions_fulfilled,
+                    q.redemptions_canceled
+                ),
+                (0, 0, 0, 0)
+            );
+            assert!(
+                d.eventsub.is_empty() && d.long_waits.is_empty() && d.outbox_failures.is_empty()
+            );
+            assert_eq!(
+                (d.long_waits_total, d.outbox_failures_total, d.cue_last_id),
+                (0, 0, 2)
+            );
+        }
+        let json = serde_json::to_value(&fresh).unwrap();
+        for key in [
+            "queue",
+            "eventsub",
+            "long_waits",
+            "long_waits_total",
+            "outbox_failures",
+            "outbox_failures_total",
+            "cue_last_id",
+        ] {
+            assert!(!json[key].is_null(), "{key} is always present");
+        }
+    }
+}
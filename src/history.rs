@@ -0,0 +1,104 @@
+use serde::Serialize;
+use sqlx::{FromRow, QueryBuilder, SqlitePool};
+
+use crate::{
+    pagination::{self, Cursor, Keyed, Page, PageRequest},
+    vod,
+};
+
+/// One completed turn, newest first (`GET /api/history`, `/api/users/:user_id/history`).
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub user_id: String,
+    /// From the profile cache; `None` when the user was never cached.
+    pub user_login: Option<String>,
+    pub display_name: Option<String>,
+    pub completed_at: i64,
+    pub reward_id: Option<String>,
+    /// `queue` or `import`; `None` for rows recorded before sources were tracked.
+    pub source: Option<String>,
+    pub session_id: Option<String>,
+    pub queue_item_id: Option<String>,
+    /// When the turn began (the item reached position 0).
+    pub turn_started_at: Option<i64>,
+    /// Archive of the stream the turn was played in; `None` with VODs off, for turns
+    /// not yet looked up, and for rows recorded before this was tracked.
+    pub vod_url: Option<String>,
+    pub vod_offset_secs: Option<i64>,
+    /// `vod_url` opened at the start of the turn (`?t=1h02m03s`).
+    #[sqlx(skip)]
+    pub vod_link: Option<String>,
+}
+
+impl Keyed for HistoryEntry {
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            ts: self.completed_at,
+            id: self.id,
+        }
+    }
+}
+
+/// Completions, optionally for one user.
+pub async fn list_history(
+    pool: &SqlitePool,
+    user_id: Option<&str>,
+    req: PageRequest,
+) -> anyhow::Result<Page<HistoryEntry>> {
+    let mut qb = QueryBuilder::new(
+        r#"SELECT p.id, p.user_id, c.user_login, c.display_name, p.completed_at,
+                  p.reward_id, p.source, p.session_id, p.queue_item_id,
+                  p.turn_started_at, p.vod_url, p.vod_offset_secs
+           FROM participations p
+           LEFT JOIN user_cache c ON c.user_id = p.user_id
+           WHERE 1 = 1"#,
+    );
+    if let Some(user_id) = user_id {
+        qb.push(" AND p.user_id = ").push_bind(user_id.to_string());
+    }
+    let mut page: Page<HistoryEntry> =
+        pagination::fetch_page(pool, qb, "p.completed_at", "p.id", req).await?;
+    for e in &mut page.items {
+        e.vod_link = e
+            .vod_url
+            .as_deref()
+            .zip(e.vod_offset_secs)
+            .map(|(url, o)| vod::timestamped_link(url, o));
+    }
+    Ok(page)
+}
+
+/// One redemption that entered the queue (`GET /api/redemptions/recent`).
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct RedemptionEntry {
+    pub id: i64,
+    pub user_id: String,
+    pub user_login: Option<String>,
+    pub display_name: Option<String>,
+    pub reward_id: String,
+    pub entered_at: i64,
+}
+
+impl Keyed for RedemptionEntry {
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            ts: self.entered_at,
+            id: self.id,
+        }
+    }
+}
+
+/// Accepted entries that came from a reward; manual adds and ingest have none.
+pub async fn list_recent_redemptions(
+    pool: &SqlitePool,
+    req: PageRequest,
+) -> anyhow::Result<Page<RedemptionEntry>> {
+    let qb = QueryBuilder::new(
+        r#"SELECT e.id, e.user_id, c.user_login, c.display_name, e.reward_id, e.entered_at
+           FROM queue_entries e
+           LEFT JOIN user_cache c ON c.user_id = e.user_id
+           WHERE e.reward_id IS NOT NULL"#,
+    );
+    pagination::fetch_page(pool, qb, "e.entered_at", "e.id", req).await
+}
@@ -0,0 +1,564 @@
+//! Turn history from other queue tools. Each format is an [`ImportAdapter`] that turns
+//! the file into [`ExternalRecord`]s; resolving logins and writing is shared.
+
+use std::collections::{BTreeSet, HashMap};
+
+use serde::Serialize;
+use sqlx::FromRow;
+
+use crate::{
+    stats::{self, ParticipationRecord},
+    twitch, util, AppState,
+};
+
+/// One completed turn as another tool recorded it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExternalRecord {
+    /// 1-based line in the file, for error messages and dry-run output.
+    pub line: usize,
+    pub user_login: String,
+    /// Present when the export carries Twitch user ids; otherwise resolved from the login.
+    pub user_id: Option<String>,
+    pub completed_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn parse_error(line: usize, message: impl Into<String>) -> ParseError {
+    ParseError {
+        line,
+        message: message.into(),
+    }
+}
+
+/// A source format. To add one, implement this and list it in [`ADAPTERS`].
+pub trait ImportAdapter: Sync {
+    /// Name used by `--format` and `?format=`.
+    fn format(&self) -> &'static str;
+    fn parse(&self, input: &str) -> Result<Vec<ExternalRecord>, ParseError>;
+}
+
+/// Our own CSV: a header row with `login` (or `user_login`), `completed_at` (epoch
+/// seconds or `YYYY-MM-DD HH:MM:SS` UTC) and optionally `user_id`.
+pub struct CsvAdapter;
+
+/// Streamlabs queue exports (CSV). Column names differ between versions, so the user
+/// column is any of `Username` / `User` / `Name` and the time any of `Date` / `Time` /
+/// `Timestamp` / `Joined`, case-insensitively. Times are read as UTC.
+pub struct StreamlabsAdapter;
+
+pub const ADAPTERS: &[&dyn ImportAdapter] = &[&CsvAdapter, &StreamlabsAdapter];
+
+pub fn adapter(format: &str) -> Option<&'static dyn ImportAdapter> {
+    ADAPTERS.iter().copied().find(|a| a.format() == format)
+}
+
+pub fn format_names() -> Vec<&'static str> {
+    ADAPTERS.iter().map(|a| a.format()).collect()
+}
+
+impl ImportAdapter for CsvAdapter {
+    fn format(&self) -> &'static str {
+        "csv"
+    }
+
+    fn parse(&self, input: &str) -> Result<Vec<ExternalRecord>, ParseError> {
+        let table = Table::parse(input)?;
+        let login = table.column(&["login", "user_login"])?;
+        let completed_at = table.column(&["completed_at"])?;
+        let user_id = table.optional_column(&["user_id"]);
+        table.records(login, completed_at, user_id)
+    }
+}
+
+impl ImportAdapter for StreamlabsAdapter {
+    fn format(&self) -> &'static str {
+        "streamlabs"
+    }
+
+    fn parse(&self, input: &str) -> Result<Vec<ExternalRecord>, ParseError> {
+        let table = Table::parse(input)?;
+        let login = table.column(&["username", "user", "name"])?;
+        let completed_at = table.column(&["date", "time", "timestamp", "joined"])?;
+        table.records(login, completed_at, None)
+    }
+}
+
+/// A CSV file: lowercased header and the data rows with their line numbers.
+struct Table {
+    header: Vec<String>,
+    rows: Vec<(usize, Vec<String>)>,
+}
+
+impl Table {
+    fn parse(input: &str) -> Result<Self, ParseError> {
+        let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+        let mut lines = input
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, l))
+            .filter(|(_, l)| !util::is_blank(l));
+        let Some((header_line, header)) = lines.next() else {
+            return Err(parse_error(1, "the file is empty"));
+        };
+        let header = split_csv_line(header)
+            .map_err(|m| parse_error(header_line, m))?
+            .into_iter()
+            .map(|h| h.trim().to_lowercase())
+            .collect();
+        let rows = lines
+            .map(|(n, l)| {
+                split_csv_line(l)
+                    .map(|f| (n, f))
+                    .map_err(|m| parse_error(n, m))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { header, rows })
+    }
+
+    fn optional_column(&self, names: &[&str]) -> Option<usize> {
+        self.header.iter().position(|h| names.contains(&h.as_str()))
+    }
+
+    fn column(&self, names: &[&str]) -> Result<usize, ParseError> {
+        self.optional_column(names)
+            .ok_or_else(|| parse_error(1, format!("missing column (one of: {})", names.join(", "))))
+    }
+
+    fn records(
+        &self,
+        login: usize,
+        completed_at: usize,
+        user_id: Option<usize>,
+    ) -> Result<Vec<ExternalRecord>, ParseError> {
+        let now = util::now_epoch();
+        fn field(fields: &[String], i: usize) -> &str {
+            fields.get(i).map(|s| s.trim()).unwrap_or("")
+        }
+        self.rows
+            .iter()
+            .map(|(line, fields)| {
+                let user_login = field(fields, login).trim_start_matches('@').to_lowercase();
+                if user_login.is_empty() {
+                    return Err(parse_error(*line, "login is empty"));
+                }
+                let raw = field(fields, completed_at);
+                let ts = parse_time(raw)
+                    .ok_or_else(|| parse_error(*line, format!("cannot read time {raw:?}")))?;
+                if !stats::is_plausible_completed_at(ts, now) {
+                    return Err(parse_error(
+                        *line,
+                        format!("time {raw:?} is before Twitch existed or in the future"),
+                    ));
+                }
+                Ok(ExternalRecord {
+                    line: *line,
+                    user_login,
+                    user_id: user_id
+                        .map(|i| field(fields, i))
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_string),
+                    completed_at: ts,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Epoch seconds or a UTC date-time.
+fn parse_time(raw: &str) -> Option<i64> {
+    if !raw.is_empty() && raw.bytes().all(|b| b.is_ascii_digit()) {
+        return raw.parse().ok();
+    }
+    util::parse_utc_datetime(raw)
+}
+
+/// Splits one CSV line. Quoted fields may contain commas and `""`; fields spanning
+/// lines are not supported.
+fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut cur = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                cur.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if cur.trim().is_empty() => {
+                cur.clear();
+                quoted = true;
+            }
+            (false, ',') => fields.push(std::mem::take(&mut cur)),
+            _ => cur.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quote".to_string());
+    }
+    fields.push(cur);
+    Ok(fields)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportOptions {
+    /// Report what would be written without writing.
+    pub dry_run: bool,
+    /// Do not call Helix: rows without a user id are stored as unresolved for [`resolve_pending`].
+    pub offline: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryImportReport {
+    pub format: &'static str,
+    pub dry_run: bool,
+    pub offline: bool,
+    pub parsed: usize,
+    /// Rows with a user id (from the file or Helix), written as participations.
+    pub participations: Vec<ExternalRecord>,
+    /// Rows kept by login for the resolve pass (offline mode).
+    pub unresolved: Vec<ExternalRecord>,
+    /// Logins Twitch does not know (renamed, deleted or banned); their rows are skipped.
+    pub unknown_logins: Vec<String>,
+    /// Participations written; 0 on a dry run.
+    pub inserted: u64,
+    /// Already recorded (same user and time); 0 on a dry run.
+    pub duplicates: u64,
+}
+
+/// User ids for `logins` from Helix, and the (sorted) logins Twitch does not know.
+async fn lookup_user_ids<'a>(
+    state: &AppState,
+    logins: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<(HashMap<String, String>, Vec<String>)> {
+    let logins: Vec<String> = logins
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if logins.is_empty() {
+        return Ok((HashMap::new(), Vec::new()));
+    }
+    let access_token = twitch::get_fresh_access_token(&state.twitch).await?;
+    let users = twitch::helix_get_users_by_logins(&state.twitch, &access_token, &logins).await?;
+    let unknown = logins
+        .into_iter()
+        .filter(|l| !users.contains_key(l))
+        .collect();
+    Ok((
+        users.into_iter().map(|(login, u)| (login, u.id)).collect(),
+        unknown,
+    ))
+}
+
+/// Parses `input` with `adapter` and writes the result: participations for rows with a
+/// user id, `unresolved_imports` for the rest when offline. One transaction.
+pub async fn import_history(
+    state: &AppState,
+    adapter: &dyn ImportAdapter,
+    input: &str,
+    opts: ImportOptions,
+) -> anyhow::Result<HistoryImportReport> {
+    let records = adapter.parse(input)?;
+    let parsed = records.len();
+    let (mut participations, by_login): (Vec<_>, Vec<_>) =
+        records.into_iter().partition(|r| r.user_id.is_some());
+    let (unresolved, unknown_logins) = if opts.offline {
+        (by_login, Vec::new())
+    } else {
+        let (ids, unknown) =
+            lookup_user_ids(state, by_login.iter().map(|r| r.user_login.as_str())).await?;
+        participations.extend(by_login.into_iter().filter_map(|mut r| {
+            r.user_id = Some(ids.get(&r.user_login)?.clone());
+            Some(r)
+        }));
+        (Vec::new(), unknown)
+    };
+
+    let mut report = HistoryImportReport {
+        format: adapter.format(),
+        dry_run: opts.dry_run,
+        offline: opts.offline,
+        parsed,
+        participations,
+        unresolved,
+        unknown_logins,
+        inserted: 0,
+        duplicates: 0,
+    };
+    if opts.dry_run {
+        return Ok(report);
+    }
+
+    let now = util::now_epoch();
+    let mut tx = state.queue.db.write().begin().await?;
+    let records: Vec<ParticipationRecord> = report
+        .participations
+        .iter()
+        .filter_map(|r| {
+            Some(ParticipationRecord {
+                user_id: r.user_id.clone()?,
+                completed_at: r.completed_at,
+            })
+        })
+        .collect();
+    let written = stats::import_participations_tx(&mut tx, &records).await?;
+    for r in &report.unresolved {
+        sqlx::query(
+            r#"INSERT INTO unresolved_imports (user_login, completed_at, format, imported_at)
+               VALUES (?1, ?2, ?3, ?4)
+               ON CONFLICT DO NOTHING"#,
+        )
+        .bind(&r.user_login)
+        .bind(r.completed_at)
+        .bind(adapter.format())
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    report.inserted = written.inserted;
+    report.duplicates = written.duplicates;
+    Ok(report)
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct UnresolvedImport {
+    pub id: i64,
+    pub user_login: String,
+    pub completed_at: i64,
+    /// Filled by the resolve pass.
+    #[sqlx(skip)]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolveReport {
+    pub dry_run: bool,
+    /// Unresolved rows before this pass.
+    pub pending: usize,
+    pub resolved: Vec<UnresolvedImport>,
+    /// Still unknown to Twitch; their rows stay in `unresolved_imports`.
+    pub unknown_logins: Vec<String>,
+    pub inserted: u64,
+    pub duplicates: u64,
+}
+
+/// Looks up the logins of offline imports and moves the rows that resolve into
+/// participations, in one transaction.
+pub async fn resolve_pending(state: &AppState, dry_run: bool) -> anyhow::Result<ResolveReport> {
+    let rows = sqlx::query_as::<_, UnresolvedImport>(
+        "SELECT id, user_login, completed_at FROM unresolved_imports ORDER BY id",
+    )
+    .fetch_all(state.queue.db.write())
+    .await?;
+    let pending = rows.len();
+    let (ids, unknown_logins) =
+        lookup_user_ids(state, rows.iter().map(|r| r.user_login.as_str())).await?;
+    let resolved = rows
+        .into_iter()
+        .filter_map(|mut r| {
+            r.user_id = Some(ids.get(&r.user_login)?.clone());
+            Some(r)
+        })
+        .collect();
+
+    let mut report = ResolveReport {
+        dry_run,
+        pending,
+        resolved,
+        unknown_logins,
+        inserted: 0,
+        duplicates: 0,
+    };
+    if dry_run {
+        return Ok(report);
+    }
+
+    let mut tx = state.queue.db.write().begin().await?;
+    let records: Vec<ParticipationRecord> = report
+        .resolved
+        .iter()
+        .filter_map(|r| {
+            Some(ParticipationRecord {
+                user_id: r.user_id.clone()?,
+                completed_at: r.completed_at,
+            })
+        })
+        .collect();
+    let written = stats::import_participations_tx(&mut tx, &records).await?;
+    for r in &report.resolved {
+        sqlx::query("DELETE FROM unresolved_imports WHERE id = ?1")
+            .bind(r.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    report.inserted = written.inserted;
+    report.duplicates = written.duplicates;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestApp;
+
+    fn fixture(name: &str) -> String {
+        let path = format!(
+            "{}/tests/fixtures/history_import/{name}",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    fn record(
+        line: usize,
+        user_login: &str,
+        user_id: Option<&str>,
+        completed_at: i64,
+    ) -> ExternalRecord {
+        ExternalRecord {
+            line,
+            user_login: user_login.to_string(),
+            user_id: user_id.map(str::to_string),
+            completed_at,
+        }
+    }
+
+    async fn written_rows(app: &TestApp) -> (Vec<(String, i64)>, Vec<(String, i64, String)>) {
+        let pool = app.queue.db.read();
+        let participations = sqlx::query_as(
+            "SELECT user_id, completed_at FROM participations ORDER BY completed_at",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        let unresolved = sqlx::query_as(
+            "SELECT user_login, completed_at, format FROM unresolved_imports ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        (participations, unresolved)
+    }
+
+    const OFFLINE: ImportOptions = ImportOptions {
+        dry_run: false,
+        offline: true,
+    };
+
+    #[test]
+    fn streamlabs_fixture_parses_to_these_records() {
+        // BOM, CRLF, a blank line, quoted messages, "@" and mixed case in names.
+        assert_eq!(
+            StreamlabsAdapter.parse(&fixture("streamlabs.csv")).unwrap(),
+            [
+                record(2, "cooleruser", None, 1_714_566_896),
+                record(3, "second_viewer", None, 1_714_568_400),
+                record(5, "third_viewer", None, 1_714_680_900),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_fixture_parses_to_these_records() {
+        assert_eq!(
+            CsvAdapter.parse(&fixture("history.csv")).unwrap(),
+            [
+                record(2, "cooler_user", Some("9001"), 1_714_566_896),
+                record(3, "renamed_viewer", None, 1_714_770_000),
+                record(4, "plain_viewer", None, 1_714_766_400),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn streamlabs_import_writes_these_rows() {
+        let app = TestApp::new("").await;
+        let report = import_history(
+            &app.state,
+            &StreamlabsAdapter,
+            &fixture("streamlabs.csv"),
+            OFFLINE,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (report.parsed, report.inserted, report.unresolved.len()),
+            (3, 0, 3)
+        );
+        let (participations, unresolved) = written_rows(&app).await;
+        assert!(participations.is_empty());
+        assert_eq!(
+            unresolved,
+            [
+                (
+                    "cooleruser".to_string(),
+                    1_714_566_896,
+                    "streamlabs".to_string()
+                ),
+                (
+                    "second_viewer".to_string(),
+                    1_714_568_400,
+                    "streamlabs".to_string()
+                ),
+                (
+                    "third_viewer".to_string(),
+                    1_714_680_900,
+                    "streamlabs".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn csv_import_writes_these_rows() {
+        let app = TestApp::new("").await;
+        let dry_run = ImportOptions {
+            dry_run: true,
+            ..OFFLINE
+        };
+        let report = import_history(&app.state, &CsvAdapter, &fixture("history.csv"), dry_run)
+            .await
+            .unwrap();
+        assert_eq!(
+            (report.participations.len(), report.unresolved.len()),
+            (1, 2)
+        );
+        assert_eq!(written_rows(&app).await, (Vec::new(), Vec::new()));
+
+        let report = import_history(&app.state, &CsvAdapter, &fixture("history.csv"), OFFLINE)
+            .await
+            .unwrap();
+        assert_eq!((report.inserted, report.duplicates), (1, 0));
+        assert_eq!(
+            written_rows(&app).await,
+            (
+                vec![("9001".to_string(), 1_714_566_896)],
+                vec![
+                    (
+                        "renamed_viewer".to_string(),
+                        1_714_770_000,
+                        "csv".to_string()
+                    ),
+                    ("plain_viewer".to_string(), 1_714_766_400, "csv".to_string()),
+                ]
+            )
+        );
+    }
+}
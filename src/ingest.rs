@@ -0,0 +1,194 @@
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::config::EnqueueSource;
+
+/// `sha256=<hex HMAC-SHA256 of the raw body>` with `ingest.secret` as the key.
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Body of `POST /api/ingest/enqueue`. `timestamp` and `nonce` are part of the signed
+/// body, so a captured request cannot be replayed with fresh values.
+#[derive(Debug, Deserialize)]
+pub struct IngestEnqueueRequest {
+    /// Exactly one of `login` / `user_id`.
+    #[serde(default)]
+    pub login: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Must be `external` when given.
+    #[serde(default)]
+    pub source: Option<EnqueueSource>,
+    /// Stored as the item's private note.
+    #[serde(default)]
+    pub note: Option<String>,
+    /// At most the highest reward priority.
+    #[serde(default)]
+    pub priority: i64,
+    /// Epoch seconds when the request was signed.
+    pub timestamp: i64,
+    /// Unique per request; a nonce seen before is rejected.
+    pub nonce: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IngestRejection {
+    BadSignature,
+    /// `timestamp` is more than the allowed skew away from the server clock.
+    Stale {
+        skew_secs: i64,
+    },
+    BlankNonce,
+}
+
+/// Checks `header` (`sha256=<hex>`) against the HMAC of `body`. The MAC comparison is
+/// constant-time.
+pub fn verify_signature(
+    secret: &str,
+    body: &[u8],
+    header: Option<&str>,
+) -> Result<(), IngestRejection> {
+    let hex = header
+        .and_then(|h| h.trim().strip_prefix("sha256="))
+        .ok_or(IngestRejection::BadSignature)?;
+    let expected = decode_hex(hex).ok_or(IngestRejection::BadSignature)?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    mac.verify_slice(&expected)
+        .map_err(|_| IngestRejection::BadSignature)
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Timestamp within `max_skew_secs` of `now` (either direction) and a usable nonce.
+pub fn check_freshness(
+    req: &IngestEnqueueRequest,
+    now: i64,
+    max_skew_secs: u64,
+) -> Result<(), IngestRejection> {
+    let skew_secs = now.saturating_sub(req.timestamp);
+    if skew_secs.unsigned_abs() > max_skew_secs {
+        return Err(IngestRejection::Stale { skew_secs });
+    }
+    if req.nonce.trim().is_empty() {
+        return Err(IngestRejection::BlankNonce);
+    }
+    Ok(())
+}
+
+/// `processed_messages` id for a nonce; namespaced away from EventSub message ids.
+pub fn nonce_key(nonce: &str) -> String {
+    format!("ingest:{}", nonce.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ingest_signature as sign;
+
+    fn request(timestamp: i64, nonce: &str) -> IngestEnqueueRequest {
+        IngestEnqueueRequest {
+            login: Some("viewer".to_string()),
+            user_id: None,
+            source: None,
+            note: None,
+            priority: 0,
+            timestamp,
+            nonce: nonce.to_string(),
+        }
+    }
+
+    #[test]
+    fn only_the_signature_of_the_exact_body_with_the_shared_secret_passes() {
+        let body = br#"{"login":"viewer","timestamp":1700000000,"nonce":"n1"}"#;
+        let good = sign("secret", body);
+        assert_eq!(verify_signature("secret", body, Some(&good)), Ok(()));
+        assert_eq!(
+            verify_signature("secret", body, Some(&format!("  {good} "))),
+            Ok(())
+        );
+
+        let tampered = br#"{"login":"viewer","timestamp":1700000000,"nonce":"n2"}"#;
+        for (secret, body, header) in [
+            ("secret", &tampered[..], Some(good.clone())),
+            ("other", &body[..], Some(good.clone())),
+            ("secret", &body[..], None),
+            (
+                "secret",
+                &body[..],
+                Some(good.trim_start_matches("sha256=").to_string()),
+            ),
+            ("secret", &body[..], Some(good.replace("sha256=", "sha1="))),
+            (
+                "secret",
+                &body[..],
+                Some(good[..good.len() - 1].to_string()),
+            ),
+            (
+                "secret",
+                &body[..],
+                Some(format!("{}zz", &good[..good.len() - 2])),
+            ),
+            ("secret", &body[..], Some("sha256=".to_string())),
+        ] {
+            assert_eq!(
+                verify_signature(secret, body, header.as_deref()),
+                Err(IngestRejection::BadSignature),
+                "{secret} {header:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn timestamps_within_the_skew_pass_in_both_directions() {
+        let now = 1_700_000_000;
+        for timestamp in [now, now - 300, now + 300, now - 1, now + 1] {
+            assert_eq!(
+                check_freshness(&request(timestamp, "n"), now, 300),
+                Ok(()),
+                "{timestamp}"
+            );
+        }
+        assert_eq!(
+            check_freshness(&request(now - 301, "n"), now, 300),
+            Err(IngestRejection::Stale { skew_secs: 301 })
+        );
+        assert_eq!(
+            check_freshness(&request(now + 301, "n"), now, 300),
+            Err(IngestRejection::Stale { skew_secs: -301 })
+        );
+        assert_eq!(
+            check_freshness(&request(0, "n"), now, 300),
+            Err(IngestRejection::Stale { skew_secs: now })
+        );
+        assert_eq!(check_freshness(&request(now, "n"), now, 0), Ok(()));
+        assert_eq!(
+            check_freshness(&request(now - 1, "n"), now, 0),
+            Err(IngestRejection::Stale { skew_secs: 1 })
+        );
+    }
+
+    #[test]
+    fn blank_nonces_are_rejected_and_nonces_are_namespaced() {
+        let now = 1_700_000_000;
+        assert_eq!(
+            check_freshness(&request(now, ""), now, 300),
+            Err(IngestRejection::BlankNonce)
+        );
+        assert_eq!(
+            check_freshness(&request(now, "  \t"), now, 300),
+            Err(IngestRejection::BlankNonce)
+        );
+        assert_eq!(nonce_key(" n1 "), "ingest:n1");
+        assert_ne!(nonce_key("n1"), "n1");
+    }
+}
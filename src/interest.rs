@@ -0,0 +1,209 @@
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{outbox, util};
+
+/// Why a join redemption did not reach the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Overlay heartbeat lost or the post-raid pause.
+    QueuePaused,
+}
+
+impl DropReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::QueuePaused => "queue_paused",
+        }
+    }
+}
+
+/// A dropped join redemption, enough to enqueue the viewer later.
+#[derive(Debug, Clone)]
+pub struct Interest {
+    pub user_id: String,
+    pub user_login: String,
+    pub display_name: String,
+    pub reward_id: String,
+    pub redemption_id: Option<String>,
+    pub user_input: Option<String>,
+    pub reason: DropReason,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct PendingInterestDto {
+    pub id: i64,
+    pub user_id: String,
+    pub user_login: String,
+    pub display_name: String,
+    pub reward_id: String,
+    #[serde(skip)]
+    pub redemption_id: Option<String>,
+    #[serde(skip)]
+    pub user_input: Option<String>,
+    pub reason: String,
+    /// Latest drop for this user; the row expires `queue.pending_interest_ttl_secs` later.
+    pub recorded_at: i64,
+}
+
+/// Records (or refreshes) the user's interest. A repeat drop keeps the row and id but
+/// takes the newest redemption and time.
+pub async fn record(pool: &SqlitePool, interest: &Interest, now: i64) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"INSERT INTO pending_interest (user_id, user_login, display_name, reward_id, redemption_id, user_input, reason, recorded_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+           ON CONFLICT(user_id) DO UPDATE SET
+             user_login = excluded.user_login,
+             display_name = excluded.display_name,
+             reward_id = excluded.reward_id,
+             redemption_id = excluded.redemption_id,
+             user_input = excluded.user_input,
+             reason = excluded.reason,
+             recorded_at = excluded.recorded_at"#,
+    )
+    .bind(&interest.user_id)
+    .bind(&interest.user_login)
+    .bind(&interest.display_name)
+    .bind(&interest.reward_id)
+    .bind(&interest.redemption_id)
+    .bind(&interest.user_input)
+    .bind(interest.reason.as_str())
+    .bind(now)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+const SELECT_COLUMNS: &str =
+    "SELECT id, user_id, user_login, display_name, reward_id, redemption_id, user_input, reason, recorded_at FROM pending_interest";
+
+/// Oldest first, the order the viewers knocked.
+pub async fn list(pool: &SqlitePool) -> anyhow::Result<Vec<PendingInterestDto>> {
+    let rows = sqlx::query_as::<_, PendingInterestDto>(&format!(
+        "{SELECT_COLUMNS} ORDER BY recorded_at ASC, id ASC"
+    ))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn get(pool: &SqlitePool, id: i64) -> anyhow::Result<Option<PendingInterestDto>> {
+    let row = sqlx::query_as::<_, PendingInterestDto>(&format!("{SELECT_COLUMNS} WHERE id = ?1"))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row)
+}
+
+/// Removes the row only if it was not refreshed since it was read (`recorded_at`).
+pub async fn remove(pool: &SqlitePool, id: i64, recorded_at: i64) -> anyhow::Result<bool> {
+    let result = sqlx::query("DELETE FROM pending_interest WHERE id = ?1 AND recorded_at = ?2")
+        .bind(id)
+        .bind(recorded_at)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Withdraws the user's interest (they redeemed the cancel reward), refunding the held
+/// join redemption like canceling a queued item would. False if there was no row.
+pub async fn withdraw(pool: &SqlitePool, user_id: &str) -> anyhow::Result<bool> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query_as::<_, (String, Option<String>)>(
+        "DELETE FROM pending_interest WHERE user_id = ?1 RETURNING reward_id, redemption_id",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((reward_id, redemption_id)) = row else {
+        tx.rollback().await?;
+        return Ok(false);
+    };
+    if let Some(redemption_id) = redemption_id {
+        let event = outbox::OutboxEvent::RedemptionStatus {
+            reward_id,
+            redemption_id,
+            status: outbox::RedemptionStatus::Canceled,
+        };
+        outbox::insert_tx(&mut tx, &event, util::now_epoch()).await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn cleanup(pool: &SqlitePool, cutoff: i64) -> anyhow::Result<u64> {
+    let result = sqlx::query("DELETE FROM pending_interest WHERE recorded_at < ?1")
+        .bind(cutoff)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn dropped(user_id: &str, redemption_id: &str) -> Interest {
+        Interest {
+            user_id: user_id.to_string(),
+            user_login: user_id.to_string(),
+            display_name: user_id.to_string(),
+            reward_id: "join".to_string(),
+            redemption_id: Some(redemption_id.to_string()),
+            user_input: None,
+            reason: DropReason::QueuePaused,
+        }
+    }
+
+    fn users(rows: &[PendingInterestDto]) -> Vec<(&str, i64)> {
+        rows.iter()
+            .map(|r| (r.user_id.as_str(), r.recorded_at))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rows_expire_from_their_latest_drop() {
+        let service = testing::queue_service("").await;
+        let pool = service.db.write();
+        record(pool, &dropped("a", "r1"), 100).await.unwrap();
+        record(pool, &dropped("b", "r2"), 150).await.unwrap();
+        let first_id = list(pool).await.unwrap()[0].id;
+
+        // A repeat drop keeps the row but restarts its clock.
+        record(pool, &dropped("a", "r3"), 200).await.unwrap();
+        let rows = list(pool).await.unwrap();
+        assert_eq!(users(&rows), [("b", 150), ("a", 200)]);
+        let a = rows.iter().find(|r| r.user_id == "a").unwrap();
+        assert_eq!((a.id, a.redemption_id.as_deref()), (first_id, Some("r3")));
+
+        assert_eq!(
+            cleanup(pool, 150).await.unwrap(),
+            0,
+            "the cutoff itself is not expired"
+        );
+        assert_eq!(cleanup(pool, 151).await.unwrap(), 1);
+        assert_eq!(users(&list(pool).await.unwrap()), [("a", 200)]);
+        assert_eq!(cleanup(pool, 1_000).await.unwrap(), 1);
+        assert!(list(pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_row_refreshed_after_it_was_read_is_not_removed() {
+        let service = testing::queue_service("").await;
+        let pool = service.db.write();
+        record(pool, &dropped("a", "r1"), 100).await.unwrap();
+        let read = list(pool).await.unwrap().remove(0);
+
+        record(pool, &dropped("a", "r2"), 120).await.unwrap();
+        assert!(
+            !remove(pool, read.id, read.recorded_at).await.unwrap(),
+            "the newer redemption stays listed"
+        );
+        let current = get(pool, read.id).await.unwrap().unwrap();
+        assert_eq!(current.redemption_id.as_deref(), Some("r2"));
+        assert!(remove(pool, current.id, current.recorded_at).await.unwrap());
+        assert!(get(pool, read.id).await.unwrap().is_none());
+    }
+}
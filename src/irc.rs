@@ -0,0 +1,194 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::{net::TcpStream, sync::Mutex};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+
+use crate::{db, twitch::TwitchClient};
+
+pub const IRC_WS_URL: &str = "wss://irc-ws.chat.twitch.tv:443";
+/// Scope IRC needs to send messages (`chat.irc_fallback`).
+pub const CHAT_EDIT_SCOPE: &str = "chat:edit";
+
+/// How long to wait for Twitch to accept PASS / NICK before giving up on a connection.
+const LOGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+type WsSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+struct Connection {
+    login: String,
+    sink: Arc<Mutex<WsSink>>,
+    /// Cleared by the reader task when the socket closes.
+    alive: Arc<AtomicBool>,
+    joined: Option<String>,
+}
+
+/// Sends chat messages over Twitch IRC. The connection is opened on the first send,
+/// kept open (answering PINGs) and reopened when it has dropped.
+pub struct IrcSender {
+    /// [`IRC_WS_URL`]; tests point it at a local stub.
+    url: String,
+    conn: Mutex<Option<Connection>>,
+}
+
+impl IrcSender {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// PRIVMSG to `#channel_login` as `login`; retried once on a fresh connection.
+    pub async fn send(
+        &self,
+        access_token: &str,
+        login: &str,
+        channel_login: &str,
+        message: &str,
+        reply_parent_message_id: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let channel = channel_login.to_lowercase();
+        let text = message.replace(['\r', '\n'], " ");
+        let line = match reply_parent_message_id {
+            Some(id) => format!("@reply-parent-msg-id={id} PRIVMSG #{channel} :{text}"),
+            None => format!("PRIVMSG #{channel} :{text}"),
+        };
+
+        let mut conn = self.conn.lock().await;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let reusable = conn
+                .as_ref()
+                .is_some_and(|c| c.alive.load(Ordering::Relaxed) && c.login == login);
+            if !reusable {
+                *conn = Some(connect(&self.url, access_token, login).await?);
+            }
+            let c = conn.as_mut().expect("connected above");
+            match send_on(c, &channel, &line).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt == 1 => {
+                    debug!(error = ?e, "irc send failed; reconnecting");
+                    *conn = None;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+async fn send_on(c: &mut Connection, channel: &str, line: &str) -> anyhow::Result<()> {
+    let mut sink = c.sink.lock().await;
+    if c.joined.as_deref() != Some(channel) {
+        sink.send(Message::Text(format!("JOIN #{channel}"))).await?;
+        c.joined = Some(channel.to_string());
+    }
+    sink.send(Message::Text(line.to_string())).await?;
+    Ok(())
+}
+
+async fn connect(url: &str, access_token: &str, login: &str) -> anyhow::Result<Connection> {
+    let (ws, _resp) = tokio_tungstenite::connect_async(url).await?;
+    let (mut write, mut read) = ws.split();
+    write
+        .send(Message::Text(format!("PASS oauth:{access_token}")))
+        .await?;
+    write
+        .send(Message::Text(format!("NICK {}", login.to_lowercase())))
+        .await?;
+
+    // 001 = welcome; a NOTICE before it means the login was refused.
+    let welcome = tokio::time::timeout(LOGIN_TIMEOUT, async {
+        while let Some(msg) = read.next().await {
+            let Message::Text(text) = msg? else { continue };
+            for line in text.lines() {
+                if line.split(' ').nth(1) == Some("001") {
+                    return Ok(());
+                }
+                if line.contains(" NOTICE ") {
+                    anyhow::bail!("irc login refused: {line}");
+                }
+            }
+        }
+        anyhow::bail!("irc connection closed during login")
+    })
+    .await;
+    match welcome {
+        Ok(result) => result?,
+        Err(_) => anyhow::bail!("irc login timed out"),
+    }
+    info!(login = %login, "irc chat connected");
+
+    let sink = Arc::new(Mutex::new(write));
+    let alive = Arc::new(AtomicBool::new(true));
+    {
+        let sink = Arc::clone(&sink);
+        let alive = Arc::clone(&alive);
+        tokio::spawn(async move {
+            'read: while let Some(Ok(msg)) = read.next().await {
+                let Message::Text(text) = msg else { continue };
+                for line in text.lines() {
+                    if let Some(server) = line.strip_prefix("PING ") {
+                        if sink
+                            .lock()
+                            .await
+                            .send(Message::Text(format!("PONG {server}")))
+                            .await
+                            .is_err()
+                        {
+                            break 'read;
+                        }
+                    } else if line.split(' ').nth(1) == Some("RECONNECT") {
+                        debug!("irc server asked to reconnect");
+                        break 'read;
+                    }
+                }
+            }
+            alive.store(false, Ordering::Relaxed);
+            debug!("irc chat connection closed");
+        });
+    }
+
+    Ok(Connection {
+        login: login.to_string(),
+        sink,
+        alive,
+        joined: None,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidateResponse {
+    #[serde(default)]
+    scopes: Vec<String>,
+}
+
+/// Startup check for `chat.irc_fallback`: warns when the token cannot send over IRC.
+pub async fn check_chat_edit_scope(
+    twitch: &TwitchClient,
+    access_token: &str,
+) -> anyhow::Result<bool> {
+    let resp = twitch
+        .http
+        .get(twitch.oauth("/validate"))
+        .header("Authorization", format!("OAuth {access_token}"))
+        .send()
+        .await?
+        .error_for_status()?;
+    let body: ValidateResponse = resp.json().await?;
+    db::set_oauth_scopes(twitch.db.write(), &body.scopes).await?;
+    let ok = body.scopes.iter().any(|s| s == CHAT_EDIT_SCOPE);
+    if !ok {
+        warn!(
+            scopes = ?body.scopes,
+            "chat.irc_fallback is on but the token lacks {CHAT_EDIT_SCOPE}; log in again to enable the IRC fallback"
+        );
+    }
+    Ok(ok)
+}
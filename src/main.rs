@@ -1,74 +1,235 @@
+mod access;
+mod admin_users;
+mod agenda;
+mod audit;
+mod cli;
 mod config;
+mod cues;
 mod db;
+mod diagnostics;
+mod digest;
+mod history;
+mod history_import;
+mod ingest;
+mod interest;
+mod irc;
+mod outbox;
+mod overlay_token;
+mod pagination;
+mod prefs;
+mod profiles;
 mod queue;
+mod redact;
+mod reward_prompt;
+mod roster;
+mod stats;
+mod sweep;
+#[cfg(test)]
+mod testing;
+mod timing;
 mod twitch;
 mod util;
+mod vod;
 mod web;
 
 use std::sync::Arc;
 
 use anyhow::Context;
-use config::Config;
-use sqlx::SqlitePool;
-use tokio::sync::RwLock;
+use config::{Config, Settings};
 use tracing::{error, info};
 
+/// Everything the web handlers and background loops share. Each capability is
+/// constructible on its own; handlers extract the one they need (the `FromRef` impls in
+/// `web`) and the EventSub loop takes only the Twitch client and the queue.
 pub struct AppState {
-    pub config: Arc<Config>,
-    pub db: SqlitePool,
-    pub http: reqwest::Client,
-    /// OAuth state (CSRF) for the current login attempt.
-    pub oauth_state: RwLock<Option<String>>,
+    pub settings: Settings,
+    pub queue: queue::QueueService,
+    /// HTTP client, OAuth state, Helix limits and the EventSub session status.
+    pub twitch: Arc<twitch::TwitchClient>,
+    /// Signing keys for overlay URLs (`server.require_overlay_token`).
+    pub overlay_keys: std::sync::RwLock<overlay_token::OverlayKeys>,
+    /// Archive VOD of the current stream session, for the links on completed turns.
+    pub vod_cache: vod::VodCache,
+    /// `GET /api/stats/public`, recomputed at most once a minute.
+    pub public_stats_cache: stats::PublicStatsCache,
+}
+
+impl AppState {
+    pub fn new(
+        settings: Settings,
+        queue: queue::QueueService,
+        twitch: twitch::TwitchClient,
+        overlay_keys: overlay_token::OverlayKeys,
+    ) -> Self {
+        Self {
+            settings,
+            queue,
+            twitch: Arc::new(twitch),
+            overlay_keys: std::sync::RwLock::new(overlay_keys),
+            vod_cache: vod::VodCache::default(),
+            public_stats_cache: stats::PublicStatsCache::default(),
+        }
+    }
+}
+
+fn build_http_client(cfg: &config::HttpConfig) -> anyhow::Result<reqwest::Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    for (name, value) in &cfg.extra_headers {
+        let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid header name {name:?}"))?;
+        let value = reqwest::header::HeaderValue::from_str(value)
+            .with_context(|| format!("invalid value for header {name}"))?;
+        headers.insert(name, value);
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .user_agent("twitch-obs-queue/0.1")
+        .default_headers(headers);
+    if cfg.timeout_secs > 0 {
+        builder = builder.timeout(std::time::Duration::from_secs(cfg.timeout_secs));
+    }
+    if cfg.connect_timeout_secs > 0 {
+        builder = builder.connect_timeout(std::time::Duration::from_secs(cfg.connect_timeout_secs));
+    }
+    let proxy_url = cfg.proxy_url.trim();
+    if !proxy_url.is_empty() {
+        builder = builder.proxy(reqwest::Proxy::all(proxy_url).context("invalid http.proxy_url")?);
+    }
+    Ok(builder.build()?)
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let command = cli::parse(std::env::args().skip(1))?;
+
+    // Subcommands print their report on stdout, so their logs go to stderr.
+    let log_writer = if command.is_some() {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stderr)
+    } else {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stdout)
+    };
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "info,twitch_obs_queue=info".into()),
         )
+        .with_writer(log_writer)
         .init();
 
     let config_path = std::env::var("CONFIG").unwrap_or_else(|_| "config.toml".to_string());
     let config = Config::load(&config_path).with_context(|| format!("failed to load {config_path}"))?;
 
-    let db = db::init_pool(&config.server.db_path)
+    twitch::check_redirect_url(&config);
+
+    let db = db::Db::open(
+        &config.server.db_path,
+        config.server.db_backup_keep,
+        config.server.read_pool_max_connections,
+    )
+    .await
+    .with_context(|| format!("failed to init sqlite at {}", config.server.db_path))?;
+
+    let overlay_keys = overlay_token::load_or_create(db.write())
         .await
-        .with_context(|| format!("failed to init sqlite at {}", config.server.db_path))?;
+        .context("failed to load overlay token keys")?;
 
-    let http = reqwest::Client::builder()
-        .user_agent("twitch-obs-queue/0.1")
-        .build()?;
+    let http = build_http_client(&config.http).context("invalid [http] config")?;
 
-    let state = Arc::new(AppState {
-        config: Arc::new(config),
-        db,
-        http,
-        oauth_state: RwLock::new(None),
-    });
+    let settings = Settings::new(config);
+    let queue = queue::QueueService::new(db.clone(), settings.clone());
+    let twitch = twitch::TwitchClient::new(http, settings.clone(), db);
+    let state = Arc::new(AppState::new(settings, queue, twitch, overlay_keys));
+
+    if let Some(command) = command {
+        return cli::run(&state, command).await;
+    }
 
     // Background: EventSub websocket + enqueue logic
     {
-        let state = Arc::clone(&state);
+        let (twitch, queue) = (Arc::clone(&state.twitch), state.queue.clone());
         tokio::spawn(async move {
-            if let Err(e) = twitch::run_eventsub_loop(state).await {
+            if let Err(e) = twitch::run_eventsub_loop(twitch, queue).await {
                 error!(error = ?e, "eventsub loop exited");
             }
         });
     }
 
-    // Background: cleanup processed message ids
+    // Background: remind that no join reward is configured (no-op once one is)
+    tokio::spawn(twitch::run_config_reminder_loop(Arc::clone(&state.twitch)));
+
+    // Background: keep reward prompts in sync with the queue rules (no-op unless configured)
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(reward_prompt::run_sync_loop(state));
+    }
+
+    // Background: side effects recorded in the outbox
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(outbox::run_dispatcher(state));
+    }
+
+    // Background: finish completes whose grace period has elapsed (also ones left over from before a restart)
     {
         let state = Arc::clone(&state);
         tokio::spawn(async move {
             loop {
-                let ttl = state.config.queue.processed_message_ttl_secs as i64;
-                let cutoff = util::now_epoch() - ttl;
-                match db::cleanup_processed_messages(&state.db, cutoff).await {
-                    Ok(n) if n > 0 => info!(deleted = n, "cleaned processed_messages"),
+                match queue::finalize_completions(
+                    state.queue.db.write(),
+                    state.settings.queue.complete_grace_secs,
+                )
+                .await
+                {
+                    Ok(n) if n > 0 => info!(completed = n, "finalized completed items"),
+                    Ok(_) => {}
+                    Err(e) => error!(error = ?e, "failed to finalize completed items"),
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        });
+    }
+
+    // Background: attach VOD timestamps to completed turns
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(vod::run_linker(state));
+    }
+
+    // Background: cleanup processed message ids (on an interval and after bursts)
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(sweep::run_processed_message_sweeper(state));
+    }
+
+    // Background: cleanup history tables
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                let cutoff =
+                    util::now_epoch() - state.settings.queue.processed_message_ttl_secs as i64;
+                let history_cutoff = util::now_epoch() - 30 * 24 * 60 * 60;
+                match db::cleanup_token_events(state.queue.db.write(), history_cutoff).await {
+                    Ok(n) if n > 0 => info!(deleted = n, "cleaned token_events"),
+                    Ok(_) => {}
+                    Err(e) => error!(error = ?e, "failed to cleanup token_events"),
+                }
+                match db::cleanup_eventsub_events(state.queue.db.write(), history_cutoff).await {
+                    Ok(n) if n > 0 => info!(deleted = n, "cleaned eventsub_events"),
+                    Ok(_) => {}
+                    Err(e) => error!(error = ?e, "failed to cleanup eventsub_events"),
+                }
+                let interest_cutoff =
+                    util::now_epoch() - state.settings.queue.pending_interest_ttl_secs as i64;
+                match interest::cleanup(state.queue.db.write(), interest_cutoff).await {
+                    Ok(n) if n > 0 => info!(deleted = n, "cleaned expired pending_interest"),
+                    Ok(_) => {}
+                    Err(e) => error!(error = ?e, "failed to cleanup pending_interest"),
+                }
+                match outbox::cleanup_done(state.queue.db.write(), cutoff).await {
+                    Ok(n) if n > 0 => info!(deleted = n, "cleaned dispatched outbox entries"),
                     Ok(_) => {}
-                    Err(e) => error!(error = ?e, "failed to cleanup processed_messages"),
+                    Err(e) => error!(error = ?e, "failed to cleanup outbox"),
                 }
                 tokio::time::sleep(std::time::Duration::from_secs(60 * 10)).await;
             }
@@ -78,7 +239,7 @@ async fn main() -> anyhow::Result<()> {
     let app = web::router(state.clone());
 
     let addr = state
-        .config
+        .settings
         .server
         .bind
         .parse::<std::net::SocketAddr>()
@@ -0,0 +1,664 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use tracing::{debug, error, warn};
+
+use crate::{db, queue, twitch, util, AppState};
+
+/// Side effects recorded in the same transaction as the queue mutation and
+/// performed later by [`run_dispatcher`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event_type", content = "payload", rename_all = "snake_case")]
+pub enum OutboxEvent {
+    /// Mark a channel point redemption FULFILLED / CANCELED on Twitch.
+    RedemptionStatus {
+        reward_id: String,
+        redemption_id: String,
+        status: RedemptionStatus,
+    },
+    /// Post a message to `alerts.webhook_url` (Discord-compatible `{"content": ...}`).
+    AlertWebhook { content: String },
+    /// Pause or unpause the Twitch join rewards (`twitch.pause_rewards_with_queue`).
+    /// `paused` is the state at the time of the change; the entry applies the pause
+    /// state current when it runs, so a retried pause cannot undo a later resume.
+    JoinRewardsPaused { paused: bool },
+    /// Post to the broadcaster's chat through Helix, or over IRC when Helix is down and
+    /// `chat.irc_fallback` is set; the row records which one delivered it.
+    ChatMessage { message: String },
+    /// Whisper to a viewer from the broadcaster (`chat.join_whisper`).
+    Whisper { to_user_id: String, message: String },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RedemptionStatus {
+    Fulfilled,
+    Canceled,
+}
+
+impl RedemptionStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RedemptionStatus::Fulfilled => "FULFILLED",
+            RedemptionStatus::Canceled => "CANCELED",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct OutboxEntryDto {
+    pub id: i64,
+    pub event_type: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    /// `helix` or `irc` once a chat message is delivered.
+    pub transport: Option<String>,
+}
+
+pub async fn insert_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    event: &OutboxEvent,
+    now: i64,
+) -> anyhow::Result<()> {
+    // Split the adjacently-tagged representation into the two columns.
+    let v = serde_json::to_value(event)?;
+    let event_type = v
+        .get("event_type")
+        .and_then(|t| t.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let payload = v.get("payload").cloned().unwrap_or_default().to_string();
+
+    sqlx::query(
+        r#"INSERT INTO outbox (event_type, payload, status, attempts, next_attempt_at, created_at, updated_at)
+           VALUES (?1, ?2, 'pending', 0, ?3, ?3, ?3)"#,
+    )
+    .bind(event_type)
+    .bind(payload)
+    .bind(now)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Same as [`insert_tx`] for side effects not tied to a queue mutation.
+pub async fn insert(pool: &SqlitePool, event: &OutboxEvent, now: i64) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    insert_tx(&mut tx, event, now).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+fn decode(entry: &OutboxEntryDto) -> anyhow::Result<OutboxEvent> {
+    let payload: serde_json::Value = serde_json::from_str(&entry.payload)?;
+    let v = serde_json::json!({ "event_type": entry.event_type, "payload": payload });
+    Ok(serde_json::from_value(v)?)
+}
+
+pub async fn list_failed(pool: &SqlitePool) -> anyhow::Result<Vec<OutboxEntryDto>> {
+    let rows = sqlx::query_as::<_, OutboxEntryDto>(
+        r#"SELECT id, event_type, payload, status, attempts, next_attempt_at, last_error, created_at, updated_at, transport
+           FROM outbox
+           WHERE status = 'failed'
+           ORDER BY id ASC"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Puts a failed entry back into the pending state. Returns false if no failed entry has that id.
+pub async fn retry(pool: &SqlitePool, id: i64) -> anyhow::Result<bool> {
+    let now = util::now_epoch();
+    let result = sqlx::query(
+        r#"UPDATE outbox
+           SET status = 'pending', attempts = 0, next_attempt_at = ?2, updated_at = ?2
+           WHERE id = ?1 AND status = 'failed'"#,
+    )
+    .bind(id)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn cleanup_done(pool: &SqlitePool, cutoff: i64) -> anyhow::Result<u64> {
+    let result = sqlx::query(
+        r#"DELETE FROM outbox
+           WHERE status = 'done' AND updated_at < ?1"#,
+    )
+    .bind(cutoff)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+const EVENT_TYPE_REDEMPTION_STATUS: &str = "redemption_status";
+
+/// `skip_event_type` lets deferred event types wait for an explicit flush.
+async fn fetch_due(
+    pool: &SqlitePool,
+    now: i64,
+    skip_event_type: Option<&str>,
+) -> anyhow::Result<Vec<OutboxEntryDto>> {
+    let rows = sqlx::query_as::<_, OutboxEntryDto>(
+        r#"SELECT id, event_type, payload, status, attempts, next_attempt_at, last_error, created_at, updated_at, transport
+           FROM outbox
+           WHERE status = 'pending' AND next_attempt_at <= ?1
+             AND (?2 IS NULL OR event_type != ?2)
+           ORDER BY id ASC
+           LIMIT 50"#,
+    )
+    .bind(now)
+    .bind(skip_event_type)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+async fn mark_done(
+    pool: &SqlitePool,
+    id: i64,
+    transport: Option<twitch::ChatTransport>,
+    now: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"UPDATE outbox
+           SET status = 'done', attempts = attempts + 1, last_error = NULL, updated_at = ?2, transport = ?3
+           WHERE id = ?1"#,
+    )
+    .bind(id)
+    .bind(now)
+    .bind(transport.map(twitch::ChatTransport::as_str))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn mark_attempt_failed(
+    pool: &SqlitePool,
+    entry: &OutboxEntryDto,
+    err: &str,
+    max_attempts: u32,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let attempts = entry.attempts + 1;
+    let gave_up = attempts >= max_attempts as i64;
+    let status = if gave_up { "failed" } else { "pending" };
+
+    sqlx::query(
+        r#"UPDATE outbox
+           SET status = ?2, attempts = ?3, next_attempt_at = ?4, last_error = ?5, updated_at = ?6
+           WHERE id = ?1"#,
+    )
+    .bind(entry.id)
+    .bind(status)
+    .bind(attempts)
+    .bind(now + backoff_secs(attempts))
+    .bind(err)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    Ok(gave_up)
+}
+
+/// 5s, 10s, 20s, ... capped at 10 minutes.
+fn backoff_secs(attempts: i64) -> i64 {
+    let exp = attempts.clamp(0, 7) as u32;
+    (5i64 << exp).min(600)
+}
+
+/// Performs `event`; for a chat message, returns the transport that delivered it.
+async fn perform(
+    state: &AppState,
+    event: &OutboxEvent,
+) -> anyhow::Result<Option<twitch::ChatTransport>> {
+    match event {
+        OutboxEvent::RedemptionStatus {
+            reward_id,
+            redemption_id,
+            status,
+        } => {
+            let access_token = twitch::get_fresh_access_token(&state.twitch).await?;
+            let Some(broadcaster_id) = db::get_broadcaster_id(state.queue.db.read()).await? else {
+                anyhow::bail!("broadcaster_id is not known yet");
+            };
+            twitch::helix_update_redemption_status(
+                &state.twitch,
+                &access_token,
+                &broadcaster_id,
+                reward_id,
+                &[redemption_id.as_str()],
+                *status,
+            )
+            .await?;
+            Ok(None)
+        }
+        OutboxEvent::AlertWebhook { content } => {
+            let url = state.settings.alerts.webhook_url.trim();
+            if url.is_empty() {
+                // Nowhere to send; the failure is still in the logs.
+                return Ok(None);
+            }
+            state
+                .twitch
+                .http
+                .post(url)
+                .json(&serde_json::json!({ "content": content }))
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(None)
+        }
+        OutboxEvent::JoinRewardsPaused { .. } => {
+            let paused = queue::is_paused(state.queue.db.read()).await?;
+            match twitch::set_join_rewards_paused(&state.twitch, paused).await? {
+                0 => Ok(None),
+                failed => anyhow::bail!("{failed} join rewards could not be updated"),
+            }
+        }
+        OutboxEvent::ChatMessage { message } => {
+            let access_token = twitch::get_fresh_access_token(&state.twitch).await?;
+            let (Some(broadcaster_id), Some(broadcaster_login)) = (
+                db::get_broadcaster_id(state.queue.db.read()).await?,
+                db::get_broadcaster_login(state.queue.db.read()).await?,
+            ) else {
+                anyhow::bail!("broadcaster is not known yet");
+            };
+            let channel = twitch::ChatChannel {
+                broadcaster_id: &broadcaster_id,
+                broadcaster_login: &broadcaster_login,
+            };
+            let transport =
+                twitch::send_chat_message(&state.twitch, &access_token, channel, message, None)
+                    .await?;
+            Ok(Some(transport))
+        }
+        OutboxEvent::Whisper {
+            to_user_id,
+            message,
+        } => {
+            let access_token = twitch::get_fresh_access_token(&state.twitch).await?;
+            let Some(broadcaster_id) = db::get_broadcaster_id(state.queue.db.read()).await? else {
+                anyhow::bail!("broadcaster_id is not known yet");
+            };
+            twitch::helix_send_whisper(
+                &state.twitch,
+                &access_token,
+                &broadcaster_id,
+                to_user_id,
+                message,
+            )
+            .await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Performs pending outbox entries until the process exits.
+/// Entries survive restarts, so a crash between commit and dispatch only delays them.
+pub async fn run_dispatcher(state: Arc<AppState>) {
+    let poll = std::time::Duration::from_secs(state.settings.outbox.poll_interval_secs.max(1));
+    let max_attempts = state.settings.outbox.max_attempts.max(1);
+
+    let skip = state
+        .settings
+        .twitch
+        .defer_redemption_updates
+        .then_some(EVENT_TYPE_REDEMPTION_STATUS);
+
+    loop {
+        if let Err(e) = dispatch_due(&state, util::now_epoch(), skip, max_attempts).await {
+            error!(error = ?e, "failed to read outbox");
+        }
+        tokio::time::sleep(poll).await;
+    }
+}
+
+/// One pass of [`run_dispatcher`]: performs the entries due at `now` and records
+/// each outcome. Returns how many entries were attempted.
+pub(crate) async fn dispatch_due(
+    state: &AppState,
+    now: i64,
+    skip_event_type: Option<&str>,
+    max_attempts: u32,
+) -> anyhow::Result<usize> {
+    let entries = fetch_due(state.queue.db.write(), now, skip_event_type).await?;
+    let attempted = entries.len();
+    for entry in entries {
+        let result = match decode(&entry) {
+            Ok(event) => perform(state, &event).await,
+            Err(e) => Err(e.context("failed to decode outbox payload")),
+        };
+
+        let now = util::now_epoch();
+        match result {
+            Ok(transport) => {
+                debug!(outbox_id = entry.id, event_type = %entry.event_type, ?transport, "outbox entry dispatched");
+                if let Err(e) = mark_done(state.queue.db.write(), entry.id, transport, now).await {
+                    error!(error = ?e, outbox_id = entry.id, "failed to mark outbox entry done");
+                }
+            }
+            Err(err) => {
+                let msg = format!("{err:#}");
+                match mark_attempt_failed(state.queue.db.write(), &entry, &msg, max_attempts, now)
+                    .await
+                {
+                    Ok(true) => {
+                        error!(outbox_id = entry.id, event_type = %entry.event_type, error = %msg, "outbox entry gave up")
+                    }
+                    Ok(false) => {
+                        warn!(outbox_id = entry.id, event_type = %entry.event_type, error = %msg, "outbox entry failed; will retry")
+                    }
+                    Err(e) => {
+                        error!(error = ?e, outbox_id = entry.id, "failed to record outbox failure")
+                    }
+                }
+            }
+        }
+    }
+    Ok(attempted)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FlushResult {
+    pub updated: u64,
+    pub failed: u64,
+}
+
+/// Sends every pending redemption status update now, batching IDs per reward and status.
+/// Used at stream end with `twitch.defer_redemption_updates`.
+pub async fn flush_redemptions(state: &AppState) -> anyhow::Result<FlushResult> {
+    let entries = sqlx::query_as::<_, OutboxEntryDto>(
+        r#"SELECT id, event_type, payload, status, attempts, next_attempt_at, last_error, created_at, updated_at, transport
+           FROM outbox
+           WHERE status = 'pending' AND event_type = ?1
+           ORDER BY id ASC"#,
+    )
+    .bind(EVENT_TYPE_REDEMPTION_STATUS)
+    .fetch_all(state.queue.db.read())
+    .await?;
+
+    // (reward_id, status) -> [(outbox entry, redemption_id)]
+    let mut groups: BTreeMap<(String, RedemptionStatus), Vec<(OutboxEntryDto, String)>> =
+        BTreeMap::new();
+    for entry in entries {
+        if let Ok(OutboxEvent::RedemptionStatus {
+            reward_id,
+            redemption_id,
+            status,
+        }) = decode(&entry)
+        {
+            groups
+                .entry((reward_id, status))
+                .or_default()
+                .push((entry, redemption_id));
+        }
+    }
+
+    let mut result = FlushResult::default();
+    if groups.is_empty() {
+        return Ok(result);
+    }
+
+    let access_token = twitch::get_fresh_access_token(&state.twitch).await?;
+    let Some(broadcaster_id) = db::get_broadcaster_id(state.queue.db.read()).await? else {
+        anyhow::bail!("broadcaster_id is not known yet");
+    };
+    let max_attempts = state.settings.outbox.max_attempts.max(1);
+
+    for ((reward_id, status), items) in &groups {
+        for chunk in items.chunks(twitch::MAX_REDEMPTIONS_PER_UPDATE) {
+            let ids: Vec<&str> = chunk.iter().map(|(_, r)| r.as_str()).collect();
+            let sent = twitch::helix_update_redemption_status(
+                &state.twitch,
+                &access_token,
+                &broadcaster_id,
+                reward_id,
+                &ids,
+                *status,
+            )
+            .await;
+
+            let now = util::now_epoch();
+            for (entry, _) in chunk {
+                match &sent {
+                    Ok(()) => {
+                        mark_done(state.queue.db.write(), entry.id, None, now).await?;
+                        result.updated += 1;
+                    }
+                    Err(e) => {
+                        mark_attempt_failed(
+                            state.queue.db.write(),
+                            entry,
+                            &format!("{e:#}"),
+                            max_attempts,
+                            now,
+                        )
+                        .await?;
+                        result.failed += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{http::StatusCode, routing::post, Json, Router};
+
+    use super::*;
+    use crate::{
+        queue::{self, DeleteMode},
+        testing::{self, TestApp},
+    };
+
+    /// Webhook endpoint answering with `status` and recording every body it got.
+    async fn mock_webhook(status: StatusCode) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&received);
+        let router = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| {
+                let sink = Arc::clone(&sink);
+                async move {
+                    sink.lock().unwrap().push(body);
+                    status
+                }
+            }),
+        );
+        (format!("{}/hook", testing::serve(router).await), received)
+    }
+
+    async fn pending(pool: &SqlitePool) -> Vec<OutboxEntryDto> {
+        sqlx::query_as::<_, OutboxEntryDto>(
+            r#"SELECT id, event_type, payload, status, attempts, next_attempt_at, last_error, created_at, updated_at, transport
+               FROM outbox WHERE status = 'pending' ORDER BY id"#,
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn side_effect_of_a_committed_complete_survives_a_crash_before_dispatch() {
+        let app = TestApp::new("").await;
+        let mut user = testing::new_user("u1");
+        user.reward_id = Some("reward".to_string());
+        user.redemption_id = Some("redemption".to_string());
+        let id = testing::enqueue(&app.queue, user).await;
+        queue::delete_item(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &id,
+            DeleteMode::Completed,
+        )
+        .await
+        .unwrap();
+
+        // Crash: the process goes away before the dispatcher ran, then starts again on the same file.
+        let TestApp { state, path } = app;
+        drop(state);
+        let db = db::Db::open(path.as_str(), 0, 1).await.unwrap();
+        let app = TestApp::with_db("", db, path).await;
+
+        let entries = pending(app.queue.db.read()).await;
+        assert_eq!(entries.len(), 1);
+        match decode(&entries[0]).unwrap() {
+            OutboxEvent::RedemptionStatus {
+                reward_id,
+                redemption_id,
+                status,
+            } => {
+                assert_eq!(
+                    (reward_id.as_str(), redemption_id.as_str()),
+                    ("reward", "redemption")
+                );
+                assert_eq!(status, RedemptionStatus::Fulfilled);
+            }
+            other => panic!("unexpected event {other:?}"),
+        }
+
+        // Not logged in yet: the attempt fails and the entry waits for its backoff.
+        let now = util::now_epoch();
+        assert_eq!(dispatch_due(&app, now, None, 5).await.unwrap(), 1);
+        let entries = pending(app.queue.db.read()).await;
+        assert_eq!(entries[0].attempts, 1);
+        assert!(entries[0].next_attempt_at > now);
+        assert!(entries[0].last_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn rolled_back_mutation_leaves_no_side_effect() {
+        let app = TestApp::new("").await;
+        let mut tx = app.queue.db.write().begin().await.unwrap();
+        insert_tx(
+            &mut tx,
+            &OutboxEvent::AlertWebhook {
+                content: "lost".to_string(),
+            },
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        // Crash before commit: the transaction is dropped.
+        drop(tx);
+        assert!(pending(app.queue.db.read()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pending_webhook_is_sent_after_restart() {
+        let (url, received) = mock_webhook(StatusCode::NO_CONTENT).await;
+        let config = format!("[alerts]\nwebhook_url = \"{url}\"\n");
+        let app = TestApp::new(&config).await;
+        insert(
+            app.queue.db.write(),
+            &OutboxEvent::AlertWebhook {
+                content: "hello".to_string(),
+            },
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+
+        let TestApp { state, path } = app;
+        drop(state);
+        let db = db::Db::open(path.as_str(), 0, 1).await.unwrap();
+        let app = TestApp::with_db(&config, db, path).await;
+
+        assert_eq!(
+            dispatch_due(&app, util::now_epoch(), None, 5)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![serde_json::json!({ "content": "hello" })]
+        );
+        assert!(pending(app.queue.db.read()).await.is_empty());
+        // Done entries are not sent again.
+        assert_eq!(
+            dispatch_due(&app, util::now_epoch() + 3600, None, 5)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts_and_can_be_retried() {
+        let (url, received) = mock_webhook(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let app = TestApp::new(&format!("[alerts]\nwebhook_url = \"{url}\"\n")).await;
+        let now = util::now_epoch();
+        insert(
+            app.queue.db.write(),
+            &OutboxEvent::AlertWebhook {
+                content: "x".to_string(),
+            },
+            now,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(dispatch_due(&app, now, None, 2).await.unwrap(), 1);
+        // Still backing off.
+        assert_eq!(dispatch_due(&app, now, None, 2).await.unwrap(), 0);
+        assert_eq!(
+            dispatch_due(&app, now + backoff_secs(1), None, 2)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        let failed = list_failed(app.queue.db.read()).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts, 2);
+        assert!(failed[0].last_error.as_deref().unwrap().contains("500"));
+
+        assert!(retry(app.queue.db.write(), failed[0].id).await.unwrap());
+        assert!(list_failed(app.queue.db.read()).await.unwrap().is_empty());
+        assert_eq!(pending(app.queue.db.read()).await[0].attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn deferred_event_type_waits_for_flush() {
+        let app = TestApp::new("").await;
+        let event = OutboxEvent::RedemptionStatus {
+            reward_id: "r".to_string(),
+            redemption_id: "x".to_string(),
+            status: RedemptionStatus::Canceled,
+        };
+        insert(app.queue.db.write(), &event, util::now_epoch())
+            .await
+            .unwrap();
+        let attempted = dispatch_due(
+            &app,
+            util::now_epoch(),
+            Some(EVENT_TYPE_REDEMPTION_STATUS),
+            5,
+        )
+        .await
+        .unwrap();
+        assert_eq!(attempted, 0);
+        assert_eq!(pending(app.queue.db.read()).await[0].attempts, 0);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_ten_minutes() {
+        let steps: Vec<i64> = (0..9).map(backoff_secs).collect();
+        assert_eq!(steps, vec![5, 10, 20, 40, 80, 160, 320, 600, 600]);
+    }
+}
@@ -0,0 +1,228 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::db;
+
+const KV_OVERLAY_KEYS: &str = "overlay_keys";
+
+/// Overlay pages that get a signed URL from the rotation / status endpoints.
+pub const OVERLAY_ROUTES: &[&str] = &["/obs"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OverlayKey {
+    kid: String,
+    secret: String,
+}
+
+impl OverlayKey {
+    fn generate() -> Self {
+        Self {
+            kid: Uuid::new_v4().simple().to_string()[..8].to_string(),
+            secret: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        }
+    }
+
+    fn signature(&self) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts any key length");
+        mac.update(b"overlay:");
+        mac.update(self.kid.as_bytes());
+        let bytes = mac.finalize().into_bytes();
+        bytes[..16].iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// `<kid>.<signature>`; the key id tells validation which secret to check against.
+    fn token(&self) -> String {
+        format!("{}.{}", self.kid, self.signature())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PreviousKey {
+    key: OverlayKey,
+    expires_at: i64,
+}
+
+/// Signing keys for overlay URLs. After a rotation the previous key stays valid until
+/// `expires_at`, so browser sources keep working until their URLs are updated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverlayKeys {
+    current: OverlayKey,
+    previous: Option<PreviousKey>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OverlayTokenStatusDto {
+    pub current_key_id: String,
+    /// Key ids whose tokens validate right now (current first).
+    pub accepted_key_ids: Vec<String>,
+    pub previous_key_id: Option<String>,
+    pub previous_expires_at: Option<i64>,
+}
+
+impl OverlayKeys {
+    fn generate() -> Self {
+        Self {
+            current: OverlayKey::generate(),
+            previous: None,
+        }
+    }
+
+    /// Token for new overlay URLs.
+    pub fn token(&self) -> String {
+        self.current.token()
+    }
+
+    /// True if `token` was signed by the current key, or by the previous key before it expired.
+    pub fn validate(&self, token: &str, now: i64) -> bool {
+        let Some((kid, _)) = token.split_once('.') else {
+            return false;
+        };
+        let key = if kid == self.current.kid {
+            &self.current
+        } else {
+            match &self.previous {
+                Some(p) if p.key.kid == kid && now < p.expires_at => &p.key,
+                _ => return false,
+            }
+        };
+        // Compare digests so the comparison time does not depend on the token.
+        Sha256::digest(token.as_bytes()) == Sha256::digest(key.token().as_bytes())
+    }
+
+    /// New current key; the old one is accepted for `overlap_secs` more.
+    pub fn rotate(&self, now: i64, overlap_secs: u64) -> Self {
+        Self {
+            current: OverlayKey::generate(),
+            previous: (overlap_secs > 0).then(|| PreviousKey {
+                key: self.current.clone(),
+                expires_at: now + overlap_secs as i64,
+            }),
+        }
+    }
+
+    pub fn status(&self, now: i64) -> OverlayTokenStatusDto {
+        let previous = self.previous.as_ref();
+        let mut accepted_key_ids = vec![self.current.kid.clone()];
+        if let Some(p) = previous.filter(|p| now < p.expires_at) {
+            accepted_key_ids.push(p.key.kid.clone());
+        }
+        OverlayTokenStatusDto {
+            current_key_id: self.current.kid.clone(),
+            accepted_key_ids,
+            previous_key_id: previous.map(|p| p.key.kid.clone()),
+            previous_expires_at: previous.map(|p| p.expires_at),
+        }
+    }
+}
+
+/// Keys from the DB, creating the first key on first use.
+pub async fn load_or_create(pool: &SqlitePool) -> anyhow::Result<OverlayKeys> {
+    if let Some(json) = db::get_kv(pool, KV_OVERLAY_KEYS).await? {
+        return Ok(serde_json::from_str(&json)?);
+    }
+    let keys = OverlayKeys::generate();
+    save(pool, &keys).await?;
+    Ok(keys)
+}
+
+pub async fn save(pool: &SqlitePool, keys: &OverlayKeys) -> anyhow::Result<()> {
+    db::set_kv(pool, KV_OVERLAY_KEYS, &serde_json::to_string(keys)?).await
+}
+
+/// `<base><route>?token=...` for every overlay route.
+pub fn overlay_urls(base: &str, keys: &OverlayKeys) -> Vec<String> {
+    let token = keys.token();
+    OVERLAY_ROUTES
+        .iter()
+        .map(|route| format!("{base}{route}?token={token}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_token_validates_and_tampering_does_not() {
+        let keys = OverlayKeys::generate();
+        let token = keys.token();
+        assert!(keys.validate(&token, 0));
+
+        let (kid, sig) = token.split_once('.').unwrap();
+        let flipped = if sig.starts_with('0') {
+            sig.replacen('0', "1", 1)
+        } else {
+            format!("0{}", &sig[1..])
+        };
+        assert!(!keys.validate(&format!("{kid}.{flipped}"), 0));
+        assert!(!keys.validate(kid, 0));
+        assert!(!keys.validate("", 0));
+    }
+
+    #[test]
+    fn previous_key_works_until_the_overlap_ends() {
+        let keys = OverlayKeys::generate();
+        let old = keys.token();
+        let rotated = keys.rotate(1_000, 3_600);
+
+        assert!(rotated.validate(&rotated.token(), 1_000));
+        assert!(rotated.validate(&old, 1_000));
+        assert!(rotated.validate(&old, 4_599));
+        assert!(!rotated.validate(&old, 4_600), "expired old key");
+
+        let status = rotated.status(1_000);
+        assert_eq!(
+            status.accepted_key_ids,
+            [
+                status.current_key_id.clone(),
+                status.previous_key_id.clone().unwrap()
+            ]
+        );
+        assert_eq!(status.previous_expires_at, Some(4_600));
+        assert_eq!(
+            rotated.status(4_600).accepted_key_ids,
+            [status.current_key_id]
+        );
+    }
+
+    #[test]
+    fn rotating_twice_or_without_overlap_drops_the_old_key() {
+        let keys = OverlayKeys::generate();
+        let first = keys.token();
+        assert!(!keys.rotate(0, 0).validate(&first, 0));
+
+        let twice = keys.rotate(0, 3_600).rotate(10, 3_600);
+        assert!(!twice.validate(&first, 10));
+    }
+
+    #[test]
+    fn unknown_key_id_is_rejected_even_with_a_valid_signature() {
+        let keys = OverlayKeys::generate();
+        let other = OverlayKeys::generate();
+        assert!(!keys.validate(&other.token(), 0));
+
+        // The signature of the current key under a key id that is not ours.
+        let sig = keys.token().split_once('.').unwrap().1.to_string();
+        assert!(!keys.validate(&format!("deadbeef.{sig}"), 0));
+    }
+
+    #[tokio::test]
+    async fn keys_survive_a_reload() {
+        let (db, _path) = crate::testing::temp_db(0).await;
+        let keys = load_or_create(db.write()).await.unwrap();
+        let rotated = keys.rotate(crate::util::now_epoch(), 60);
+        save(db.write(), &rotated).await.unwrap();
+
+        let loaded = load_or_create(db.write()).await.unwrap();
+        assert_eq!(loaded.token(), rotated.token());
+        assert!(loaded.validate(&keys.token(), crate::util::now_epoch()));
+        assert_eq!(
+            overlay_urls("http://h", &loaded),
+            [format!("http://h/obs?token={}", rotated.token())]
+        );
+    }
+}
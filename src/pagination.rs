@@ -0,0 +1,255 @@
+use base64::Engine;
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, FromRow, QueryBuilder, Sqlite, SqlitePool};
+
+pub const DEFAULT_LIMIT: i64 = 50;
+pub const MAX_LIMIT: i64 = 500;
+
+/// Sort key of the last row of a page: its timestamp and rowid. Pages are ordered
+/// newest first, so rows inserted between fetches land before the cursor and never
+/// shift or repeat later pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub ts: i64,
+    pub id: i64,
+}
+
+impl Cursor {
+    /// Opaque to clients; the version prefix lets the format change later.
+    pub fn encode(&self) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("v1:{}:{}", self.ts, self.id))
+    }
+
+    pub fn decode(raw: &str) -> Option<Self> {
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(raw.trim())
+            .ok()?;
+        let text = String::from_utf8(bytes).ok()?;
+        let mut parts = text.strip_prefix("v1:")?.split(':');
+        let ts = parts.next()?.parse().ok()?;
+        let id = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { ts, id })
+    }
+}
+
+/// Rows that can be paged: their position in the (timestamp, rowid) order.
+pub trait Keyed {
+    fn cursor(&self) -> Cursor;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PageRequest {
+    pub limit: i64,
+    pub after: Option<Cursor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCursor;
+
+impl std::fmt::Display for InvalidCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cursor is not a value returned as next_cursor")
+    }
+}
+
+impl PageRequest {
+    /// `limit` is clamped to 1..=`MAX_LIMIT`; a cursor that does not decode is an error
+    /// rather than a silent restart from the first page.
+    pub fn parse(limit: Option<i64>, cursor: Option<&str>) -> Result<Self, InvalidCursor> {
+        let after = match cursor.filter(|c| !c.is_empty()) {
+            Some(raw) => Some(Cursor::decode(raw).ok_or(InvalidCursor)?),
+            None => None,
+        };
+        Ok(Self {
+            limit: limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+            after,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Pass as `?cursor=` for the next page; `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Finishes `qb` with the cursor predicate, ordering and limit, and runs it.
+/// `qb` must end inside a WHERE clause (`... WHERE 1 = 1` at minimum); `ts_col` and
+/// `id_col` are the columns `T::cursor` reads.
+pub async fn fetch_page<T>(
+    pool: &SqlitePool,
+    mut qb: QueryBuilder<'_, Sqlite>,
+    ts_col: &str,
+    id_col: &str,
+    req: PageRequest,
+) -> anyhow::Result<Page<T>>
+where
+    T: for<'r> FromRow<'r, SqliteRow> + Keyed + Send + Unpin,
+{
+    if let Some(after) = req.after {
+        qb.push(format!(" AND ({ts_col} < "))
+            .push_bind(after.ts)
+            .push(format!(" OR ({ts_col} = "))
+            .push_bind(after.ts)
+            .push(format!(" AND {id_col} < "))
+            .push_bind(after.id)
+            .push("))");
+    }
+    // One extra row tells whether another page exists.
+    qb.push(format!(" ORDER BY {ts_col} DESC, {id_col} DESC LIMIT "))
+        .push_bind(req.limit + 1);
+    let mut items = qb.build_query_as::<T>().fetch_all(pool).await?;
+    let next_cursor = if items.len() as i64 > req.limit {
+        items.truncate(req.limit as usize);
+        items.last().map(|last| last.cursor().encode())
+    } else {
+        None
+    };
+    Ok(Page { items, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{self, FullParticipation, ParticipationSource},
+        testing::{self, TestApp},
+    };
+
+    #[derive(Debug, FromRow)]
+    struct Row {
+        id: i64,
+        completed_at: i64,
+    }
+
+    impl Keyed for Row {
+        fn cursor(&self) -> Cursor {
+            Cursor {
+                ts: self.completed_at,
+                id: self.id,
+            }
+        }
+    }
+
+    /// `completed_at` of each row, following `next_cursor` until it runs out.
+    async fn pages(pool: &SqlitePool, limit: i64) -> Vec<Vec<i64>> {
+        let mut out = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let req = PageRequest::parse(Some(limit), cursor.as_deref()).unwrap();
+            let qb = QueryBuilder::new("SELECT id, completed_at FROM participations WHERE 1 = 1");
+            let page: Page<Row> = fetch_page(pool, qb, "completed_at", "id", req)
+                .await
+                .unwrap();
+            out.push(page.items.iter().map(|r| r.completed_at).collect());
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return out,
+            }
+        }
+    }
+
+    async fn seed(pool: &SqlitePool, completed_at: &[i64]) {
+        for (i, &at) in completed_at.iter().enumerate() {
+            let p = FullParticipation {
+                user_id: format!("u{i}"),
+                completed_at: at,
+                queue_item_id: None,
+                reward_id: None,
+                source: ParticipationSource::Queue,
+                session_id: None,
+                turn_started_at: None,
+            };
+            db::insert_participation(pool, &p).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_cover_every_row_once_and_stop_on_the_last() {
+        let service = testing::queue_service("").await;
+        let pool = service.db.write();
+        assert_eq!(
+            pages(pool, 2).await,
+            vec![Vec::<i64>::new()],
+            "an empty table is one empty page"
+        );
+
+        seed(pool, &[10, 20, 30, 40]).await;
+        assert_eq!(
+            pages(pool, 2).await,
+            vec![vec![40, 30], vec![20, 10]],
+            "no empty page after an exact multiple"
+        );
+        assert_eq!(pages(pool, 4).await, vec![vec![40, 30, 20, 10]]);
+
+        // Equal timestamps are ordered by rowid, so a page boundary between them loses nothing.
+        seed(pool, &[20, 5]).await;
+        assert_eq!(
+            pages(pool, 2).await,
+            vec![vec![40, 30], vec![20, 20], vec![10, 5]]
+        );
+        assert_eq!(
+            pages(pool, 4).await,
+            vec![vec![40, 30, 20, 20], vec![10, 5]],
+            "a short last page"
+        );
+    }
+
+    #[test]
+    fn cursors_round_trip_and_anything_else_is_rejected() {
+        let cursor = Cursor {
+            ts: 1_700_000_000,
+            id: 42,
+        };
+        let req = PageRequest::parse(None, Some(&cursor.encode())).unwrap();
+        assert_eq!((req.limit, req.after), (DEFAULT_LIMIT, Some(cursor)));
+        assert_eq!(PageRequest::parse(Some(0), Some("")).unwrap().limit, 1);
+        assert_eq!(
+            PageRequest::parse(Some(10_000), None).unwrap().limit,
+            MAX_LIMIT
+        );
+
+        let b64 = |s: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(s);
+        for raw in [
+            "garbage!",
+            &b64("1700000000:42"),
+            &b64("v2:1:2"),
+            &b64("v1:1"),
+            &b64("v1:1:2:3"),
+            &b64("v1:x:2"),
+        ] {
+            assert_eq!(
+                PageRequest::parse(None, Some(raw)).unwrap_err(),
+                InvalidCursor,
+                "{raw}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn an_invalid_cursor_is_a_400() {
+        let app = TestApp::new("").await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        for path in [
+            "/api/history?cursor=garbage",
+            "/api/users/1/history?cursor=djE6MQ",
+        ] {
+            let res = reqwest::get(format!("{base}{path}")).await.unwrap();
+            assert_eq!(res.status().as_u16(), 400, "{path}");
+            assert_eq!(res.text().await.unwrap(), InvalidCursor.to_string());
+        }
+        let res = reqwest::get(format!("{base}/api/history?cursor="))
+            .await
+            .unwrap();
+        assert_eq!(
+            res.status().as_u16(),
+            200,
+            "an empty cursor is the first page"
+        );
+    }
+}
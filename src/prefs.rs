@@ -0,0 +1,140 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::config::QueueConfig;
+
+/// What a viewer agreed to be notified or shown about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, FromRow)]
+pub struct UserPrefs {
+    /// Named in chat announcements (`chat.join_announce`).
+    pub chat_mention: bool,
+    /// Whispered to (`chat.join_whisper`).
+    pub whisper: bool,
+    /// Named on public pages; otherwise listed anonymously.
+    pub public_listing: bool,
+}
+
+impl UserPrefs {
+    /// For users who never set anything: `queue.default_notify` everywhere.
+    pub fn defaults(cfg: &QueueConfig) -> Self {
+        let on = cfg.default_notify;
+        Self {
+            chat_mention: on,
+            whisper: on,
+            public_listing: on,
+        }
+    }
+
+    fn all(on: bool) -> Self {
+        Self {
+            chat_mention: on,
+            whisper: on,
+            public_listing: on,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserPrefsDto {
+    pub user_id: String,
+    /// What the viewer chose, or `null` if they never did.
+    pub stored: Option<UserPrefs>,
+    pub updated_at: Option<i64>,
+    /// What senders and public views must follow.
+    pub effective: UserPrefs,
+    /// The broadcaster's emergency switch (`POST /api/prefs/override`) is on.
+    pub overridden: bool,
+}
+
+#[derive(Debug, FromRow)]
+struct PrefsRow {
+    #[sqlx(flatten)]
+    prefs: UserPrefs,
+    updated_at: i64,
+}
+
+async fn get_row(pool: &SqlitePool, user_id: &str) -> anyhow::Result<Option<PrefsRow>> {
+    let row = sqlx::query_as::<_, PrefsRow>(
+        "SELECT chat_mention, whisper, public_listing, updated_at FROM user_prefs WHERE user_id = ?1",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row)
+}
+
+/// What applies to `user_id`: their stored choice, or the config defaults when they never
+/// chose or the choices are `overridden`.
+pub async fn effective(
+    pool: &SqlitePool,
+    cfg: &QueueConfig,
+    overridden: bool,
+    user_id: &str,
+) -> anyhow::Result<UserPrefs> {
+    Ok(describe(pool, cfg, overridden, user_id).await?.effective)
+}
+
+/// Queued users who must be listed anonymously on public views (`public_listing` off).
+pub async fn unlisted_in_queue(
+    pool: &SqlitePool,
+    cfg: &QueueConfig,
+    overridden: bool,
+) -> anyhow::Result<HashSet<String>> {
+    let rows: Vec<(String, Option<bool>)> = sqlx::query_as(
+        "SELECT q.user_id, p.public_listing FROM queue_items q LEFT JOIN user_prefs p ON p.user_id = q.user_id",
+    )
+    .fetch_all(pool)
+    .await?;
+    let default = UserPrefs::defaults(cfg).public_listing;
+    Ok(rows
+        .into_iter()
+        .filter(|(_, stored)| !stored.filter(|_| !overridden).unwrap_or(default))
+        .map(|(user_id, _)| user_id)
+        .collect())
+}
+
+/// The viewer's stored choice and what applies to them. With `overridden` the choice
+/// is ignored and the config defaults apply.
+pub async fn describe(
+    pool: &SqlitePool,
+    cfg: &QueueConfig,
+    overridden: bool,
+    user_id: &str,
+) -> anyhow::Result<UserPrefsDto> {
+    let row = get_row(pool, user_id).await?;
+    let effective = match (&row, overridden) {
+        (Some(r), false) => r.prefs,
+        _ => UserPrefs::defaults(cfg),
+    };
+    Ok(UserPrefsDto {
+        user_id: user_id.to_string(),
+        stored: row.as_ref().map(|r| r.prefs),
+        updated_at: row.map(|r| r.updated_at),
+        effective,
+        overridden,
+    })
+}
+
+/// `!queue optout` / `!queue optin`: turns every preference off or on at once.
+pub async fn set_all(pool: &SqlitePool, user_id: &str, on: bool, now: i64) -> anyhow::Result<()> {
+    let prefs = UserPrefs::all(on);
+    sqlx::query(
+        r#"INSERT INTO user_prefs (user_id, chat_mention, whisper, public_listing, updated_at)
+           VALUES (?1, ?2, ?3, ?4, ?5)
+           ON CONFLICT(user_id) DO UPDATE SET
+             chat_mention = excluded.chat_mention,
+             whisper = excluded.whisper,
+             public_listing = excluded.public_listing,
+             updated_at = excluded.updated_at"#,
+    )
+    .bind(user_id)
+    .bind(prefs.chat_mention)
+    .bind(prefs.whisper)
+    .bind(prefs.public_listing)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
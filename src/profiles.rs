@@ -0,0 +1,485 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{
+    agenda,
+    config::{Config, QueueConfig, QueueTiebreak},
+    db, queue,
+};
+
+const KV_ACTIVE_PROFILE: &str = "active_profile";
+const KV_QUEUE_OVERRIDES: &str = "queue_overrides";
+const KV_OVERLAY_THEME: &str = "overlay_theme";
+const MAX_NAME_LEN: usize = 64;
+
+/// Runtime settings a profile switches together. Only settings stored in the database
+/// can be switched; everything in config.toml still needs a restart, so unknown keys
+/// are rejected rather than silently ignored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSettings {
+    /// `None` switches to a rolling queue (same as `DELETE /api/queue/slots`).
+    #[serde(default)]
+    pub slot_schedule: Option<agenda::SlotSchedule>,
+    /// Same as `POST /api/queue/pause` / `resume`.
+    #[serde(default)]
+    pub queue_paused: bool,
+    /// Queue rules over the `[queue]` values of config.toml.
+    #[serde(default)]
+    pub queue: QueueOverrides,
+    /// Theme name handed to the overlay (`/api/overlay/bootstrap`); `None` is its default look.
+    #[serde(default)]
+    pub overlay_theme: Option<String>,
+}
+
+/// `[queue]` values a profile replaces; `None` keeps the one from config.toml.
+/// Per-reward overrides in `twitch.reward_policies` still apply on top.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueOverrides {
+    /// `queue.tiebreak`: the fairness order among equal participation counts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiebreak: Option<QueueTiebreak>,
+    /// `queue.max_queue_size`. Sets a cap; a cap from config.toml cannot be lifted here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_queue_size: Option<usize>,
+    /// `queue.cooldown_secs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown_secs: Option<u64>,
+    /// `queue.max_participations_per_window`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_participations_per_window: Option<u32>,
+}
+
+impl QueueOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(&self, queue: &mut QueueConfig) {
+        if let Some(tiebreak) = self.tiebreak {
+            queue.tiebreak = tiebreak;
+        }
+        if let Some(max_queue_size) = self.max_queue_size {
+            queue.max_queue_size = Some(max_queue_size);
+        }
+        if let Some(secs) = self.cooldown_secs {
+            queue.cooldown_secs = secs;
+        }
+        if let Some(n) = self.max_participations_per_window {
+            queue.max_participations_per_window = n;
+        }
+    }
+}
+
+fn check_slot_schedule(schedule: Option<&agenda::SlotSchedule>) -> Result<(), String> {
+    match schedule {
+        Some(s) if s.slot_secs <= 0 => Err("slot_schedule.slot_secs must be positive".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Theme names end up in the overlay's markup: same character set as profile names.
+fn check_overlay_theme(theme: Option<&str>) -> Result<(), String> {
+    match theme {
+        Some(t) if !is_valid_name(t) => {
+            Err("overlay_theme must be 1-64 characters of A-Z, a-z, 0-9, - and _".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+impl ProfileSettings {
+    /// The checks the individual endpoints apply.
+    pub fn validate(&self) -> Result<(), String> {
+        check_slot_schedule(self.slot_schedule.as_ref())?;
+        check_overlay_theme(self.overlay_theme.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingsProfile {
+    pub name: String,
+    pub settings: ProfileSettings,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct ProfileRow {
+    name: String,
+    settings: String,
+    updated_at: i64,
+}
+
+impl TryFrom<ProfileRow> for SettingsProfile {
+    type Error = anyhow::Error;
+
+    fn try_from(r: ProfileRow) -> anyhow::Result<Self> {
+        Ok(Self {
+            settings: serde_json::from_str(&r.settings)?,
+            name: r.name,
+            updated_at: r.updated_at,
+        })
+    }
+}
+
+/// Profile names appear in URLs: 1-64 of A-Z, a-z, 0-9, - and _.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The settings in effect right now, for saving them as a profile.
+pub async fn capture(pool: &SqlitePool) -> anyhow::Result<ProfileSettings> {
+    Ok(ProfileSettings {
+        slot_schedule: agenda::get_schedule(pool).await?,
+        queue_paused: queue::is_paused(pool).await?,
+        queue: queue_overrides(pool).await?,
+        overlay_theme: overlay_theme(pool).await?,
+    })
+}
+
+/// The queue rules of the last activated profile (empty before any activation).
+pub async fn queue_overrides(pool: &SqlitePool) -> anyhow::Result<QueueOverrides> {
+    match db::get_kv(pool, KV_QUEUE_OVERRIDES).await? {
+        Some(json) => Ok(serde_json::from_str(&json)?),
+        None => Ok(QueueOverrides::default()),
+    }
+}
+
+/// `config` with [`queue_overrides`] applied: what enqueueing, thawing and the reward
+/// prompts go by.
+pub async fn effective_config(pool: &SqlitePool, config: &Config) -> anyhow::Result<Config> {
+    let mut config = config.clone();
+    queue_overrides(pool).await?.apply(&mut config.queue);
+    Ok(config)
+}
+
+pub async fn overlay_theme(pool: &SqlitePool) -> anyhow::Result<Option<String>> {
+    db::get_kv(pool, KV_OVERLAY_THEME).await
+}
+
+pub async fn list(pool: &SqlitePool) -> anyhow::Result<Vec<SettingsProfile>> {
+    sqlx::query_as::<_, ProfileRow>(
+        "SELECT name, settings, updated_at FROM settings_profiles ORDER BY name",
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(SettingsProfile::try_from)
+    .collect()
+}
+
+pub async fn get(pool: &SqlitePool, name: &str) -> anyhow::Result<Option<SettingsProfile>> {
+    sqlx::query_as::<_, ProfileRow>(
+        "SELECT name, settings, updated_at FROM settings_profiles WHERE name = ?1",
+    )
+    .bind(name)
+    .fetch_optional(pool)
+    .await?
+    .map(SettingsProfile::try_from)
+    .transpose()
+}
+
+/// Creates or replaces `name`. Validate `settings` first.
+pub async fn put(
+    pool: &SqlitePool,
+    name: &str,
+    settings: &ProfileSettings,
+    now: i64,
+) -> anyhow::Result<SettingsProfile> {
+    sqlx::query(
+        r#"INSERT INTO settings_profiles (name, settings, updated_at)
+           VALUES (?1, ?2, ?3)
+           ON CONFLICT(name) DO UPDATE SET
+             settings = excluded.settings,
+             updated_at = excluded.updated_at"#,
+    )
+    .bind(name)
+    .bind(serde_json::to_string(settings)?)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    Ok(SettingsProfile {
+        name: name.to_string(),
+        settings: settings.clone(),
+        updated_at: now,
+    })
+}
+
+#[derive(Debug)]
+pub enum ActivateOutcome {
+    Applied(ProfileSettings),
+    NotFound,
+    /// Nothing was changed.
+    Invalid(String),
+}
+
+/// Applies every setting of `name` in one transaction: all of them or, on any
+/// failure, none. Side effects (the join rewards' pause state) are queued in the
+/// outbox with the change, as the individual endpoints do.
+pub async fn activate(
+    pool: &SqlitePool,
+    config: &Config,
+    name: &str,
+    now: i64,
+) -> anyhow::Result<ActivateOutcome> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query_as::<_, ProfileRow>(
+        "SELECT name, settings, updated_at FROM settings_profiles WHERE name = ?1",
+    )
+    .bind(name)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(ActivateOutcome::NotFound);
+    };
+    let settings = match serde_json::from_str::<ProfileSettings>(&row.settings) {
+        Ok(s) => s,
+        Err(e) => {
+            return Ok(ActivateOutcome::Invalid(format!(
+                "stored settings are unreadable: {e}"
+            )))
+        }
+    };
+    // Each setting is checked right before it is applied; returning drops the
+    // transaction, which rolls back the ones applied before it.
+    if let Err(msg) = check_slot_schedule(settings.slot_schedule.as_ref()) {
+        return Ok(ActivateOutcome::Invalid(msg));
+    }
+    match &settings.slot_schedule {
+        Some(schedule) => {
+            agenda::set_schedule(&mut tx, schedule.clone()).await?;
+        }
+        None => agenda::clear_schedule(&mut *tx).await?,
+    }
+    queue::set_paused_tx(
+        &mut tx,
+        config.twitch.pause_rewards_with_queue,
+        settings.queue_paused,
+        now,
+    )
+    .await?;
+    if settings.queue.is_empty() {
+        db::delete_kv(&mut *tx, KV_QUEUE_OVERRIDES).await?;
+    } else {
+        db::set_kv(
+            &mut *tx,
+            KV_QUEUE_OVERRIDES,
+            &serde_json::to_string(&settings.queue)?,
+        )
+        .await?;
+    }
+    if let Err(msg) = check_overlay_theme(settings.overlay_theme.as_deref()) {
+        return Ok(ActivateOutcome::Invalid(msg));
+    }
+    match &settings.overlay_theme {
+        Some(theme) => db::set_kv(&mut *tx, KV_OVERLAY_THEME, theme).await?,
+        None => db::delete_kv(&mut *tx, KV_OVERLAY_THEME).await?,
+    }
+    db::set_kv(&mut *tx, KV_ACTIVE_PROFILE, name).await?;
+    tx.commit().await?;
+    Ok(ActivateOutcome::Applied(settings))
+}
+
+/// Last activated profile. Settings changed one by one since then are not tracked.
+pub async fn active_profile(pool: &SqlitePool) -> anyhow::Result<Option<String>> {
+    db::get_kv(pool, KV_ACTIVE_PROFILE).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::TestApp, util};
+
+    async fn outbox_events(pool: &SqlitePool) -> Vec<(String, String)> {
+        sqlx::query_as(
+            "SELECT event_type, payload FROM outbox WHERE status = 'pending' ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn activating_a_paused_profile_queues_the_reward_pause() {
+        let app = TestApp::new("[twitch]\npause_rewards_with_queue = true\n").await;
+        let paused = ProfileSettings {
+            queue_paused: true,
+            ..Default::default()
+        };
+        put(app.queue.db.write(), "closed", &paused, 0)
+            .await
+            .unwrap();
+
+        let outcome = activate(
+            app.queue.db.write(),
+            &app.settings,
+            "closed",
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome, ActivateOutcome::Applied(_)));
+        assert!(queue::is_paused(app.queue.db.read()).await.unwrap());
+        assert_eq!(
+            outbox_events(app.queue.db.read()).await,
+            vec![(
+                "join_rewards_paused".to_string(),
+                r#"{"paused":true}"#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn without_reward_pausing_activation_queues_nothing() {
+        let app = TestApp::new("").await;
+        let paused = ProfileSettings {
+            queue_paused: true,
+            ..Default::default()
+        };
+        put(app.queue.db.write(), "closed", &paused, 0)
+            .await
+            .unwrap();
+
+        activate(
+            app.queue.db.write(),
+            &app.settings,
+            "closed",
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        assert!(queue::is_paused(app.queue.db.read()).await.unwrap());
+        assert!(outbox_events(app.queue.db.read()).await.is_empty());
+    }
+
+    fn event_settings() -> ProfileSettings {
+        ProfileSettings {
+            slot_schedule: Some(agenda::SlotSchedule {
+                id: String::new(),
+                start_at: 0,
+                slot_secs: 600,
+                breaks: Vec::new(),
+            }),
+            queue_paused: true,
+            queue: QueueOverrides {
+                tiebreak: Some(QueueTiebreak::OldestLastCompletion),
+                max_queue_size: Some(5),
+                cooldown_secs: Some(0),
+                max_participations_per_window: Some(1),
+            },
+            overlay_theme: Some("event".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn activation_applies_every_setting() {
+        let app = TestApp::new("[queue]\nmax_queue_size = 50\ncooldown_secs = 300\n").await;
+        put(app.queue.db.write(), "event", &event_settings(), 0)
+            .await
+            .unwrap();
+
+        let outcome = activate(
+            app.queue.db.write(),
+            &app.settings,
+            "event",
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome, ActivateOutcome::Applied(_)));
+        let pool = app.queue.db.read();
+        assert_eq!(capture(pool).await.unwrap().queue, event_settings().queue);
+        assert_eq!(
+            agenda::get_schedule(pool)
+                .await
+                .unwrap()
+                .map(|s| s.slot_secs),
+            Some(600)
+        );
+        assert!(queue::is_paused(pool).await.unwrap());
+        assert_eq!(overlay_theme(pool).await.unwrap().as_deref(), Some("event"));
+
+        let effective = effective_config(pool, &app.settings).await.unwrap();
+        assert_eq!(
+            effective.queue.tiebreak,
+            QueueTiebreak::OldestLastCompletion
+        );
+        assert_eq!(effective.queue.max_queue_size, Some(5));
+        assert_eq!(effective.queue.cooldown_secs, 0);
+        assert_eq!(effective.policy_for(None).max_participations_per_window, 1);
+        // Untouched values still come from config.toml.
+        assert_eq!(
+            effective.queue.participation_window_secs,
+            app.settings.queue.participation_window_secs
+        );
+
+        // Switching to an empty profile goes back to config.toml.
+        put(
+            app.queue.db.write(),
+            "plain",
+            &ProfileSettings::default(),
+            0,
+        )
+        .await
+        .unwrap();
+        activate(
+            app.queue.db.write(),
+            &app.settings,
+            "plain",
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        let effective = effective_config(app.queue.db.read(), &app.settings)
+            .await
+            .unwrap();
+        assert_eq!(
+            (
+                effective.queue.max_queue_size,
+                effective.queue.cooldown_secs
+            ),
+            (Some(50), 300)
+        );
+        assert_eq!(overlay_theme(app.queue.db.read()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn a_setting_that_fails_rolls_back_the_ones_before_it() {
+        let app = TestApp::new("[twitch]\npause_rewards_with_queue = true\n").await;
+        // `put` validates, so store the broken profile the way an older version could have.
+        let mut broken = event_settings();
+        broken.overlay_theme = Some("<script>".to_string());
+        sqlx::query(
+            "INSERT INTO settings_profiles (name, settings, updated_at) VALUES ('broken', ?1, 0)",
+        )
+        .bind(serde_json::to_string(&broken).unwrap())
+        .execute(app.queue.db.write())
+        .await
+        .unwrap();
+
+        let outcome = activate(
+            app.queue.db.write(),
+            &app.settings,
+            "broken",
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            matches!(outcome, ActivateOutcome::Invalid(msg) if msg.starts_with("overlay_theme"))
+        );
+        // Schedule, pause and queue rules were applied before the theme failed; none of them stuck.
+        let pool = app.queue.db.read();
+        assert!(agenda::get_schedule(pool).await.unwrap().is_none());
+        assert!(!queue::is_paused(pool).await.unwrap());
+        assert!(queue_overrides(pool).await.unwrap().is_empty());
+        assert_eq!(overlay_theme(pool).await.unwrap(), None);
+        assert!(outbox_events(pool).await.is_empty());
+        assert_eq!(active_profile(pool).await.unwrap(), None);
+    }
+}
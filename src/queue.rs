@@ -1,8 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+};
+
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, SqlitePool};
 use uuid::Uuid;
 
-use crate::util;
+use crate::{
+    agenda,
+    config::{Config, QueueConfig, QueuePolicy, QueueTiebreak, Settings},
+    cues::{self, CueKind, CuePayload},
+    db, outbox, prefs, profiles, roster, sweep,
+    timing::{self, Timings},
+    util,
+};
+
+/// Epoch seconds; [`util::now_epoch`] outside tests.
+pub type Clock = Arc<dyn Fn() -> i64 + Send + Sync>;
+
+/// The queue database and what every enqueue path shares. Changes are published
+/// through the cue log and the outbox in the same transaction, so the database is also
+/// the event publisher. Cheap to clone; handlers extract it on its own.
+#[derive(Clone)]
+pub struct QueueService {
+    pub db: db::Db,
+    pub settings: Settings,
+    /// Phase histograms of queue operations (`GET /api/metrics/timings`).
+    pub timings: Arc<Timings>,
+    /// Counts recorded message ids and sweeps expired ones (see `sweep`).
+    pub processed_sweeper: Arc<sweep::ProcessedMessageSweeper>,
+    /// Last time the OBS overlay polled the queue (starts at construction).
+    pub overlay_last_seen_at: Arc<AtomicI64>,
+    clock: Clock,
+}
+
+impl QueueService {
+    pub fn new(db: db::Db, settings: Settings) -> Self {
+        Self {
+            db,
+            timings: Arc::new(Timings::new(settings.server.slow_mutation_warn_ms)),
+            settings,
+            processed_sweeper: Arc::default(),
+            overlay_last_seen_at: Arc::new(AtomicI64::new(util::now_epoch())),
+            clock: Arc::new(util::now_epoch),
+        }
+    }
+
+    /// Same service reading the time from `clock`.
+    pub fn with_clock(mut self, clock: impl Fn() -> i64 + Send + Sync + 'static) -> Self {
+        self.overlay_last_seen_at.store(clock(), Ordering::Relaxed);
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn now(&self) -> i64 {
+        (self.clock)()
+    }
+
+    pub fn note_overlay_poll(&self) {
+        self.overlay_last_seen_at
+            .store(self.now(), Ordering::Relaxed);
+    }
+
+    /// True when `queue.overlay_heartbeat_timeout_secs` is set and the overlay has
+    /// not polled for longer than that; enqueueing is paused until it polls again.
+    pub fn is_overlay_heartbeat_lost(&self) -> bool {
+        let timeout = self.settings.queue.overlay_heartbeat_timeout_secs as i64;
+        timeout > 0 && self.now() - self.overlay_last_seen_at.load(Ordering::Relaxed) > timeout
+    }
+
+    /// Enqueues `user` under the queue rules in effect (config.toml with the active
+    /// profile's overrides) and the policy of `user.reward_id`. Every enqueue path
+    /// goes through this or [`QueueService::enqueue_adjusted`].
+    pub async fn enqueue(
+        &self,
+        user: NewQueueUser,
+        notices: &JoinNotices,
+    ) -> anyhow::Result<EnqueueOutcome> {
+        self.enqueue_adjusted(user, notices, |_| ()).await
+    }
+
+    /// [`QueueService::enqueue`] with the policy changed by `adjust` first.
+    pub async fn enqueue_adjusted(
+        &self,
+        user: NewQueueUser,
+        notices: &JoinNotices,
+        adjust: impl FnOnce(&mut QueuePolicy),
+    ) -> anyhow::Result<EnqueueOutcome> {
+        // On the writer like the rest of the enqueue, which must not wait for a read connection.
+        let config = profiles::effective_config(self.db.write(), &self.settings).await?;
+        let mut policy = config.policy_for(user.reward_id.as_deref());
+        adjust(&mut policy);
+        enqueue_user(
+            self.db.write(),
+            &self.timings,
+            &config.queue,
+            &policy,
+            user,
+            notices,
+        )
+        .await
+    }
+
+    /// The public listing: viewers who turned `public_listing` off (see `prefs`) are
+    /// anonymous in it. Admin views use [`list_queue_admin`].
+    pub async fn list(&self) -> anyhow::Result<Vec<QueueItemDto>> {
+        let mut items = list_queue(self.db.read(), &self.timings, &self.settings).await?;
+        let unlisted = prefs::unlisted_in_queue(
+            self.db.read(),
+            &self.settings.queue,
+            self.settings.prefs_overridden(),
+        )
+        .await?;
+        for item in items.iter_mut().filter(|i| unlisted.contains(&i.user_id)) {
+            item.anonymize();
+        }
+        Ok(items)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct NewQueueUser {
@@ -10,6 +129,14 @@ pub struct NewQueueUser {
     pub user_login: String,
     pub display_name: String,
     pub profile_image_url: String,
+    /// Reward that produced this entry (selects the per-reward policy).
+    pub reward_id: Option<String>,
+    /// Set when the redemption status should be updated on Twitch later.
+    pub redemption_id: Option<String>,
+    /// Redemption text; used as the game name when the roster has no entry.
+    pub user_input: Option<String>,
+    /// Twitch's `redeemed_at` in epoch milliseconds; the enqueue time is used when `None`.
+    pub redeemed_at_ms: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -20,14 +147,369 @@ pub struct QueueItemDto {
     pub display_name: String,
     pub profile_image_url: String,
     pub enqueued_at: i64,
+    /// Seconds since `enqueued_at`, measured by the server clock.
+    pub enqueued_age_secs: i64,
     pub position: i64,
     pub recent_participation_count: i64,
+    /// `queue.tiers` label for `recent_participation_count`.
+    pub tier: Option<String>,
+    /// Most recent completed turn at any time; `None` if never played. Used by `queue.tiebreak`.
+    pub last_completed_at: Option<i64>,
+    /// Estimated epoch second when this item's turn starts.
+    /// `None` when `queue.seconds_per_item` is 0.
+    pub estimated_start_at: Option<i64>,
+    /// Start of this item's fixed slot when a slot schedule is set (see `agenda`).
+    pub scheduled_at: Option<i64>,
+    /// Enqueued before the current stream session started.
+    pub from_previous_session: bool,
+    /// Reward whose policy applied when this item was enqueued.
+    pub reward_id: Option<String>,
+    pub priority: i64,
+    /// `priority` plus waiting-time aging (`queue.aging_interval_secs`); what placement uses.
+    pub effective_priority: i64,
+    pub tags: Vec<String>,
+    /// Badge text for the originating reward (`twitch.reward_labels`).
+    pub label: Option<String>,
+    /// In-game name from the roster, else the redemption text.
+    pub game_name: Option<String>,
+    /// Game from the roster entry, if any.
+    pub game: Option<String>,
+    /// Marked complete at this epoch second and waiting out `queue.complete_grace_secs`.
+    pub completing_at: Option<i64>,
+    /// The viewer stepped away at this epoch second (see [`set_away`]). Away items come
+    /// after everyone present and have no estimate or slot.
+    pub away_since: Option<i64>,
+    /// Where the item re-enters among present items on return (clamped to their count).
+    pub away_return_position: Option<i64>,
+}
+
+/// Shown instead of the display name of viewers who are not publicly listed.
+pub const ANONYMOUS_DISPLAY_NAME: &str = "匿名";
+
+impl QueueItemDto {
+    /// Drops everything that names the viewer: ids, names, avatar and in-game name.
+    fn anonymize(&mut self) {
+        self.user_id.clear();
+        self.user_login.clear();
+        self.display_name = ANONYMOUS_DISPLAY_NAME.to_string();
+        self.profile_image_url.clear();
+        self.game_name = None;
+        self.game = None;
+    }
+}
+
+/// Admin view of a queue item: the public fields plus ones that must never reach the
+/// overlay or other public output.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueItemAdminDto {
+    #[serde(flatten)]
+    pub item: QueueItemDto,
+    pub private_note: Option<String>,
 }
 
+/// Longest accepted `private_note`, in characters.
+pub const MAX_PRIVATE_NOTE_CHARS: usize = 500;
+
 #[derive(Debug, Clone, Serialize)]
 pub enum EnqueueOutcome {
-    Added { id: String, position: i64 },
+    Added(EnqueueReceipt),
+    /// The queue is frozen; the entry joins on thaw.
+    Pending {
+        frozen_at: i64,
+    },
     AlreadyQueued,
+    Rejected(RejectReason),
+    /// `queue.max_queue_size` viewers are already waiting.
+    QueueFull {
+        max_queue_size: usize,
+    },
+}
+
+/// Where an accepted entry landed, computed inside the enqueue transaction.
+#[derive(Debug, Clone, Serialize)]
+pub struct EnqueueReceipt {
+    pub id: String,
+    /// 0-based; also the number of people ahead.
+    pub position: i64,
+    /// Queue length including the new item (away viewers not counted).
+    pub queue_len: i64,
+    /// `position * queue.seconds_per_item`; `None` when the estimate is disabled.
+    pub estimated_wait_secs: Option<i64>,
+    /// Priority of the policy that applied.
+    pub priority: i64,
+    /// Priority used for placement (see [`QueueItemDto::effective_priority`]); a new entry
+    /// has not aged yet, so this equals `priority`.
+    pub effective_priority: i64,
+    /// The priority moved this entry ahead of where fairness alone would put it.
+    pub priority_placement: bool,
+    /// Where the ranking alone put this entry; differs from `position` when the
+    /// manual-order rule or first-come-first-served mode moved it.
+    pub ranked_position: i64,
+    /// Placed below a manually raised item instead of where the ranking put it.
+    pub manual_order_applied: bool,
+    /// `queue.tiebreak` moved this entry ahead of equal-count users who played more recently.
+    pub tiebreak_applied: bool,
+    /// Placed last by first-come-first-served mode (see [`start_ffa`]), ignoring the ranking.
+    pub ffa_applied: bool,
+    pub recent_participation_count: i64,
+    pub last_completed_at: Option<i64>,
+}
+
+impl EnqueueReceipt {
+    /// Fills a join announcement (`chat.join_announce`, `alerts.join_message`): `{user}`,
+    /// `{position}` (1-based), `{ahead}`, `{total}` (queue length), `{wait_min}` (estimated
+    /// wait in whole minutes, rounded up; `?` without an estimate) and `{priority}`.
+    pub fn render(&self, template: &str, user: &str) -> String {
+        let wait_min = self.estimated_wait_secs.map_or_else(
+            || "?".to_string(),
+            |s| (s.max(0) as u64).div_ceil(60).to_string(),
+        );
+        template
+            .replace("{position}", &(self.position + 1).to_string())
+            .replace("{ahead}", &self.position.to_string())
+            .replace("{total}", &self.queue_len.to_string())
+            .replace("{wait_min}", &wait_min)
+            .replace("{priority}", &self.priority.to_string())
+            .replace("{user}", user)
+    }
+}
+
+/// Templates announcing an accepted join (`chat.join_announce`, `chat.join_whisper`,
+/// `alerts.join_message`), already filtered by the viewer's preferences. The enqueue
+/// transaction renders them with the receipt and queues them in the outbox.
+#[derive(Debug, Clone, Default)]
+pub struct JoinNotices {
+    pub chat: Option<String>,
+    pub whisper: Option<String>,
+    pub webhook: Option<String>,
+}
+
+impl JoinNotices {
+    fn events(
+        &self,
+        receipt: &EnqueueReceipt,
+        user_id: &str,
+        display_name: &str,
+    ) -> Vec<outbox::OutboxEvent> {
+        let render = |template: &String| receipt.render(template, display_name);
+        let chat = self
+            .chat
+            .iter()
+            .map(|t| outbox::OutboxEvent::ChatMessage { message: render(t) });
+        let whisper = self.whisper.iter().map(|t| outbox::OutboxEvent::Whisper {
+            to_user_id: user_id.to_string(),
+            message: render(t),
+        });
+        let webhook = self
+            .webhook
+            .iter()
+            .map(|t| outbox::OutboxEvent::AlertWebhook { content: render(t) });
+        chat.chain(whisper).chain(webhook).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    Cooldown {
+        remaining_secs: i64,
+    },
+    /// `count` completed turns inside the window already reach `limit`.
+    MaxParticipations {
+        limit: u32,
+        count: i64,
+    },
+    AlreadyEnteredInWindow,
+}
+
+/// What the eligibility rules need to know about the user.
+#[derive(Debug, Clone, Default)]
+pub struct UserHistory {
+    pub recent_participation_count: i64,
+    pub last_completed_at: Option<i64>,
+    /// Last completed turn from the reward being redeemed (for `rejoin_cooldown_secs`).
+    pub last_completed_from_reward_at: Option<i64>,
+    pub entered_in_window: bool,
+}
+
+/// Applies `policy` to a user's history. Pure so rules can be reasoned about in isolation.
+pub fn check_eligibility(
+    policy: &QueuePolicy,
+    history: &UserHistory,
+    now: i64,
+) -> Result<(), RejectReason> {
+    let (cooldown_secs, last_completed_at) = match policy.rejoin_cooldown_secs {
+        Some(secs) => (secs, history.last_completed_from_reward_at),
+        None => (policy.cooldown_secs, history.last_completed_at),
+    };
+    if cooldown_secs > 0 {
+        if let Some(last) = last_completed_at {
+            let remaining = last + cooldown_secs as i64 - now;
+            if remaining > 0 {
+                return Err(RejectReason::Cooldown {
+                    remaining_secs: remaining,
+                });
+            }
+        }
+    }
+
+    let limit = policy.max_participations_per_window;
+    if limit > 0 && history.recent_participation_count >= limit as i64 {
+        return Err(RejectReason::MaxParticipations {
+            limit,
+            count: history.recent_participation_count,
+        });
+    }
+
+    if policy.one_entry_per_window && history.entered_in_window {
+        return Err(RejectReason::AlreadyEnteredInWindow);
+    }
+
+    Ok(())
+}
+
+/// `priority` aged by `queue.aging_interval_secs`: each full interval since `enqueued_at`
+/// adds `queue.aging_increment`, capped at `queue.aging_priority_cap` (never below `priority`).
+/// Returns `priority` unchanged when aging is disabled.
+pub fn effective_priority(cfg: &QueueConfig, priority: i64, enqueued_at: i64, now: i64) -> i64 {
+    if cfg.aging_interval_secs == 0 {
+        return priority;
+    }
+    let steps = now.saturating_sub(enqueued_at).max(0) / cfg.aging_interval_secs as i64;
+    let aged = priority.saturating_add(steps.saturating_mul(cfg.aging_increment));
+    aged.min(cfg.aging_priority_cap.max(priority))
+}
+
+/// Ranking inputs of one queued item:
+/// `(effective priority, recent participations, last completion, redeemed_at_ms)`.
+pub type RankKey = (i64, i64, Option<i64>, Option<i64>);
+
+/// Insertion index for a new item with `priority`, `count` participations,
+/// `last_completed_at` and `redeemed_at_ms`, given the current queue in order: before
+/// the first item with lower priority, or with the same priority and strictly MORE
+/// participations. With [`QueueTiebreak::OldestLastCompletion`], also before an
+/// equal-count item whose last completion is more recent (`None`, never played, is oldest).
+/// An item that ties on all of that but was redeemed later also goes after the newcomer,
+/// so a burst delivered out of order still ends up in redemption order; items without
+/// a time (or an equal one) keep their place ahead.
+pub fn insertion_index(
+    current: &[RankKey],
+    priority: i64,
+    count: i64,
+    last_completed_at: Option<i64>,
+    redeemed_at_ms: Option<i64>,
+    tiebreak: QueueTiebreak,
+) -> usize {
+    let by_last = tiebreak == QueueTiebreak::OldestLastCompletion;
+    current
+        .iter()
+        .position(|&(p, c, last, redeemed)| {
+            p < priority
+                || (p == priority
+                    && (c > count
+                        || (c == count
+                            && ((by_last && last_completed_at < last)
+                                || ((!by_last || last_completed_at == last)
+                                    && matches!((redeemed_at_ms, redeemed), (Some(mine), Some(theirs)) if mine < theirs))))))
+        })
+        .unwrap_or(current.len())
+}
+
+/// Moves a fairness placement that would land above the deepest manually raised
+/// item (`last_raised`) to just below it, so an admin's move-up survives later
+/// redemptions. The placement is kept when the newcomer has a higher priority than
+/// that item, or when that item has at least `gap_threshold` more recent
+/// participations. `gap_threshold <= 0` disables the rule.
+pub fn respect_manual_order(
+    current: &[RankKey],
+    last_raised: Option<usize>,
+    index: usize,
+    priority: i64,
+    count: i64,
+    gap_threshold: i64,
+) -> usize {
+    let Some(raised) = last_raised else {
+        return index;
+    };
+    if gap_threshold <= 0 || index > raised {
+        return index;
+    }
+    let (raised_priority, raised_count, _, _) = current[raised];
+    if priority > raised_priority || raised_count - count >= gap_threshold {
+        return index;
+    }
+    raised + 1
+}
+
+/// Where a new item goes in `current`.
+struct Placement {
+    index: usize,
+    /// Index by the ranking, before [`respect_manual_order`].
+    ranked_index: usize,
+    /// Index by fairness alone (priority 0, no manual-order rule).
+    fair_index: usize,
+    manual_order_applied: bool,
+    /// `queue.tiebreak` put the item somewhere other than plain insertion order would.
+    tiebreak_applied: bool,
+    effective_priority: i64,
+}
+
+/// Ranking inputs of the new item.
+struct Newcomer {
+    priority: i64,
+    enqueued_at: i64,
+    count: i64,
+    last_completed_at: Option<i64>,
+    redeemed_at_ms: Option<i64>,
+}
+
+fn place(
+    current: &[QueueItemWithCountsRow],
+    me: &Newcomer,
+    cfg: &QueueConfig,
+    now: i64,
+) -> Placement {
+    let ranked: Vec<RankKey> = current
+        .iter()
+        .map(|c| {
+            let priority = effective_priority(cfg, c.item.priority, c.item.enqueued_at, now);
+            (
+                priority,
+                c.recent_participation_count,
+                c.last_completed_at,
+                c.redeemed_at_ms,
+            )
+        })
+        .collect();
+    let my_priority = effective_priority(cfg, me.priority, me.enqueued_at, now);
+    let last_raised = current.iter().rposition(|c| c.manually_raised);
+    let rank = |priority, tiebreak| {
+        insertion_index(
+            &ranked,
+            priority,
+            me.count,
+            me.last_completed_at,
+            me.redeemed_at_ms,
+            tiebreak,
+        )
+    };
+    let by_rank = rank(my_priority, cfg.tiebreak);
+    let index = respect_manual_order(
+        &ranked,
+        last_raised,
+        by_rank,
+        my_priority,
+        me.count,
+        cfg.manual_order_gap_threshold,
+    );
+    Placement {
+        index,
+        ranked_index: by_rank,
+        fair_index: rank(0, cfg.tiebreak),
+        manual_order_applied: index != by_rank,
+        tiebreak_applied: by_rank != rank(my_priority, QueueTiebreak::Insertion),
+        effective_priority: my_priority,
+    }
 }
 
 #[derive(Debug, Copy, Clone, Deserialize)]
@@ -46,50 +528,245 @@ struct QueueItemRow {
     profile_image_url: String,
     enqueued_at: i64,
     position: i64,
+    reward_id: Option<String>,
+    redemption_id: Option<String>,
+    priority: i64,
+    tags: String,
+}
+
+fn split_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Start of the fairness window: participations completed at or after this
+/// epoch second count towards priority. Plain epoch arithmetic, so it does not
+/// shift around DST changes.
+pub fn participation_window_start(now: i64, participation_window_secs: i64) -> i64 {
+    now.saturating_sub(participation_window_secs.max(0))
+}
+
+/// Estimated start times for a queue of `len` items.
+///
+/// Position 0 is the active item; its turn began at `active_started_at`, so the
+/// next item starts once the rest of its `seconds_per_item` has elapsed (never
+/// before `now`). Later items follow at `seconds_per_item` intervals.
+pub fn estimated_start_times(
+    len: usize,
+    now: i64,
+    active_started_at: i64,
+    seconds_per_item: i64,
+) -> Vec<i64> {
+    if len == 0 {
+        return Vec::new();
+    }
+
+    let active_started_at = active_started_at.min(now);
+    let elapsed = now - active_started_at;
+    let first_gap = (seconds_per_item - elapsed).max(0);
+
+    let mut out = Vec::with_capacity(len);
+    out.push(active_started_at);
+    for pos in 1..len as i64 {
+        out.push(now + first_gap + (pos - 1) * seconds_per_item);
+    }
+    out
+}
+
+/// Start of the current stream session: the last stream.online notification,
+/// or `previous_session_fallback_hours` ago if none has been seen.
+/// `None` means no boundary is known (nothing is treated as stale).
+pub async fn current_session_started_at(
+    pool: &SqlitePool,
+    previous_session_fallback_hours: u64,
+    now: i64,
+) -> anyhow::Result<Option<i64>> {
+    if let Some(at) = db::get_stream_online_at(pool).await? {
+        return Ok(Some(at));
+    }
+    Ok(session_fallback_boundary(
+        previous_session_fallback_hours,
+        now,
+    ))
+}
+
+fn session_fallback_boundary(previous_session_fallback_hours: u64, now: i64) -> Option<i64> {
+    if previous_session_fallback_hours == 0 {
+        return None;
+    }
+    Some(now - (previous_session_fallback_hours as i64) * 60 * 60)
 }
 
+#[tracing::instrument(skip_all, fields(total_ms = tracing::field::Empty, phases = tracing::field::Empty))]
 pub async fn list_queue(
     pool: &SqlitePool,
-    participation_window_secs: i64,
+    timings: &Timings,
+    config: &Config,
 ) -> anyhow::Result<Vec<QueueItemDto>> {
+    let mut timer = timing::PhaseTimer::read(timings, "list_queue");
+    let cfg = &config.queue;
     let now = util::now_epoch();
-    let window_start = now - participation_window_secs;
+    let window_start = participation_window_start(now, cfg.participation_window_secs as i64);
+    let seconds_per_item = cfg.seconds_per_item as i64;
+    let session_started_at =
+        current_session_started_at(pool, cfg.previous_session_fallback_hours, now).await?;
 
-    let rows = sqlx::query_as::<_, QueueItemRow>(
-        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position
-           FROM queue_items
-           ORDER BY position ASC"#,
-    )
-    .fetch_all(pool)
-    .await?;
+    let rows = queue_with_counts(pool, window_start).await?;
+    let present_len = rows.iter().filter(|r| !r.is_away()).count();
+    let schedule = agenda::get_schedule(pool).await?;
+    timer.phase("snapshot");
+
+    let estimates = if seconds_per_item > 0 {
+        // The active item's turn began when it was enqueued or when the previous turn completed.
+        let last_completed_at =
+            sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(completed_at) FROM participations")
+                .fetch_one(pool)
+                .await?;
+        let active_started_at = rows
+            .first()
+            .filter(|r| !r.is_away())
+            .map(|r| r.item.enqueued_at.max(last_completed_at.unwrap_or(0)))
+            .unwrap_or(now);
+        estimated_start_times(present_len, now, active_started_at, seconds_per_item)
+    } else {
+        Vec::new()
+    };
 
     let mut out = Vec::with_capacity(rows.len());
-    for r in rows {
-        let c = count_participations(pool, &r.user_id, window_start).await?;
+    for (idx, counted) in rows.into_iter().enumerate() {
+        let away = counted.is_away();
+        let r = counted.item;
         out.push(QueueItemDto {
             id: r.id,
             user_id: r.user_id,
             user_login: r.user_login,
             display_name: r.display_name,
-            profile_image_url: r.profile_image_url,
+            // Rows stored before `twitch.profile_image_hosts` existed may still hold any URL.
+            profile_image_url: if util::is_allowed_profile_image_url(
+                &r.profile_image_url,
+                &config.twitch.profile_image_hosts,
+            ) {
+                r.profile_image_url
+            } else {
+                String::new()
+            },
             enqueued_at: r.enqueued_at,
+            enqueued_age_secs: now.saturating_sub(r.enqueued_at).max(0),
             position: r.position,
-            recent_participation_count: c,
+            recent_participation_count: counted.recent_participation_count,
+            tier: cfg
+                .tier_for(counted.recent_participation_count)
+                .map(str::to_string),
+            last_completed_at: counted.last_completed_at,
+            estimated_start_at: estimates.get(idx).copied(),
+            scheduled_at: schedule
+                .as_ref()
+                .filter(|_| !away)
+                .map(|s| s.slot_start(r.position)),
+            from_previous_session: session_started_at.is_some_and(|b| r.enqueued_at < b),
+            label: r
+                .reward_id
+                .as_deref()
+                .and_then(|id| config.twitch.reward_labels.get(id))
+                .cloned(),
+            reward_id: r.reward_id,
+            priority: r.priority,
+            effective_priority: effective_priority(cfg, r.priority, r.enqueued_at, now),
+            tags: split_tags(&r.tags),
+            game_name: counted.game_name,
+            game: counted.game,
+            completing_at: counted.completing_at,
+            away_since: counted.away_since,
+            away_return_position: counted.away_return_position,
         });
     }
 
+    if cfg.disambiguate_duplicate_names {
+        disambiguate_display_names(&mut out);
+    }
+
+    timer.phase("build");
     Ok(out)
 }
 
+/// [`list_queue`] with the admin-only fields attached.
+pub async fn list_queue_admin(
+    pool: &SqlitePool,
+    timings: &Timings,
+    config: &Config,
+) -> anyhow::Result<Vec<QueueItemAdminDto>> {
+    let items = list_queue(pool, timings, config).await?;
+    let notes: HashMap<String, String> = sqlx::query_as::<_, (String, String)>(
+        "SELECT id, private_note FROM queue_items WHERE private_note IS NOT NULL",
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    Ok(items
+        .into_iter()
+        .map(|item| QueueItemAdminDto {
+            private_note: notes.get(&item.id).cloned(),
+            item,
+        })
+        .collect())
+}
+
+/// Sets or clears (`None` / blank) the private note. False if the item does not exist.
+pub async fn set_private_note(
+    pool: &SqlitePool,
+    id: &str,
+    note: Option<&str>,
+) -> anyhow::Result<bool> {
+    let note = note.map(str::trim).filter(|n| !n.is_empty());
+    let res = sqlx::query("UPDATE queue_items SET private_note = ?2 WHERE id = ?1")
+        .bind(id)
+        .bind(note)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Appends ` (login)` to display names shared by more than one queued item.
+fn disambiguate_display_names(items: &mut [QueueItemDto]) {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for item in items.iter() {
+        *counts.entry(item.display_name.to_lowercase()).or_default() += 1;
+    }
+
+    for item in items.iter_mut() {
+        if counts
+            .get(&item.display_name.to_lowercase())
+            .copied()
+            .unwrap_or(0)
+            > 1
+        {
+            item.display_name = format!("{} ({})", item.display_name, item.user_login);
+        }
+    }
+}
+
+/// Also true while the user's entry is held by a freeze.
 pub async fn is_user_queued(pool: &SqlitePool, user_id: &str) -> anyhow::Result<bool> {
-    let row = sqlx::query("SELECT 1 FROM queue_items WHERE user_id = ?1 LIMIT 1")
+    let row = sqlx::query(
+        r#"SELECT 1 FROM queue_items WHERE user_id = ?1
+           UNION ALL
+           SELECT 1 FROM pending_queue_items WHERE user_id = ?1
+           LIMIT 1"#,
+    )
         .bind(user_id)
         .fetch_optional(pool)
         .await?;
     Ok(row.is_some())
 }
 
-pub async fn cancel_by_user_id(pool: &SqlitePool, user_id: &str) -> anyhow::Result<bool> {
+pub async fn cancel_by_user_id(
+    pool: &SqlitePool,
+    timings: &Timings,
+    user_id: &str,
+) -> anyhow::Result<bool> {
     let id = sqlx::query_scalar::<_, String>(
         r#"SELECT id
            FROM queue_items
@@ -101,242 +778,3569 @@ pub async fn cancel_by_user_id(pool: &SqlitePool, user_id: &str) -> anyhow::Resu
     .await?;
 
     let Some(id) = id else {
-        return Ok(false);
+        return cancel_pending(pool, user_id).await;
     };
 
-    delete_item(pool, &id, DeleteMode::Canceled).await?;
-    Ok(true)
+    match delete_item(pool, timings, &id, DeleteMode::Canceled).await {
+        Ok(()) => Ok(true),
+        // Completed or canceled by someone else since the lookup; nothing left to cancel.
+        Err(e) if e.to_string().contains("not found") => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
-pub async fn enqueue_user(
-    pool: &SqlitePool,
-    participation_window_secs: i64,
-    user: NewQueueUser,
-) -> anyhow::Result<EnqueueOutcome> {
-    let now = util::now_epoch();
-    let window_start = now - participation_window_secs;
-
+/// Drops an entry held by a freeze, canceling its redemption like a live cancel would.
+async fn cancel_pending(pool: &SqlitePool, user_id: &str) -> anyhow::Result<bool> {
     let mut tx = pool.begin().await?;
-
-    // Already queued?
-    let existing = sqlx::query_as::<_, QueueItemRow>(
-        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position
-           FROM queue_items
-           WHERE user_id = ?1
-           LIMIT 1"#,
+    let row = sqlx::query_as::<_, (Option<String>, Option<String>)>(
+        "DELETE FROM pending_queue_items WHERE user_id = ?1 RETURNING reward_id, redemption_id",
     )
-    .bind(&user.user_id)
+    .bind(user_id)
     .fetch_optional(&mut *tx)
     .await?;
 
-    if existing.is_some() {
+    let Some((reward_id, redemption_id)) = row else {
         tx.rollback().await?;
-        return Ok(EnqueueOutcome::AlreadyQueued);
+        return Ok(false);
+    };
+    if let (Some(reward_id), Some(redemption_id)) = (reward_id, redemption_id) {
+        let event = outbox::OutboxEvent::RedemptionStatus {
+            reward_id,
+            redemption_id,
+            status: outbox::RedemptionStatus::Canceled,
+        };
+        outbox::insert_tx(&mut tx, &event, util::now_epoch()).await?;
     }
+    tx.commit().await?;
+    Ok(true)
+}
 
-    // Fetch current queue in order
-    let current = sqlx::query_as::<_, QueueItemRow>(
-        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position
-           FROM queue_items
-           ORDER BY position ASC"#,
-    )
-    .fetch_all(&mut *tx)
-    .await?;
-
-    let my_count = count_participations_tx(&mut tx, &user.user_id, window_start).await?;
-
-    // Decide insertion point: before the first user who has strictly MORE completed participations
-    let mut insert_pos: i64 = current.len() as i64;
-    for (idx, item) in current.iter().enumerate() {
-        let c = count_participations_tx(&mut tx, &item.user_id, window_start).await?;
-        if c > my_count {
-            insert_pos = idx as i64;
-            break;
-        }
-    }
+/// Moves the user's item to the last position among present viewers (or, while
+/// frozen, to the end of the pending entries). An away item comes back there too.
+/// Unlike an admin move it clears `manually_raised`. Returns false if the user is not queued.
+pub async fn move_to_back_by_user_id(pool: &SqlitePool, user_id: &str) -> anyhow::Result<bool> {
+    let mut tx = pool.begin().await?;
 
-    // Shift down items at/after insert_pos
-    sqlx::query(
-        r#"UPDATE queue_items
-           SET position = position + 1
-           WHERE position >= ?1"#,
+    let item = sqlx::query_as::<_, (String, i64, Option<i64>)>(
+        "SELECT id, position, away_since FROM queue_items WHERE user_id = ?1 LIMIT 1",
     )
-    .bind(insert_pos)
-    .execute(&mut *tx)
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    let id = Uuid::new_v4().to_string();
-    sqlx::query(
-        r#"INSERT INTO queue_items (id, user_id, user_login, display_name, profile_image_url, enqueued_at, position)
-           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
-    )
-    .bind(&id)
-    .bind(&user.user_id)
-    .bind(&user.user_login)
-    .bind(&user.display_name)
-    .bind(&user.profile_image_url)
-    .bind(now)
-    .bind(insert_pos)
-    .execute(&mut *tx)
-    .await?;
+    let head_before = head_id_tx(&mut tx).await?;
+    let moved = match item {
+        Some((id, position, away_since)) => {
+            let present = present_len_tx(&mut tx).await?;
+            // Present: the last present slot. Away: just after the present block.
+            let to = if away_since.is_some() {
+                present
+            } else {
+                present - 1
+            };
+            reposition_tx(&mut tx, &id, position, to).await?;
+            sqlx::query(
+                r#"UPDATE queue_items
+                   SET manually_raised = 0, away_since = NULL, away_return_position = NULL
+                   WHERE id = ?1"#,
+            )
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+            cue_head_change_tx(&mut tx, head_before.as_deref(), util::now_epoch()).await?;
+            true
+        }
+        None => {
+            let result = sqlx::query(
+                r#"UPDATE pending_queue_items
+                   SET id = (SELECT MAX(id) + 1 FROM pending_queue_items)
+                   WHERE user_id = ?1"#,
+            )
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+            result.rows_affected() > 0
+        }
+    };
 
     tx.commit().await?;
+    Ok(moved)
+}
 
-    Ok(EnqueueOutcome::Added {
-        id,
-        position: insert_pos,
-    })
+/// Number of present (not away) items; they hold positions `0..n`.
+pub async fn present_len_tx(tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> anyhow::Result<i64> {
+    let n =
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM queue_items WHERE away_since IS NULL")
+            .fetch_one(&mut **tx)
+            .await?;
+    Ok(n)
 }
 
-pub async fn delete_item(
-    pool: &SqlitePool,
+/// Moves item `id` from position `from` to `to`, shifting the items in between by one.
+async fn reposition_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
     id: &str,
-    mode: DeleteMode,
+    from: i64,
+    to: i64,
 ) -> anyhow::Result<()> {
+    if to < from {
+        sqlx::query(
+            "UPDATE queue_items SET position = position + 1 WHERE position >= ?1 AND position < ?2",
+        )
+        .bind(to)
+        .bind(from)
+        .execute(&mut **tx)
+        .await?;
+    } else if to > from {
+        sqlx::query(
+            "UPDATE queue_items SET position = position - 1 WHERE position > ?1 AND position <= ?2",
+        )
+        .bind(from)
+        .bind(to)
+        .execute(&mut **tx)
+        .await?;
+    }
+    sqlx::query("UPDATE queue_items SET position = ?2 WHERE id = ?1")
+        .bind(id)
+        .bind(to)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AwayChange {
+    /// Parked after everyone present, remembering `return_position`.
+    Away { return_position: i64 },
+    /// Back among present viewers at `position`.
+    Back { position: i64 },
+    /// Already in the requested state.
+    Unchanged,
+}
+
+/// Parks the user's item: it moves behind everyone present (in the order people
+/// stepped away) and is skipped for the front until [`set_back`]. Its place among
+/// present viewers is remembered. `None` if the user is not in the live queue.
+pub async fn set_away(pool: &SqlitePool, user_id: &str) -> anyhow::Result<Option<AwayChange>> {
     let now = util::now_epoch();
     let mut tx = pool.begin().await?;
-
-    // Find item
-    let item = sqlx::query_as::<_, QueueItemRow>(
-        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position
-           FROM queue_items
-           WHERE id = ?1"#,
+    let Some((id, position, away_since)) = sqlx::query_as::<_, (String, i64, Option<i64>)>(
+        "SELECT id, position, away_since FROM queue_items WHERE user_id = ?1",
     )
-    .bind(id)
+    .bind(user_id)
     .fetch_optional(&mut *tx)
-    .await?;
-
-    let Some(item) = item else {
-        tx.rollback().await?;
-        anyhow::bail!("queue item not found");
+    .await?
+    else {
+        return Ok(None);
     };
+    if away_since.is_some() {
+        return Ok(Some(AwayChange::Unchanged));
+    }
 
-    // Remove
-    sqlx::query("DELETE FROM queue_items WHERE id = ?1")
-        .bind(id)
+    let head_before = head_id_tx(&mut tx).await?;
+    let len = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM queue_items")
+        .fetch_one(&mut *tx)
+        .await?;
+    reposition_tx(&mut tx, &id, position, len - 1).await?;
+    sqlx::query("UPDATE queue_items SET away_since = ?2, away_return_position = ?3 WHERE id = ?1")
+        .bind(&id)
+        .bind(now)
+        .bind(position)
         .execute(&mut *tx)
         .await?;
+    cue_head_change_tx(&mut tx, head_before.as_deref(), now).await?;
+    tx.commit().await?;
+    Ok(Some(AwayChange::Away {
+        return_position: position,
+    }))
+}
 
-    // Close gap
+/// Brings a parked item back at `min(remembered position, present count)`. This is not
+/// a new entry: eligibility, fairness placement and the entry history are not touched.
+pub async fn set_back(pool: &SqlitePool, user_id: &str) -> anyhow::Result<Option<AwayChange>> {
+    let now = util::now_epoch();
+    let mut tx = pool.begin().await?;
+    let Some((id, position, away_since, return_position)) = sqlx::query_as::<
+        _,
+        (String, i64, Option<i64>, Option<i64>),
+    >(
+        "SELECT id, position, away_since, away_return_position FROM queue_items WHERE user_id = ?1",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    if away_since.is_none() {
+        return Ok(Some(AwayChange::Unchanged));
+    }
+
+    let head_before = head_id_tx(&mut tx).await?;
+    let present = present_len_tx(&mut tx).await?;
+    let to = return_position_clamped(return_position, present);
+    reposition_tx(&mut tx, &id, position, to).await?;
     sqlx::query(
-        r#"UPDATE queue_items
-           SET position = position - 1
-           WHERE position > ?1"#,
+        "UPDATE queue_items SET away_since = NULL, away_return_position = NULL WHERE id = ?1",
     )
-    .bind(item.position)
+    .bind(&id)
     .execute(&mut *tx)
     .await?;
-
-    // If completed, add a participation record (used for fairness)
-    if matches!(mode, DeleteMode::Completed) {
-        sqlx::query(
-            r#"INSERT INTO participations (user_id, completed_at)
-               VALUES (?1, ?2)"#,
-        )
-        .bind(&item.user_id)
-        .bind(now)
-        .execute(&mut *tx)
-        .await?;
-    }
-
+    cue_head_change_tx(&mut tx, head_before.as_deref(), now).await?;
     tx.commit().await?;
-    Ok(())
+    Ok(Some(AwayChange::Back { position: to }))
 }
 
-pub async fn move_up(pool: &SqlitePool, id: &str) -> anyhow::Result<()> {
-    move_by(pool, id, -1).await
+/// Remembered position clamped to the present items: with fewer people present than
+/// when they left, the viewer returns at the end of the present line.
+fn return_position_clamped(remembered: Option<i64>, present_len: i64) -> i64 {
+    remembered.unwrap_or(present_len).clamp(0, present_len)
 }
 
-pub async fn move_down(pool: &SqlitePool, id: &str) -> anyhow::Result<()> {
-    move_by(pool, id, 1).await
+pub async fn user_id_of_item(pool: &SqlitePool, id: &str) -> anyhow::Result<Option<String>> {
+    let user_id = sqlx::query_scalar::<_, String>("SELECT user_id FROM queue_items WHERE id = ?1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(user_id)
 }
 
-async fn move_by(pool: &SqlitePool, id: &str, delta: i64) -> anyhow::Result<()> {
-    let mut tx = pool.begin().await?;
-
-    let item = sqlx::query_as::<_, QueueItemRow>(
-        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position
+/// [`set_away`] or [`set_back`], whichever changes the state.
+pub async fn toggle_away(pool: &SqlitePool, user_id: &str) -> anyhow::Result<Option<AwayChange>> {
+    match set_away(pool, user_id).await? {
+        Some(AwayChange::Unchanged) => set_back(pool, user_id).await,
+        other => Ok(other),
+    }
+}
+
+/// Cancels every item enqueued before `session_started_at`. Returns how many were removed.
+pub async fn clear_previous_session(
+    pool: &SqlitePool,
+    timings: &Timings,
+    session_started_at: i64,
+) -> anyhow::Result<u64> {
+    let ids = sqlx::query_scalar::<_, String>(
+        r#"SELECT id
            FROM queue_items
-           WHERE id = ?1"#,
+           WHERE enqueued_at < ?1
+           ORDER BY position ASC"#,
     )
-    .bind(id)
-    .fetch_optional(&mut *tx)
+    .bind(session_started_at)
+    .fetch_all(pool)
     .await?;
 
-    let Some(item) = item else {
-        tx.rollback().await?;
-        anyhow::bail!("queue item not found");
-    };
+    let mut removed = 0;
+    for id in ids {
+        delete_item(pool, timings, &id, DeleteMode::Canceled).await?;
+        removed += 1;
+    }
+    Ok(removed)
+}
 
-    let new_pos = item.position + delta;
-    if new_pos < 0 {
+/// Empties the queue in one transaction. With `Completed` everyone removed counts as
+/// having played (and their redemptions are fulfilled); with `Canceled` they are
+/// refunded. Returns the number of items removed.
+pub async fn clear_all(pool: &SqlitePool, mode: DeleteMode) -> anyhow::Result<u64> {
+    let now = util::now_epoch();
+    let mut tx = pool.begin().await?;
+
+    // Last first, so no positions need closing up along the way.
+    let items = sqlx::query_as::<_, QueueItemRow>(
+        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position,
+                  reward_id, redemption_id, priority, tags
+           FROM queue_items
+           ORDER BY position DESC"#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let head_before = head_id_tx(&mut tx).await?;
+    let removed = items.len() as u64;
+    for item in items {
+        remove_item_tx(&mut tx, item, mode, now).await?;
+    }
+    cue_head_change_tx(&mut tx, head_before.as_deref(), now).await?;
+
+    tx.commit().await?;
+    Ok(removed)
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(user_id = %user.user_id, total_ms = tracing::field::Empty, phases = tracing::field::Empty)
+)]
+pub async fn enqueue_user(
+    pool: &SqlitePool,
+    timings: &Timings,
+    cfg: &QueueConfig,
+    policy: &QueuePolicy,
+    user: NewQueueUser,
+    notices: &JoinNotices,
+) -> anyhow::Result<EnqueueOutcome> {
+    let mut timer = timing::PhaseTimer::mutation(timings, "enqueue_user");
+    let now = util::now_epoch();
+
+    let mut tx = pool.begin().await?;
+    let outcome = enqueue_tx(&mut tx, cfg, policy, user, notices, now, &mut timer).await?;
+    if matches!(
+        outcome,
+        EnqueueOutcome::Added(_) | EnqueueOutcome::Pending { .. }
+    ) {
+        tx.commit().await?;
+        timer.phase("commit");
+    } else {
         tx.rollback().await?;
-        return Ok(());
     }
+    Ok(outcome)
+}
 
-    let swap = sqlx::query_as::<_, QueueItemRow>(
-        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position
+/// What [`enqueue_user`] would do right now, without storing anything: the same
+/// decision, taken on a read snapshot. `Added` carries the receipt with an empty `id`.
+pub async fn explain_enqueue(
+    pool: &SqlitePool,
+    timings: &Timings,
+    cfg: &QueueConfig,
+    policy: &QueuePolicy,
+    user: NewQueueUser,
+) -> anyhow::Result<EnqueueOutcome> {
+    let mut timer = timing::PhaseTimer::read(timings, "explain_enqueue");
+    let mut tx = pool.begin().await?;
+    let plan = plan_enqueue_tx(&mut tx, cfg, policy, &user, util::now_epoch(), &mut timer).await?;
+    tx.rollback().await?;
+    Ok(match plan {
+        EnqueuePlan::Refuse(outcome) => outcome,
+        EnqueuePlan::Hold { frozen_at, .. } => EnqueueOutcome::Pending { frozen_at },
+        EnqueuePlan::Place { receipt, .. } => EnqueueOutcome::Added(receipt),
+    })
+}
+
+/// One readable line per rule that decided `outcome` or moved its placement.
+pub fn explain_reasons(outcome: &EnqueueOutcome) -> Vec<String> {
+    let r = match outcome {
+        EnqueueOutcome::Added(r) => r,
+        EnqueueOutcome::Pending { frozen_at } => {
+            return vec![format!(
+                "the queue is frozen (since {frozen_at}); the entry would be placed on thaw"
+            )]
+        }
+        EnqueueOutcome::AlreadyQueued => return vec!["already in the queue".to_string()],
+        EnqueueOutcome::Rejected(RejectReason::Cooldown { remaining_secs }) => {
+            return vec![format!(
+                "cooldown: {remaining_secs}s left since the last turn"
+            )]
+        }
+        EnqueueOutcome::Rejected(RejectReason::MaxParticipations { limit, count }) => {
+            return vec![format!(
+                "{count} turns in the participation window already reach the limit of {limit}"
+            )]
+        }
+        EnqueueOutcome::Rejected(RejectReason::AlreadyEnteredInWindow) => {
+            return vec!["already entered once in the participation window".to_string()]
+        }
+        EnqueueOutcome::QueueFull { max_queue_size } => {
+            return vec![format!(
+                "queue.max_queue_size: {max_queue_size} viewers are already waiting"
+            )]
+        }
+    };
+    let mut reasons = vec![format!(
+        "ranked #{} by priority {} and {} recent participation(s)",
+        r.ranked_position + 1,
+        r.effective_priority,
+        r.recent_participation_count
+    )];
+    if r.priority_placement {
+        reasons.push(format!(
+            "priority {} put it ahead of where fairness alone would",
+            r.effective_priority
+        ));
+    }
+    if r.tiebreak_applied {
+        reasons.push(
+            "queue.tiebreak put it ahead of equal-count viewers who played more recently"
+                .to_string(),
+        );
+    }
+    if r.manual_order_applied {
+        reasons.push(format!(
+            "manual order: kept below the manually raised viewer, so #{} became #{}",
+            r.ranked_position + 1,
+            r.position + 1
+        ));
+    }
+    if r.ffa_applied {
+        reasons.push("first-come-first-served mode: placed last".to_string());
+    }
+    reasons
+}
+
+/// What [`enqueue_tx`] is going to write, decided from reads alone so that
+/// [`explain_enqueue`] can work from a read snapshot.
+enum EnqueuePlan {
+    /// Nothing to write: already queued, rejected or full.
+    Refuse(EnqueueOutcome),
+    /// Frozen: eligibility and the entry count apply now, placement happens on thaw.
+    Hold {
+        fields: NewItemFields,
+        frozen_at: i64,
+    },
+    /// Inserted at `receipt.position`; the insert fills in `receipt.id`.
+    Place {
+        fields: NewItemFields,
+        receipt: EnqueueReceipt,
+    },
+}
+
+async fn plan_enqueue_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    cfg: &QueueConfig,
+    policy: &QueuePolicy,
+    user: &NewQueueUser,
+    now: i64,
+    timer: &mut timing::PhaseTimer<'_>,
+) -> anyhow::Result<EnqueuePlan> {
+    let window_start = participation_window_start(now, cfg.participation_window_secs as i64);
+
+    // Already queued?
+    let existing = sqlx::query_as::<_, QueueItemRow>(
+        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position,
+                  reward_id, redemption_id, priority, tags
            FROM queue_items
-           WHERE position = ?1
+           WHERE user_id = ?1
            LIMIT 1"#,
     )
-    .bind(new_pos)
-    .fetch_optional(&mut *tx)
+    .bind(&user.user_id)
+    .fetch_optional(&mut **tx)
     .await?;
 
-    let Some(swap) = swap else {
-        tx.rollback().await?;
-        return Ok(());
+    let pending = sqlx::query("SELECT 1 FROM pending_queue_items WHERE user_id = ?1 LIMIT 1")
+        .bind(&user.user_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+    if existing.is_some() || pending.is_some() {
+        return Ok(EnqueuePlan::Refuse(EnqueueOutcome::AlreadyQueued));
+    }
+
+    // Fetch current queue in order (same snapshot as the insert below)
+    let current = queue_with_counts(&mut **tx, window_start).await?;
+
+    let NewcomerCountsRow {
+        c: my_count,
+        last_completed_at,
+    } = newcomer_counts_tx(tx, &user.user_id, window_start).await?;
+    let last_completed_from_reward_at = match user.reward_id.as_deref() {
+        Some(reward_id) if policy.rejoin_cooldown_secs.is_some() => sqlx::query_scalar::<
+            _,
+            Option<i64>,
+        >(
+            "SELECT MAX(completed_at) FROM participations WHERE user_id = ?1 AND reward_id = ?2",
+        )
+        .bind(&user.user_id)
+        .bind(reward_id)
+        .fetch_one(&mut **tx)
+        .await?,
+        _ => None,
     };
+    let entered_in_window =
+        sqlx::query("SELECT 1 FROM queue_entries WHERE user_id = ?1 AND entered_at >= ?2 LIMIT 1")
+            .bind(&user.user_id)
+            .bind(window_start)
+            .fetch_optional(&mut **tx)
+            .await?
+            .is_some();
 
-    // Swap positions
-    sqlx::query("UPDATE queue_items SET position = ?1 WHERE id = ?2")
-        .bind(new_pos)
-        .bind(&item.id)
-        .execute(&mut *tx)
-        .await?;
+    timer.phase("snapshot");
 
-    sqlx::query("UPDATE queue_items SET position = ?1 WHERE id = ?2")
-        .bind(item.position)
-        .bind(&swap.id)
-        .execute(&mut *tx)
-        .await?;
+    let history = UserHistory {
+        recent_participation_count: my_count,
+        last_completed_at,
+        last_completed_from_reward_at,
+        entered_in_window,
+    };
+    if let Err(reason) = check_eligibility(policy, &history, now) {
+        return Ok(EnqueuePlan::Refuse(EnqueueOutcome::Rejected(reason)));
+    }
 
-    tx.commit().await?;
+    // The cap holds wherever fairness would place the newcomer.
+    if let Some(max_queue_size) = cfg.max_queue_size {
+        if waiting_count_tx(tx).await? >= max_queue_size as i64 {
+            return Ok(EnqueuePlan::Refuse(EnqueueOutcome::QueueFull {
+                max_queue_size,
+            }));
+        }
+    }
+
+    let display_name = stored_display_name(cfg, &user.display_name, &user.user_login);
+    let mut fields = NewItemFields {
+        user_id: user.user_id.clone(),
+        user_login: user.user_login.clone(),
+        display_name,
+        display_name_raw: Some(user.display_name.clone()),
+        profile_image_url: user.profile_image_url.clone(),
+        enqueued_at: now,
+        reward_id: user.reward_id.clone(),
+        redemption_id: user.redemption_id.clone(),
+        priority: policy.priority,
+        tags: policy.tags.join(","),
+        user_input: user
+            .user_input
+            .as_deref()
+            .and_then(roster::clean_user_input),
+        redeemed_at_ms: Some(user.redeemed_at_ms.unwrap_or_else(util::now_epoch_millis)),
+    };
+
+    if let Some(frozen_at) = frozen_at_tx(tx).await? {
+        return Ok(EnqueuePlan::Hold { fields, frozen_at });
+    }
+
+    let newcomer = Newcomer {
+        priority: policy.priority,
+        enqueued_at: now,
+        count: my_count,
+        last_completed_at,
+        redeemed_at_ms: fields.redeemed_at_ms,
+    };
+    // Present items come first, so an index among them is also a position.
+    let present: Vec<_> = current.into_iter().filter(|c| !c.is_away()).collect();
+    let mut placement = place(&present, &newcomer, cfg, now);
+    let ffa_applied = ffa_slot_open_tx(tx, now).await?;
+    if ffa_applied {
+        placement.index = present.len();
+        fields.tags = policy
+            .tags
+            .iter()
+            .map(String::as_str)
+            .chain([FFA_TAG])
+            .collect::<Vec<_>>()
+            .join(",");
+    }
+    let insert_pos = placement.index as i64;
+    // Taking the place of a head that is not completing completes it (`queue.complete_on_advance`).
+    let displaced = cfg.complete_on_advance
+        && insert_pos == 0
+        && present.first().is_some_and(|h| h.completing_at.is_none());
+    timer.phase("decide");
+
+    let spi = cfg.seconds_per_item as i64;
+    let receipt = EnqueueReceipt {
+        id: String::new(),
+        position: insert_pos,
+        queue_len: present.len() as i64 + 1 - i64::from(displaced),
+        estimated_wait_secs: (spi > 0).then_some(insert_pos * spi),
+        priority: policy.priority,
+        effective_priority: placement.effective_priority,
+        priority_placement: !ffa_applied && placement.index < placement.fair_index,
+        ranked_position: placement.ranked_index as i64,
+        manual_order_applied: placement.manual_order_applied,
+        tiebreak_applied: placement.tiebreak_applied,
+        ffa_applied,
+        recent_participation_count: my_count,
+        last_completed_at,
+    };
+    Ok(EnqueuePlan::Place { fields, receipt })
+}
+
+async fn enqueue_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    cfg: &QueueConfig,
+    policy: &QueuePolicy,
+    user: NewQueueUser,
+    notices: &JoinNotices,
+    now: i64,
+    timer: &mut timing::PhaseTimer<'_>,
+) -> anyhow::Result<EnqueueOutcome> {
+    let (fields, mut receipt) = match plan_enqueue_tx(tx, cfg, policy, &user, now, timer).await? {
+        EnqueuePlan::Refuse(outcome) => return Ok(outcome),
+        EnqueuePlan::Hold { fields, frozen_at } => {
+            sqlx::query(
+                r#"INSERT INTO pending_queue_items (user_id, user_login, display_name, display_name_raw, profile_image_url, enqueued_at, reward_id, redemption_id, priority, tags, user_input, redeemed_at_ms)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"#,
+            )
+            .bind(&fields.user_id)
+            .bind(&fields.user_login)
+            .bind(&fields.display_name)
+            .bind(&fields.display_name_raw)
+            .bind(&fields.profile_image_url)
+            .bind(fields.enqueued_at)
+            .bind(&fields.reward_id)
+            .bind(&fields.redemption_id)
+            .bind(fields.priority)
+            .bind(&fields.tags)
+            .bind(&fields.user_input)
+            .bind(fields.redeemed_at_ms)
+            .execute(&mut **tx)
+            .await?;
+            record_entry_tx(tx, &user.user_id, user.reward_id.as_deref(), now).await?;
+            return Ok(EnqueueOutcome::Pending { frozen_at });
+        }
+        EnqueuePlan::Place { fields, receipt } => (fields, receipt),
+    };
+
+    take_ffa_slot_tx(tx, now).await?;
+    let head_before = head_id_tx(tx).await?;
+    receipt.id = insert_item_tx(tx, &fields, receipt.position).await?;
+    record_entry_tx(tx, &user.user_id, user.reward_id.as_deref(), now).await?;
+    let joined = CuePayload::user(&fields.display_name, &fields.profile_image_url);
+    cues::emit_tx(tx, CueKind::UserJoined, &joined, now).await?;
+    if cfg.complete_on_advance {
+        complete_displaced_head_tx(tx, head_before.as_deref(), now).await?;
+    }
+    cue_head_change_tx(tx, head_before.as_deref(), now).await?;
+    for event in notices.events(&receipt, &user.user_id, &user.display_name) {
+        outbox::insert_tx(tx, &event, now).await?;
+    }
+    timer.phase("write");
+    Ok(EnqueueOutcome::Added(receipt))
+}
+
+/// Viewers in the queue or held by a freeze (`queue.max_queue_size`).
+async fn waiting_count_tx(tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> anyhow::Result<i64> {
+    let n = sqlx::query_scalar::<_, i64>(
+        "SELECT (SELECT COUNT(*) FROM queue_items) + (SELECT COUNT(*) FROM pending_queue_items)",
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(n)
+}
+
+/// Id of the item at position 0, captured before a mutation for [`cue_head_change_tx`].
+/// An away item there means everyone is away, so there is no head.
+async fn head_id_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> anyhow::Result<Option<String>> {
+    let id = sqlx::query_scalar::<_, String>(
+        "SELECT id FROM queue_items WHERE position = 0 AND away_since IS NULL",
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(id)
+}
+
+/// Emits `cue:user_up_next` when a different viewer is now at position 0.
+async fn cue_head_change_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    head_before: Option<&str>,
+    now: i64,
+) -> anyhow::Result<()> {
+    let head = sqlx::query_as::<_, (String, String, String)>(
+        "SELECT id, display_name, profile_image_url FROM queue_items WHERE position = 0 AND away_since IS NULL",
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some((id, display_name, profile_image_url)) = head {
+        if head_before != Some(id.as_str()) {
+            // First time at the front only; moving down and back keeps the original start.
+            sqlx::query("UPDATE queue_items SET turn_started_at = ?2 WHERE id = ?1 AND turn_started_at IS NULL")
+                .bind(&id)
+                .bind(now)
+                .execute(&mut **tx)
+                .await?;
+            let payload = CuePayload::user(&display_name, &profile_image_url);
+            cues::emit_tx(tx, CueKind::UserUpNext, &payload, now).await?;
+        }
+    }
     Ok(())
 }
 
-async fn count_participations(pool: &SqlitePool, user_id: &str, window_start: i64) -> anyhow::Result<i64> {
-    let row = sqlx::query_as::<_, CountRow>(
-        r#"SELECT COUNT(*) as c
-           FROM participations
-           WHERE user_id = ?1 AND completed_at >= ?2"#,
+/// `queue.complete_on_advance`: once someone else holds position 0, the viewer who held
+/// it before the mutation (`head_before`) is removed as completed. True if one was.
+async fn complete_displaced_head_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    head_before: Option<&str>,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let Some(before) = head_before else {
+        return Ok(false);
+    };
+    if head_id_tx(tx).await?.as_deref() == Some(before) {
+        return Ok(false);
+    }
+    // A completing head is left to `finalize_completions`, so it still completes at the
+    // time complete was pressed and can be aborted within the grace period.
+    let item = sqlx::query_as::<_, QueueItemRow>(
+        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position,
+                  reward_id, redemption_id, priority, tags
+           FROM queue_items
+           WHERE id = ?1 AND completing_at IS NULL"#,
     )
-    .bind(user_id)
-    .bind(window_start)
-    .fetch_one(pool)
+    .bind(before)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(item) = item else {
+        return Ok(false);
+    };
+    remove_item_tx(tx, item, DeleteMode::Completed, now).await?;
+    Ok(true)
+}
+
+/// Display name as stored: sanitized (falling back to the login) when
+/// `queue.sanitize_display_names` is set.
+fn stored_display_name(cfg: &QueueConfig, display_name: &str, user_login: &str) -> String {
+    if !cfg.sanitize_display_names {
+        return display_name.to_string();
+    }
+    let clean = util::sanitize_display_name(display_name);
+    if clean.is_empty() {
+        user_login.to_string()
+    } else {
+        clean
+    }
+}
+
+/// Columns written for a new queue item (live or pending).
+#[derive(Debug, FromRow)]
+struct NewItemFields {
+    user_id: String,
+    user_login: String,
+    display_name: String,
+    display_name_raw: Option<String>,
+    profile_image_url: String,
+    enqueued_at: i64,
+    reward_id: Option<String>,
+    redemption_id: Option<String>,
+    priority: i64,
+    tags: String,
+    user_input: Option<String>,
+    redeemed_at_ms: Option<i64>,
+}
+
+/// Inserts at `pos`, shifting later items down. Returns the new item id.
+async fn insert_item_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    fields: &NewItemFields,
+    pos: i64,
+) -> anyhow::Result<String> {
+    // Shift down items at/after pos
+    sqlx::query(
+        r#"UPDATE queue_items
+           SET position = position + 1
+           WHERE position >= ?1"#,
+    )
+    .bind(pos)
+    .execute(&mut **tx)
+    .await?;
+
+    let id = Uuid::new_v4().to_string();
+    sqlx::query(
+        r#"INSERT INTO queue_items (id, user_id, user_login, display_name, profile_image_url, enqueued_at, position, reward_id, redemption_id, priority, tags, display_name_raw, user_input, redeemed_at_ms)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)"#,
+    )
+    .bind(&id)
+    .bind(&fields.user_id)
+    .bind(&fields.user_login)
+    .bind(&fields.display_name)
+    .bind(&fields.profile_image_url)
+    .bind(fields.enqueued_at)
+    .bind(pos)
+    .bind(&fields.reward_id)
+    .bind(&fields.redemption_id)
+    .bind(fields.priority)
+    .bind(&fields.tags)
+    .bind(&fields.display_name_raw)
+    .bind(&fields.user_input)
+    .bind(fields.redeemed_at_ms)
+    .execute(&mut **tx)
     .await?;
-    Ok(row.c)
+    roster::resolve_items_tx(tx, Some(&id)).await?;
+    Ok(id)
 }
 
-async fn count_participations_tx(
+async fn record_entry_tx(
     tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
     user_id: &str,
-    window_start: i64,
-) -> anyhow::Result<i64> {
-    let row = sqlx::query_as::<_, CountRow>(
-        r#"SELECT COUNT(*) as c
-           FROM participations
-           WHERE user_id = ?1 AND completed_at >= ?2"#,
+    reward_id: Option<&str>,
+    now: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"INSERT INTO queue_entries (user_id, reward_id, entered_at)
+           VALUES (?1, ?2, ?3)"#,
     )
     .bind(user_id)
-    .bind(window_start)
-    .fetch_one(&mut **tx)
+    .bind(reward_id)
+    .bind(now)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Where a viewer stands, for chat replies.
+#[derive(Debug, Clone, Copy)]
+pub struct UserPosition {
+    /// 1-based; `None` when the user has no item (held entries during a freeze included).
+    pub position: Option<i64>,
+    /// Items in the live queue, away viewers included; entries held by a freeze are not.
+    pub total: i64,
+}
+
+pub async fn user_position(pool: &SqlitePool, user_id: &str) -> anyhow::Result<UserPosition> {
+    let position =
+        sqlx::query_scalar::<_, i64>("SELECT position FROM queue_items WHERE user_id = ?1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+    let total = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM queue_items")
+        .fetch_one(pool)
+        .await?;
+    Ok(UserPosition {
+        position: position.map(|p| p + 1),
+        total,
+    })
+}
+
+/// Viewers in the live queue or held by a freeze.
+pub async fn queued_user_ids(pool: &SqlitePool) -> anyhow::Result<Vec<String>> {
+    let ids = sqlx::query_scalar::<_, String>(
+        r#"SELECT user_id FROM queue_items
+           UNION
+           SELECT user_id FROM pending_queue_items"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(ids)
+}
+
+/// User at position 1 (next after the active item), so the overlay can load their
+/// avatar before the switch. `None` when nobody is next.
+pub async fn next_up_user_id(pool: &SqlitePool) -> anyhow::Result<Option<String>> {
+    let id = sqlx::query_scalar::<_, String>(
+        "SELECT user_id FROM queue_items WHERE position = 1 AND away_since IS NULL",
+    )
+    .fetch_optional(pool)
     .await?;
-    Ok(row.c)
+    Ok(id)
+}
+
+/// One entry of `POST /api/queue/import`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportItem {
+    pub user_id: String,
+    pub user_login: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub profile_image_url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportError {
+    BlankUserId { index: usize },
+    Duplicate { user_id: String },
+}
+
+/// Validates an import. With `merge_duplicates`, later entries for a user_id
+/// already seen are dropped (the earliest position wins) and counted; otherwise a
+/// duplicate rejects the whole import.
+pub fn dedup_import(
+    items: Vec<ImportItem>,
+    merge_duplicates: bool,
+) -> Result<(Vec<ImportItem>, usize), ImportError> {
+    let mut seen = std::collections::HashSet::new();
+    let mut kept = Vec::with_capacity(items.len());
+    let mut merged = 0;
+    for (index, item) in items.into_iter().enumerate() {
+        if util::is_blank(&item.user_id) {
+            return Err(ImportError::BlankUserId { index });
+        }
+        if !seen.insert(item.user_id.clone()) {
+            if !merge_duplicates {
+                return Err(ImportError::Duplicate {
+                    user_id: item.user_id,
+                });
+            }
+            merged += 1;
+            continue;
+        }
+        kept.push(item);
+    }
+    Ok((kept, merged))
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportResult {
+    pub imported: usize,
+    pub merged_duplicates: usize,
+    /// Already in the queue (or held by a freeze); left where they are.
+    pub already_queued: usize,
+    /// Users that were queued more than once afterwards and got merged (see [`dedupe_users`]).
+    pub deduped_users: usize,
+}
+
+/// Appends validated items to the end of the queue in file order.
+pub async fn import_items(
+    pool: &SqlitePool,
+    cfg: &QueueConfig,
+    items: &[ImportItem],
+) -> anyhow::Result<ImportResult> {
+    let now = util::now_epoch();
+    let mut tx = pool.begin().await?;
+    let mut result = ImportResult::default();
+    let head_before = head_id_tx(&mut tx).await?;
+
+    for item in items {
+        let queued = sqlx::query(
+            r#"SELECT 1 FROM queue_items WHERE user_id = ?1
+               UNION ALL
+               SELECT 1 FROM pending_queue_items WHERE user_id = ?1
+               LIMIT 1"#,
+        )
+        .bind(&item.user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if queued.is_some() {
+            result.already_queued += 1;
+            continue;
+        }
+
+        let len = present_len_tx(&mut tx).await?;
+        let fields = NewItemFields {
+            user_id: item.user_id.clone(),
+            user_login: item.user_login.clone(),
+            display_name: stored_display_name(cfg, &item.display_name, &item.user_login),
+            display_name_raw: Some(item.display_name.clone()),
+            profile_image_url: item.profile_image_url.clone(),
+            enqueued_at: now,
+            reward_id: None,
+            redemption_id: None,
+            priority: 0,
+            tags: String::new(),
+            user_input: None,
+            redeemed_at_ms: Some(util::now_epoch_millis()),
+        };
+        insert_item_tx(&mut tx, &fields, len).await?;
+        result.imported += 1;
+    }
+    result.deduped_users = dedupe_users_tx(&mut tx).await?.merged.len();
+    // Appended items only become the head of an empty queue; no per-item join cues for a bulk import.
+    cue_head_change_tx(&mut tx, head_before.as_deref(), now).await?;
+
+    tx.commit().await?;
+    Ok(result)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MergedUser {
+    pub user_id: String,
+    pub kept_id: String,
+    pub removed_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DedupeReport {
+    pub merged: Vec<MergedUser>,
+    /// Frozen-queue redemptions dropped because the user is already queued.
+    pub removed_pending: u64,
 }
 
 #[derive(Debug, FromRow)]
-struct CountRow {
-    c: i64,
+struct DuplicateRow {
+    id: String,
+    enqueued_at: i64,
+    priority: i64,
+    private_note: Option<String>,
+}
+
+/// Merges queue items that share a user_id into the earliest-positioned one (earliest
+/// `enqueued_at`, highest priority, notes joined), removes the rest and renumbers positions.
+/// Only databases from before the user_id constraint can hold such rows; the migration
+/// adding it runs this first (see `db::init_pool`).
+pub async fn dedupe_users(pool: &SqlitePool) -> anyhow::Result<DedupeReport> {
+    let mut tx = pool.begin().await?;
+    let report = dedupe_users_tx(&mut tx).await?;
+    tx.commit().await?;
+    Ok(report)
+}
+
+async fn dedupe_users_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> anyhow::Result<DedupeReport> {
+    let mut report = DedupeReport::default();
+    let user_ids = sqlx::query_scalar::<_, String>(
+        "SELECT user_id FROM queue_items GROUP BY user_id HAVING COUNT(*) > 1",
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    for user_id in user_ids {
+        let rows = sqlx::query_as::<_, DuplicateRow>(
+            r#"SELECT id, enqueued_at, priority, private_note
+               FROM queue_items
+               WHERE user_id = ?1
+               ORDER BY position ASC, enqueued_at ASC"#,
+        )
+        .bind(&user_id)
+        .fetch_all(&mut **tx)
+        .await?;
+        let Some((kept, rest)) = rows.split_first() else {
+            continue;
+        };
+
+        let enqueued_at = rows
+            .iter()
+            .map(|r| r.enqueued_at)
+            .min()
+            .unwrap_or(kept.enqueued_at);
+        let priority = rows
+            .iter()
+            .map(|r| r.priority)
+            .max()
+            .unwrap_or(kept.priority);
+        let mut notes: Vec<&str> = Vec::new();
+        for note in rows
+            .iter()
+            .filter_map(|r| r.private_note.as_deref())
+            .map(str::trim)
+        {
+            if !note.is_empty() && !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+        let note: String = notes
+            .join(" / ")
+            .chars()
+            .take(MAX_PRIVATE_NOTE_CHARS)
+            .collect();
+
+        sqlx::query("UPDATE queue_items SET enqueued_at = ?2, priority = ?3, private_note = ?4 WHERE id = ?1")
+            .bind(&kept.id)
+            .bind(enqueued_at)
+            .bind(priority)
+            .bind((!note.is_empty()).then_some(&note))
+            .execute(&mut **tx)
+            .await?;
+        for r in rest {
+            sqlx::query("DELETE FROM queue_items WHERE id = ?1")
+                .bind(&r.id)
+                .execute(&mut **tx)
+                .await?;
+        }
+        report.merged.push(MergedUser {
+            user_id,
+            kept_id: kept.id.clone(),
+            removed_ids: rest.iter().map(|r| r.id.clone()).collect(),
+        });
+    }
+
+    if !report.merged.is_empty() {
+        // Close the gaps left by the removed rows, keeping the relative order.
+        sqlx::query(
+            r#"UPDATE queue_items
+               SET position = (
+                 SELECT COUNT(*) FROM queue_items AS q
+                 WHERE q.position < queue_items.position
+                    OR (q.position = queue_items.position AND q.id < queue_items.id)
+               )"#,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    report.removed_pending = sqlx::query(
+        "DELETE FROM pending_queue_items WHERE user_id IN (SELECT user_id FROM queue_items)",
+    )
+    .execute(&mut **tx)
+    .await?
+    .rows_affected();
+
+    Ok(report)
+}
+
+/// Copies cached avatars onto queued items that have none (e.g. imported without one).
+pub async fn fill_missing_profile_images(pool: &SqlitePool) -> anyhow::Result<u64> {
+    let result = sqlx::query(
+        r#"UPDATE queue_items
+           SET profile_image_url = (SELECT c.profile_image_url FROM user_cache c WHERE c.user_id = queue_items.user_id)
+           WHERE profile_image_url = ''
+             AND EXISTS (SELECT 1 FROM user_cache c WHERE c.user_id = queue_items.user_id)"#,
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+const KV_QUEUE_FROZEN_AT: &str = "queue_frozen_at";
+const KV_QUEUE_FFA: &str = "queue_ffa";
+const KV_QUEUE_PAUSED: &str = "queue_paused";
+
+/// Paused by the broadcaster (`POST /api/queue/pause`): redemptions and chat joins are
+/// not enqueued, the queue itself is untouched.
+pub async fn is_paused(pool: &SqlitePool) -> anyhow::Result<bool> {
+    Ok(db::get_kv(pool, KV_QUEUE_PAUSED).await?.as_deref() == Some("1"))
+}
+
+/// Pauses or resumes intake. With `twitch.pause_rewards_with_queue` the join rewards on
+/// Twitch follow through the outbox, so a Helix failure never holds up the local change.
+/// Resuming a paused queue emits `queue_opened`. The pause endpoints and profile activation both go through this.
+pub async fn set_paused_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    pause_rewards: bool,
+    paused: bool,
+    now: i64,
+) -> anyhow::Result<()> {
+    if paused {
+        db::set_kv(&mut **tx, KV_QUEUE_PAUSED, "1").await?;
+    } else {
+        let resumed = sqlx::query("DELETE FROM app_kv WHERE key = ?1")
+            .bind(KV_QUEUE_PAUSED)
+            .execute(&mut **tx)
+            .await?
+            .rows_affected()
+            > 0;
+        if resumed {
+            cues::emit_tx(tx, CueKind::QueueOpened, &CuePayload::default(), now).await?;
+        }
+    }
+    if pause_rewards {
+        outbox::insert_tx(tx, &outbox::OutboxEvent::JoinRewardsPaused { paused }, now).await?;
+    }
+    Ok(())
+}
+
+pub async fn set_paused(
+    pool: &SqlitePool,
+    pause_rewards: bool,
+    paused: bool,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    set_paused_tx(&mut tx, pause_rewards, paused, util::now_epoch()).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Tag of items placed by first-come-first-served mode.
+pub const FFA_TAG: &str = "ffa";
+
+/// First-come-first-served mode: new entries go to the end regardless of fairness
+/// until `until` passes or `entries_left` runs out, whichever comes first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FfaState {
+    pub started_at: i64,
+    pub until: Option<i64>,
+    pub entries_left: Option<i64>,
+}
+
+impl FfaState {
+    fn is_active(&self, now: i64) -> bool {
+        self.until.is_none_or(|t| now < t) && self.entries_left.is_none_or(|n| n > 0)
+    }
+}
+
+/// Starts (or restarts) FFA mode. At least one of the limits must be given.
+pub async fn start_ffa(
+    pool: &SqlitePool,
+    duration_secs: Option<u64>,
+    entries: Option<u32>,
+) -> anyhow::Result<FfaState> {
+    anyhow::ensure!(
+        duration_secs.is_some() || entries.is_some(),
+        "give duration_secs or entries"
+    );
+    let now = util::now_epoch();
+    let state = FfaState {
+        started_at: now,
+        until: duration_secs.map(|d| now + d as i64),
+        entries_left: entries.map(i64::from),
+    };
+    db::set_kv(pool, KV_QUEUE_FFA, &serde_json::to_string(&state)?).await?;
+    Ok(state)
+}
+
+/// Ends FFA mode early; false if it was not running.
+pub async fn stop_ffa(pool: &SqlitePool) -> anyhow::Result<bool> {
+    let running = ffa_state(pool).await?.is_some();
+    db::delete_kv(pool, KV_QUEUE_FFA).await?;
+    Ok(running)
+}
+
+/// The running FFA mode, if any. An expired one reads as `None`.
+pub async fn ffa_state(pool: &SqlitePool) -> anyhow::Result<Option<FfaState>> {
+    let raw = db::get_kv(pool, KV_QUEUE_FFA).await?;
+    let now = util::now_epoch();
+    Ok(raw
+        .and_then(|s| serde_json::from_str::<FfaState>(&s).ok())
+        .filter(|s| s.is_active(now)))
+}
+
+/// Whether first-come-first-served mode places the next entry; [`take_ffa_slot_tx`]
+/// then uses up its slot.
+async fn ffa_slot_open_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    now: i64,
+) -> anyhow::Result<bool> {
+    Ok(ffa_state_tx(tx)
+        .await?
+        .is_some_and(|state| state.is_active(now)))
+}
+
+async fn ffa_state_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> anyhow::Result<Option<FfaState>> {
+    let raw = sqlx::query_scalar::<_, String>("SELECT value FROM app_kv WHERE key = ?1")
+        .bind(KV_QUEUE_FFA)
+        .fetch_optional(&mut **tx)
+        .await?;
+    Ok(raw.and_then(|s| serde_json::from_str::<FfaState>(&s).ok()))
+}
+
+/// Uses one FFA entry if the mode is running, in the enqueue transaction so a burst
+/// cannot overspend the budget. Clears the mode once it is used up or expired.
+async fn take_ffa_slot_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    now: i64,
+) -> anyhow::Result<()> {
+    let Some(mut state) = ffa_state_tx(tx).await? else {
+        return Ok(());
+    };
+    if !state.is_active(now) {
+        sqlx::query("DELETE FROM app_kv WHERE key = ?1")
+            .bind(KV_QUEUE_FFA)
+            .execute(&mut **tx)
+            .await?;
+        return Ok(());
+    }
+    if let Some(n) = state.entries_left.as_mut() {
+        *n -= 1;
+    }
+    if state.is_active(now) {
+        sqlx::query("UPDATE app_kv SET value = ?2 WHERE key = ?1")
+            .bind(KV_QUEUE_FFA)
+            .bind(serde_json::to_string(&state)?)
+            .execute(&mut **tx)
+            .await?;
+    } else {
+        sqlx::query("DELETE FROM app_kv WHERE key = ?1")
+            .bind(KV_QUEUE_FFA)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+const KV_QUEUE_FREEZE_MEMBERS: &str = "queue_freeze_members";
+
+async fn frozen_at_tx(tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> anyhow::Result<Option<i64>> {
+    let v = sqlx::query_scalar::<_, String>("SELECT value FROM app_kv WHERE key = ?1")
+        .bind(KV_QUEUE_FROZEN_AT)
+        .fetch_optional(&mut **tx)
+        .await?;
+    Ok(v.and_then(|s| s.parse().ok()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FreezeStateDto {
+    pub frozen: bool,
+    pub frozen_at: Option<i64>,
+    /// user_ids in the queue when it was frozen (the eligible set for a draw).
+    pub members: Vec<String>,
+    /// Redemptions waiting for thaw.
+    pub pending_count: i64,
+}
+
+pub async fn freeze_state(pool: &SqlitePool) -> anyhow::Result<FreezeStateDto> {
+    let frozen_at = db::get_kv(pool, KV_QUEUE_FROZEN_AT)
+        .await?
+        .and_then(|s| s.parse().ok());
+    let members = match db::get_kv(pool, KV_QUEUE_FREEZE_MEMBERS).await? {
+        Some(json) if frozen_at.is_some() => serde_json::from_str(&json)?,
+        _ => Vec::new(),
+    };
+    let pending_count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pending_queue_items")
+        .fetch_one(pool)
+        .await?;
+    Ok(FreezeStateDto {
+        frozen: frozen_at.is_some(),
+        frozen_at,
+        members,
+        pending_count,
+    })
+}
+
+/// Snapshots current members and diverts new entries to the pending list.
+/// Freezing an already frozen queue keeps the original snapshot.
+pub async fn freeze(pool: &SqlitePool) -> anyhow::Result<FreezeStateDto> {
+    let mut tx = pool.begin().await?;
+    if frozen_at_tx(&mut tx).await?.is_none() {
+        let members = sqlx::query_scalar::<_, String>(
+            "SELECT user_id FROM queue_items ORDER BY position ASC",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for (key, value) in [
+            (KV_QUEUE_FROZEN_AT, util::now_epoch().to_string()),
+            (KV_QUEUE_FREEZE_MEMBERS, serde_json::to_string(&members)?),
+        ] {
+            sqlx::query(
+                r#"INSERT INTO app_kv (key, value) VALUES (?1, ?2)
+                   ON CONFLICT(key) DO UPDATE SET value = excluded.value"#,
+            )
+            .bind(key)
+            .bind(value)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    freeze_state(pool).await
+}
+
+/// Ends the freeze and merges pending entries into the live queue in arrival
+/// order, placed by the usual fairness rules. Returns how many were merged.
+pub async fn thaw(pool: &SqlitePool, cfg: &QueueConfig) -> anyhow::Result<u64> {
+    let now = util::now_epoch();
+    let window_start = participation_window_start(now, cfg.participation_window_secs as i64);
+    let mut tx = pool.begin().await?;
+    let was_frozen = frozen_at_tx(&mut tx).await?.is_some();
+    let head_before = head_id_tx(&mut tx).await?;
+
+    let pending = sqlx::query_as::<_, NewItemFields>(
+        r#"SELECT user_id, user_login, display_name, display_name_raw, profile_image_url, enqueued_at,
+                  reward_id, redemption_id, priority, tags, user_input, redeemed_at_ms
+           FROM pending_queue_items
+           ORDER BY id ASC"#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut merged = 0;
+    for fields in &pending {
+        let mut current = queue_with_counts(&mut *tx, window_start).await?;
+        current.retain(|c| !c.is_away());
+        let counts = newcomer_counts_tx(&mut tx, &fields.user_id, window_start).await?;
+        let newcomer = Newcomer {
+            priority: fields.priority,
+            enqueued_at: fields.enqueued_at,
+            count: counts.c,
+            last_completed_at: counts.last_completed_at,
+            redeemed_at_ms: fields.redeemed_at_ms,
+        };
+        let placement = place(&current, &newcomer, cfg, now);
+        insert_item_tx(&mut tx, fields, placement.index as i64).await?;
+        let joined = CuePayload::user(&fields.display_name, &fields.profile_image_url);
+        cues::emit_tx(&mut tx, CueKind::UserJoined, &joined, now).await?;
+        merged += 1;
+    }
+    if was_frozen {
+        cues::emit_tx(&mut tx, CueKind::QueueOpened, &CuePayload::default(), now).await?;
+    }
+    if cfg.complete_on_advance {
+        complete_displaced_head_tx(&mut tx, head_before.as_deref(), now).await?;
+    }
+    cue_head_change_tx(&mut tx, head_before.as_deref(), now).await?;
+
+    sqlx::query("DELETE FROM pending_queue_items")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM app_kv WHERE key IN (?1, ?2)")
+        .bind(KV_QUEUE_FROZEN_AT)
+        .bind(KV_QUEUE_FREEZE_MEMBERS)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(merged)
+}
+
+#[tracing::instrument(skip(pool, timings), fields(total_ms = tracing::field::Empty, phases = tracing::field::Empty))]
+pub async fn delete_item(
+    pool: &SqlitePool,
+    timings: &Timings,
+    id: &str,
+    mode: DeleteMode,
+) -> anyhow::Result<()> {
+    let mut timer = timing::PhaseTimer::mutation(timings, "delete_item");
+    let now = util::now_epoch();
+    let mut tx = pool.begin().await?;
+
+    // Find item
+    let item = sqlx::query_as::<_, QueueItemRow>(
+        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position,
+                  reward_id, redemption_id, priority, tags
+           FROM queue_items
+           WHERE id = ?1"#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(item) = item else {
+        tx.rollback().await?;
+        anyhow::bail!("queue item not found");
+    };
+
+    timer.phase("snapshot");
+
+    let head_before = head_id_tx(&mut tx).await?;
+    remove_item_tx(&mut tx, item, mode, now).await?;
+    cue_head_change_tx(&mut tx, head_before.as_deref(), now).await?;
+    timer.phase("write");
+
+    tx.commit().await?;
+    timer.phase("commit");
+    Ok(())
+}
+
+/// First phase of a graceful complete: marks the item, leaving it in place.
+/// Marking an item that is already completing keeps the original time.
+pub async fn begin_complete(pool: &SqlitePool, id: &str) -> anyhow::Result<()> {
+    let found = sqlx::query_scalar::<_, i64>(
+        "UPDATE queue_items SET completing_at = COALESCE(completing_at, ?2) WHERE id = ?1 RETURNING 1",
+    )
+    .bind(id)
+    .bind(util::now_epoch())
+    .fetch_optional(pool)
+    .await?;
+    if found.is_none() {
+        anyhow::bail!("queue item not found");
+    }
+    Ok(())
+}
+
+/// Restores an item marked by [`begin_complete`]. False if it is not completing (any more).
+pub async fn abort_complete(pool: &SqlitePool, id: &str) -> anyhow::Result<bool> {
+    let res = sqlx::query(
+        "UPDATE queue_items SET completing_at = NULL WHERE id = ?1 AND completing_at IS NOT NULL",
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Second phase: removes items whose grace period has elapsed, as completed at the time
+/// complete was pressed. Returns how many were removed.
+pub async fn finalize_completions(pool: &SqlitePool, grace_secs: u64) -> anyhow::Result<usize> {
+    let now = util::now_epoch();
+    let mut tx = pool.begin().await?;
+    let due = sqlx::query_as::<_, (i64, String)>(
+        r#"SELECT completing_at, id FROM queue_items
+           WHERE completing_at IS NOT NULL AND completing_at <= ?1
+           ORDER BY position DESC"#,
+    )
+    .bind(now - grace_secs as i64)
+    .fetch_all(&mut *tx)
+    .await?;
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let head_before = head_id_tx(&mut tx).await?;
+    // Highest position first, so the positions of the rest stay valid.
+    for (completing_at, id) in &due {
+        let item = sqlx::query_as::<_, QueueItemRow>(
+            r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position,
+                      reward_id, redemption_id, priority, tags
+               FROM queue_items
+               WHERE id = ?1"#,
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        remove_item_tx(&mut tx, item, DeleteMode::Completed, *completing_at).await?;
+    }
+    cue_head_change_tx(&mut tx, head_before.as_deref(), now).await?;
+    tx.commit().await?;
+    Ok(due.len())
+}
+
+/// Removes `item`, closes the position gap, and records participation / outbox side effects.
+async fn remove_item_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    item: QueueItemRow,
+    mode: DeleteMode,
+    now: i64,
+) -> anyhow::Result<()> {
+    // Remove. No row means another complete/cancel got here first; its side effects stand.
+    let Some(turn_started_at) = sqlx::query_scalar::<_, Option<i64>>(
+        "DELETE FROM queue_items WHERE id = ?1 RETURNING turn_started_at",
+    )
+    .bind(&item.id)
+    .fetch_optional(&mut **tx)
+    .await?
+    else {
+        anyhow::bail!("queue item not found");
+    };
+
+    // Close gap
+    sqlx::query(
+        r#"UPDATE queue_items
+           SET position = position - 1
+           WHERE position > ?1"#,
+    )
+    .bind(item.position)
+    .execute(&mut **tx)
+    .await?;
+
+    // If completed, add a participation record (used for fairness)
+    if matches!(mode, DeleteMode::Completed) {
+        let session_id = db::current_session_id(&mut **tx).await?;
+        let participation = db::FullParticipation {
+            user_id: item.user_id.clone(),
+            completed_at: now,
+            queue_item_id: Some(item.id.clone()),
+            reward_id: item.reward_id.clone(),
+            source: db::ParticipationSource::Queue,
+            session_id,
+            turn_started_at,
+        };
+        if !db::insert_participation(&mut **tx, &participation).await? {
+            // Already completed once; the fulfillment was queued with that participation.
+            tracing::warn!(queue_item_id = %item.id, "queue item already has a participation; skipping duplicate complete");
+            return Ok(());
+        }
+    }
+
+    // Twitch-side redemption status is updated later by the outbox dispatcher
+    if let (Some(reward_id), Some(redemption_id)) = (item.reward_id, item.redemption_id) {
+        let status = match mode {
+            DeleteMode::Completed => outbox::RedemptionStatus::Fulfilled,
+            DeleteMode::Canceled => outbox::RedemptionStatus::Canceled,
+        };
+        let event = outbox::OutboxEvent::RedemptionStatus {
+            reward_id,
+            redemption_id,
+            status,
+        };
+        outbox::insert_tx(tx, &event, now).await?;
+    }
+
+    Ok(())
+}
+
+/// With `complete_on_advance`, whoever leaves position 0 is completed (see [`move_by`]).
+pub async fn move_up(
+    pool: &SqlitePool,
+    timings: &Timings,
+    id: &str,
+    complete_on_advance: bool,
+) -> anyhow::Result<()> {
+    move_by(pool, timings, id, -1, complete_on_advance).await
+}
+
+pub async fn move_down(
+    pool: &SqlitePool,
+    timings: &Timings,
+    id: &str,
+    complete_on_advance: bool,
+) -> anyhow::Result<()> {
+    move_by(pool, timings, id, 1, complete_on_advance).await
+}
+
+/// Swaps the item with its neighbour. If `complete_on_advance` is set and the swap
+/// involves position 0, the item that was at position 0 is removed as completed,
+/// matching a "just move the next person up" workflow.
+#[tracing::instrument(skip(pool, timings), fields(total_ms = tracing::field::Empty, phases = tracing::field::Empty))]
+async fn move_by(
+    pool: &SqlitePool,
+    timings: &Timings,
+    id: &str,
+    delta: i64,
+    complete_on_advance: bool,
+) -> anyhow::Result<()> {
+    let mut timer = timing::PhaseTimer::mutation(timings, "move_by");
+    let mut tx = pool.begin().await?;
+
+    let item = sqlx::query_as::<_, QueueItemRow>(
+        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position,
+                  reward_id, redemption_id, priority, tags
+           FROM queue_items
+           WHERE id = ?1"#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(item) = item else {
+        tx.rollback().await?;
+        anyhow::bail!("queue item not found");
+    };
+
+    let new_pos = item.position + delta;
+    if new_pos < 0 {
+        tx.rollback().await?;
+        return Ok(());
+    }
+
+    let swap = sqlx::query_as::<_, QueueItemRow>(
+        r#"SELECT id, user_id, user_login, display_name, profile_image_url, enqueued_at, position,
+                  reward_id, redemption_id, priority, tags
+           FROM queue_items
+           WHERE position = ?1
+           LIMIT 1"#,
+    )
+    .bind(new_pos)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(swap) = swap else {
+        tx.rollback().await?;
+        return Ok(());
+    };
+
+    // Away items stay parked behind the present ones; they move only by returning.
+    // Completing items keep their place until they are finalized or aborted.
+    let involves_parked = sqlx::query(
+        "SELECT 1 FROM queue_items WHERE id IN (?1, ?2) AND (away_since IS NOT NULL OR completing_at IS NOT NULL)",
+    )
+    .bind(&item.id)
+    .bind(&swap.id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+    if involves_parked {
+        tx.rollback().await?;
+        return Ok(());
+    }
+
+    timer.phase("snapshot");
+    let head_before = head_id_tx(&mut tx).await?;
+
+    // Swap positions. Moving up records the admin's intent; moving down withdraws it.
+    sqlx::query("UPDATE queue_items SET position = ?1, manually_raised = ?3 WHERE id = ?2")
+        .bind(new_pos)
+        .bind(&item.id)
+        .bind(delta < 0)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("UPDATE queue_items SET position = ?1 WHERE id = ?2")
+        .bind(item.position)
+        .bind(&swap.id)
+        .execute(&mut *tx)
+        .await?;
+
+    if complete_on_advance {
+        complete_displaced_head_tx(&mut tx, head_before.as_deref(), util::now_epoch()).await?;
+    }
+    cue_head_change_tx(&mut tx, head_before.as_deref(), util::now_epoch()).await?;
+    timer.phase("write");
+
+    tx.commit().await?;
+    timer.phase("commit");
+    Ok(())
+}
+
+/// Moves the item to position 0, shifting the items it passes down by one.
+pub async fn move_to_top(
+    pool: &SqlitePool,
+    timings: &Timings,
+    id: &str,
+    complete_on_advance: bool,
+) -> anyhow::Result<()> {
+    move_to_position(pool, timings, id, 0, complete_on_advance).await
+}
+
+/// Moves the item behind the last present item (away items stay parked after it).
+pub async fn move_to_bottom(
+    pool: &SqlitePool,
+    timings: &Timings,
+    id: &str,
+    complete_on_advance: bool,
+) -> anyhow::Result<()> {
+    move_to_position(pool, timings, id, i64::MAX, complete_on_advance).await
+}
+
+/// Moves the item to `target`, clamped to 0..=the last present position. Renumbers
+/// positions in one transaction; the span between the old and the new position shifts
+/// by one, so positions stay contiguous. Already there, away or completing: no-op. With
+/// `complete_on_advance`, whoever leaves position 0 is completed, as in [`move_by`].
+#[tracing::instrument(skip(pool, timings), fields(total_ms = tracing::field::Empty, phases = tracing::field::Empty))]
+pub async fn move_to_position(
+    pool: &SqlitePool,
+    timings: &Timings,
+    id: &str,
+    target: i64,
+    complete_on_advance: bool,
+) -> anyhow::Result<()> {
+    let mut timer = timing::PhaseTimer::mutation(timings, "move_to_position");
+    let mut tx = pool.begin().await?;
+
+    let item = sqlx::query_as::<_, (i64, bool, bool)>(
+        "SELECT position, away_since IS NOT NULL, completing_at IS NOT NULL FROM queue_items WHERE id = ?1",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((position, away, completing)) = item else {
+        tx.rollback().await?;
+        anyhow::bail!("queue item not found");
+    };
+    // Away items stay parked behind the present ones; they move only by returning.
+    // A completing item is on its way out; abort the complete first to move it.
+    if away || completing {
+        tx.rollback().await?;
+        return Ok(());
+    }
+
+    let last_present = sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MAX(position) FROM queue_items WHERE away_since IS NULL",
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .unwrap_or(position);
+    let target = target.clamp(0, last_present);
+    if target == position {
+        tx.rollback().await?;
+        return Ok(());
+    }
+
+    timer.phase("snapshot");
+    let head_before = head_id_tx(&mut tx).await?;
+
+    let up = target < position;
+    let shift = if up {
+        "UPDATE queue_items SET position = position + 1 WHERE position >= ?1 AND position < ?2"
+    } else {
+        "UPDATE queue_items SET position = position - 1 WHERE position > ?2 AND position <= ?1"
+    };
+    sqlx::query(shift)
+        .bind(target)
+        .bind(position)
+        .execute(&mut *tx)
+        .await?;
+    // Like move_up/move_down: moving up records the admin's intent, moving down withdraws it.
+    sqlx::query("UPDATE queue_items SET position = ?1, manually_raised = ?3 WHERE id = ?2")
+        .bind(target)
+        .bind(id)
+        .bind(up)
+        .execute(&mut *tx)
+        .await?;
+
+    if complete_on_advance {
+        complete_displaced_head_tx(&mut tx, head_before.as_deref(), util::now_epoch()).await?;
+    }
+    cue_head_change_tx(&mut tx, head_before.as_deref(), util::now_epoch()).await?;
+    timer.phase("write");
+
+    tx.commit().await?;
+    timer.phase("commit");
+    Ok(())
+}
+
+#[derive(Debug, FromRow)]
+struct QueueItemWithCountsRow {
+    #[sqlx(flatten)]
+    item: QueueItemRow,
+    manually_raised: bool,
+    recent_participation_count: i64,
+    last_completed_at: Option<i64>,
+    redeemed_at_ms: Option<i64>,
+    game_name: Option<String>,
+    game: Option<String>,
+    completing_at: Option<i64>,
+    away_since: Option<i64>,
+    away_return_position: Option<i64>,
+}
+
+impl QueueItemWithCountsRow {
+    fn is_away(&self) -> bool {
+        self.away_since.is_some()
+    }
+}
+
+/// The queue in position order, joined with windowed per-user aggregates in one query.
+/// Every path that needs "the queue with counts" reads it through here, so a new
+/// counted dimension is a one-place change. Only queued users' history is grouped, so
+/// the cost follows the queue length rather than the size of `participations`.
+async fn queue_with_counts<'e, E>(
+    executor: E,
+    window_start: i64,
+) -> anyhow::Result<Vec<QueueItemWithCountsRow>>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let rows = sqlx::query_as::<_, QueueItemWithCountsRow>(
+        r#"SELECT q.id, q.user_id, q.user_login, q.display_name, q.profile_image_url, q.enqueued_at, q.position,
+                  q.reward_id, q.redemption_id, q.priority, q.tags, q.manually_raised, q.game_name, q.game, q.completing_at,
+                  q.away_since, q.away_return_position, q.redeemed_at_ms,
+                  COALESCE(p.c, 0) AS recent_participation_count,
+                  p.last_completed_at
+           FROM queue_items q
+           LEFT JOIN (
+             SELECT user_id, SUM(completed_at >= ?1) AS c, MAX(completed_at) AS last_completed_at
+             FROM participations
+             WHERE user_id IN (SELECT user_id FROM queue_items)
+             GROUP BY user_id
+           ) p ON p.user_id = q.user_id
+           ORDER BY q.position ASC"#,
+    )
+    .bind(window_start)
+    .fetch_all(executor)
+    .await?;
+    Ok(rows)
+}
+
+/// A newcomer's windowed count and last completion, aggregated the same way as
+/// `queue_with_counts` so it is compared against queued users like for like.
+async fn newcomer_counts_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    user_id: &str,
+    window_start: i64,
+) -> anyhow::Result<NewcomerCountsRow> {
+    let row = sqlx::query_as::<_, NewcomerCountsRow>(
+        r#"SELECT COALESCE(SUM(completed_at >= ?2), 0) AS c, MAX(completed_at) AS last_completed_at
+           FROM participations
+           WHERE user_id = ?1"#,
+    )
+    .bind(user_id)
+    .bind(window_start)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(row)
+}
+
+#[derive(Debug, FromRow)]
+struct NewcomerCountsRow {
+    c: i64,
+    last_completed_at: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestApp};
+
+    /// 2024-03-10T07:00:00Z: US clocks jump from 02:00 EST to 03:00 EDT.
+    const US_SPRING_FORWARD: i64 = 1_710_054_000;
+    /// 2024-10-27T01:00:00Z: EU clocks fall back from 03:00 CEST to 02:00 CET.
+    const EU_FALL_BACK: i64 = 1_729_990_800;
+
+    #[tokio::test]
+    async fn overlay_heartbeat_is_lost_after_the_timeout_on_the_services_clock() {
+        let now = Arc::new(AtomicI64::new(1_000));
+        let clock = Arc::clone(&now);
+        let service = testing::queue_service("[queue]\noverlay_heartbeat_timeout_secs = 30\n")
+            .await
+            .with_clock(move || clock.load(Ordering::Relaxed));
+        assert!(!service.is_overlay_heartbeat_lost());
+
+        now.store(1_031, Ordering::Relaxed);
+        assert!(service.is_overlay_heartbeat_lost());
+        service.note_overlay_poll();
+        assert!(!service.is_overlay_heartbeat_lost());
+    }
+
+    #[test]
+    fn window_start_is_a_fixed_number_of_seconds_across_spring_forward() {
+        // One hour after the switch, a 24h window reaches back to 2024-03-09T08:00:00Z,
+        // which is 23 wall-clock hours in New York but exactly 86400 seconds.
+        let now = US_SPRING_FORWARD + 3600;
+        assert_eq!(participation_window_start(now, 86_400), 1_709_971_200);
+        assert_eq!(
+            participation_window_start(US_SPRING_FORWARD, 86_400),
+            1_709_967_600
+        );
+        assert_eq!(
+            participation_window_start(US_SPRING_FORWARD - 1, 3600),
+            US_SPRING_FORWARD - 3601
+        );
+    }
+
+    #[test]
+    fn window_start_is_a_fixed_number_of_seconds_across_fall_back() {
+        // The repeated 02:00-03:00 local hour does not stretch the window to 25 hours.
+        assert_eq!(
+            participation_window_start(EU_FALL_BACK, 86_400),
+            1_729_904_400
+        );
+        assert_eq!(
+            participation_window_start(EU_FALL_BACK + 1800, 7200),
+            EU_FALL_BACK - 5400
+        );
+    }
+
+    #[test]
+    fn window_start_edge_cases() {
+        assert_eq!(participation_window_start(EU_FALL_BACK, 0), EU_FALL_BACK);
+        assert_eq!(participation_window_start(EU_FALL_BACK, -5), EU_FALL_BACK);
+        assert_eq!(participation_window_start(i64::MIN + 10, 86_400), i64::MIN);
+    }
+
+    #[test]
+    fn session_fallback_boundary_ignores_dst() {
+        assert_eq!(
+            session_fallback_boundary(24, US_SPRING_FORWARD + 3600),
+            Some(1_709_971_200)
+        );
+        assert_eq!(
+            session_fallback_boundary(24, EU_FALL_BACK),
+            Some(1_729_904_400)
+        );
+        assert_eq!(session_fallback_boundary(0, EU_FALL_BACK), None);
+    }
+
+    #[test]
+    fn eligibility_follows_the_resolved_policy() {
+        let now = 10_000;
+        let strict = QueuePolicy {
+            cooldown_secs: 600,
+            max_participations_per_window: 2,
+            one_entry_per_window: true,
+            ..Default::default()
+        };
+        let lenient = QueuePolicy {
+            one_entry_per_window: false,
+            max_participations_per_window: 0,
+            ..strict.clone()
+        };
+        let per_reward = QueuePolicy {
+            rejoin_cooldown_secs: Some(3600),
+            ..strict.clone()
+        };
+        let played = |count, last: Option<i64>, last_reward: Option<i64>, entered| UserHistory {
+            recent_participation_count: count,
+            last_completed_at: last,
+            last_completed_from_reward_at: last_reward,
+            entered_in_window: entered,
+        };
+        let cases = [
+            (&strict, played(0, None, None, false), Ok(())),
+            (
+                &strict,
+                played(1, Some(now - 100), None, true),
+                Err(RejectReason::Cooldown {
+                    remaining_secs: 500,
+                }),
+            ),
+            (
+                &strict,
+                played(2, Some(now - 700), None, true),
+                Err(RejectReason::MaxParticipations { limit: 2, count: 2 }),
+            ),
+            (
+                &strict,
+                played(1, Some(now - 700), None, true),
+                Err(RejectReason::AlreadyEnteredInWindow),
+            ),
+            (&lenient, played(5, Some(now - 700), None, true), Ok(())),
+            (
+                &lenient,
+                played(5, Some(now - 599), None, true),
+                Err(RejectReason::Cooldown { remaining_secs: 1 }),
+            ),
+            // The per-reward cooldown replaces the global one and looks at that reward only.
+            (&per_reward, played(0, Some(now - 10), None, false), Ok(())),
+            (
+                &per_reward,
+                played(0, Some(now - 10), Some(now - 3000), false),
+                Err(RejectReason::Cooldown {
+                    remaining_secs: 600,
+                }),
+            ),
+        ];
+        for (i, (policy, history, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(
+                check_eligibility(policy, &history, now),
+                expected,
+                "case {i}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn enqueued_item_records_the_policy_that_applied() {
+        let app = TestApp::new(
+            "[twitch]\ntarget_reward_ids = [\"cheap\", \"vip\"]\n[twitch.reward_policies.vip]\npriority = 5\ntags = [\"vip\"]\n",
+        )
+        .await;
+        testing::enqueue(&app.queue, testing::new_user("first")).await;
+        let mut vip = testing::new_user("vip");
+        vip.reward_id = Some("vip".to_string());
+        let policy = app.settings.policy_for(vip.reward_id.as_deref());
+        let outcome = enqueue_user(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            vip,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap();
+        let EnqueueOutcome::Added(receipt) = outcome else {
+            panic!("not added: {outcome:?}")
+        };
+        assert_eq!(
+            (
+                receipt.position,
+                receipt.priority,
+                receipt.priority_placement
+            ),
+            (0, 5, true)
+        );
+
+        let items = list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        assert_eq!(items[0].reward_id.as_deref(), Some("vip"));
+        assert_eq!(items[0].priority, 5);
+        assert_eq!(items[0].tags, vec!["vip".to_string()]);
+        assert_eq!(
+            (items[1].reward_id.as_deref(), items[1].priority),
+            (None, 0)
+        );
+    }
+
+    async fn order(app: &TestApp) -> Vec<String> {
+        list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|i| i.user_id)
+            .collect()
+    }
+
+    async fn played(app: &TestApp) -> Vec<String> {
+        sqlx::query_scalar("SELECT user_id FROM participations ORDER BY id")
+            .fetch_all(app.queue.db.read())
+            .await
+            .unwrap()
+    }
+
+    const ADVANCE: &str = "[queue]\ncomplete_on_advance = true\n[twitch]\ntarget_reward_ids = [\"vip\"]\n[twitch.reward_policies.vip]\npriority = 5\n";
+
+    #[tokio::test]
+    async fn complete_on_advance_completes_the_head_displaced_by_a_move() {
+        let app = TestApp::new(ADVANCE).await;
+        testing::enqueue(&app.queue, testing::new_user("a")).await;
+        let b = testing::enqueue(&app.queue, testing::new_user("b")).await;
+        testing::enqueue(&app.queue, testing::new_user("c")).await;
+
+        move_up(app.queue.db.write(), &app.queue.timings, &b, true)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["b", "c"]);
+        assert_eq!(played(&app).await, ["a"]);
+
+        let d = testing::enqueue(&app.queue, testing::new_user("d")).await;
+        move_to_top(app.queue.db.write(), &app.queue.timings, &d, true)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["d", "c"]);
+        assert_eq!(played(&app).await, ["a", "b"]);
+
+        // Reordering behind the head leaves it alone.
+        let e = testing::enqueue(&app.queue, testing::new_user("e")).await;
+        move_to_position(app.queue.db.write(), &app.queue.timings, &e, 1, true)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["d", "e", "c"]);
+        assert_eq!(played(&app).await, ["a", "b"]);
+
+        move_to_bottom(app.queue.db.write(), &app.queue.timings, &d, true)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["e", "c"]);
+        assert_eq!(played(&app).await, ["a", "b", "d"]);
+    }
+
+    #[tokio::test]
+    async fn complete_on_advance_completes_the_head_when_a_new_entry_lands_first() {
+        let app = TestApp::new(ADVANCE).await;
+        testing::enqueue(&app.queue, testing::new_user("a")).await;
+        testing::enqueue(&app.queue, testing::new_user("b")).await;
+
+        let mut vip = testing::new_user("vip");
+        vip.reward_id = Some("vip".to_string());
+        let policy = app.settings.policy_for(Some("vip"));
+        let EnqueueOutcome::Added(receipt) = enqueue_user(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            vip,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap() else {
+            panic!("not added");
+        };
+        assert_eq!((receipt.position, receipt.queue_len), (0, 2));
+        assert_eq!(order(&app).await, ["vip", "b"]);
+        assert_eq!(played(&app).await, ["a"]);
+
+        // Joining behind the head leaves it alone.
+        testing::enqueue(&app.queue, testing::new_user("c")).await;
+        assert_eq!(played(&app).await, ["a"]);
+    }
+
+    #[tokio::test]
+    async fn without_complete_on_advance_a_new_head_displaces_nobody() {
+        let app = TestApp::new(
+            &ADVANCE.replace("complete_on_advance = true", "complete_on_advance = false"),
+        )
+        .await;
+        testing::enqueue(&app.queue, testing::new_user("a")).await;
+        let b = testing::enqueue(&app.queue, testing::new_user("b")).await;
+        move_to_top(app.queue.db.write(), &app.queue.timings, &b, false)
+            .await
+            .unwrap();
+        let mut vip = testing::new_user("vip");
+        vip.reward_id = Some("vip".to_string());
+        let policy = app.settings.policy_for(Some("vip"));
+        enqueue_user(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            vip,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(order(&app).await, ["vip", "b", "a"]);
+        assert!(played(&app).await.is_empty());
+    }
+
+    /// `(user_id, position)` of every item, away and completing ones included.
+    async fn positions(app: &TestApp) -> Vec<(String, i64)> {
+        sqlx::query_as("SELECT user_id, position FROM queue_items ORDER BY position")
+            .fetch_all(app.queue.db.read())
+            .await
+            .unwrap()
+    }
+
+    async fn assert_positions(app: &TestApp, expected: &[&str]) {
+        let expected: Vec<(String, i64)> = expected
+            .iter()
+            .zip(0..)
+            .map(|(user, pos)| (user.to_string(), pos))
+            .collect();
+        assert_eq!(positions(app).await, expected);
+    }
+
+    #[tokio::test]
+    async fn move_to_position_clamps_the_target_and_keeps_positions_contiguous() {
+        let app = TestApp::new("").await;
+        let a = testing::enqueue(&app.queue, testing::new_user("a")).await;
+        let b = testing::enqueue(&app.queue, testing::new_user("b")).await;
+        testing::enqueue(&app.queue, testing::new_user("c")).await;
+        let d = testing::enqueue(&app.queue, testing::new_user("d")).await;
+        let e = testing::enqueue(&app.queue, testing::new_user("e")).await;
+        begin_complete(app.queue.db.write(), &b).await.unwrap();
+        set_away(app.queue.db.write(), "e").await.unwrap();
+        assert_positions(&app, &["a", "b", "c", "d", "e"]).await;
+
+        let to = |id: &str, target: i64| {
+            let (pool, timings, id) = (app.queue.db.write(), &app.queue.timings, id.to_string());
+            async move {
+                move_to_position(pool, timings, &id, target, false)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // A negative target lands at the top.
+        to(&d, -5).await;
+        assert_positions(&app, &["d", "a", "b", "c", "e"]).await;
+
+        // Past the end lands behind the last present item; the away one stays parked.
+        to(&d, 100).await;
+        assert_positions(&app, &["a", "b", "c", "d", "e"]).await;
+
+        // Into the middle, past the completing item, which keeps its mark.
+        to(&a, 2).await;
+        assert_positions(&app, &["b", "c", "a", "d", "e"]).await;
+        let completing: Option<i64> =
+            sqlx::query_scalar("SELECT completing_at FROM queue_items WHERE id = ?1")
+                .bind(&b)
+                .fetch_one(app.queue.db.read())
+                .await
+                .unwrap();
+        assert!(completing.is_some());
+
+        // And back up past it again.
+        to(&d, 0).await;
+        assert_positions(&app, &["d", "b", "c", "a", "e"]).await;
+
+        // The away and the completing item do not move.
+        to(&e, 0).await;
+        to(&b, 3).await;
+        assert_positions(&app, &["d", "b", "c", "a", "e"]).await;
+    }
+
+    #[tokio::test]
+    async fn move_to_top_and_bottom_keep_positions_contiguous_and_are_no_ops_at_the_ends() {
+        let app = TestApp::new("").await;
+        let a = testing::enqueue(&app.queue, testing::new_user("a")).await;
+        testing::enqueue(&app.queue, testing::new_user("b")).await;
+        let c = testing::enqueue(&app.queue, testing::new_user("c")).await;
+        testing::enqueue(&app.queue, testing::new_user("d")).await;
+        set_away(app.queue.db.write(), "d").await.unwrap();
+        let (pool, timings) = (app.queue.db.write(), &app.queue.timings);
+
+        // Everything the moves could touch: order, raised flags and the cue log.
+        let snapshot = || async {
+            let rows: Vec<(String, i64, bool)> = sqlx::query_as(
+                "SELECT user_id, position, manually_raised FROM queue_items ORDER BY position",
+            )
+            .fetch_all(app.queue.db.read())
+            .await
+            .unwrap();
+            let cues: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM cues")
+                .fetch_one(app.queue.db.read())
+                .await
+                .unwrap();
+            (rows, cues)
+        };
+
+        move_to_top(pool, timings, &c, false).await.unwrap();
+        assert_positions(&app, &["c", "a", "b", "d"]).await;
+        let before = snapshot().await;
+        move_to_top(pool, timings, &c, false).await.unwrap();
+        assert_eq!(snapshot().await, before);
+
+        move_to_bottom(pool, timings, &a, false).await.unwrap();
+        assert_positions(&app, &["c", "b", "a", "d"]).await;
+        let before = snapshot().await;
+        move_to_bottom(pool, timings, &a, false).await.unwrap();
+        assert_eq!(snapshot().await, before);
+
+        // The head to the bottom and back.
+        move_to_bottom(pool, timings, &c, false).await.unwrap();
+        assert_positions(&app, &["b", "a", "c", "d"]).await;
+        move_to_top(pool, timings, &c, false).await.unwrap();
+        assert_positions(&app, &["c", "b", "a", "d"]).await;
+    }
+
+    async fn seed_participations(pool: &SqlitePool, rows: &[(&str, i64)]) {
+        let mut tx = pool.begin().await.unwrap();
+        for (user_id, completed_at) in rows {
+            sqlx::query("INSERT INTO participations (user_id, completed_at, source) VALUES (?1, ?2, 'import')")
+                .bind(user_id)
+                .bind(completed_at)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+        tx.commit().await.unwrap();
+    }
+
+    /// The per-row counting `queue_with_counts` replaced, kept as the reference.
+    async fn count_one_by_one(
+        pool: &SqlitePool,
+        user_id: &str,
+        window_start: i64,
+    ) -> (i64, Option<i64>) {
+        let c: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM participations WHERE user_id = ?1 AND completed_at >= ?2",
+        )
+        .bind(user_id)
+        .bind(window_start)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        let last: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(completed_at) FROM participations WHERE user_id = ?1")
+                .bind(user_id)
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        (c, last)
+    }
+
+    #[tokio::test]
+    async fn every_reader_sees_the_same_counts() {
+        let app = TestApp::new("[queue]\nparticipation_window_secs = 3600\n").await;
+        let pool = app.queue.db.write();
+        let now = util::now_epoch();
+        seed_participations(
+            pool,
+            &[
+                ("a", now - 10),
+                ("a", now - 3599),
+                ("a", now - 7200),
+                ("b", now - 7200),
+                ("gone", now - 5),
+            ],
+        )
+        .await;
+        for user in ["a", "b", "c"] {
+            testing::enqueue(&app.queue, testing::new_user(user)).await;
+        }
+        let window_start = participation_window_start(util::now_epoch(), 3600);
+
+        let read = app.queue.db.read();
+        let listed = list_queue(read, &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        let admin = list_queue_admin(read, &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        let rows = queue_with_counts(read, window_start).await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        for (i, item) in listed.iter().enumerate() {
+            let expected = count_one_by_one(read, &item.user_id, window_start).await;
+            let newcomer = newcomer_counts_tx(&mut tx, &item.user_id, window_start)
+                .await
+                .unwrap();
+            assert_eq!(
+                (item.recent_participation_count, item.last_completed_at),
+                expected,
+                "{}",
+                item.user_id
+            );
+            assert_eq!(
+                (
+                    admin[i].item.recent_participation_count,
+                    admin[i].item.last_completed_at
+                ),
+                expected
+            );
+            assert_eq!(
+                (
+                    rows[i].recent_participation_count,
+                    rows[i].last_completed_at
+                ),
+                expected
+            );
+            assert_eq!((newcomer.c, newcomer.last_completed_at), expected);
+        }
+        tx.rollback().await.unwrap();
+
+        let counts: Vec<(&str, i64)> = listed
+            .iter()
+            .map(|i| (i.user_id.as_str(), i.recent_participation_count))
+            .collect();
+        assert_eq!(counts, vec![("b", 0), ("c", 0), ("a", 2)]);
+
+        // The receipt of a later join reads the same aggregate.
+        seed_participations(pool, &[("d", now - 1), ("d", now - 2)]).await;
+        let d = testing::new_user("d");
+        let cfg = &app.settings.queue;
+        let EnqueueOutcome::Added(receipt) = enqueue_user(
+            pool,
+            &app.queue.timings,
+            cfg,
+            &cfg.default_policy(),
+            d,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap() else {
+            panic!("not added");
+        };
+        let listed_d = list_queue(pool, &app.queue.timings, &app.settings)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|i| i.user_id == "d")
+            .unwrap();
+        assert_eq!(receipt.recent_participation_count, 2);
+        assert_eq!(
+            (
+                receipt.recent_participation_count,
+                receipt.last_completed_at
+            ),
+            (
+                listed_d.recent_participation_count,
+                listed_d.last_completed_at
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_counts_match_the_per_row_reference_over_a_seeded_history() {
+        let app = TestApp::new("").await;
+        let pool = app.queue.db.write();
+        let window_start = 1_700_000_000;
+        // Per user: none, only old turns, only recent ones, both, and turns exactly on
+        // and just before the window start; plus history of users who are not queued.
+        let users: Vec<String> = (0..30).map(|i| format!("user{i}")).collect();
+        let mut history = Vec::new();
+        for (i, user) in users.iter().enumerate() {
+            for k in 0..i % 5 {
+                let offset = ((i * 7919 + k * 104_729) % 20_000) as i64 - 10_000;
+                history.push((user.as_str(), window_start + offset));
+            }
+            match i % 6 {
+                0 => history.push((user.as_str(), window_start)),
+                1 => history.push((user.as_str(), window_start - 1)),
+                2 => history.extend([(user.as_str(), window_start), (user.as_str(), window_start)]),
+                _ => {}
+            }
+        }
+        history.extend([
+            ("not_queued", window_start + 5),
+            ("not_queued", window_start - 5),
+        ]);
+        seed_participations(pool, &history).await;
+        for user in &users {
+            testing::enqueue(&app.queue, testing::new_user(user)).await;
+        }
+        for user in users.iter().step_by(7) {
+            set_away(pool, user).await.unwrap();
+        }
+
+        let read = app.queue.db.read();
+        let rows = queue_with_counts(read, window_start).await.unwrap();
+        assert_eq!(rows.len(), users.len());
+        let mut tx = pool.begin().await.unwrap();
+        for row in &rows {
+            let expected = count_one_by_one(read, &row.item.user_id, window_start).await;
+            assert_eq!(
+                (row.recent_participation_count, row.last_completed_at),
+                expected,
+                "{}",
+                row.item.user_id
+            );
+            let newcomer = newcomer_counts_tx(&mut tx, &row.item.user_id, window_start)
+                .await
+                .unwrap();
+            assert_eq!(
+                (newcomer.c, newcomer.last_completed_at),
+                expected,
+                "{}",
+                row.item.user_id
+            );
+        }
+        tx.rollback().await.unwrap();
+        // The seed is not degenerate: counts vary and some users have none.
+        let distinct: std::collections::BTreeSet<i64> =
+            rows.iter().map(|r| r.recent_participation_count).collect();
+        assert!(distinct.len() >= 3 && distinct.contains(&0), "{distinct:?}");
+    }
+
+    /// `cargo test -- --ignored queue_with_counts_benchmark --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn queue_with_counts_benchmark() {
+        let app = TestApp::new("").await;
+        let pool = app.queue.db.write();
+        let now = util::now_epoch();
+        let users: Vec<String> = (0..1000).map(|i| format!("user{i}")).collect();
+        let rows: Vec<(&str, i64)> = (0..50_000)
+            .map(|i| {
+                (
+                    users[i % users.len()].as_str(),
+                    now - (i as i64 * 37) % (30 * 86_400),
+                )
+            })
+            .collect();
+        seed_participations(pool, &rows).await;
+        for user in users.iter().take(200) {
+            testing::enqueue(&app.queue, testing::new_user(user)).await;
+        }
+        let window_start = participation_window_start(now, 86_400);
+
+        let runs = 20;
+        let started = std::time::Instant::now();
+        for _ in 0..runs {
+            queue_with_counts(pool, window_start).await.unwrap();
+        }
+        let joined = started.elapsed() / runs;
+
+        let ids = queued_user_ids(pool).await.unwrap();
+        let started = std::time::Instant::now();
+        for _ in 0..runs {
+            for id in &ids {
+                count_one_by_one(pool, id, window_start).await;
+            }
+        }
+        let per_row = started.elapsed() / runs;
+        println!(
+            "200 items / 50k participations: queue_with_counts {joined:?}, per-row {per_row:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn enqueueing_runs_the_same_statements_at_500_items_as_at_10() {
+        let app = TestApp::new("[queue]\nmax_participations_per_window = 0\n").await;
+        let now = util::now_epoch();
+        let history: Vec<(String, i64)> = (0..500)
+            .map(|i| (format!("user{i}"), now - 60 * (i % 7)))
+            .collect();
+        let history: Vec<(&str, i64)> = history.iter().map(|(u, t)| (u.as_str(), *t)).collect();
+        seed_participations(app.queue.db.write(), &history).await;
+
+        let pool = app.queue.db.write();
+        let mut per_enqueue = Vec::new();
+        for i in 0..500 {
+            let join = testing::enqueue(&app.queue, testing::new_user(&format!("user{i}")));
+            if i == 10 || i == 499 {
+                per_enqueue.push(testing::count_statements(pool, join).await.1);
+            } else {
+                join.await;
+            }
+        }
+        assert_eq!(
+            per_enqueue[0], per_enqueue[1],
+            "statements per enqueue grew with the queue: {per_enqueue:?}"
+        );
+        assert!(per_enqueue[1] < 30, "{per_enqueue:?}");
+    }
+
+    /// `cargo test -- --ignored phase_timings_benchmark --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn phase_timings_benchmark() {
+        let app = TestApp::new("[queue]\nmax_participations_per_window = 0\n").await;
+        let mut ids = Vec::new();
+        for i in 0..500 {
+            ids.push(testing::enqueue(&app.queue, testing::new_user(&format!("user{i}"))).await);
+        }
+        for _ in 0..20 {
+            list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+                .await
+                .unwrap();
+        }
+        for id in ids.iter().rev().step_by(10) {
+            move_to_top(app.queue.db.write(), &app.queue.timings, id, false)
+                .await
+                .unwrap();
+        }
+        for id in ids.iter().take(50) {
+            delete_item(
+                app.queue.db.write(),
+                &app.queue.timings,
+                id,
+                DeleteMode::Completed,
+            )
+            .await
+            .unwrap();
+        }
+
+        println!(
+            "{:<20} {:<10} {:>6} {:>10} {:>10}",
+            "op", "phase", "count", "sum ms", "mean ms"
+        );
+        for h in app.queue.timings.snapshot() {
+            let mean = h.sum_ms / h.count.max(1) as f64;
+            println!(
+                "{:<20} {:<10} {:>6} {:>10.1} {:>10.3}",
+                h.op, h.phase, h.count, h.sum_ms, mean
+            );
+        }
+    }
+
+    fn receipt(
+        position: i64,
+        queue_len: i64,
+        estimated_wait_secs: Option<i64>,
+        priority: i64,
+    ) -> EnqueueReceipt {
+        EnqueueReceipt {
+            id: "item".to_string(),
+            position,
+            queue_len,
+            estimated_wait_secs,
+            priority,
+            effective_priority: priority,
+            priority_placement: false,
+            ranked_position: position,
+            manual_order_applied: false,
+            tiebreak_applied: false,
+            ffa_applied: false,
+            recent_participation_count: 0,
+            last_completed_at: None,
+        }
+    }
+
+    #[test]
+    fn join_announcement_fills_every_placeholder() {
+        let template =
+            "{user}: #{position}, {ahead} ahead of {total}, ~{wait_min} min, priority {priority}";
+        assert_eq!(
+            receipt(3, 7, Some(3 * 150), 2).render(template, "Alice"),
+            "Alice: #4, 3 ahead of 7, ~8 min, priority 2"
+        );
+        assert_eq!(
+            receipt(0, 1, Some(0), 0).render(template, "Bob"),
+            "Bob: #1, 0 ahead of 1, ~0 min, priority 0"
+        );
+        assert_eq!(receipt(0, 1, None, 0).render("{wait_min}", "x"), "?");
+        // A display name that looks like a placeholder is not expanded again.
+        assert_eq!(
+            receipt(1, 2, None, 0).render("{user} {position}", "{position}"),
+            "{position} 2"
+        );
+        assert_eq!(
+            receipt(1, 2, None, 0).render("no placeholders", "x"),
+            "no placeholders"
+        );
+    }
+
+    async fn stored_names(app: &TestApp) -> (String, Option<String>, Option<String>) {
+        sqlx::query_as("SELECT display_name, display_name_raw, user_input FROM queue_items")
+            .fetch_one(app.queue.db.read())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn display_name_and_input_are_stored_sanitized_with_the_raw_name_kept() {
+        let raw = "\u{202E}evil\u{200B}\u{200B}name\u{0007}";
+        let mut user = testing::new_user("u1");
+        user.display_name = raw.to_string();
+        user.user_input = Some("\u{2066}Tetris\u{2069}\n".to_string());
+
+        let app = TestApp::new("").await;
+        testing::enqueue(&app.queue, user.clone()).await;
+        let (name, name_raw, input) = stored_names(&app).await;
+        assert_eq!(name.as_bytes(), b"evil\xE2\x80\x8Bname");
+        assert_eq!(name_raw.as_deref(), Some(raw));
+        assert_eq!(input.as_deref(), Some("Tetris"));
+        assert_eq!(
+            list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+                .await
+                .unwrap()[0]
+                .display_name,
+            name
+        );
+
+        let app = TestApp::new("[queue]\nsanitize_display_names = false\n").await;
+        testing::enqueue(&app.queue, user).await;
+        let (name, name_raw, _) = stored_names(&app).await;
+        assert_eq!(name, raw);
+        assert_eq!(name_raw.as_deref(), Some(raw));
+    }
+
+    #[tokio::test]
+    async fn name_with_nothing_printable_falls_back_to_the_login() {
+        let app = TestApp::new("").await;
+        let mut user = testing::new_user("u1");
+        user.user_login = "login1".to_string();
+        user.display_name = "\u{202E}\u{200B}".to_string();
+        testing::enqueue(&app.queue, user).await;
+        assert_eq!(stored_names(&app).await.0, "login1");
+    }
+
+    #[tokio::test]
+    async fn session_boundary_prefers_stream_online() {
+        let app = TestApp::new("").await;
+        let pool = app.queue.db.write();
+        let now = EU_FALL_BACK;
+        assert_eq!(
+            current_session_started_at(pool, 6, now).await.unwrap(),
+            Some(now - 6 * 3600)
+        );
+        assert_eq!(
+            current_session_started_at(pool, 0, now).await.unwrap(),
+            None
+        );
+
+        db::set_stream_online_at(pool, now - 60).await.unwrap();
+        assert_eq!(
+            current_session_started_at(pool, 6, now).await.unwrap(),
+            Some(now - 60)
+        );
+        assert_eq!(
+            current_session_started_at(pool, 0, now).await.unwrap(),
+            Some(now - 60)
+        );
+    }
+
+    async fn set_enqueued_at(pool: &SqlitePool, id: &str, at: i64) {
+        sqlx::query("UPDATE queue_items SET enqueued_at = ?2 WHERE id = ?1")
+            .bind(id)
+            .bind(at)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn items_before_the_stream_online_boundary_are_marked_and_cleared() {
+        let app = TestApp::new("[queue]\nprevious_session_fallback_hours = 0\n").await;
+        let pool = app.queue.db.write();
+        let now = util::now_epoch();
+        let mut old = testing::new_user("old");
+        old.reward_id = Some("r".to_string());
+        old.redemption_id = Some("x".to_string());
+        let old = testing::enqueue(&app.queue, old).await;
+        let new = testing::enqueue(&app.queue, testing::new_user("new")).await;
+        set_enqueued_at(pool, &old, now - 7200).await;
+        set_enqueued_at(pool, &new, now - 60).await;
+
+        // No boundary known yet: nothing is stale.
+        let items = list_queue(pool, &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        assert!(items.iter().all(|i| !i.from_previous_session));
+
+        db::set_stream_online_at(pool, now - 3600).await.unwrap();
+        let items = list_queue(pool, &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        let flags: Vec<(&str, bool)> = items
+            .iter()
+            .map(|i| (i.user_id.as_str(), i.from_previous_session))
+            .collect();
+        assert_eq!(flags, vec![("old", true), ("new", false)]);
+
+        assert_eq!(
+            clear_previous_session(pool, &app.queue.timings, now - 3600)
+                .await
+                .unwrap(),
+            1
+        );
+        let items = list_queue(pool, &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].user_id, "new");
+        assert_eq!(items[0].position, 0);
+        let canceled: Vec<String> = sqlx::query_scalar("SELECT payload FROM outbox")
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        assert_eq!(canceled.len(), 1);
+        assert!(canceled[0].contains("CANCELED"));
+        let played: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM participations")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(played, 0);
+    }
+
+    #[tokio::test]
+    async fn fallback_hours_mark_items_when_no_stream_online_was_seen() {
+        let app = TestApp::new("[queue]\nprevious_session_fallback_hours = 2\n").await;
+        let pool = app.queue.db.write();
+        let now = util::now_epoch();
+        let old = testing::enqueue(&app.queue, testing::new_user("old")).await;
+        let new = testing::enqueue(&app.queue, testing::new_user("new")).await;
+        set_enqueued_at(pool, &old, now - 3 * 3600).await;
+        set_enqueued_at(pool, &new, now - 3600).await;
+
+        let items = list_queue(pool, &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        let flags: Vec<bool> = items.iter().map(|i| i.from_previous_session).collect();
+        assert_eq!(flags, vec![true, false]);
+    }
+
+    /// `(priority, recent count)` ranking keys; completion and redemption times unset.
+    fn keys(items: &[(i64, i64)]) -> Vec<RankKey> {
+        items.iter().map(|&(p, c)| (p, c, None, None)).collect()
+    }
+
+    #[test]
+    fn manual_raise_then_normal_enqueue_stays_below_the_raised_item() {
+        // c (1 turn) was raised above a and b (1 turn each); a newcomer with 0 turns ranks first.
+        let current = keys(&[(0, 1), (0, 1), (0, 1)]);
+        let ranked = insertion_index(&current, 0, 0, None, None, QueueTiebreak::Insertion);
+        assert_eq!(ranked, 0);
+        assert_eq!(respect_manual_order(&current, Some(0), ranked, 0, 0, 2), 1);
+        // The gap to the raised item is large enough: fairness wins again.
+        let current = keys(&[(0, 3), (0, 3), (0, 3)]);
+        assert_eq!(respect_manual_order(&current, Some(0), 0, 0, 0, 2), 0);
+        assert_eq!(respect_manual_order(&current, Some(0), 0, 0, 2, 2), 1);
+        // Already below the raised item, or the rule disabled: unchanged.
+        assert_eq!(respect_manual_order(&current, Some(0), 2, 0, 0, 2), 2);
+        assert_eq!(
+            respect_manual_order(&keys(&[(0, 1), (0, 1)]), Some(0), 0, 0, 0, 0),
+            0
+        );
+        assert_eq!(
+            respect_manual_order(&keys(&[(0, 1), (0, 1)]), None, 0, 0, 0, 2),
+            0
+        );
+    }
+
+    #[test]
+    fn manual_raise_then_priority_enqueue_keeps_its_priority_placement() {
+        // Two raised items: the deepest one (index 1) is the barrier.
+        let current = keys(&[(0, 1), (0, 1), (0, 0)]);
+        let ranked = insertion_index(&current, 5, 1, None, None, QueueTiebreak::Insertion);
+        assert_eq!(ranked, 0);
+        assert_eq!(respect_manual_order(&current, Some(1), ranked, 5, 1, 2), 0);
+        // Equal priority to the raised item does not count as higher.
+        let current = keys(&[(5, 1), (5, 1)]);
+        assert_eq!(respect_manual_order(&current, Some(1), 0, 5, 0, 2), 2);
+    }
+
+    const MANUAL: &str =
+        "[twitch]\ntarget_reward_ids = [\"vip\"]\n[twitch.reward_policies.vip]\npriority = 5\n";
+
+    /// a, b and c each played once; c was then moved to the top by hand.
+    async fn queue_with_raised_c() -> TestApp {
+        let app = TestApp::new(MANUAL).await;
+        let t = util::now_epoch() - 3600;
+        seed_participations(app.queue.db.write(), &[("a", t), ("b", t), ("c", t)]).await;
+        testing::enqueue(&app.queue, testing::new_user("a")).await;
+        testing::enqueue(&app.queue, testing::new_user("b")).await;
+        let c = testing::enqueue(&app.queue, testing::new_user("c")).await;
+        move_to_top(app.queue.db.write(), &app.queue.timings, &c, false)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["c", "a", "b"]);
+        app
+    }
+
+    async fn explain(app: &TestApp, user_id: &str, reward_id: Option<&str>) -> EnqueueOutcome {
+        let mut user = testing::new_user(user_id);
+        user.reward_id = reward_id.map(str::to_string);
+        let policy = app.settings.policy_for(reward_id);
+        explain_enqueue(
+            app.queue.db.read(),
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            user,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn explain_states_that_the_manual_order_moved_a_normal_entry() {
+        let app = queue_with_raised_c().await;
+        let EnqueueOutcome::Added(r) = explain(&app, "d", None).await else {
+            panic!("not added")
+        };
+        assert_eq!((r.id.as_str(), r.ranked_position, r.position), ("", 0, 1));
+        assert!(r.manual_order_applied);
+        assert_eq!(
+            explain_reasons(&EnqueueOutcome::Added(r)),
+            [
+                "ranked #1 by priority 0 and 0 recent participation(s)",
+                "manual order: kept below the manually raised viewer, so #1 became #2",
+            ]
+        );
+        // Explaining stored nothing; the real enqueue lands where the explanation said.
+        assert_eq!(order(&app).await, ["c", "a", "b"]);
+        testing::enqueue(&app.queue, testing::new_user("d")).await;
+        assert_eq!(order(&app).await, ["c", "d", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn explain_shows_a_priority_entry_passing_the_raised_item() {
+        let app = queue_with_raised_c().await;
+        // Fairness alone would put v, who played as often as everyone, last.
+        seed_participations(app.queue.db.write(), &[("v", util::now_epoch() - 3600)]).await;
+        let EnqueueOutcome::Added(r) = explain(&app, "v", Some("vip")).await else {
+            panic!("not added")
+        };
+        assert_eq!((r.ranked_position, r.position), (0, 0));
+        assert!(!r.manual_order_applied);
+        assert!(r.priority_placement);
+        let reasons = explain_reasons(&EnqueueOutcome::Added(r));
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[1].starts_with("priority 5 put it ahead"));
+
+        let mut user = testing::new_user("v");
+        user.reward_id = Some("vip".to_string());
+        let policy = app.settings.policy_for(Some("vip"));
+        enqueue_user(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            user,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(order(&app).await, ["v", "c", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn explain_reports_why_an_entry_would_not_be_added() {
+        let app = queue_with_raised_c().await;
+        assert!(matches!(
+            explain(&app, "a", None).await,
+            EnqueueOutcome::AlreadyQueued
+        ));
+        assert_eq!(
+            explain_reasons(&EnqueueOutcome::AlreadyQueued),
+            ["already in the queue"]
+        );
+        assert_eq!(
+            explain_reasons(&EnqueueOutcome::QueueFull { max_queue_size: 3 }),
+            ["queue.max_queue_size: 3 viewers are already waiting"]
+        );
+    }
+
+    #[tokio::test]
+    async fn explain_leaves_the_ffa_budget_alone_and_matches_the_enqueue() {
+        let app = TestApp::new(ADVANCE).await;
+        testing::enqueue(&app.queue, testing::new_user("a")).await;
+        testing::enqueue(&app.queue, testing::new_user("b")).await;
+
+        // A priority entry taking the head completes it, so the queue does not grow.
+        let EnqueueOutcome::Added(explained) = explain(&app, "v", Some("vip")).await else {
+            panic!("not added")
+        };
+        assert_eq!((explained.position, explained.queue_len), (0, 2));
+        let mut vip = testing::new_user("v");
+        vip.reward_id = Some("vip".to_string());
+        let policy = app.settings.policy_for(Some("vip"));
+        let EnqueueOutcome::Added(mut added) = enqueue_user(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            vip,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap() else {
+            panic!("not added")
+        };
+        added.id.clear();
+        assert_eq!(
+            serde_json::to_value(&added).unwrap(),
+            serde_json::to_value(&explained).unwrap()
+        );
+        assert_eq!(order(&app).await, ["v", "b"]);
+
+        start_ffa(app.queue.db.write(), None, Some(1))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let EnqueueOutcome::Added(r) = explain(&app, "c", None).await else {
+                panic!("not added")
+            };
+            assert!(r.ffa_applied);
+        }
+        let EnqueueOutcome::Added(r) = app
+            .queue
+            .enqueue(testing::new_user("c"), &JoinNotices::default())
+            .await
+            .unwrap()
+        else {
+            panic!("not added")
+        };
+        assert!(r.ffa_applied, "explaining did not use up the one FFA entry");
+        let EnqueueOutcome::Added(r) = explain(&app, "d", None).await else {
+            panic!("not added")
+        };
+        assert!(!r.ffa_applied);
+    }
+
+    /// `(recent count, last completion)` keys at priority 0.
+    #[tokio::test]
+    async fn same_second_redemptions_end_up_in_redemption_order_whatever_order_they_arrive() {
+        let second = util::now_epoch() * 1000;
+        // Processing delays (ms) per redemption; each row is one burst.
+        let delays: [[u64; 5]; 4] = [
+            [40, 30, 20, 10, 0],
+            [0, 40, 10, 30, 20],
+            [20, 0, 40, 10, 30],
+            [30, 10, 0, 40, 20],
+        ];
+        for burst in delays {
+            let app = Arc::new(TestApp::new("").await);
+            let tasks: Vec<_> = burst
+                .iter()
+                .enumerate()
+                .map(|(i, &delay)| {
+                    let app = Arc::clone(&app);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                        let mut user = testing::new_user(&format!("u{i}"));
+                        user.redeemed_at_ms = Some(second + 100 * i as i64);
+                        testing::enqueue(&app.queue, user).await;
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            assert_eq!(
+                order(&app).await,
+                ["u0", "u1", "u2", "u3", "u4"],
+                "delays {burst:?}"
+            );
+        }
+    }
+
+    fn played_keys(items: &[(i64, Option<i64>)]) -> Vec<RankKey> {
+        items.iter().map(|&(c, last)| (0, c, last, None)).collect()
+    }
+
+    #[test]
+    fn oldest_last_completion_goes_first_among_equal_counts() {
+        let current = played_keys(&[
+            (1, Some(100)),
+            (1, Some(200)),
+            (1, Some(300)),
+            (2, Some(50)),
+        ]);
+        let at = |last, tiebreak| insertion_index(&current, 0, 1, last, None, tiebreak);
+        use QueueTiebreak::{Insertion, OldestLastCompletion};
+
+        assert_eq!(at(Some(250), OldestLastCompletion), 2);
+        assert_eq!(at(Some(50), OldestLastCompletion), 0);
+        // Equal completion times keep insertion order.
+        assert_eq!(at(Some(200), OldestLastCompletion), 2);
+        // Never played sorts ahead of everyone with the same count.
+        assert_eq!(at(None, OldestLastCompletion), 0);
+        // The tie-break never crosses a count boundary.
+        assert_eq!(at(Some(10_000), OldestLastCompletion), 3);
+        for last in [None, Some(50), Some(250)] {
+            assert_eq!(at(last, Insertion), 3);
+        }
+    }
+
+    #[test]
+    fn never_played_items_already_queued_stay_ahead() {
+        let current = played_keys(&[(0, None), (0, None)]);
+        assert_eq!(
+            insertion_index(
+                &current,
+                0,
+                0,
+                Some(100),
+                None,
+                QueueTiebreak::OldestLastCompletion
+            ),
+            2
+        );
+        assert_eq!(
+            insertion_index(
+                &current,
+                0,
+                0,
+                None,
+                None,
+                QueueTiebreak::OldestLastCompletion
+            ),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn tiebreak_orders_the_queue_and_is_explained() {
+        let app = TestApp::new(
+            "[queue]\ntiebreak = \"oldest_last_completion\"\nmax_participations_per_window = 0\n",
+        )
+        .await;
+        let now = util::now_epoch();
+        seed_participations(
+            app.queue.db.write(),
+            &[
+                ("recent", now - 60),
+                ("older", now - 3600),
+                ("newcomer", now - 1800),
+            ],
+        )
+        .await;
+        testing::enqueue(&app.queue, testing::new_user("older")).await;
+        testing::enqueue(&app.queue, testing::new_user("recent")).await;
+
+        let EnqueueOutcome::Added(r) = explain(&app, "newcomer", None).await else {
+            panic!("not added")
+        };
+        assert_eq!(r.position, 1);
+        assert!(r.tiebreak_applied);
+        assert!(explain_reasons(&EnqueueOutcome::Added(r))
+            .iter()
+            .any(|l| l.starts_with("queue.tiebreak")));
+
+        testing::enqueue(&app.queue, testing::new_user("newcomer")).await;
+        testing::enqueue(&app.queue, testing::new_user("never")).await;
+        let items = list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        let rows: Vec<(&str, i64, Option<i64>)> = items
+            .iter()
+            .map(|i| {
+                (
+                    i.user_id.as_str(),
+                    i.recent_participation_count,
+                    i.last_completed_at,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("never", 0, None),
+                ("older", 1, Some(now - 3600)),
+                ("newcomer", 1, Some(now - 1800)),
+                ("recent", 1, Some(now - 60)),
+            ]
+        );
+    }
+
+    async fn participations_of(pool: &SqlitePool) -> Vec<(String, i64)> {
+        sqlx::query_as("SELECT user_id, completed_at FROM participations ORDER BY completed_at")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn backdate_completing(pool: &SqlitePool, id: &str, at: i64) {
+        sqlx::query("UPDATE queue_items SET completing_at = ?2 WHERE id = ?1")
+            .bind(id)
+            .bind(at)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn aborted_complete_restores_the_item_without_side_effects() {
+        let app = TestApp::new("").await;
+        let a = testing::enqueue(&app.queue, testing::new_user("a")).await;
+        begin_complete(app.queue.db.write(), &a).await.unwrap();
+        assert!(
+            list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+                .await
+                .unwrap()[0]
+                .completing_at
+                .is_some()
+        );
+
+        assert!(abort_complete(app.queue.db.write(), &a).await.unwrap());
+        assert!(!abort_complete(app.queue.db.write(), &a).await.unwrap());
+
+        assert_eq!(
+            finalize_completions(app.queue.db.write(), 0).await.unwrap(),
+            0
+        );
+        let items = list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        assert_eq!((items.len(), items[0].completing_at), (1, None));
+        assert!(participations_of(app.queue.db.read()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn completes_are_finalized_once_the_grace_period_expires() {
+        let app = TestApp::new("").await;
+        let a = testing::enqueue(&app.queue, testing::new_user("a")).await;
+        testing::enqueue(&app.queue, testing::new_user("b")).await;
+        begin_complete(app.queue.db.write(), &a).await.unwrap();
+        let pressed_at = util::now_epoch() - 20;
+        backdate_completing(app.queue.db.write(), &a, pressed_at).await;
+
+        assert_eq!(
+            finalize_completions(app.queue.db.write(), 30)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(participations_of(app.queue.db.read()).await.is_empty());
+
+        assert_eq!(
+            finalize_completions(app.queue.db.write(), 10)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(order(&app).await, ["b"]);
+        // Recorded at the time complete was pressed, not when the ticker got to it.
+        assert_eq!(
+            participations_of(app.queue.db.read()).await,
+            [("a".to_string(), pressed_at)]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_complete_pending_across_a_restart_is_still_finalized() {
+        let app = TestApp::new("").await;
+        let a = testing::enqueue(&app.queue, testing::new_user("a")).await;
+        begin_complete(app.queue.db.write(), &a).await.unwrap();
+        let pressed_at = util::now_epoch() - 5;
+        backdate_completing(app.queue.db.write(), &a, pressed_at).await;
+
+        let TestApp { state, path } = app;
+        drop(state);
+        let db = db::Db::open(path.as_str(), 0, 1).await.unwrap();
+        let app = TestApp::with_db("", db, path).await;
+
+        let items = list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        assert_eq!(items[0].completing_at, Some(pressed_at));
+        // Pressing complete again after the restart keeps the original time.
+        begin_complete(app.queue.db.write(), &a).await.unwrap();
+        assert_eq!(
+            finalize_completions(app.queue.db.write(), 5).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            participations_of(app.queue.db.read()).await,
+            [("a".to_string(), pressed_at)]
+        );
+    }
+
+    #[tokio::test]
+    async fn completing_items_are_not_moved_or_completed_by_advancing() {
+        let app = TestApp::new("").await;
+        let a = testing::enqueue(&app.queue, testing::new_user("a")).await;
+        let b = testing::enqueue(&app.queue, testing::new_user("b")).await;
+        let c = testing::enqueue(&app.queue, testing::new_user("c")).await;
+        begin_complete(app.queue.db.write(), &c).await.unwrap();
+
+        move_to_top(app.queue.db.write(), &app.queue.timings, &c, true)
+            .await
+            .unwrap();
+        move_up(app.queue.db.write(), &app.queue.timings, &c, true)
+            .await
+            .unwrap();
+        move_down(app.queue.db.write(), &app.queue.timings, &b, true)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["a", "b", "c"]);
+
+        // The completing head is passed but left for finalize_completions (and abort).
+        begin_complete(app.queue.db.write(), &a).await.unwrap();
+        move_to_top(app.queue.db.write(), &app.queue.timings, &b, true)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["b", "a", "c"]);
+        assert!(participations_of(app.queue.db.read()).await.is_empty());
+        assert!(abort_complete(app.queue.db.write(), &a).await.unwrap());
+
+        // Without a complete in flight the displaced head is completed as before.
+        move_to_top(app.queue.db.write(), &app.queue.timings, &a, true)
+            .await
+            .unwrap();
+        assert_eq!(order(&app).await, ["a", "c"]);
+        assert_eq!(participations_of(app.queue.db.read()).await.len(), 1);
+    }
+
+    #[test]
+    fn effective_priority_ages_in_steps_up_to_the_cap() {
+        let mut cfg = QueueConfig::default();
+        assert_eq!(
+            effective_priority(&cfg, 1, 0, 1_000_000),
+            1,
+            "aging disabled"
+        );
+
+        cfg.aging_interval_secs = 600;
+        cfg.aging_increment = 2;
+        cfg.aging_priority_cap = 5;
+        assert_eq!(effective_priority(&cfg, 0, 1_000, 1_599), 0);
+        assert_eq!(effective_priority(&cfg, 0, 1_000, 1_600), 2);
+        assert_eq!(effective_priority(&cfg, 0, 1_000, 2_200), 4);
+        assert_eq!(effective_priority(&cfg, 0, 1_000, 100_000), 5);
+        // A base priority above the cap is kept, and clock skew never lowers it.
+        assert_eq!(effective_priority(&cfg, 7, 1_000, 100_000), 7);
+        assert_eq!(effective_priority(&cfg, 1, 2_000, 1_000), 1);
+    }
+
+    async fn aged_queue(config: &str) -> (TestApp, Vec<(String, i64, i64)>) {
+        let app = TestApp::new(config).await;
+        let old = testing::enqueue(&app.queue, testing::new_user("old")).await;
+        set_enqueued_at(app.queue.db.write(), &old, util::now_epoch() - 3 * 600).await;
+        testing::enqueue(&app.queue, testing::new_user("young")).await;
+
+        let mut vip = testing::new_user("vip");
+        vip.reward_id = Some("vip".to_string());
+        let policy = app.settings.policy_for(Some("vip"));
+        enqueue_user(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            vip,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap();
+
+        let items = list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        let rows = items
+            .into_iter()
+            .map(|i| (i.user_id, i.priority, i.effective_priority))
+            .collect();
+        (app, rows)
+    }
+
+    const VIP3: &str =
+        "[twitch]\ntarget_reward_ids = [\"vip\"]\n[twitch.reward_policies.vip]\npriority = 3\n";
+
+    #[tokio::test]
+    async fn an_aged_cheap_entry_is_no_longer_leapfrogged() {
+        let (_app, rows) = aged_queue(&format!("[queue]\naging_interval_secs = 600\n{VIP3}")).await;
+        let row = |user: &str, p: i64, e: i64| (user.to_string(), p, e);
+        // "old" has aged up to the highest reward priority; "young" has not aged yet.
+        assert_eq!(
+            rows,
+            [row("old", 0, 3), row("vip", 3, 3), row("young", 0, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn without_aging_placement_is_unchanged() {
+        let (_app, rows) = aged_queue(&format!("[queue]\naging_interval_secs = 0\n{VIP3}")).await;
+        let row = |user: &str, p: i64| (user.to_string(), p, p);
+        assert_eq!(rows, [row("vip", 3), row("old", 0), row("young", 0)]);
+    }
+
+    #[test]
+    fn return_position_is_clamped_to_the_present_line() {
+        assert_eq!(return_position_clamped(Some(2), 5), 2);
+        assert_eq!(return_position_clamped(Some(5), 5), 5);
+        assert_eq!(
+            return_position_clamped(Some(7), 3),
+            3,
+            "past the end: last of the present items"
+        );
+        assert_eq!(return_position_clamped(Some(3), 0), 0);
+        assert_eq!(return_position_clamped(Some(-1), 3), 0);
+        assert_eq!(return_position_clamped(None, 4), 4);
+    }
+
+    #[tokio::test]
+    async fn returning_past_the_end_of_a_shrunken_queue_lands_last() {
+        let app = TestApp::new("").await;
+        let pool = app.queue.db.write();
+        let mut ids = Vec::new();
+        for user in ["a", "b", "c", "d", "e"] {
+            ids.push(testing::enqueue(&app.queue, testing::new_user(user)).await);
+        }
+        assert_eq!(
+            set_away(pool, "d").await.unwrap(),
+            Some(AwayChange::Away { return_position: 3 })
+        );
+        assert_eq!(order(&app).await, ["a", "b", "c", "e", "d"]);
+        assert_eq!(
+            set_away(pool, "d").await.unwrap(),
+            Some(AwayChange::Unchanged)
+        );
+
+        for id in &ids[1..3] {
+            delete_item(pool, &app.queue.timings, id, DeleteMode::Canceled)
+                .await
+                .unwrap();
+        }
+        assert_eq!(order(&app).await, ["a", "e", "d"]);
+        assert_eq!(
+            set_back(pool, "d").await.unwrap(),
+            Some(AwayChange::Back { position: 2 })
+        );
+        assert_eq!(order(&app).await, ["a", "e", "d"]);
+        assert_eq!(
+            set_back(pool, "d").await.unwrap(),
+            Some(AwayChange::Unchanged)
+        );
+        assert_eq!(set_back(pool, "nobody").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn returning_keeps_the_remembered_index_after_a_priority_entry_joined() {
+        let app = TestApp::new(
+            "[twitch]\ntarget_reward_ids = [\"vip\"]\n[twitch.reward_policies.vip]\npriority = 5\n",
+        )
+        .await;
+        let pool = app.queue.db.write();
+        for user in ["a", "b", "c"] {
+            testing::enqueue(&app.queue, testing::new_user(user)).await;
+        }
+        set_away(pool, "b").await.unwrap();
+
+        let mut vip = testing::new_user("vip");
+        vip.reward_id = Some("vip".to_string());
+        let policy = app.settings.policy_for(vip.reward_id.as_deref());
+        enqueue_user(
+            pool,
+            &app.queue.timings,
+            &app.settings.queue,
+            &policy,
+            vip,
+            &JoinNotices::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            order(&app).await,
+            ["vip", "a", "c", "b"],
+            "ranked among the present items only"
+        );
+
+        // Coming back is not a new entry: the remembered index is used as is, with no
+        // priority placement against the items now around it.
+        assert_eq!(
+            set_back(pool, "b").await.unwrap(),
+            Some(AwayChange::Back { position: 1 })
+        );
+        assert_eq!(order(&app).await, ["vip", "b", "a", "c"]);
+    }
+
+    /// Two veterans with a recent turn, so fair placement puts newcomers ahead of them.
+    async fn ffa_app() -> TestApp {
+        let app = TestApp::new("").await;
+        let now = util::now_epoch();
+        seed_participations(
+            app.queue.db.write(),
+            &[("vet1", now - 60), ("vet2", now - 60)],
+        )
+        .await;
+        testing::enqueue(&app.queue, testing::new_user("vet1")).await;
+        testing::enqueue(&app.queue, testing::new_user("vet2")).await;
+        app
+    }
+
+    fn ffa_placed(items: &[QueueItemDto]) -> Vec<bool> {
+        items
+            .iter()
+            .map(|i| i.tags.iter().any(|t| t == FFA_TAG))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn ffa_budget_running_out_mid_burst_places_only_that_many_last() {
+        let app = ffa_app().await;
+        start_ffa(app.queue.db.write(), None, Some(2))
+            .await
+            .unwrap();
+
+        let burst = (1..=4).map(|i| {
+            let queue = app.queue.clone();
+            tokio::spawn(async move {
+                queue
+                    .enqueue(
+                        testing::new_user(&format!("new{i}")),
+                        &JoinNotices::default(),
+                    )
+                    .await
+                    .unwrap()
+            })
+        });
+        let applied = futures_util::future::join_all(burst)
+            .await
+            .into_iter()
+            .filter(|o| matches!(o, Ok(EnqueueOutcome::Added(r)) if r.ffa_applied))
+            .count();
+        assert_eq!(applied, 2);
+        assert!(ffa_state(app.queue.db.read()).await.unwrap().is_none());
+
+        // The two that got the budget went last; the rest were placed fairly, ahead of the veterans.
+        let items = list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        assert_eq!(ffa_placed(&items), [false, false, false, false, true, true]);
+        let ids: Vec<_> = items.iter().map(|i| i.user_id.as_str()).collect();
+        assert_eq!(ids[2..4], ["vet1", "vet2"]);
+        assert!(ids[..2]
+            .iter()
+            .chain(&ids[4..])
+            .all(|id| id.starts_with("new")));
+    }
+
+    #[tokio::test]
+    async fn an_ffa_window_survives_a_restart() {
+        let app = ffa_app().await;
+        let started = start_ffa(app.queue.db.write(), Some(600), Some(3))
+            .await
+            .unwrap();
+        testing::enqueue(&app.queue, testing::new_user("new1")).await;
+
+        let TestApp { state, path } = app;
+        drop(state);
+        let db = db::Db::open(path.as_str(), 0, 1).await.unwrap();
+        let app = TestApp::with_db("", db, path).await;
+
+        let state = ffa_state(app.queue.db.read())
+            .await
+            .unwrap()
+            .expect("still running");
+        assert_eq!(
+            (state.started_at, state.until, state.entries_left),
+            (started.started_at, started.until, Some(2))
+        );
+        testing::enqueue(&app.queue, testing::new_user("new2")).await;
+        assert_eq!(order(&app).await, ["vet1", "vet2", "new1", "new2"]);
+        assert_eq!(
+            ffa_state(app.queue.db.read())
+                .await
+                .unwrap()
+                .and_then(|s| s.entries_left),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn an_ffa_window_that_ran_out_during_a_restart_is_not_applied() {
+        let app = ffa_app().await;
+        let now = util::now_epoch();
+        let expired = FfaState {
+            started_at: now - 700,
+            until: Some(now - 100),
+            entries_left: Some(5),
+        };
+        db::set_kv(
+            app.queue.db.write(),
+            KV_QUEUE_FFA,
+            &serde_json::to_string(&expired).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let TestApp { state, path } = app;
+        drop(state);
+        let db = db::Db::open(path.as_str(), 0, 1).await.unwrap();
+        let app = TestApp::with_db("", db, path).await;
+
+        assert!(ffa_state(app.queue.db.read()).await.unwrap().is_none());
+        testing::enqueue(&app.queue, testing::new_user("new1")).await;
+        assert_eq!(order(&app).await, ["new1", "vet1", "vet2"]);
+        assert_eq!(
+            db::get_kv(app.queue.db.read(), KV_QUEUE_FFA).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn enqueue_goes_by_the_active_profiles_queue_rules() {
+        let app = TestApp::new("").await;
+        let capped = profiles::ProfileSettings {
+            queue: profiles::QueueOverrides {
+                max_queue_size: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        profiles::put(app.queue.db.write(), "capped", &capped, 0)
+            .await
+            .unwrap();
+        profiles::activate(
+            app.queue.db.write(),
+            &app.settings,
+            "capped",
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+
+        testing::enqueue(&app.queue, testing::new_user("a")).await;
+        let outcome = app
+            .queue
+            .enqueue(testing::new_user("b"), &JoinNotices::default())
+            .await
+            .unwrap();
+        assert!(
+            matches!(outcome, EnqueueOutcome::QueueFull { max_queue_size: 1 }),
+            "{outcome:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn max_queue_size_turns_away_even_a_first_timer_once_reached() {
+        let app = TestApp::new("[queue]\nmax_queue_size = 2\n").await;
+        let now = util::now_epoch();
+        seed_participations(
+            app.queue.db.write(),
+            &[("vet1", now - 60), ("vet2", now - 60)],
+        )
+        .await;
+        testing::enqueue(&app.queue, testing::new_user("vet1")).await;
+        testing::enqueue(&app.queue, testing::new_user("vet2")).await;
+
+        // Fairness would put the first-timer ahead of both veterans.
+        let outcome = app
+            .queue
+            .enqueue(testing::new_user("new1"), &JoinNotices::default())
+            .await
+            .unwrap();
+        assert!(
+            matches!(outcome, EnqueueOutcome::QueueFull { max_queue_size: 2 }),
+            "{outcome:?}"
+        );
+        assert_eq!(order(&app).await, ["vet1", "vet2"]);
+
+        // Left out, there is no cap.
+        let app = TestApp::new("").await;
+        assert_eq!(app.settings.queue.max_queue_size, None);
+        for i in 0..5 {
+            testing::enqueue(&app.queue, testing::new_user(&format!("u{i}"))).await;
+        }
+        assert!(Config::parse("[queue]\nmax_queue_size = 0\n").is_err());
+    }
 }
@@ -0,0 +1,187 @@
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+/// Fields holding a Twitch user id; the pseudonym is derived from these.
+const ID_FIELDS: &[&str] = &["user_id"];
+/// Fields that identify the user by name and get the same pseudonym as the id.
+const LOGIN_FIELDS: &[&str] = &["user_login", "login"];
+/// Dropped entirely: free text and in-game names from viewers, admin notes, and avatar URLs (unique per user).
+const STRIPPED_FIELDS: &[&str] = &[
+    "user_input",
+    "game_name",
+    "private_note",
+    "profile_image_url",
+];
+
+/// Replaces user ids and logins in serialized output with pseudonyms that are
+/// stable within one export (so rows still join) but not across exports: each
+/// `Redactor` draws a random HMAC key that is never stored.
+pub struct Redactor {
+    key: [u8; 32],
+    keep_display_names: bool,
+}
+
+impl Redactor {
+    pub fn new(keep_display_names: bool) -> Self {
+        let mut key = [0u8; 32];
+        key[..16].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+        key[16..].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+        Self {
+            key,
+            keep_display_names,
+        }
+    }
+
+    pub fn pseudonym(&self, user_id: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(user_id.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let hex: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+        format!("anon-{hex}")
+    }
+
+    /// Serializes `data` and redacts every object in it.
+    pub fn redact<T: serde::Serialize>(&self, data: &T) -> serde_json::Result<Value> {
+        let mut value = serde_json::to_value(data)?;
+        self.redact_value(&mut value);
+        Ok(value)
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact_value(v)),
+            Value::Object(map) => {
+                let pseudonym = ID_FIELDS
+                    .iter()
+                    .find_map(|f| map.get(*f).and_then(Value::as_str))
+                    .map(|id| self.pseudonym(id));
+                for field in STRIPPED_FIELDS {
+                    map.remove(*field);
+                }
+                for (key, v) in map.iter_mut() {
+                    let key = key.as_str();
+                    if ID_FIELDS.contains(&key) || LOGIN_FIELDS.contains(&key) {
+                        *v = match (&pseudonym, v.as_str()) {
+                            (Some(p), _) => Value::String(p.clone()),
+                            (None, Some(s)) => Value::String(self.pseudonym(s)),
+                            (None, None) => Value::Null,
+                        };
+                    } else if key == "display_name" && !self.keep_display_names {
+                        *v = pseudonym.clone().map_or(Value::Null, Value::String);
+                    } else {
+                        self.redact_value(v);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        queue,
+        testing::{self, TestApp},
+    };
+
+    /// A queue as `GET /api/queue` serializes it, with ids and logins that are easy to spot.
+    async fn queue_export() -> Value {
+        let app = TestApp::new("").await;
+        for (id, login, name) in [
+            ("11111111", "alice_login", "AliceName"),
+            ("22222222", "bob_login", "BobName"),
+        ] {
+            let mut user = testing::new_user(id);
+            user.user_login = login.to_string();
+            user.display_name = name.to_string();
+            user.profile_image_url = format!("https://static-cdn.jtvnw.net/{id}.png");
+            user.user_input = Some("secret game".to_string());
+            testing::enqueue(&app.queue, user).await;
+        }
+        serde_json::to_value(
+            queue::list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+                .await
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn redacted_queue_contains_no_raw_identity() {
+        let raw = queue_export().await;
+        assert!(raw.to_string().contains("alice_login"));
+
+        let text = Redactor::new(false).redact(&raw).unwrap().to_string();
+        for needle in [
+            "11111111",
+            "22222222",
+            "alice_login",
+            "bob_login",
+            "AliceName",
+            "BobName",
+            "secret game",
+            "jtvnw",
+        ] {
+            assert!(!text.contains(needle), "{needle} leaked: {text}");
+        }
+        assert!(!text.contains("\"user_input\"") && !text.contains("\"profile_image_url\""));
+    }
+
+    #[tokio::test]
+    async fn pseudonyms_join_within_one_export_but_not_across_exports() {
+        let raw = queue_export().await;
+        let first = Redactor::new(false);
+        let a = first.redact(&raw).unwrap();
+        let b = first.redact(&raw).unwrap();
+        assert_eq!(a, b);
+
+        let item = &a[0];
+        let p = item["user_id"].as_str().unwrap();
+        assert!(p.starts_with("anon-") && p.len() == "anon-".len() + 16);
+        assert_eq!(item["user_login"], item["user_id"]);
+        assert_eq!(item["display_name"], item["user_id"]);
+        assert_ne!(a[0]["user_id"], a[1]["user_id"]);
+
+        let other = Redactor::new(false).redact(&raw).unwrap();
+        assert_ne!(other[0]["user_id"], a[0]["user_id"]);
+        assert_ne!(other[1]["user_id"], a[1]["user_id"]);
+    }
+
+    #[tokio::test]
+    async fn display_names_can_be_kept() {
+        let redacted = Redactor::new(true).redact(&queue_export().await).unwrap();
+        assert_eq!(redacted[0]["display_name"], "AliceName");
+        assert_ne!(redacted[0]["user_login"], "alice_login");
+    }
+
+    #[test]
+    fn nested_rows_of_the_same_user_share_a_pseudonym() {
+        let r = Redactor::new(false);
+        let export = json!({
+            "participations": [
+                { "user_id": "42", "completed_at": 1 },
+                { "user_id": "42", "completed_at": 2 },
+            ],
+            "users": [{ "login": "viewer42", "user_id": "42", "game_name": "x" }],
+            "broadcaster": { "login": "streamer" },
+        });
+        let out = r.redact(&export).unwrap();
+        let p = r.pseudonym("42");
+        assert_eq!(out["participations"][0]["user_id"], p.as_str());
+        assert_eq!(out["participations"][1]["user_id"], p.as_str());
+        assert_eq!(out["users"][0]["login"], p.as_str());
+        assert!(out["users"][0].get("game_name").is_none());
+        // A login without an id next to it gets its own pseudonym.
+        assert_eq!(
+            out["broadcaster"]["login"],
+            r.pseudonym("streamer").as_str()
+        );
+        assert_eq!(out["participations"][1]["completed_at"], 2);
+    }
+}
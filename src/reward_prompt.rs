@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{Config, QueuePolicy},
+    db, profiles, twitch, util, AppState,
+};
+
+const KV_PREFIX: &str = "reward_prompt:";
+const KV_WARNING: &str = "reward_prompt_sync_warning";
+
+/// Twitch is not updated more often than this per reward, even if the rendered text changes.
+const MIN_PUSH_INTERVAL_SECS: i64 = 60;
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// What was last pushed to a reward.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptSyncState {
+    pub prompt: String,
+    pub pushed_at: i64,
+}
+
+/// Fills `{cooldown_minutes}`, `{max_per_window}`, `{window_hours}`,
+/// `{one_entry_per_window}`, `{queue_cap}` and `{open_hours}` in `template`.
+/// Unknown placeholders are left as is.
+pub fn render(template: &str, policy: &QueuePolicy, config: &Config) -> String {
+    let cooldown_minutes = policy
+        .rejoin_cooldown_secs
+        .unwrap_or(policy.cooldown_secs)
+        .div_ceil(60);
+    let queue_cap = match config.queue.max_queue_size {
+        None => "無制限".to_string(),
+        Some(n) => n.to_string(),
+    };
+    let open_hours = config.twitch.reward_prompt_open_hours.trim();
+    template
+        .replace("{cooldown_minutes}", &cooldown_minutes.to_string())
+        .replace(
+            "{max_per_window}",
+            &policy.max_participations_per_window.to_string(),
+        )
+        .replace(
+            "{window_hours}",
+            &(config.queue.participation_window_secs / 3600).to_string(),
+        )
+        .replace(
+            "{one_entry_per_window}",
+            if policy.one_entry_per_window {
+                "1回"
+            } else {
+                "制限なし"
+            },
+        )
+        .replace("{queue_cap}", &queue_cap)
+        .replace(
+            "{open_hours}",
+            if open_hours.is_empty() {
+                "随時"
+            } else {
+                open_hours
+            },
+        )
+}
+
+pub async fn get_state(
+    pool: &SqlitePool,
+    reward_id: &str,
+) -> anyhow::Result<Option<PromptSyncState>> {
+    match db::get_kv(pool, &format!("{KV_PREFIX}{reward_id}")).await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+async fn set_state(
+    pool: &SqlitePool,
+    reward_id: &str,
+    state: &PromptSyncState,
+) -> anyhow::Result<()> {
+    db::set_kv(
+        pool,
+        &format!("{KV_PREFIX}{reward_id}"),
+        &serde_json::to_string(state)?,
+    )
+    .await
+}
+
+/// Last sync failure (e.g. missing scope), shown on the status endpoint until a push succeeds.
+pub async fn get_warning(pool: &SqlitePool) -> anyhow::Result<Option<String>> {
+    db::get_kv(pool, KV_WARNING).await
+}
+
+/// Pushes the rendered prompt to every target reward whose text changed.
+/// Exits immediately when `twitch.reward_prompt_template` is empty.
+pub async fn run_sync_loop(state: Arc<AppState>) {
+    let template = state
+        .settings
+        .twitch
+        .reward_prompt_template
+        .trim()
+        .to_string();
+    if template.is_empty() {
+        return;
+    }
+
+    loop {
+        if let Err(e) = sync_once(&state, &template).await {
+            debug!(error = ?e, "reward prompt sync skipped");
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+async fn sync_once(state: &AppState, template: &str) -> anyhow::Result<()> {
+    let access_token = twitch::get_fresh_access_token(&state.twitch).await?;
+    let Some(broadcaster_id) = db::get_broadcaster_id(state.queue.db.read()).await? else {
+        anyhow::bail!("broadcaster_id is not known yet");
+    };
+
+    let config = profiles::effective_config(state.queue.db.read(), &state.settings).await?;
+    for reward_id in config.twitch.target_reward_ids.iter().map(|r| r.trim()) {
+        if reward_id.is_empty() {
+            continue;
+        }
+
+        let prompt = render(template, &config.policy_for(Some(reward_id)), &config);
+        let now = util::now_epoch();
+        if let Some(last) = get_state(state.queue.db.read(), reward_id).await? {
+            if last.prompt == prompt || now - last.pushed_at < MIN_PUSH_INTERVAL_SECS {
+                continue;
+            }
+        }
+
+        match twitch::helix_update_reward_prompt(
+            &state.twitch,
+            &access_token,
+            &broadcaster_id,
+            reward_id,
+            &prompt,
+        )
+        .await
+        {
+            Ok(()) => {
+                set_state(
+                    state.queue.db.write(),
+                    reward_id,
+                    &PromptSyncState {
+                        prompt,
+                        pushed_at: now,
+                    },
+                )
+                .await?;
+                db::delete_kv(state.queue.db.write(), KV_WARNING).await?;
+                info!(reward_id = %reward_id, "updated reward prompt");
+            }
+            Err(e) => {
+                warn!(error = ?e, reward_id = %reward_id, "failed to update reward prompt");
+                db::set_kv(
+                    state.queue.db.write(),
+                    KV_WARNING,
+                    &format!("{reward_id}: {e:#}"),
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str =
+        "{window_hours}h/{max_per_window} {one_entry_per_window} cd{cooldown_minutes} cap{queue_cap} {open_hours} {unknown}";
+
+    fn rendered(config_toml: &str, reward_id: Option<&str>) -> String {
+        let config = Config::parse(config_toml).unwrap();
+        render(TEMPLATE, &config.policy_for(reward_id), &config)
+    }
+
+    #[test]
+    fn defaults_render_unlimited_cap_and_any_time() {
+        let config = Config::parse("").unwrap();
+        let expected = format!(
+            "{}h/{} 制限なし cd{} cap無制限 随時 {{unknown}}",
+            config.queue.participation_window_secs / 3600,
+            config.queue.default_policy().max_participations_per_window,
+            config.queue.default_policy().cooldown_secs.div_ceil(60),
+        );
+        assert_eq!(rendered("", None), expected);
+    }
+
+    #[test]
+    fn every_placeholder_follows_its_setting() {
+        let toml = r#"
+            [twitch]
+            target_reward_ids = ["r1"]
+            reward_prompt_open_hours = " 20:00〜23:00 "
+            [queue]
+            participation_window_secs = 43200
+            max_participations_per_window = 2
+            one_entry_per_window = true
+            cooldown_secs = 90
+            max_queue_size = 15
+        "#;
+        assert_eq!(
+            rendered(toml, None),
+            "12h/2 1回 cd2 cap15 20:00〜23:00 {unknown}"
+        );
+    }
+
+    #[test]
+    fn reward_policy_overrides_reach_the_prompt() {
+        let toml = r#"
+            [twitch]
+            target_reward_ids = ["r1", "r2"]
+            [twitch.reward_policies.r2]
+            cooldown_secs = 600
+            rejoin_cooldown_secs = 1800
+            max_participations_per_window = 5
+            [queue]
+            participation_window_secs = 86400
+            max_participations_per_window = 1
+            cooldown_secs = 60
+        "#;
+        assert_eq!(
+            rendered(toml, Some("r1")),
+            "24h/1 制限なし cd1 cap無制限 随時 {unknown}"
+        );
+        assert_eq!(
+            rendered(toml, Some("r2")),
+            "24h/5 制限なし cd30 cap無制限 随時 {unknown}"
+        );
+    }
+}
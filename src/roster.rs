@@ -0,0 +1,246 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::util;
+
+/// Longer redemption inputs are not treated as a game name.
+pub const MAX_USER_INPUT_CHARS: usize = 50;
+
+/// Canonical in-game name for a viewer, matched by Twitch user id or login.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RosterEntry {
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub user_login: Option<String>,
+    pub game_name: String,
+    #[serde(default)]
+    pub game: Option<String>,
+}
+
+impl RosterEntry {
+    /// Trims fields, lowercases the login and drops empty optionals.
+    fn normalized(self) -> anyhow::Result<Self> {
+        let non_empty =
+            |s: Option<String>| s.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+        let entry = Self {
+            user_id: non_empty(self.user_id),
+            user_login: non_empty(self.user_login)
+                .map(|l| l.trim_start_matches('@').to_lowercase()),
+            game_name: util::sanitize_display_name(&self.game_name),
+            game: non_empty(self.game),
+        };
+        if entry.user_id.is_none() && entry.user_login.is_none() {
+            anyhow::bail!(
+                "roster entry for {:?} needs user_id or user_login",
+                entry.game_name
+            );
+        }
+        if entry.game_name.is_empty() {
+            anyhow::bail!("roster entry for {} has an empty game_name", entry.key());
+        }
+        Ok(entry)
+    }
+
+    fn key(&self) -> &str {
+        self.user_id
+            .as_deref()
+            .or(self.user_login.as_deref())
+            .unwrap_or_default()
+    }
+}
+
+/// Redemption text usable as a game name: sanitized like display names, non-empty and short.
+pub fn clean_user_input(raw: &str) -> Option<String> {
+    let s = util::sanitize_display_name(raw);
+    (!s.is_empty() && s.chars().count() <= MAX_USER_INPUT_CHARS).then_some(s)
+}
+
+/// Parses `user,game_name[,game]` lines. `user` is a numeric user id or a login.
+/// A leading `user,...` header line is skipped; fields may be double-quoted.
+pub fn parse_csv(text: &str) -> anyhow::Result<Vec<RosterEntry>> {
+    let mut out = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line_no = idx + 1;
+        if util::is_blank(line) {
+            continue;
+        }
+        let fields = split_csv_line(line)
+            .ok_or_else(|| anyhow::anyhow!("line {line_no}: unterminated quote"))?;
+        if idx == 0
+            && matches!(
+                fields[0].trim(),
+                "user" | "user_id" | "user_login" | "login"
+            )
+        {
+            continue;
+        }
+        if fields.len() < 2 || fields.len() > 3 {
+            anyhow::bail!("line {line_no}: expected user,game_name[,game]");
+        }
+        let user = fields[0].trim();
+        let is_id = !user.is_empty() && user.bytes().all(|b| b.is_ascii_digit());
+        let entry = RosterEntry {
+            user_id: is_id.then(|| user.to_string()),
+            user_login: (!is_id).then(|| user.to_string()),
+            game_name: fields[1].clone(),
+            game: fields.get(2).cloned(),
+        };
+        out.push(
+            entry
+                .normalized()
+                .map_err(|e| anyhow::anyhow!("line {line_no}: {e}"))?,
+        );
+    }
+    Ok(out)
+}
+
+fn split_csv_line(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut cur = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                cur.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut cur)),
+            _ => cur.push(c),
+        }
+    }
+    if in_quotes {
+        return None;
+    }
+    fields.push(cur);
+    Some(fields)
+}
+
+pub async fn list(pool: &SqlitePool) -> anyhow::Result<Vec<RosterEntry>> {
+    let rows = sqlx::query_as::<_, RosterEntry>(
+        "SELECT user_id, user_login, game_name, game FROM roster ORDER BY game_name COLLATE NOCASE ASC",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+#[derive(Debug, Serialize)]
+pub struct RosterUpdateDto {
+    pub entries: usize,
+    /// Queued items whose game name was re-resolved.
+    pub refreshed_items: u64,
+}
+
+/// Normalizes entries for [`save`]; fails on the first entry without a user or game name.
+pub fn validate(entries: Vec<RosterEntry>) -> anyhow::Result<Vec<RosterEntry>> {
+    entries.into_iter().map(RosterEntry::normalized).collect()
+}
+
+/// Stores validated `entries` (replacing the whole roster when `replace`) and re-resolves queued items.
+pub async fn save(
+    pool: &SqlitePool,
+    entries: Vec<RosterEntry>,
+    replace: bool,
+) -> anyhow::Result<RosterUpdateDto> {
+    let now = util::now_epoch();
+
+    let mut tx = pool.begin().await?;
+    if replace {
+        sqlx::query("DELETE FROM roster").execute(&mut *tx).await?;
+    }
+    for e in &entries {
+        // The same viewer may already be listed under the other key; keep one row per viewer.
+        sqlx::query("DELETE FROM roster WHERE user_id = ?1 OR user_login = ?2")
+            .bind(&e.user_id)
+            .bind(&e.user_login)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO roster (user_id, user_login, game_name, game, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(&e.user_id)
+        .bind(&e.user_login)
+        .bind(&e.game_name)
+        .bind(&e.game)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    let refreshed_items = resolve_items_tx(&mut tx, None).await?;
+    tx.commit().await?;
+    Ok(RosterUpdateDto {
+        entries: entries.len(),
+        refreshed_items,
+    })
+}
+
+/// Sets `game_name` / `game` on queued items (one item, or all when `item_id` is `None`):
+/// the roster entry for the user id wins over one for the login, then the item's own input.
+pub async fn resolve_items_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    item_id: Option<&str>,
+) -> anyhow::Result<u64> {
+    let res = sqlx::query(
+        r#"UPDATE queue_items
+           SET game_name = COALESCE(
+                 (SELECT game_name FROM roster WHERE user_id = queue_items.user_id),
+                 (SELECT game_name FROM roster WHERE user_login = lower(queue_items.user_login)),
+                 user_input
+               ),
+               game = CASE
+                 WHEN EXISTS (SELECT 1 FROM roster WHERE user_id = queue_items.user_id)
+                   THEN (SELECT game FROM roster WHERE user_id = queue_items.user_id)
+                 ELSE (SELECT game FROM roster WHERE user_login = lower(queue_items.user_login))
+               END
+           WHERE ?1 IS NULL OR id = ?1"#,
+    )
+    .bind(item_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_input_is_sanitized_byte_exact() {
+        assert_eq!(
+            clean_user_input("\u{202E}Mario Kart\u{202C}")
+                .unwrap()
+                .as_bytes(),
+            b"Mario Kart"
+        );
+        assert_eq!(
+            clean_user_input(" Splatoon\u{200B}\u{200B}3\n")
+                .unwrap()
+                .as_bytes(),
+            b"Splatoon\xE2\x80\x8B3"
+        );
+        assert_eq!(
+            clean_user_input("Pok\u{0065}\u{0301}mon")
+                .unwrap()
+                .as_bytes(),
+            b"Pok\xC3\xA9mon"
+        );
+        assert_eq!(clean_user_input("\u{2066}\u{2069}\u{0000}"), None);
+        assert_eq!(clean_user_input("   "), None);
+    }
+
+    #[test]
+    fn user_input_length_is_counted_after_sanitizing() {
+        let padded = format!("\u{202E}{}\u{202C}", "a".repeat(MAX_USER_INPUT_CHARS));
+        assert_eq!(
+            clean_user_input(&padded).unwrap(),
+            "a".repeat(MAX_USER_INPUT_CHARS)
+        );
+        assert_eq!(
+            clean_user_input(&"a".repeat(MAX_USER_INPUT_CHARS + 1)),
+            None
+        );
+    }
+}
@@ -0,0 +1,601 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{db, twitch, util};
+
+/// Distinct users who completed a turn in session `session_id`. Rows without a
+/// recorded session (legacy rows, or no stream.online seen), or every row when
+/// `session_id` is `None`, count when completed at or after `since`.
+pub async fn unique_participants(
+    pool: &SqlitePool,
+    session_id: Option<&str>,
+    since: i64,
+) -> anyhow::Result<i64> {
+    let n = sqlx::query_scalar::<_, i64>(
+        r#"SELECT COUNT(DISTINCT user_id)
+           FROM participations
+           WHERE session_id = ?1
+              OR ((?1 IS NULL OR session_id IS NULL) AND completed_at >= ?2)"#,
+    )
+    .bind(session_id)
+    .bind(since)
+    .fetch_one(pool)
+    .await?;
+    Ok(n)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UniqueParticipantsDto {
+    /// Session start used as the lower bound; `None` counts all history.
+    pub since: Option<i64>,
+    pub count: i64,
+    /// `queue.unique_participants_goal`; `None` when unset.
+    pub goal: Option<u64>,
+}
+
+/// Twitch launched in June 2011; anything earlier is not a real participation.
+const IMPORT_MIN_COMPLETED_AT: i64 = 1_306_886_400;
+/// Tolerated clock skew for imported timestamps in the future.
+const IMPORT_MAX_FUTURE_SECS: i64 = 5 * 60;
+
+/// One completed turn from another queue tool (`POST /api/stats/import`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParticipationRecord {
+    pub user_id: String,
+    pub completed_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportRecordError {
+    BlankUserId { index: usize },
+    CompletedAtOutOfRange { index: usize, completed_at: i64 },
+}
+
+impl std::fmt::Display for ImportRecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BlankUserId { index } => write!(f, "records[{index}].user_id is empty"),
+            Self::CompletedAtOutOfRange { index, completed_at } => write!(
+                f,
+                "records[{index}].completed_at {completed_at} is not between {IMPORT_MIN_COMPLETED_AT} and now"
+            ),
+        }
+    }
+}
+
+/// False for timestamps before Twitch existed or in the future (epoch milliseconds end up here).
+pub fn is_plausible_completed_at(completed_at: i64, now: i64) -> bool {
+    (IMPORT_MIN_COMPLETED_AT..=now + IMPORT_MAX_FUTURE_SECS).contains(&completed_at)
+}
+
+/// Rejects blank ids and implausible timestamps (see [`is_plausible_completed_at`]).
+pub fn validate_participation_records(
+    records: &[ParticipationRecord],
+    now: i64,
+) -> Result<(), ImportRecordError> {
+    for (index, r) in records.iter().enumerate() {
+        if crate::util::is_blank(&r.user_id) {
+            return Err(ImportRecordError::BlankUserId { index });
+        }
+        if !is_plausible_completed_at(r.completed_at, now) {
+            return Err(ImportRecordError::CompletedAtOutOfRange {
+                index,
+                completed_at: r.completed_at,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ParticipationImportDto {
+    pub inserted: u64,
+    /// Same user_id and completed_at as an existing row or an earlier record.
+    pub duplicates: u64,
+}
+
+/// Inserts validated records in one transaction, skipping exact duplicates so
+/// re-running an import is harmless.
+pub async fn import_participations(
+    pool: &SqlitePool,
+    records: &[ParticipationRecord],
+) -> anyhow::Result<ParticipationImportDto> {
+    let mut tx = pool.begin().await?;
+    let result = import_participations_tx(&mut tx, records).await?;
+    tx.commit().await?;
+    Ok(result)
+}
+
+/// [`import_participations`] inside a caller's transaction.
+pub async fn import_participations_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    records: &[ParticipationRecord],
+) -> anyhow::Result<ParticipationImportDto> {
+    let mut inserted = 0;
+    for r in records {
+        let user_id = r.user_id.trim();
+        let exists = sqlx::query(
+            "SELECT 1 FROM participations WHERE user_id = ?1 AND completed_at = ?2 LIMIT 1",
+        )
+        .bind(user_id)
+        .bind(r.completed_at)
+        .fetch_optional(&mut **tx)
+        .await?
+        .is_some();
+        if exists {
+            continue;
+        }
+        let participation = db::FullParticipation {
+            user_id: user_id.to_string(),
+            completed_at: r.completed_at,
+            queue_item_id: None,
+            reward_id: None,
+            source: db::ParticipationSource::Import,
+            session_id: None,
+            turn_started_at: None,
+        };
+        db::insert_participation(&mut **tx, &participation).await?;
+        inserted += 1;
+    }
+    Ok(ParticipationImportDto {
+        inserted,
+        duplicates: records.len() as u64 - inserted,
+    })
+}
+
+/// Records `cost` for `reward_id` unless it equals the last recorded cost.
+/// Returns true if a row was written.
+pub async fn record_reward_cost(
+    pool: &SqlitePool,
+    reward_id: &str,
+    cost: i64,
+    observed_at: i64,
+) -> anyhow::Result<bool> {
+    let result = sqlx::query(
+        r#"INSERT INTO reward_cost_history (reward_id, cost, observed_at)
+           SELECT ?1, ?2, ?3
+           WHERE COALESCE(
+             (SELECT cost
+              FROM reward_cost_history
+              WHERE reward_id = ?1
+              ORDER BY observed_at DESC, id DESC
+              LIMIT 1),
+             -1
+           ) != ?2"#,
+    )
+    .bind(reward_id)
+    .bind(cost)
+    .bind(observed_at)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct CostPoint {
+    pub cost: i64,
+    pub observed_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct EnqueueBucket {
+    pub bucket_start: i64,
+    pub enqueues: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RewardPricingDto {
+    pub reward_id: String,
+    pub from: i64,
+    pub to: i64,
+    pub interval_secs: i64,
+    /// Cost in effect at `from` (if known), followed by every change up to `to`.
+    pub costs: Vec<CostPoint>,
+    /// Enqueues per interval; empty intervals are omitted.
+    pub buckets: Vec<EnqueueBucket>,
+    /// EventSub outages in the range, so a chart can shade spans where no redemptions could arrive.
+    pub downtime: Vec<twitch::DowntimeInterval>,
+}
+
+pub async fn reward_pricing(
+    pool: &SqlitePool,
+    reward_id: &str,
+    from: i64,
+    to: i64,
+    interval_secs: i64,
+) -> anyhow::Result<RewardPricingDto> {
+    let interval_secs = interval_secs.max(1);
+
+    let costs = sqlx::query_as::<_, CostPoint>(
+        r#"SELECT cost, observed_at FROM (
+             SELECT cost, observed_at
+             FROM reward_cost_history
+             WHERE reward_id = ?1 AND observed_at < ?2
+             ORDER BY observed_at DESC, id DESC
+             LIMIT 1
+           )
+           UNION ALL
+           SELECT cost, observed_at FROM (
+             SELECT cost, observed_at
+             FROM reward_cost_history
+             WHERE reward_id = ?1 AND observed_at >= ?2 AND observed_at < ?3
+             ORDER BY observed_at ASC, id ASC
+           )"#,
+    )
+    .bind(reward_id)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    let buckets = sqlx::query_as::<_, EnqueueBucket>(
+        r#"SELECT ?2 + ((entered_at - ?2) / ?4) * ?4 AS bucket_start,
+                  COUNT(*) AS enqueues
+           FROM queue_entries
+           WHERE reward_id = ?1 AND entered_at >= ?2 AND entered_at < ?3
+           GROUP BY bucket_start
+           ORDER BY bucket_start ASC"#,
+    )
+    .bind(reward_id)
+    .bind(from)
+    .bind(to)
+    .bind(interval_secs)
+    .fetch_all(pool)
+    .await?;
+
+    let downtime = twitch::eventsub_timeline(pool, from, to, util::now_epoch())
+        .await?
+        .downtime;
+
+    Ok(RewardPricingDto {
+        reward_id: reward_id.to_string(),
+        from,
+        to,
+        interval_secs,
+        costs,
+        buckets,
+        downtime,
+    })
+}
+
+/// Below this many completions in the window the average wait is withheld, since it
+/// would come close to describing one viewer's turn.
+const PUBLIC_MIN_SAMPLE: i64 = 5;
+/// `GET /api/stats/public` is recomputed at most this often.
+const PUBLIC_STATS_TTL_SECS: i64 = 60;
+const WEEK_SECS: i64 = 7 * 24 * 60 * 60;
+const MONTH_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Aggregate numbers safe to show anyone (`server.public_stats`); nothing here
+/// identifies a viewer. Weeks and months are rolling 7 / 30 day windows.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicStatsDto {
+    pub completions_week: i64,
+    pub completions_month: i64,
+    pub completions_all_time: i64,
+    pub unique_participants_month: i64,
+    /// Mean seconds from joining to completion over the month; `None` with too few turns.
+    pub average_wait_secs_month: Option<i64>,
+    pub computed_at: i64,
+}
+
+/// The last [`PublicStatsDto`] computed, reused for [`PUBLIC_STATS_TTL_SECS`].
+#[derive(Debug, Default)]
+pub struct PublicStatsCache {
+    last: std::sync::Mutex<Option<PublicStatsDto>>,
+}
+
+async fn completions_since(pool: &SqlitePool, since: i64) -> anyhow::Result<i64> {
+    let n = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM participations WHERE completed_at >= ?1",
+    )
+    .bind(since)
+    .fetch_one(pool)
+    .await?;
+    Ok(n)
+}
+
+/// Mean wait of turns completed from the queue since `since`, measured from the user's
+/// latest entry before completion; `None` below [`PUBLIC_MIN_SAMPLE`] turns.
+async fn average_wait_since(pool: &SqlitePool, since: i64) -> anyhow::Result<Option<i64>> {
+    let (samples, average) = sqlx::query_as::<_, (i64, Option<f64>)>(
+        r#"SELECT COUNT(wait), AVG(wait) FROM (
+             SELECT p.completed_at - (
+                      SELECT MAX(e.entered_at) FROM queue_entries e
+                      WHERE e.user_id = p.user_id AND e.entered_at <= p.completed_at
+                    ) AS wait
+             FROM participations p
+             WHERE p.source = 'queue' AND p.completed_at >= ?1
+           )"#,
+    )
+    .bind(since)
+    .fetch_one(pool)
+    .await?;
+    Ok(average
+        .filter(|_| samples >= PUBLIC_MIN_SAMPLE)
+        .map(|a| a.round() as i64))
+}
+
+/// [`PublicStatsDto`], served from a cache younger than [`PUBLIC_STATS_TTL_SECS`].
+pub async fn public_stats(
+    pool: &SqlitePool,
+    cache: &PublicStatsCache,
+    now: i64,
+) -> anyhow::Result<PublicStatsDto> {
+    if let Some(cached) = cache.last.lock().unwrap().as_ref() {
+        if now - cached.computed_at < PUBLIC_STATS_TTL_SECS {
+            return Ok(cached.clone());
+        }
+    }
+    let month_start = now - MONTH_SECS;
+    let dto = PublicStatsDto {
+        completions_week: completions_since(pool, now - WEEK_SECS).await?,
+        completions_month: completions_since(pool, month_start).await?,
+        completions_all_time: completions_since(pool, i64::MIN).await?,
+        unique_participants_month: unique_participants(pool, None, month_start).await?,
+        average_wait_secs_month: average_wait_since(pool, month_start).await?,
+        computed_at: now,
+    };
+    *cache.last.lock().unwrap() = Some(dto.clone());
+    Ok(dto)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        db::{self, FullParticipation, ParticipationSource},
+        queue,
+        testing::{self, TestApp},
+    };
+
+    fn participation(
+        user_id: &str,
+        completed_at: i64,
+        session_id: Option<&str>,
+    ) -> FullParticipation {
+        FullParticipation {
+            user_id: user_id.to_string(),
+            completed_at,
+            queue_item_id: None,
+            reward_id: None,
+            source: ParticipationSource::Queue,
+            session_id: session_id.map(str::to_string),
+            turn_started_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn completing_an_item_records_its_item_reward_and_session() {
+        let app = TestApp::new("").await;
+        db::set_stream_online_at(app.queue.db.write(), 1_700_000_000)
+            .await
+            .unwrap();
+        let mut user = testing::new_user("u1");
+        user.reward_id = Some("reward-1".into());
+        let id = testing::enqueue(&app.queue, user).await;
+
+        queue::delete_item(
+            app.queue.db.write(),
+            &app.queue.timings,
+            &id,
+            queue::DeleteMode::Completed,
+        )
+        .await
+        .unwrap();
+
+        let row = sqlx::query_as::<
+            _,
+            (
+                String,
+                Option<String>,
+                Option<String>,
+                String,
+                Option<String>,
+            ),
+        >(
+            "SELECT user_id, queue_item_id, reward_id, source, session_id FROM participations",
+        )
+        .fetch_one(app.queue.db.read())
+        .await
+        .unwrap();
+        assert_eq!(
+            row,
+            (
+                "u1".to_string(),
+                Some(id),
+                Some("reward-1".to_string()),
+                "queue".to_string(),
+                Some("1700000000".to_string())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn a_queue_item_is_recorded_once() {
+        let app = TestApp::new("").await;
+        let mut p = participation("u1", 1_700_000_000, None);
+        p.queue_item_id = Some("item-1".into());
+
+        assert!(db::insert_participation(app.queue.db.write(), &p)
+            .await
+            .unwrap());
+        p.completed_at += 5;
+        assert!(!db::insert_participation(app.queue.db.write(), &p)
+            .await
+            .unwrap());
+
+        let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM participations")
+            .fetch_one(app.queue.db.read())
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    async fn count(app: &TestApp, sql: &str) -> i64 {
+        sqlx::query_scalar::<_, i64>(sql)
+            .fetch_one(app.queue.db.read())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn parallel_completes_of_one_item_record_one_turn_and_one_fulfillment() {
+        let app = Arc::new(TestApp::new("").await);
+        let mut user = testing::new_user("u1");
+        user.reward_id = Some("reward-1".into());
+        user.redemption_id = Some("redemption-1".into());
+        let id = testing::enqueue(&app.queue, user).await;
+        testing::enqueue(&app.queue, testing::new_user("u2")).await;
+
+        // Double taps and retries of the complete, racing an end-of-stream clear.
+        let mut tasks = Vec::new();
+        for _ in 0..16 {
+            let (app, id) = (Arc::clone(&app), id.clone());
+            tasks.push(tokio::spawn(async move {
+                queue::delete_item(
+                    app.queue.db.write(),
+                    &app.queue.timings,
+                    &id,
+                    queue::DeleteMode::Completed,
+                )
+                .await
+                .is_ok()
+            }));
+        }
+        let clearing = {
+            let app = Arc::clone(&app);
+            tokio::spawn(async move {
+                queue::clear_all(app.queue.db.write(), queue::DeleteMode::Completed)
+                    .await
+                    .unwrap()
+            })
+        };
+        let mut succeeded = 0;
+        for task in tasks {
+            succeeded += usize::from(task.await.unwrap());
+        }
+        let cleared = clearing.await.unwrap();
+
+        // Exactly one of the completes (or the clear) removed the item.
+        assert_eq!(succeeded + usize::from(cleared == 2), 1);
+        assert_eq!(
+            count(
+                &app,
+                "SELECT COUNT(*) FROM participations WHERE user_id = 'u1'"
+            )
+            .await,
+            1
+        );
+        assert_eq!(
+            count(
+                &app,
+                "SELECT COUNT(*) FROM outbox WHERE event_type = 'redemption_status'"
+            )
+            .await,
+            1
+        );
+        assert_eq!(
+            count(&app, "SELECT COUNT(*) FROM outbox WHERE payload LIKE '%redemption-1%' AND payload LIKE '%FULFILLED%'").await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn unique_participants_joins_on_session_with_a_time_fallback_for_legacy_rows() {
+        let app = TestApp::new("").await;
+        let since = 1_700_000_000;
+        for p in [
+            participation("a", since + 10, Some("s1")),
+            participation("a", since + 20, Some("s1")),
+            participation("b", since - 100, Some("s1")),
+            participation("c", since + 30, Some("s2")),
+            participation("d", since + 40, None),
+            participation("e", since - 40, None),
+        ] {
+            db::insert_participation(app.queue.db.write(), &p)
+                .await
+                .unwrap();
+        }
+
+        // Session rows count regardless of time, legacy rows only from `since` on.
+        assert_eq!(
+            unique_participants(app.queue.db.read(), Some("s1"), since)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            unique_participants(app.queue.db.read(), Some("s2"), since)
+                .await
+                .unwrap(),
+            2
+        );
+        // Without a session every row is judged by time.
+        assert_eq!(
+            unique_participants(app.queue.db.read(), None, since)
+                .await
+                .unwrap(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn public_stats_are_cached_until_the_ttl_runs_out() {
+        let app = TestApp::new("").await;
+        let cache = PublicStatsCache::default();
+        let now = 1_700_000_000;
+        db::insert_participation(app.queue.db.write(), &participation("a", now - 10, None))
+            .await
+            .unwrap();
+
+        let first = public_stats(app.queue.db.read(), &cache, now)
+            .await
+            .unwrap();
+        assert_eq!((first.completions_week, first.computed_at), (1, now));
+
+        db::insert_participation(app.queue.db.write(), &participation("b", now - 5, None))
+            .await
+            .unwrap();
+        let hit = public_stats(app.queue.db.read(), &cache, now + PUBLIC_STATS_TTL_SECS - 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            (hit.completions_week, hit.computed_at),
+            (1, now),
+            "served from the cache"
+        );
+
+        let fresh = public_stats(app.queue.db.read(), &cache, now + PUBLIC_STATS_TTL_SECS)
+            .await
+            .unwrap();
+        assert_eq!(
+            (fresh.completions_week, fresh.computed_at),
+            (2, now + PUBLIC_STATS_TTL_SECS)
+        );
+        assert_eq!(fresh.unique_participants_month, 2);
+        assert_eq!(
+            fresh.average_wait_secs_month, None,
+            "too few turns to publish"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_public_endpoint_is_a_404_unless_enabled() {
+        const PASSWORDS: &str = "[server]\nadmin_password = \"admin-pw\"\n";
+        for (enabled, status) in [(false, 404), (true, 200)] {
+            let app = TestApp::new(&format!("{PASSWORDS}public_stats = {enabled}\n")).await;
+            let base = testing::serve(crate::web::router(app.state.clone())).await;
+            let res = reqwest::get(format!("{base}/api/stats/public"))
+                .await
+                .unwrap();
+            assert_eq!(
+                res.status().as_u16(),
+                status,
+                "public_stats = {enabled}, no credentials"
+            );
+        }
+    }
+}
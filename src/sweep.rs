@@ -0,0 +1,223 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+use crate::{config::QueueConfig, db, timing, util, AppState};
+
+/// Rows per DELETE; other writers get the lock between batches.
+const BATCH_ROWS: u32 = 500;
+/// One sweep stops after this many batches; the rest waits for the next trigger.
+const MAX_BATCHES: u32 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SweepTrigger {
+    /// `queue.processed_message_cleanup_interval_secs` elapsed (also the sweep at startup).
+    Interval,
+    /// `queue.processed_message_cleanup_threshold` ids were recorded since the last sweep.
+    Volume,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SweepReport {
+    pub finished_at: i64,
+    pub trigger: SweepTrigger,
+    pub deleted: u64,
+    pub batches: u32,
+    pub duration_ms: u64,
+    /// `MAX_BATCHES` was reached with expired rows left.
+    pub truncated: bool,
+}
+
+/// Keeps `processed_messages` small: swept on an interval and, during bursts, as soon
+/// as enough new ids were recorded.
+#[derive(Debug, Default)]
+pub struct ProcessedMessageSweeper {
+    inserts_since_sweep: AtomicU64,
+    volume_sweeps: AtomicU64,
+    wake: Notify,
+    last: Mutex<Option<SweepReport>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProcessedMessagesDto {
+    pub rows: i64,
+    pub inserts_since_sweep: u64,
+    pub volume_sweeps: u64,
+    pub last_sweep: Option<SweepReport>,
+}
+
+impl ProcessedMessageSweeper {
+    /// Call after recording a message id; wakes the sweep when the threshold is crossed.
+    pub fn note_insert(&self, cfg: &QueueConfig) {
+        let n = self.inserts_since_sweep.fetch_add(1, Ordering::Relaxed) + 1;
+        if cfg.processed_message_cleanup_threshold > 0
+            && n == cfg.processed_message_cleanup_threshold
+        {
+            self.wake.notify_one();
+        }
+    }
+
+    pub async fn metrics(&self, pool: &SqlitePool) -> anyhow::Result<ProcessedMessagesDto> {
+        Ok(ProcessedMessagesDto {
+            rows: db::count_processed_messages(pool).await?,
+            inserts_since_sweep: self.inserts_since_sweep.load(Ordering::Relaxed),
+            volume_sweeps: self.volume_sweeps.load(Ordering::Relaxed),
+            last_sweep: self.last.lock().unwrap().clone(),
+        })
+    }
+}
+
+/// Deletes expired rows in batches of `BATCH_ROWS`, yielding between them.
+async fn sweep(
+    pool: &SqlitePool,
+    timings: &timing::Timings,
+    cutoff: i64,
+    trigger: SweepTrigger,
+) -> anyhow::Result<SweepReport> {
+    let started = Instant::now();
+    let mut deleted = 0;
+    let mut batches = 0;
+    let mut truncated = false;
+    loop {
+        let n = db::cleanup_processed_messages(pool, cutoff, BATCH_ROWS).await?;
+        deleted += n;
+        batches += 1;
+        if n < u64::from(BATCH_ROWS) {
+            break;
+        }
+        if batches >= MAX_BATCHES {
+            truncated = true;
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    let elapsed = started.elapsed();
+    timings.record("processed_messages_sweep", elapsed);
+    Ok(SweepReport {
+        finished_at: util::now_epoch(),
+        trigger,
+        deleted,
+        batches,
+        duration_ms: elapsed.as_millis() as u64,
+        truncated,
+    })
+}
+
+pub async fn run_processed_message_sweeper(state: Arc<AppState>) {
+    let cfg = &state.settings.queue;
+    let sweeper = &state.queue.processed_sweeper;
+    let interval = Duration::from_secs(cfg.processed_message_cleanup_interval_secs);
+    let mut trigger = SweepTrigger::Interval;
+    loop {
+        sweeper.inserts_since_sweep.store(0, Ordering::Relaxed);
+        if trigger == SweepTrigger::Volume {
+            sweeper.volume_sweeps.fetch_add(1, Ordering::Relaxed);
+        }
+        let cutoff = util::now_epoch() - cfg.processed_message_ttl_secs as i64;
+        match sweep(
+            state.queue.db.write(),
+            &state.queue.timings,
+            cutoff,
+            trigger,
+        )
+        .await
+        {
+            Ok(report) => {
+                if report.deleted > 0 || trigger == SweepTrigger::Volume {
+                    info!(
+                        ?trigger,
+                        deleted = report.deleted,
+                        duration_ms = report.duration_ms,
+                        truncated = report.truncated,
+                        "cleaned processed_messages"
+                    );
+                }
+                *sweeper.last.lock().unwrap() = Some(report);
+            }
+            Err(e) => error!(error = ?e, "failed to cleanup processed_messages"),
+        }
+        trigger = tokio::select! {
+            _ = tokio::time::sleep(interval) => SweepTrigger::Interval,
+            _ = sweeper.wake.notified() => SweepTrigger::Volume,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestApp;
+
+    const BURST: &str = "[queue]\nprocessed_message_cleanup_threshold = 5\nprocessed_message_cleanup_interval_secs = 3600\nprocessed_message_ttl_secs = 60\n";
+
+    async fn last_sweep(sweeper: &ProcessedMessageSweeper) -> Option<SweepReport> {
+        for _ in 0..200 {
+            if let Some(report) = sweeper.last.lock().unwrap().clone() {
+                return Some(report);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_inserts_sweeps_before_the_interval() {
+        let app = TestApp::new(BURST).await;
+        let pool = app.queue.db.write();
+        let sweeper = Arc::clone(&app.queue.processed_sweeper);
+        tokio::spawn(run_processed_message_sweeper(app.state.clone()));
+        assert_eq!(
+            last_sweep(&sweeper).await.unwrap().trigger,
+            SweepTrigger::Interval,
+            "the sweep at startup"
+        );
+        *sweeper.last.lock().unwrap() = None;
+
+        let now = util::now_epoch();
+        for i in 0..5 {
+            db::mark_processed_message(pool, &format!("old-{i}"), now - 600)
+                .await
+                .unwrap();
+            db::mark_processed_message(pool, &format!("new-{i}"), now)
+                .await
+                .unwrap();
+        }
+        for _ in 0..4 {
+            sweeper.note_insert(&app.settings.queue);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(
+            sweeper.last.lock().unwrap().is_none(),
+            "below the threshold nothing runs"
+        );
+        assert_eq!(sweeper.metrics(pool).await.unwrap().inserts_since_sweep, 4);
+
+        sweeper.note_insert(&app.settings.queue);
+        let report = last_sweep(&sweeper)
+            .await
+            .expect("the threshold wakes the sweeper");
+        assert_eq!(
+            (report.trigger, report.deleted, report.truncated),
+            (SweepTrigger::Volume, 5, false)
+        );
+        let metrics = sweeper.metrics(pool).await.unwrap();
+        assert_eq!(
+            (
+                metrics.rows,
+                metrics.inserts_since_sweep,
+                metrics.volume_sweeps
+            ),
+            (5, 0, 1)
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Helpers shared by the unit tests: throwaway databases, an `AppState` or a bare
+//! `QueueService` around one and a local HTTP server standing in for outside services.
+
+use std::{ops::Deref, path::PathBuf, sync::Arc};
+
+use crate::{
+    config::{Config, Settings},
+    db, overlay_token, queue, twitch, AppState,
+};
+
+/// A database file under the temp dir, removed together with its WAL files on drop.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new() -> Self {
+        Self(std::env::temp_dir().join(format!(
+            "twitch_obs_queue-test-{}.db",
+            uuid::Uuid::new_v4().simple()
+        )))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().expect("temp dir is valid UTF-8")
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", self.as_str()));
+        }
+    }
+}
+
+/// A migrated database in a fresh file with `read_connections` read connections.
+pub async fn temp_db(read_connections: u32) -> (db::Db, TempPath) {
+    let path = TempPath::new();
+    let db = db::Db::open(path.as_str(), 0, read_connections)
+        .await
+        .expect("open temp db");
+    (db, path)
+}
+
+/// An `AppState` on its own database, configured from a TOML snippet.
+pub struct TestApp {
+    pub state: Arc<AppState>,
+    pub path: TempPath,
+}
+
+impl TestApp {
+    pub async fn new(config_toml: &str) -> Self {
+        let (db, path) = temp_db(1).await;
+        Self::build(config_toml, db, path, None, None, None).await
+    }
+
+    /// Like [`TestApp::new`] with Helix requests sent to `helix_url` (see [`serve`]).
+    pub async fn with_helix(config_toml: &str, helix_url: &str) -> Self {
+        let (db, path) = temp_db(1).await;
+        Self::build(config_toml, db, path, Some(helix_url), None, None).await
+    }
+
+    /// Like [`TestApp::new`] with token and /validate requests sent to `oauth_url`.
+    pub async fn with_oauth(config_toml: &str, oauth_url: &str) -> Self {
+        let (db, path) = temp_db(1).await;
+        Self::build(config_toml, db, path, None, Some(oauth_url), None).await
+    }
+
+    /// Like [`TestApp::with_helix`] with the IRC chat fallback connecting to `irc_url`.
+    pub async fn with_chat(config_toml: &str, helix_url: &str, irc_url: &str) -> Self {
+        let (db, path) = temp_db(1).await;
+        Self::build(config_toml, db, path, Some(helix_url), None, Some(irc_url)).await
+    }
+
+    /// An `AppState` on an existing database, e.g. one reopened to simulate a restart.
+    pub async fn with_db(config_toml: &str, db: db::Db, path: TempPath) -> Self {
+        Self::build(config_toml, db, path, None, None, None).await
+    }
+
+    async fn build(
+        config_toml: &str,
+        db: db::Db,
+        path: TempPath,
+        helix_url: Option<&str>,
+        oauth_url: Option<&str>,
+        irc_url: Option<&str>,
+    ) -> Self {
+        let config = Config::parse(config_toml).expect("test config");
+        let overlay_keys = overlay_token::load_or_create(db.write())
+            .await
+            .expect("overlay keys");
+        let settings = Settings::new(config);
+        let mut twitch =
+            twitch::TwitchClient::new(reqwest::Client::new(), settings.clone(), db.clone());
+        if let Some(url) = helix_url {
+            twitch.helix_url = url.to_string();
+        }
+        if let Some(url) = oauth_url {
+            twitch.oauth_url = url.to_string();
+        }
+        if let Some(url) = irc_url {
+            twitch.irc = crate::irc::IrcSender::new(url);
+        }
+        let queue = queue::QueueService::new(db, settings.clone());
+        let state = Arc::new(AppState::new(settings, queue, twitch, overlay_keys));
+        Self { state, path }
+    }
+}
+
+impl Deref for TestApp {
+    type Target = AppState;
+
+    fn deref(&self) -> &AppState {
+        &self.state
+    }
+}
+
+/// Serves `router` on an ephemeral local port and returns its base URL (no trailing slash).
+pub async fn serve(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind mock server");
+    let addr = listener.local_addr().expect("mock server address");
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, router).await;
+    });
+    format!("http://{addr}")
+}
+
+/// A join with no reward, redemption or input; override fields as needed.
+pub fn new_user(user_id: &str) -> queue::NewQueueUser {
+    queue::NewQueueUser {
+        user_id: user_id.to_string(),
+        user_login: user_id.to_string(),
+        display_name: user_id.to_string(),
+        profile_image_url: String::new(),
+        reward_id: None,
+        redemption_id: None,
+        user_input: None,
+        redeemed_at_ms: None,
+    }
+}
+
+/// A queue on its own in-memory database, configured from a TOML snippet.
+pub async fn queue_service(config_toml: &str) -> queue::QueueService {
+    let db = db::Db::in_memory().await.expect("in-memory db");
+    queue::QueueService::new(
+        db,
+        Settings::new(Config::parse(config_toml).expect("test config")),
+    )
+}
+
+/// Enqueues `user` under the default policy and returns the new item's id.
+pub async fn enqueue(queue: &queue::QueueService, user: queue::NewQueueUser) -> String {
+    match queue
+        .enqueue(user, &queue::JoinNotices::default())
+        .await
+        .expect("enqueue")
+    {
+        queue::EnqueueOutcome::Added(receipt) => receipt.id,
+        other => panic!("expected the user to be added, got {other:?}"),
+    }
+}
+
+/// The `x-signature` header of a `POST /api/ingest/enqueue` with `body`.
+pub fn ingest_signature(secret: &str, body: &[u8]) -> String {
+    use hmac::Mac;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts any key length");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+/// Runs `fut` and counts the SQL statements it ran on the connection behind `pool`,
+/// which must have exactly one (like [`db::Db::write`]).
+///
+/// sqlx runs each SQLite connection on its own worker thread and logs every statement
+/// there under the `sqlx::query` target, so a process-wide tracing layer counts them per
+/// thread. Other tests' connections live on other threads and do not interfere. The
+/// layer only listens while a count is running: logging every statement is slow.
+pub async fn count_statements<T>(
+    pool: &sqlx::SqlitePool,
+    fut: impl std::future::Future<Output = T>,
+) -> (T, usize) {
+    let counter = statement_counter();
+    counter
+        .active
+        .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let before = statements_so_far(counter, pool).await;
+    let out = fut.await;
+    let after = statements_so_far(counter, pool).await;
+    counter
+        .active
+        .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    // Less the marker query of the second count.
+    (out, after - before - 1)
+}
+
+/// Runs a marker query on `pool` and returns the count of the thread it ran on.
+async fn statements_so_far(counter: &StatementCounter, pool: &sqlx::SqlitePool) -> usize {
+    let marker = format!("statement-count-marker-{}", uuid::Uuid::new_v4().simple());
+    sqlx::query(&format!("SELECT '{marker}'"))
+        .execute(pool)
+        .await
+        .expect("marker query");
+    let counts = counter.counts.lock().unwrap();
+    let thread = counts
+        .markers
+        .get(&marker)
+        .expect("the marker query was logged");
+    counts.by_thread[thread]
+}
+
+#[derive(Default)]
+struct StatementCounter {
+    /// Running [`count_statements`] calls; nothing is counted while this is 0.
+    active: std::sync::atomic::AtomicUsize,
+    counts: std::sync::Mutex<StatementCounts>,
+}
+
+#[derive(Default)]
+struct StatementCounts {
+    by_thread: std::collections::HashMap<String, usize>,
+    /// Marker text to the thread its query ran on.
+    markers: std::collections::HashMap<String, String>,
+}
+
+fn statement_counter() -> &'static StatementCounter {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    static COUNTER: std::sync::OnceLock<&'static StatementCounter> = std::sync::OnceLock::new();
+    COUNTER.get_or_init(|| {
+        let counter: &'static StatementCounter = Box::leak(Box::default());
+        tracing::subscriber::set_global_default(
+            tracing_subscriber::registry().with(StatementLayer(counter)),
+        )
+        .expect("no other global tracing subscriber in tests");
+        counter
+    })
+}
+
+struct StatementLayer(&'static StatementCounter);
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for StatementLayer {
+    fn register_callsite(
+        &self,
+        meta: &'static tracing::Metadata<'static>,
+    ) -> tracing::subscriber::Interest {
+        match meta.target() {
+            "sqlx::query" => tracing::subscriber::Interest::sometimes(),
+            _ => tracing::subscriber::Interest::never(),
+        }
+    }
+
+    /// Off outside [`count_statements`], so sqlx does not format statements for nothing.
+    fn enabled(
+        &self,
+        _meta: &tracing::Metadata<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) -> bool {
+        self.0.active.load(std::sync::atomic::Ordering::SeqCst) > 0
+    }
+
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        struct Summary(String);
+        impl tracing::field::Visit for Summary {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "summary" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+        let mut summary = Summary(String::new());
+        event.record(&mut summary);
+        let thread = std::thread::current()
+            .name()
+            .unwrap_or_default()
+            .to_string();
+        let mut counts = self.0.counts.lock().unwrap();
+        if let Some(marker) = summary
+            .0
+            .split('\'')
+            .find(|s| s.starts_with("statement-count-marker-"))
+        {
+            counts.markers.insert(marker.to_string(), thread.clone());
+        }
+        *counts.by_thread.entry(thread).or_default() += 1;
+    }
+}
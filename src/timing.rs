@@ -0,0 +1,205 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::warn;
+
+/// Upper bounds (inclusive, milliseconds) of the histogram buckets; the last bucket is unbounded.
+const BUCKET_BOUNDS_MS: &[u64] = &[1, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    /// One count per bound in `BUCKET_BOUNDS_MS`, plus the overflow bucket.
+    counts: Vec<u64>,
+    sum_micros: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            counts: vec![0; BUCKET_BOUNDS_MS.len() + 1],
+            sum_micros: 0,
+        }
+    }
+
+    fn observe(&mut self, d: Duration) {
+        let ms = d.as_millis() as u64;
+        let idx = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&b| ms <= b)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.counts[idx] += 1;
+        self.sum_micros += d.as_micros() as u64;
+    }
+}
+
+/// Per-(op, phase) histograms since startup; one per `AppState`.
+#[derive(Debug, Default)]
+pub struct Timings {
+    /// `server.slow_mutation_warn_ms`. 0 disables the warning.
+    slow_mutation_warn_ms: u64,
+    histograms: Mutex<BTreeMap<(&'static str, &'static str), Histogram>>,
+}
+
+impl Timings {
+    pub fn new(slow_mutation_warn_ms: u64) -> Self {
+        Self {
+            slow_mutation_warn_ms,
+            histograms: Mutex::default(),
+        }
+    }
+
+    fn observe(&self, op: &'static str, phase: &'static str, d: Duration) {
+        let mut map = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        map.entry((op, phase))
+            .or_insert_with(Histogram::new)
+            .observe(d);
+    }
+
+    /// Records a duration measured outside a [`PhaseTimer`] (background work) as `op`'s total.
+    pub fn record(&self, op: &'static str, d: Duration) {
+        self.observe(op, "total", d);
+    }
+
+    /// Everything observed since startup, sorted by op and phase.
+    pub fn snapshot(&self) -> Vec<HistogramDto> {
+        let map = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        map.iter()
+            .map(|(&(op, phase), h)| HistogramDto {
+                op,
+                phase,
+                count: h.counts.iter().sum(),
+                sum_ms: h.sum_micros as f64 / 1000.0,
+                buckets: h
+                    .counts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &count)| BucketDto {
+                        le_ms: BUCKET_BOUNDS_MS.get(i).copied(),
+                        count,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BucketDto {
+    /// `None` for the overflow bucket.
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramDto {
+    pub op: &'static str,
+    /// A phase name, or "total" for the whole call.
+    pub phase: &'static str,
+    pub count: u64,
+    pub sum_ms: f64,
+    pub buckets: Vec<BucketDto>,
+}
+
+/// Splits one call of `op` into named phases. Dropping it (including on early
+/// return or `?`) records the histograms, fills the `total_ms` and `phases`
+/// fields of the current span, and warns if a mutation ran past
+/// `server.slow_mutation_warn_ms`.
+pub struct PhaseTimer<'a> {
+    timings: &'a Timings,
+    op: &'static str,
+    mutation: bool,
+    started: Instant,
+    phase_started: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl<'a> PhaseTimer<'a> {
+    pub fn mutation(timings: &'a Timings, op: &'static str) -> Self {
+        Self::start(timings, op, true)
+    }
+
+    pub fn read(timings: &'a Timings, op: &'static str) -> Self {
+        Self::start(timings, op, false)
+    }
+
+    fn start(timings: &'a Timings, op: &'static str, mutation: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            timings,
+            op,
+            mutation,
+            started: now,
+            phase_started: now,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Ends the running phase and names it `name`; the next phase starts now.
+    pub fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push((name, now - self.phase_started));
+        self.phase_started = now;
+    }
+
+    fn breakdown(&self) -> String {
+        self.phases
+            .iter()
+            .map(|(name, d)| format!("{name}={:.1}ms", d.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Drop for PhaseTimer<'_> {
+    fn drop(&mut self) {
+        let total = self.started.elapsed();
+        for &(name, d) in &self.phases {
+            self.timings.observe(self.op, name, d);
+        }
+        self.timings.observe(self.op, "total", total);
+
+        let total_ms = total.as_millis() as u64;
+        let phases = self.breakdown();
+        let span = tracing::Span::current();
+        span.record("total_ms", total_ms);
+        span.record("phases", phases.as_str());
+
+        let threshold = self.timings.slow_mutation_warn_ms;
+        if self.mutation && threshold > 0 && total_ms >= threshold {
+            warn!(op = self.op, total_ms, phases = %phases, "slow queue mutation");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_timings_keeps_its_own_histograms() {
+        let a = Timings::new(0);
+        let b = Timings::default();
+        {
+            let mut timer = PhaseTimer::mutation(&a, "op");
+            timer.phase("write");
+        }
+        b.record("sweep", Duration::from_millis(7));
+
+        let phases: Vec<_> = a
+            .snapshot()
+            .iter()
+            .map(|h| (h.op, h.phase, h.count))
+            .collect();
+        assert_eq!(phases, [("op", "total", 1), ("op", "write", 1)]);
+        let sweep = &b.snapshot()[0];
+        assert_eq!((sweep.op, sweep.count), ("sweep", 1));
+        assert_eq!(
+            sweep.buckets.iter().find(|b| b.count == 1).unwrap().le_ms,
+            Some(10)
+        );
+    }
+}
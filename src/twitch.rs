@@ -1,30 +1,55 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, RwLock, Semaphore};
 use tokio_tungstenite::tungstenite::Message;
 use tracing::{debug, error, info, warn};
 use url::Url;
 
-use crate::{db, queue, util, AppState};
+use crate::{
+    config::{BroadcasterSwitchData, Settings},
+    db, interest, outbox, prefs,
+    queue::{self, QueueService},
+    stats, util,
+};
 
 const AUTHORIZE_ENDPOINT: &str = "https://id.twitch.tv/oauth2/authorize";
-const TOKEN_ENDPOINT: &str = "https://id.twitch.tv/oauth2/token";
+const OAUTH_ENDPOINT: &str = "https://id.twitch.tv/oauth2";
 const HELIX_ENDPOINT: &str = "https://api.twitch.tv/helix";
 const EVENTSUB_WS_URL: &str = "wss://eventsub.wss.twitch.tv/ws";
 
 const REQUIRED_SCOPES: &str = "channel:read:redemptions";
+/// Extra scope needed when `TwitchConfig::needs_manage_scope` is true.
+const MANAGE_REDEMPTIONS_SCOPE: &str = "channel:manage:redemptions";
+/// Extra scope needed for channel.chat.message when a chat command is set (`Config::reads_chat`).
+const READ_CHAT_SCOPE: &str = "user:read:chat";
+/// Extra scope for chat replies (`chat.position_command`).
+const WRITE_CHAT_SCOPE: &str = "user:write:chat";
+/// Extra scope for `chat.join_whisper`.
+const WHISPER_SCOPE: &str = "user:manage:whispers";
 
 const SUB_TYPE_REDEMPTION_ADD: &str = "channel.channel_points_custom_reward_redemption.add";
+const SUB_TYPE_STREAM_ONLINE: &str = "stream.online";
+const SUB_TYPE_CHANNEL_RAID: &str = "channel.raid";
+const SUB_TYPE_CHAT_MESSAGE: &str = "channel.chat.message";
+/// Chat command for viewers' own notification preferences (`!queue optout` / `!queue optin`).
+const CHAT_PREFS_COMMAND: &str = "!queue";
 
 #[derive(Debug, Deserialize)]
 struct TokenResponse {
     access_token: String,
     refresh_token: String,
     expires_in: i64,
-    token_type: String,
+    /// Refresh responses may leave it out (or send it empty); only the code exchange is trusted.
     #[serde(default)]
-    scope: Vec<String>,
+    scope: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,7 +61,7 @@ struct HelixResponse<T> {
 pub(crate) struct HelixUser {
     pub id: String,
     pub login: String,
-    display_name: String,
+    pub display_name: String,
     profile_image_url: String,
 }
 
@@ -44,8 +69,13 @@ pub(crate) struct HelixUser {
 pub struct HelixReward {
     pub id: String,
     pub title: String,
+    #[serde(default)]
+    pub prompt: String,
     pub cost: i64,
     pub is_enabled: bool,
+    /// Last prompt pushed by `twitch.reward_prompt_template` (filled in by the rewards API).
+    #[serde(default, skip_deserializing)]
+    pub prompt_sync: Option<crate::reward_prompt::PromptSyncState>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,6 +89,8 @@ struct EventSubListResponse {
     data: Vec<EventSubSubscription>,
     #[serde(default)]
     pagination: EventSubPagination,
+    #[serde(flatten)]
+    budget: HelixBudgetFields,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -71,8 +103,6 @@ struct EventSubPagination {
 struct EventSubSubscription {
     id: String,
     status: String,
-    #[serde(rename = "type")]
-    typ: String,
     transport: EventSubTransportInfo,
 }
 
@@ -82,32 +112,84 @@ struct EventSubTransportInfo {
 }
 // ここまで掃除用
 
+/// Warns at startup when `redirect_url` cannot reach our callback route,
+/// a common copy-paste mistake (trailing slash, wrong path, 127.0.0.1 vs localhost).
+pub fn check_redirect_url(config: &crate::config::Config) {
+    let raw = &config.twitch.redirect_url;
+    let url = match Url::parse(raw) {
+        Ok(u) => u,
+        Err(e) => {
+            warn!(redirect_url = %raw, error = %e, "twitch.redirect_url is not a valid URL");
+            return;
+        }
+    };
+
+    let path = url.path();
+    if path != crate::config::AUTH_CALLBACK_PATH {
+        let hint = if path.trim_end_matches('/') == crate::config::AUTH_CALLBACK_PATH {
+            "remove the trailing slash (here and on the Twitch console), or set twitch.normalize_redirect_url = true"
+        } else {
+            "the path must be /auth/callback"
+        };
+        warn!(redirect_url = %raw, path = %path, hint, "twitch.redirect_url does not point at this app's callback route");
+    }
+
+    if url.host_str() == Some("127.0.0.1") {
+        info!(redirect_url = %raw, "twitch.redirect_url uses 127.0.0.1; Twitch usually expects http://localhost");
+    }
+}
+
 pub fn build_authorize_url(config: &crate::config::Config, state: &str) -> anyhow::Result<String> {
+    let mut scopes = REQUIRED_SCOPES.to_string();
+    if config.twitch.needs_manage_scope() {
+        scopes.push(' ');
+        scopes.push_str(MANAGE_REDEMPTIONS_SCOPE);
+    }
+    if config.reads_chat() {
+        scopes.push(' ');
+        scopes.push_str(READ_CHAT_SCOPE);
+        if config.chat.needs_write_scope() {
+            scopes.push(' ');
+            scopes.push_str(WRITE_CHAT_SCOPE);
+            if config.chat.irc_fallback {
+                scopes.push(' ');
+                scopes.push_str(crate::irc::CHAT_EDIT_SCOPE);
+            }
+        }
+    }
+    if !config.chat.join_whisper.trim().is_empty() {
+        scopes.push(' ');
+        scopes.push_str(WHISPER_SCOPE);
+    }
+
     let mut url = Url::parse(AUTHORIZE_ENDPOINT)?;
     url.query_pairs_mut()
         .append_pair("client_id", &config.twitch.client_id)
         .append_pair("redirect_uri", &config.twitch.redirect_url)
         .append_pair("response_type", "code")
-        .append_pair("scope", REQUIRED_SCOPES)
+        .append_pair("scope", &scopes)
         .append_pair("state", state);
     Ok(url.to_string())
 }
 
 pub async fn exchange_code_for_token(
-    state: &AppState,
+    twitch: &TwitchClient,
     code: &str,
 ) -> anyhow::Result<db::OAuthToken> {
     let params = [
-        ("client_id", state.config.twitch.client_id.as_str()),
-        ("client_secret", state.config.twitch.client_secret.as_str()),
+        ("client_id", twitch.settings.twitch.client_id.as_str()),
+        (
+            "client_secret",
+            twitch.settings.twitch.client_secret.as_str(),
+        ),
         ("code", code),
         ("grant_type", "authorization_code"),
-        ("redirect_uri", state.config.twitch.redirect_url.as_str()),
+        ("redirect_uri", twitch.settings.twitch.redirect_url.as_str()),
     ];
 
-    let resp = state
+    let resp = twitch
         .http
-        .post(TOKEN_ENDPOINT)
+        .post(twitch.oauth("/token"))
         .form(&params)
         .send()
         .await?
@@ -118,42 +200,501 @@ pub async fn exchange_code_for_token(
         access_token: token.access_token,
         refresh_token: token.refresh_token,
         expires_at: util::now_epoch() + token.expires_in,
+        // Missing or empty reads as "unknown" rather than "nothing granted".
+        scopes: token.scope.filter(|s| !s.is_empty()),
     })
 }
 
-pub async fn refresh_access_token(
-    state: &AppState,
+async fn refresh_access_token(
+    twitch: &TwitchClient,
     refresh_token: &str,
 ) -> anyhow::Result<db::OAuthToken> {
     let params = [
-        ("client_id", state.config.twitch.client_id.as_str()),
-        ("client_secret", state.config.twitch.client_secret.as_str()),
+        ("client_id", twitch.settings.twitch.client_id.as_str()),
+        (
+            "client_secret",
+            twitch.settings.twitch.client_secret.as_str(),
+        ),
         ("refresh_token", refresh_token),
         ("grant_type", "refresh_token"),
     ];
 
-    let resp = state
+    let resp = twitch
         .http
-        .post(TOKEN_ENDPOINT)
+        .post(twitch.oauth("/token"))
         .form(&params)
         .send()
         .await?
         .error_for_status()?;
 
     let token: TokenResponse = resp.json().await?;
+    // A refresh keeps the grant, so the stored scopes stay (see `db::upsert_oauth_token`).
     Ok(db::OAuthToken {
         access_token: token.access_token,
         refresh_token: token.refresh_token,
         expires_at: util::now_epoch() + token.expires_in,
+        scopes: None,
+    })
+}
+
+/// Refreshes the access token, persists it, and records the attempt in `token_events`.
+/// Every refresh (web path, EventSub loop, outbox) goes through here.
+pub async fn refresh_and_store_token(
+    twitch: &TwitchClient,
+    refresh_token: &str,
+) -> anyhow::Result<db::OAuthToken> {
+    let result = refresh_access_token(twitch, refresh_token).await;
+    if let Err(e) = record_token_refresh(twitch, &result).await {
+        warn!(error = ?e, "failed to record token refresh attempt");
+    }
+
+    let token = result?;
+    db::upsert_oauth_token(twitch.db.write(), &token).await?;
+    Ok(token)
+}
+
+fn classify_refresh_error(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(re) if re.is_decode() => "decode".to_string(),
+        Some(re) => match re.status() {
+            Some(s) if s.is_server_error() => "http_5xx".to_string(),
+            Some(s) => format!("http_{}", s.as_u16()),
+            None => "network".to_string(),
+        },
+        None => "other".to_string(),
+    }
+}
+
+async fn record_token_refresh(
+    twitch: &TwitchClient,
+    result: &anyhow::Result<db::OAuthToken>,
+) -> anyhow::Result<()> {
+    let now = util::now_epoch();
+    let ev = match result {
+        Ok(_) => db::TokenEvent {
+            occurred_at: now,
+            success: true,
+            error_class: None,
+            error_detail: None,
+        },
+        Err(e) => db::TokenEvent {
+            occurred_at: now,
+            success: false,
+            error_class: Some(classify_refresh_error(e)),
+            error_detail: Some(format!("{e:#}")),
+        },
+    };
+    db::insert_token_event(twitch.db.write(), &ev).await?;
+
+    if ev.success {
+        db::set_kv(twitch.db.write(), KV_TOKEN_ALERT_SENT, "").await?;
+        return Ok(());
+    }
+
+    // Alert once per failure streak
+    let diag = token_diagnostics(twitch.db.write(), now).await?;
+    let threshold = twitch.settings.alerts.token_failure_threshold;
+    // On the writer, behind any set or clear of the flag still queued there, so a streak
+    // is alerted once.
+    let already_sent = db::get_kv(twitch.db.write(), KV_TOKEN_ALERT_SENT)
+        .await?
+        .is_some_and(|v| !v.is_empty());
+    if threshold > 0 && diag.consecutive_failures >= threshold as i64 && !already_sent {
+        let content = format!(
+            "Twitch token refresh failed {} times in a row ({}): {}. Re-login may be required.",
+            diag.consecutive_failures,
+            ev.error_class.as_deref().unwrap_or("unknown"),
+            ev.error_detail.as_deref().unwrap_or(""),
+        );
+        outbox::insert(
+            twitch.db.write(),
+            &outbox::OutboxEvent::AlertWebhook { content },
+            now,
+        )
+        .await?;
+        db::set_kv(twitch.db.write(), KV_TOKEN_ALERT_SENT, "1").await?;
+        error!(
+            failures = diag.consecutive_failures,
+            "token refresh keeps failing; alert queued"
+        );
+    }
+    Ok(())
+}
+
+const KV_TOKEN_ALERT_SENT: &str = "token_alert_sent";
+
+/// EventSub WebSocket lifecycle steps recorded in `eventsub_events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSubEventKind {
+    Connected,
+    Welcome,
+    /// No message within the session's keepalive timeout; the connection is dropped.
+    KeepaliveTimeout,
+    ReconnectReceived,
+    Disconnected,
+    SubscribeOk,
+    SubscribeFailed,
+}
+
+impl EventSubEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventSubEventKind::Connected => "connected",
+            EventSubEventKind::Welcome => "welcome",
+            EventSubEventKind::KeepaliveTimeout => "keepalive_timeout",
+            EventSubEventKind::ReconnectReceived => "reconnect_received",
+            EventSubEventKind::Disconnected => "disconnected",
+            EventSubEventKind::SubscribeOk => "subscribe_ok",
+            EventSubEventKind::SubscribeFailed => "subscribe_failed",
+        }
+    }
+}
+
+/// `detail` of a welcome on a reconnect URL: the subscriptions carried over.
+const WELCOME_MIGRATED: &str = "migrated";
+
+/// Best-effort: a failed write is logged, never fatal to the EventSub loop.
+async fn record_eventsub_event(
+    twitch: &TwitchClient,
+    kind: EventSubEventKind,
+    detail: Option<String>,
+) {
+    let ev = db::EventSubEvent {
+        occurred_at: util::now_epoch(),
+        kind: kind.as_str().to_string(),
+        detail,
+    };
+    if let Err(e) = db::insert_eventsub_event(twitch.db.write(), &ev).await {
+        warn!(error = ?e, kind = kind.as_str(), "failed to record eventsub event");
+    }
+}
+
+/// A span without working EventSub subscriptions (redemptions were not received).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DowntimeInterval {
+    pub start: i64,
+    /// `None` while still down at the end of the range.
+    pub end: Option<i64>,
+    /// Up to `end`, or to the end of the range when unterminated.
+    pub duration_secs: i64,
+}
+
+/// Downtime in `[from, to)` from events in time order (the first may predate `from`).
+/// Down starts at a disconnect, keepalive timeout or failed subscribe, and ends when
+/// subscriptions work again: a successful subscribe, or a welcome that migrated them.
+pub fn downtime_intervals(
+    events: &[db::EventSubEvent],
+    from: i64,
+    to: i64,
+) -> Vec<DowntimeInterval> {
+    let mut out = Vec::new();
+    let mut down_since: Option<i64> = None;
+    for e in events {
+        let at = e.occurred_at.clamp(from, to);
+        let kind = e.kind.as_str();
+        if kind == EventSubEventKind::Disconnected.as_str()
+            || kind == EventSubEventKind::KeepaliveTimeout.as_str()
+            || kind == EventSubEventKind::SubscribeFailed.as_str()
+        {
+            down_since.get_or_insert(at);
+        } else if kind == EventSubEventKind::SubscribeOk.as_str()
+            || (kind == EventSubEventKind::Welcome.as_str()
+                && e.detail.as_deref() == Some(WELCOME_MIGRATED))
+        {
+            if let Some(start) = down_since.take() {
+                if at > start {
+                    out.push(DowntimeInterval {
+                        start,
+                        end: Some(at),
+                        duration_secs: at - start,
+                    });
+                }
+            }
+        }
+    }
+    if let Some(start) = down_since {
+        out.push(DowntimeInterval {
+            start,
+            end: None,
+            duration_secs: to - start,
+        });
+    }
+    out
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventSubTimeline {
+    pub from: i64,
+    pub to: i64,
+    pub events: Vec<db::EventSubEvent>,
+    pub downtime: Vec<DowntimeInterval>,
+}
+
+/// Events in `[from, to)` with the downtime they imply (an ongoing outage runs until `now`).
+pub async fn eventsub_timeline(
+    pool: &sqlx::SqlitePool,
+    from: i64,
+    to: i64,
+    now: i64,
+) -> anyhow::Result<EventSubTimeline> {
+    let mut events = db::list_eventsub_events(pool, from, to).await?;
+    let downtime = downtime_intervals(&events, from, to.min(now).max(from));
+    // The event before `from` only sets the starting state.
+    events.retain(|e| e.occurred_at >= from);
+    Ok(EventSubTimeline {
+        from,
+        to,
+        events,
+        downtime,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenDiagnostics {
+    /// Failed refreshes since the last successful one.
+    pub consecutive_failures: i64,
+    pub last_success_at: Option<i64>,
+    pub secs_since_last_success: Option<i64>,
+    /// Negative when already expired; `None` when not authenticated.
+    pub access_token_expires_in_secs: Option<i64>,
+    /// From the login or the last /validate; kept across refreshes. `None` when unknown.
+    pub granted_scopes: Option<Vec<String>>,
+    pub recent_events: Vec<db::TokenEvent>,
+}
+
+pub async fn token_diagnostics(
+    pool: &sqlx::SqlitePool,
+    now: i64,
+) -> anyhow::Result<TokenDiagnostics> {
+    let recent_events = db::list_token_events(pool, 50).await?;
+
+    let consecutive_failures = recent_events.iter().take_while(|e| !e.success).count() as i64;
+    let last_success_at = recent_events
+        .iter()
+        .find(|e| e.success)
+        .map(|e| e.occurred_at);
+    let token = db::get_oauth_token(pool).await?;
+    let access_token_expires_in_secs = token.as_ref().map(|t| t.expires_at - now);
+    let granted_scopes = token.and_then(|t| t.scopes);
+
+    Ok(TokenDiagnostics {
+        consecutive_failures,
+        last_success_at,
+        secs_since_last_success: last_success_at.map(|t| now - t),
+        access_token_expires_in_secs,
+        granted_scopes,
+        recent_events,
     })
 }
 
-pub async fn helix_get_self(state: &AppState, access_token: &str) -> anyhow::Result<HelixUser> {
-    let url = format!("{HELIX_ENDPOINT}/users");
-    let resp = state
+/// Returns a usable access token, refreshing (and persisting) it if it is close to expiry.
+pub async fn get_fresh_access_token(twitch: &TwitchClient) -> anyhow::Result<String> {
+    let Some(token) = db::get_oauth_token(twitch.db.read()).await? else {
+        anyhow::bail!("not authenticated");
+    };
+
+    if token.expires_at > util::now_epoch() + 60 {
+        return Ok(token.access_token);
+    }
+
+    let new_token = refresh_and_store_token(twitch, &token.refresh_token).await?;
+    Ok(new_token.access_token)
+}
+
+/// Compares the account `me` behind a fresh `token` with the stored broadcaster. A
+/// different account is held as a pending switch together with its token (see
+/// `/api/auth/confirm_switch`), so two channels' data never mix and the current login
+/// stays in place until then; otherwise the token and broadcaster are stored.
+/// Returns true when a switch is now pending.
+pub async fn record_authorized_broadcaster(
+    twitch: &TwitchClient,
+    me: &HelixUser,
+    token: &db::OAuthToken,
+) -> anyhow::Result<bool> {
+    match db::get_broadcaster_id(twitch.db.read()).await? {
+        Some(previous) if previous != me.id => {
+            let pending = db::PendingBroadcasterSwitch {
+                broadcaster_id: me.id.clone(),
+                broadcaster_login: me.login.clone(),
+                detected_at: util::now_epoch(),
+            };
+            db::set_pending_oauth_token(twitch.db.write(), token).await?;
+            db::set_pending_broadcaster_switch(twitch.db.write(), &pending).await?;
+            // notify_one keeps the wakeup if the loop is not waiting on it right now.
+            twitch.eventsub.restart.notify_one();
+            warn!(previous_broadcaster_id=%previous, broadcaster_id=%me.id, broadcaster_login=%me.login, "authorized as a different broadcaster; waiting for confirmation");
+            Ok(true)
+        }
+        _ => {
+            db::upsert_oauth_token(twitch.db.write(), token).await?;
+            db::set_broadcaster_id(twitch.db.write(), &me.id).await?;
+            db::set_broadcaster_login(twitch.db.write(), &me.login).await?;
+            info!(broadcaster_id=%me.id, broadcaster_login=%me.login, "authorized");
+            Ok(false)
+        }
+    }
+}
+
+/// Applies the pending switch: the previous channel's data is archived, wiped or kept
+/// (`twitch.on_broadcaster_switch`), the new login's token and account replace the old
+/// ones and EventSub starts over. `None` when no switch is pending.
+pub async fn confirm_broadcaster_switch(
+    twitch: &TwitchClient,
+) -> anyhow::Result<Option<db::BroadcasterSwitchNotice>> {
+    let Some(pending) = db::get_pending_broadcaster_switch(twitch.db.read()).await? else {
+        return Ok(None);
+    };
+    let previous_id = db::get_broadcaster_id(twitch.db.read())
+        .await?
+        .unwrap_or_default();
+    let previous_login = db::get_broadcaster_login(twitch.db.read()).await?;
+    let now = util::now_epoch();
+
+    let mut archive_path = None;
+    let data = match twitch.settings.twitch.on_broadcaster_switch {
+        BroadcasterSwitchData::Archive => {
+            let path = format!(
+                "{}.broadcaster-{previous_id}-{now}",
+                twitch.settings.server.db_path
+            );
+            db::archive_database(twitch.db.write(), &path).await?;
+            db::wipe_channel_data(twitch.db.write()).await?;
+            archive_path = Some(path);
+            "archived"
+        }
+        BroadcasterSwitchData::Wipe => {
+            db::wipe_channel_data(twitch.db.write()).await?;
+            "wiped"
+        }
+        BroadcasterSwitchData::Keep => "kept",
+    };
+
+    if let Some(token) = db::get_pending_oauth_token(twitch.db.read()).await? {
+        db::upsert_oauth_token(twitch.db.write(), &token).await?;
+    }
+    db::set_broadcaster_id(twitch.db.write(), &pending.broadcaster_id).await?;
+    db::set_broadcaster_login(twitch.db.write(), &pending.broadcaster_login).await?;
+    db::clear_pending_broadcaster_switch(twitch.db.write()).await?;
+
+    let notice = db::BroadcasterSwitchNotice {
+        previous_broadcaster_id: previous_id,
+        previous_broadcaster_login: previous_login,
+        data: data.to_string(),
+        archive_path,
+        switched_at: now,
+    };
+    db::set_broadcaster_switch_notice(twitch.db.write(), &notice).await?;
+    twitch.eventsub.restart.notify_one();
+    Ok(Some(notice))
+}
+
+/// Drops the pending switch and the token held for it; the current login stays.
+/// False when no switch is pending.
+pub async fn cancel_broadcaster_switch(twitch: &TwitchClient) -> anyhow::Result<bool> {
+    // On the writer, behind a confirm that may be clearing the same switch.
+    if db::get_pending_broadcaster_switch(twitch.db.write())
+        .await?
+        .is_none()
+    {
+        return Ok(false);
+    }
+    db::clear_pending_broadcaster_switch(twitch.db.write()).await?;
+    Ok(true)
+}
+
+/// Outbound Twitch access and the EventSub session: OAuth, Helix and the alert webhook
+/// share this client. Tokens live in the database, so it keeps a handle to it.
+pub struct TwitchClient {
+    pub settings: Settings,
+    /// Token and broadcaster storage.
+    pub db: db::Db,
+    pub http: reqwest::Client,
+    /// OAuth state (CSRF) for the current login attempt.
+    pub oauth_state: RwLock<Option<String>>,
+    /// Shared cap on in-flight Helix requests (see [`helix_permit`]).
+    pub helix_permits: Semaphore,
+    /// Skips Helix profile lookups after repeated failures (see [`get_profile_image_url_cached`]).
+    pub profile_breaker: Mutex<util::CircuitBreaker>,
+    /// Chat over IRC when Helix is down (`chat.irc_fallback`).
+    pub irc: crate::irc::IrcSender,
+    /// Written by the EventSub loop, read by the status endpoints.
+    pub eventsub: EventSubStatus,
+    /// Last accepted chat command per (command, user id), for the `chat.*_cooldown_secs`.
+    pub chat_command_seen: Mutex<HashMap<(String, String), i64>>,
+    /// Helix base URL; tests point it at a local mock.
+    pub helix_url: String,
+    /// id.twitch.tv OAuth base URL (token, validate); tests point it at a local mock.
+    pub oauth_url: String,
+}
+
+impl TwitchClient {
+    pub fn new(http: reqwest::Client, settings: Settings, db: db::Db) -> Self {
+        let cfg = &settings.twitch;
+        Self {
+            helix_permits: Semaphore::new(cfg.max_concurrent_helix_requests.max(1)),
+            profile_breaker: Mutex::new(util::CircuitBreaker::new(
+                cfg.profile_breaker_failures,
+                cfg.profile_breaker_window_secs,
+                cfg.profile_breaker_cooldown_secs,
+            )),
+            settings,
+            db,
+            http,
+            oauth_state: RwLock::new(None),
+            irc: crate::irc::IrcSender::new(crate::irc::IRC_WS_URL),
+            eventsub: EventSubStatus::default(),
+            chat_command_seen: Mutex::new(HashMap::new()),
+            helix_url: HELIX_ENDPOINT.to_string(),
+            oauth_url: OAUTH_ENDPOINT.to_string(),
+        }
+    }
+
+    fn helix(&self, path: &str) -> String {
+        format!("{}{path}", self.helix_url)
+    }
+
+    pub(crate) fn oauth(&self, path: &str) -> String {
+        format!("{}{path}", self.oauth_url)
+    }
+}
+
+/// State of the EventSub session as seen by the rest of the app.
+#[derive(Debug, Default)]
+pub struct EventSubStatus {
+    /// Subscriptions created for the current WebSocket session.
+    pub subscription_count: AtomicUsize,
+    /// Subscription cost budget last reported by Helix (see [`EventSubBudget`]).
+    pub budget: Mutex<EventSubBudget>,
+    /// Makes the EventSub loop drop its session and start over (e.g. broadcaster changed).
+    pub restart: Notify,
+    /// Enqueueing is paused until this epoch second after a raid (`twitch.raid_pause_secs`).
+    pub raid_paused_until: AtomicI64,
+}
+
+impl EventSubStatus {
+    /// End of the pause after a recent raid, if it is still running.
+    pub fn raid_pause_until(&self, now: i64) -> Option<i64> {
+        let until = self.raid_paused_until.load(Ordering::Relaxed);
+        (until > now).then_some(until)
+    }
+}
+
+/// Bounds concurrent Helix requests across all callers (`twitch.max_concurrent_helix_requests`).
+/// Hold the permit until the response arrives.
+async fn helix_permit(twitch: &TwitchClient) -> anyhow::Result<tokio::sync::SemaphorePermit<'_>> {
+    Ok(twitch.helix_permits.acquire().await?)
+}
+
+pub async fn helix_get_self(
+    twitch: &TwitchClient,
+    access_token: &str,
+) -> anyhow::Result<HelixUser> {
+    let url = twitch.helix("/users");
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
         .http
         .get(url)
-        .header("Client-Id", &state.config.twitch.client_id)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
         .header("Authorization", format!("Bearer {access_token}"))
         .send()
         .await?
@@ -169,40 +710,266 @@ pub async fn helix_get_self(state: &AppState, access_token: &str) -> anyhow::Res
 }
 
 pub async fn helix_get_user_by_id(
-    state: &AppState,
+    twitch: &TwitchClient,
     access_token: &str,
     user_id: &str,
 ) -> anyhow::Result<HelixUser> {
-    let mut url = Url::parse(&format!("{HELIX_ENDPOINT}/users"))?;
-    url.query_pairs_mut().append_pair("id", user_id);
-    let resp = state
+    helix_get_user(twitch, access_token, "id", user_id).await
+}
+
+/// `None` when Twitch has no user with this login (deleted, banned or mistyped).
+pub async fn helix_get_user_by_login(
+    twitch: &TwitchClient,
+    access_token: &str,
+    login: &str,
+) -> anyhow::Result<Option<HelixUser>> {
+    helix_find_user(twitch, access_token, "login", &login.to_lowercase()).await
+}
+
+/// `param` is `id` or `login`.
+async fn helix_get_user(
+    twitch: &TwitchClient,
+    access_token: &str,
+    param: &str,
+    value: &str,
+) -> anyhow::Result<HelixUser> {
+    helix_find_user(twitch, access_token, param, value)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("helix /users returned empty data"))
+}
+
+async fn helix_find_user(
+    twitch: &TwitchClient,
+    access_token: &str,
+    param: &str,
+    value: &str,
+) -> anyhow::Result<Option<HelixUser>> {
+    let mut url = Url::parse(&twitch.helix("/users"))?;
+    url.query_pairs_mut().append_pair(param, value);
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
         .http
         .get(url)
-        .header("Client-Id", &state.config.twitch.client_id)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
         .header("Authorization", format!("Bearer {access_token}"))
         .send()
         .await?
         .error_for_status()?;
 
     let data: HelixResponse<HelixUser> = resp.json().await?;
-    let user = data
-        .data
-        .into_iter()
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("helix /users returned empty data"))?;
-    Ok(user)
+    Ok(data.data.into_iter().next())
+}
+
+/// Helix `/users` accepts up to 100 `id` parameters per request.
+const MAX_USERS_PER_LOOKUP: usize = 100;
+
+async fn helix_get_users_by_ids(
+    twitch: &TwitchClient,
+    access_token: &str,
+    user_ids: &[&str],
+) -> anyhow::Result<Vec<HelixUser>> {
+    helix_get_users_batch(twitch, access_token, "id", user_ids).await
+}
+
+/// Users for up to `MAX_USERS_PER_LOOKUP` values of `param` (`id` or `login`). Unknown
+/// values are simply missing from the result.
+async fn helix_get_users_batch(
+    twitch: &TwitchClient,
+    access_token: &str,
+    param: &str,
+    values: &[&str],
+) -> anyhow::Result<Vec<HelixUser>> {
+    let mut url = Url::parse(&twitch.helix("/users"))?;
+    {
+        let mut q = url.query_pairs_mut();
+        for v in values {
+            q.append_pair(param, v);
+        }
+    }
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .get(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let data: HelixResponse<HelixUser> = resp.json().await?;
+    Ok(data.data)
+}
+
+/// Looks `logins` up in batches, keyed by lowercase login. Logins unknown to Twitch
+/// (renamed, deleted, banned) are missing from the map.
+pub async fn helix_get_users_by_logins(
+    twitch: &TwitchClient,
+    access_token: &str,
+    logins: &[String],
+) -> anyhow::Result<HashMap<String, HelixUser>> {
+    let lowered: Vec<String> = logins.iter().map(|l| l.trim().to_lowercase()).collect();
+    let refs: Vec<&str> = lowered.iter().map(String::as_str).collect();
+    let mut out = HashMap::new();
+    for chunk in refs.chunks(MAX_USERS_PER_LOOKUP) {
+        for u in helix_get_users_batch(twitch, access_token, "login", chunk).await? {
+            out.insert(u.login.to_lowercase(), u);
+        }
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PrewarmResult {
+    /// Already had a cache entry within `twitch.user_cache_ttl_secs`.
+    pub fresh: usize,
+    pub prewarmed: usize,
+    /// Not attempted because the time budget ran out.
+    pub deferred: usize,
+    /// Looked up but unknown to Twitch (deleted / banned accounts) or the lookup failed.
+    pub failed: usize,
+}
+
+/// Fills `user_cache` for `user_ids` with batched lookups so the overlay does not
+/// fetch avatars one by one. Stops starting new batches once `budget` has elapsed.
+pub async fn prewarm_user_cache(
+    twitch: &TwitchClient,
+    access_token: &str,
+    user_ids: &[String],
+    budget: std::time::Duration,
+) -> anyhow::Result<PrewarmResult> {
+    let started = std::time::Instant::now();
+    let now = util::now_epoch();
+    let ttl = twitch.settings.twitch.user_cache_ttl_secs as i64;
+
+    let mut result = PrewarmResult::default();
+    let mut stale: Vec<&str> = Vec::new();
+    for id in user_ids {
+        let cached = db::get_cached_user_profile(twitch.db.read(), id).await?;
+        match cached {
+            Some(c) if ttl > 0 && now.saturating_sub(c.updated_at) <= ttl => result.fresh += 1,
+            _ => stale.push(id.as_str()),
+        }
+    }
+
+    for chunk in stale.chunks(MAX_USERS_PER_LOOKUP) {
+        if started.elapsed() >= budget {
+            result.deferred += chunk.len();
+            continue;
+        }
+        match helix_get_users_by_ids(twitch, access_token, chunk).await {
+            Ok(users) => {
+                result.failed += chunk.len().saturating_sub(users.len());
+                for u in users {
+                    let profile = db::CachedUserProfile {
+                        user_id: u.id,
+                        user_login: u.login,
+                        display_name: u.display_name,
+                        profile_image_url: util::checked_profile_image_url(
+                            &u.profile_image_url,
+                            &twitch.settings.twitch.profile_image_hosts,
+                            "helix",
+                        ),
+                        updated_at: now,
+                    };
+                    db::upsert_cached_user_profile(twitch.db.write(), &profile).await?;
+                    result.prewarmed += 1;
+                }
+            }
+            Err(e) => {
+                warn!(error=?e, count = chunk.len(), "user cache prewarm batch failed");
+                result.failed += chunk.len();
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// A Twitch user to look up by id or login.
+#[derive(Debug, Clone, Copy)]
+pub enum UserRef<'a> {
+    Id(&'a str),
+    Login(&'a str),
+}
+
+/// Profile for `user` from the user cache while fresh, else from Helix (refreshing the
+/// cache). Falls back to a stale cache entry when Helix fails or `access_token` is `None`.
+pub async fn resolve_user_cached(
+    twitch: &TwitchClient,
+    access_token: Option<&str>,
+    user: UserRef<'_>,
+) -> anyhow::Result<db::CachedUserProfile> {
+    let now = util::now_epoch();
+    let ttl = twitch.settings.twitch.user_cache_ttl_secs as i64;
+
+    let cached = match user {
+        UserRef::Id(id) => db::get_cached_user_profile(twitch.db.read(), id).await?,
+        UserRef::Login(login) => {
+            db::get_cached_user_profile_by_login(twitch.db.read(), login).await?
+        }
+    };
+    let fresh = cached
+        .as_ref()
+        .is_some_and(|c| ttl > 0 && now.saturating_sub(c.updated_at) <= ttl);
+    let access_token = match access_token {
+        Some(t) if !fresh => t,
+        _ => {
+            return cached
+                .ok_or_else(|| anyhow::anyhow!("user {user:?} is not cached and not logged in"))
+        }
+    };
+
+    let fetched = match user {
+        UserRef::Id(id) => helix_get_user(twitch, access_token, "id", id).await,
+        UserRef::Login(login) => {
+            helix_get_user(twitch, access_token, "login", &login.to_lowercase()).await
+        }
+    };
+    match fetched {
+        Ok(u) => {
+            let profile = db::CachedUserProfile {
+                user_id: u.id,
+                user_login: u.login,
+                display_name: u.display_name,
+                profile_image_url: util::checked_profile_image_url(
+                    &u.profile_image_url,
+                    &twitch.settings.twitch.profile_image_hosts,
+                    "helix",
+                ),
+                updated_at: now,
+            };
+            db::upsert_cached_user_profile(twitch.db.write(), &profile).await?;
+            Ok(profile)
+        }
+        Err(e) => match cached {
+            Some(c) => {
+                warn!(error=?e, ?user, "helix user fetch failed; using cached profile");
+                Ok(c)
+            }
+            None => Err(e),
+        },
+    }
 }
 
-async fn get_profile_image_url_cached(
-    state: &AppState,
+pub async fn get_profile_image_url_cached(
+    twitch: &TwitchClient,
     access_token: &str,
     user_id: &str,
 ) -> anyhow::Result<String> {
     let now = util::now_epoch();
-    let ttl = state.config.twitch.user_cache_ttl_secs as i64;
+    let ttl = twitch.settings.twitch.user_cache_ttl_secs as i64;
 
-    // Grab cache first (also used as fallback if Helix fails)
-    let cached = db::get_cached_user_profile(&state.db, user_id).await?;
+    // Grab cache first (also used as fallback if Helix fails). Rows written before
+    // `profile_image_hosts` existed are checked again here.
+    let cached = db::get_cached_user_profile(twitch.db.read(), user_id)
+        .await?
+        .map(|mut c| {
+            c.profile_image_url = util::checked_profile_image_url(
+                &c.profile_image_url,
+                &twitch.settings.twitch.profile_image_hosts,
+                "user_cache",
+            );
+            c
+        });
     if ttl > 0 {
         if let Some(c) = &cached {
             if now.saturating_sub(c.updated_at) <= ttl {
@@ -211,23 +978,57 @@ async fn get_profile_image_url_cached(
         }
     }
 
-    match helix_get_user_by_id(state, access_token, user_id).await {
+    if !twitch.profile_breaker.lock().unwrap().allows(now) {
+        // Helix has been failing; don't wait on it for every redemption.
+        return Ok(cached.map(|c| c.profile_image_url).unwrap_or_default());
+    }
+
+    let lookup = helix_get_user_by_id(twitch, access_token, user_id);
+    let timeout_ms = twitch.settings.twitch.profile_lookup_timeout_ms;
+    let result = if timeout_ms > 0 {
+        tokio::time::timeout(std::time::Duration::from_millis(timeout_ms), lookup)
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow::anyhow!(
+                    "helix user fetch timed out after {timeout_ms}ms"
+                ))
+            })
+    } else {
+        lookup.await
+    };
+    match result {
         Ok(u) => {
+            twitch.profile_breaker.lock().unwrap().record_success();
             // Upsert cache
             let profile = db::CachedUserProfile {
                 user_id: u.id,
                 user_login: u.login,
                 display_name: u.display_name,
-                profile_image_url: u.profile_image_url.clone(),
+                profile_image_url: util::checked_profile_image_url(
+                    &u.profile_image_url,
+                    &twitch.settings.twitch.profile_image_hosts,
+                    "helix",
+                ),
                 updated_at: now,
             };
             // Best-effort cache write (should not block enqueue)
-            if let Err(e) = db::upsert_cached_user_profile(&state.db, &profile).await {
+            if let Err(e) = db::upsert_cached_user_profile(twitch.db.write(), &profile).await {
                 warn!(error=?e, user_id=%user_id, "failed to upsert user cache");
             }
             Ok(profile.profile_image_url)
         }
         Err(e) => {
+            if twitch
+                .profile_breaker
+                .lock()
+                .unwrap()
+                .record_failure(util::now_epoch())
+            {
+                warn!(
+                    cooldown_secs = twitch.settings.twitch.profile_breaker_cooldown_secs,
+                    "helix user fetch keeps failing; skipping profile lookups for a while"
+                );
+            }
             if let Some(c) = cached {
                 warn!(error=?e, user_id=%user_id, "helix user fetch failed; using cached profile_image_url");
                 Ok(c.profile_image_url)
@@ -239,19 +1040,20 @@ async fn get_profile_image_url_cached(
 }
 
 pub async fn helix_get_custom_rewards(
-    state: &AppState,
+    twitch: &TwitchClient,
     access_token: &str,
     broadcaster_id: &str,
 ) -> anyhow::Result<Vec<HelixReward>> {
-    let mut url = Url::parse(&format!("{HELIX_ENDPOINT}/channel_points/custom_rewards"))?;
+    let mut url = Url::parse(&twitch.helix("/channel_points/custom_rewards"))?;
     url.query_pairs_mut()
         .append_pair("broadcaster_id", broadcaster_id)
         .append_pair("only_manageable_rewards", "false");
 
-    let resp = state
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
         .http
         .get(url)
-        .header("Client-Id", &state.config.twitch.client_id)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
         .header("Authorization", format!("Bearer {access_token}"))
         .send()
         .await?
@@ -261,28 +1063,488 @@ pub async fn helix_get_custom_rewards(
     Ok(data.data)
 }
 
-// --- EventSub subscription maintenance -------------------------------------
+#[derive(Debug, Clone, Deserialize)]
+pub struct HelixVideo {
+    pub url: String,
+    /// RFC 3339; for an archive still being recorded, when the stream started.
+    pub created_at: String,
+}
 
-#[derive(Debug, Deserialize)]
-struct HelixEventSubListResponse {
-    #[serde(default)]
-    data: Vec<HelixEventSubSubscription>,
-    #[serde(default)]
-    pagination: HelixPagination,
+/// The broadcaster's newest archive (past broadcast), which is the one being
+/// recorded while live. `None` when the channel keeps no VODs.
+pub async fn helix_get_latest_archive(
+    twitch: &TwitchClient,
+    access_token: &str,
+    broadcaster_id: &str,
+) -> anyhow::Result<Option<HelixVideo>> {
+    let mut url = Url::parse(&twitch.helix("/videos"))?;
+    url.query_pairs_mut()
+        .append_pair("user_id", broadcaster_id)
+        .append_pair("type", "archive")
+        .append_pair("first", "1");
+
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .get(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let data: HelixResponse<HelixVideo> = resp.json().await?;
+    Ok(data.data.into_iter().next())
 }
 
-#[derive(Debug, Default, Deserialize)]
-struct HelixPagination {
-    #[serde(default)]
-    cursor: Option<String>,
+#[derive(Debug, Serialize)]
+struct UpdateRewardRequest<'a> {
+    prompt: &'a str,
 }
 
-#[derive(Debug, Deserialize)]
-struct HelixEventSubSubscription {
-    id: String,
-    status: String,
-    #[serde(rename = "type")]
-    typ: String,
+pub async fn helix_update_reward_prompt(
+    twitch: &TwitchClient,
+    access_token: &str,
+    broadcaster_id: &str,
+    reward_id: &str,
+    prompt: &str,
+) -> anyhow::Result<()> {
+    let mut url = Url::parse(&twitch.helix("/channel_points/custom_rewards"))?;
+    url.query_pairs_mut()
+        .append_pair("broadcaster_id", broadcaster_id)
+        .append_pair("id", reward_id);
+
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .patch(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .json(&UpdateRewardRequest { prompt })
+        .send()
+        .await?;
+
+    let code = resp.status();
+    if code == reqwest::StatusCode::UNAUTHORIZED || code == reqwest::StatusCode::FORBIDDEN {
+        anyhow::bail!(
+            "reward prompt update not permitted ({code}); it needs the {MANAGE_REDEMPTIONS_SCOPE} scope \
+             (log in again) and a reward created by this client_id"
+        );
+    }
+    if !code.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        anyhow::bail!("update reward prompt failed: {code} {body}");
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct PauseRewardRequest {
+    is_paused: bool,
+}
+
+pub async fn helix_set_reward_paused(
+    twitch: &TwitchClient,
+    access_token: &str,
+    broadcaster_id: &str,
+    reward_id: &str,
+    paused: bool,
+) -> anyhow::Result<()> {
+    let mut url = Url::parse(&twitch.helix("/channel_points/custom_rewards"))?;
+    url.query_pairs_mut()
+        .append_pair("broadcaster_id", broadcaster_id)
+        .append_pair("id", reward_id);
+
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .patch(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .json(&PauseRewardRequest { is_paused: paused })
+        .send()
+        .await?;
+
+    let code = resp.status();
+    if code == reqwest::StatusCode::UNAUTHORIZED || code == reqwest::StatusCode::FORBIDDEN {
+        anyhow::bail!(
+            "reward pause not permitted ({code}); it needs the {MANAGE_REDEMPTIONS_SCOPE} scope \
+             (log in again) and a reward created by this client_id"
+        );
+    }
+    if !code.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        anyhow::bail!("pause reward failed: {code} {body}");
+    }
+
+    Ok(())
+}
+
+/// Pauses (or unpauses) every join reward on Twitch for `twitch.pause_rewards_with_queue`.
+/// Keeps going past a failing reward; returns how many could not be updated.
+pub async fn set_join_rewards_paused(twitch: &TwitchClient, paused: bool) -> anyhow::Result<usize> {
+    let access_token = get_fresh_access_token(twitch).await?;
+    let Some(broadcaster_id) = db::get_broadcaster_id(twitch.db.read()).await? else {
+        anyhow::bail!("broadcaster_id is not known yet");
+    };
+
+    let mut ids: Vec<&str> = twitch
+        .settings
+        .twitch
+        .target_reward_ids
+        .iter()
+        .map(|r| r.trim())
+        .filter(|r| !r.is_empty())
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut failed = 0;
+    for reward_id in ids {
+        if let Err(e) =
+            helix_set_reward_paused(twitch, &access_token, &broadcaster_id, reward_id, paused).await
+        {
+            warn!(error = ?e, reward_id = %reward_id, paused, "failed to update reward pause state");
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+#[derive(Debug, Serialize)]
+struct SendChatMessageRequest<'a> {
+    broadcaster_id: &'a str,
+    sender_id: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_parent_message_id: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SentChatMessage {
+    is_sent: bool,
+    #[serde(default)]
+    drop_reason: Option<serde_json::Value>,
+}
+
+/// Helix answered a chat message with an error status; see [`falls_back_to_irc`].
+#[derive(Debug, thiserror::Error)]
+#[error("send chat message failed: {status} {body}")]
+pub struct ChatSendError {
+    status: reqwest::StatusCode,
+    body: String,
+}
+
+/// Which way a chat message went out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatTransport {
+    Helix,
+    Irc,
+}
+
+impl ChatTransport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChatTransport::Helix => "helix",
+            ChatTransport::Irc => "irc",
+        }
+    }
+}
+
+/// The broadcaster's chat, by id (Helix) and login (IRC).
+#[derive(Debug, Clone, Copy)]
+pub struct ChatChannel<'a> {
+    pub broadcaster_id: &'a str,
+    pub broadcaster_login: &'a str,
+}
+
+/// Posts to chat through Helix, and over IRC when Helix fails with a 5xx and
+/// `chat.irc_fallback` is set. Client errors (scope, bans, bad input) are not retried.
+pub async fn send_chat_message(
+    twitch: &TwitchClient,
+    access_token: &str,
+    channel: ChatChannel<'_>,
+    message: &str,
+    reply_parent_message_id: Option<&str>,
+) -> anyhow::Result<ChatTransport> {
+    let helix = helix_send_chat_message(
+        twitch,
+        access_token,
+        channel.broadcaster_id,
+        message,
+        reply_parent_message_id,
+    )
+    .await;
+    let err = match helix {
+        Ok(()) => return Ok(ChatTransport::Helix),
+        Err(e) => e,
+    };
+    if !falls_back_to_irc(twitch.settings.chat.irc_fallback, &err) {
+        return Err(err);
+    }
+    warn!(error=?err, "helix chat send failed; falling back to irc");
+    twitch
+        .irc
+        .send(
+            access_token,
+            channel.broadcaster_login,
+            channel.broadcaster_login,
+            message,
+            reply_parent_message_id,
+        )
+        .await?;
+    Ok(ChatTransport::Irc)
+}
+
+/// A failed Helix send goes out over IRC only with `chat.irc_fallback` and only when
+/// Helix answered with a 5xx. A 4xx would fail the same way over IRC, and a request that
+/// got no answer at all says nothing about Helix being down.
+fn falls_back_to_irc(irc_fallback: bool, helix_err: &anyhow::Error) -> bool {
+    irc_fallback
+        && helix_err
+            .downcast_ref::<ChatSendError>()
+            .is_some_and(|e| e.status.is_server_error())
+}
+
+/// Posts `message` to the broadcaster's chat as the broadcaster (needs `user:write:chat`).
+pub async fn helix_send_chat_message(
+    twitch: &TwitchClient,
+    access_token: &str,
+    broadcaster_id: &str,
+    message: &str,
+    reply_parent_message_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let url = twitch.helix("/chat/messages");
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .post(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .json(&SendChatMessageRequest {
+            broadcaster_id,
+            sender_id: broadcaster_id,
+            message,
+            reply_parent_message_id,
+        })
+        .send()
+        .await?;
+
+    let code = resp.status();
+    if !code.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        let err = anyhow::Error::from(ChatSendError { status: code, body });
+        if code == reqwest::StatusCode::UNAUTHORIZED || code == reqwest::StatusCode::FORBIDDEN {
+            return Err(err.context(format!(
+                "send chat message not permitted ({code}); it needs the {WRITE_CHAT_SCOPE} scope (log in again)"
+            )));
+        }
+        return Err(err);
+    }
+
+    let data: HelixResponse<SentChatMessage> = resp.json().await?;
+    if let Some(sent) = data.data.into_iter().next().filter(|m| !m.is_sent) {
+        anyhow::bail!("chat message was dropped: {:?}", sent.drop_reason);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct SendWhisperRequest<'a> {
+    message: &'a str,
+}
+
+/// Whispers `message` to `to_user_id` from the broadcaster (needs `user:manage:whispers`).
+pub async fn helix_send_whisper(
+    twitch: &TwitchClient,
+    access_token: &str,
+    broadcaster_id: &str,
+    to_user_id: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let mut url = Url::parse(&twitch.helix("/whispers"))?;
+    url.query_pairs_mut()
+        .append_pair("from_user_id", broadcaster_id)
+        .append_pair("to_user_id", to_user_id);
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .post(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .json(&SendWhisperRequest { message })
+        .send()
+        .await?;
+
+    let code = resp.status();
+    if code == reqwest::StatusCode::UNAUTHORIZED || code == reqwest::StatusCode::FORBIDDEN {
+        anyhow::bail!("send whisper not permitted ({code}); it needs the {WHISPER_SCOPE} scope (log in again)");
+    }
+    if !code.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        anyhow::bail!("send whisper failed: {code} {body}");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateRedemptionStatusRequest<'a> {
+    status: &'a str,
+}
+
+/// Helix accepts up to this many redemption IDs per status update.
+pub const MAX_REDEMPTIONS_PER_UPDATE: usize = 50;
+
+/// Updates the status of up to [`MAX_REDEMPTIONS_PER_UPDATE`] redemptions of one reward.
+pub async fn helix_update_redemption_status(
+    twitch: &TwitchClient,
+    access_token: &str,
+    broadcaster_id: &str,
+    reward_id: &str,
+    redemption_ids: &[&str],
+    status: outbox::RedemptionStatus,
+) -> anyhow::Result<()> {
+    let mut url = Url::parse(&twitch.helix("/channel_points/custom_rewards/redemptions"))?;
+    {
+        let mut qp = url.query_pairs_mut();
+        for id in redemption_ids {
+            qp.append_pair("id", id);
+        }
+        qp.append_pair("broadcaster_id", broadcaster_id)
+            .append_pair("reward_id", reward_id);
+    }
+
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .patch(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .json(&UpdateRedemptionStatusRequest {
+            status: status.as_str(),
+        })
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        let code = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        anyhow::bail!("update redemption status failed: {code} {body}");
+    }
+
+    Ok(())
+}
+
+// --- EventSub subscription maintenance -------------------------------------
+
+/// `total` / `total_cost` / `max_total_cost`, present on create and list responses.
+#[derive(Debug, Default, Deserialize)]
+struct HelixBudgetFields {
+    #[serde(default)]
+    total: Option<i64>,
+    #[serde(default)]
+    total_cost: Option<i64>,
+    #[serde(default)]
+    max_total_cost: Option<i64>,
+}
+
+/// Latest subscription budget reported by Helix, and the optional subscriptions that
+/// were left out because they would not fit (`/api/status` `eventsub`).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EventSubBudget {
+    pub total: Option<i64>,
+    pub total_cost: Option<i64>,
+    pub max_total_cost: Option<i64>,
+    /// Cost of the last subscription created; new ones are projected at this cost (1 until seen).
+    pub last_subscription_cost: Option<i64>,
+    pub observed_at: Option<i64>,
+    /// One per skipped feature, naming the config key that enables it.
+    pub warnings: Vec<String>,
+}
+
+impl EventSubBudget {
+    fn observe(&mut self, fields: &HelixBudgetFields, now: i64) {
+        if fields.total_cost.is_none() && fields.max_total_cost.is_none() {
+            return;
+        }
+        self.total = fields.total.or(self.total);
+        self.total_cost = fields.total_cost.or(self.total_cost);
+        self.max_total_cost = fields.max_total_cost.or(self.max_total_cost);
+        self.observed_at = Some(now);
+    }
+
+    /// Whether `count` more subscriptions stay within `max_total_cost`. An unknown budget fits.
+    pub fn fits(&self, count: i64) -> bool {
+        match (self.total_cost, self.max_total_cost) {
+            (Some(used), Some(max)) => {
+                used + count * self.last_subscription_cost.unwrap_or(1) <= max
+            }
+            _ => true,
+        }
+    }
+}
+
+fn observe_budget(twitch: &TwitchClient, fields: &HelixBudgetFields) {
+    twitch
+        .eventsub
+        .budget
+        .lock()
+        .unwrap()
+        .observe(fields, util::now_epoch());
+}
+
+/// Helix refused a new subscription because a count or cost limit is reached (429, or a
+/// 4xx that names the cost). Optional features react to this by skipping themselves.
+#[derive(Debug, thiserror::Error)]
+#[error("EventSub subscription limit reached: {status} {body}")]
+pub struct SubscriptionLimitExceeded {
+    status: reqwest::StatusCode,
+    body: String,
+}
+
+fn is_limit_error(status: reqwest::StatusCode, body: &str) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || (status.is_client_error() && body.contains("cost"))
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateSubResponse {
+    #[serde(default)]
+    data: Vec<CreatedSubscription>,
+    #[serde(flatten)]
+    budget: HelixBudgetFields,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreatedSubscription {
+    #[serde(default)]
+    cost: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HelixEventSubListResponse {
+    #[serde(default)]
+    data: Vec<HelixEventSubSubscription>,
+    #[serde(default)]
+    pagination: HelixPagination,
+    #[serde(flatten)]
+    budget: HelixBudgetFields,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HelixPagination {
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HelixEventSubSubscription {
+    id: String,
+    status: String,
+    #[serde(rename = "type")]
+    typ: String,
     condition: serde_json::Value,
     transport: HelixEventSubTransport,
 }
@@ -299,7 +1561,7 @@ struct HelixEventSubTransport {
 }
 
 async fn helix_list_eventsub_subscriptions_by_type(
-    state: &AppState,
+    twitch: &TwitchClient,
     access_token: &str,
     typ: &str,
 ) -> anyhow::Result<Vec<HelixEventSubSubscription>> {
@@ -307,7 +1569,7 @@ async fn helix_list_eventsub_subscriptions_by_type(
     let mut cursor: Option<String> = None;
 
     for _page in 0..50 {
-        let mut url = Url::parse(&format!("{HELIX_ENDPOINT}/eventsub/subscriptions"))?;
+        let mut url = Url::parse(&twitch.helix("/eventsub/subscriptions"))?;
         {
             let mut qp = url.query_pairs_mut();
             qp.append_pair("type", typ);
@@ -316,10 +1578,11 @@ async fn helix_list_eventsub_subscriptions_by_type(
             }
         }
 
-        let resp = state
+        let _permit = helix_permit(twitch).await?;
+        let resp = twitch
             .http
             .get(url)
-            .header("Client-Id", &state.config.twitch.client_id)
+            .header("Client-Id", &twitch.settings.twitch.client_id)
             .header("Authorization", format!("Bearer {access_token}"))
             .send()
             .await?;
@@ -331,6 +1594,7 @@ async fn helix_list_eventsub_subscriptions_by_type(
 
         let resp = resp.error_for_status()?;
         let body: HelixEventSubListResponse = resp.json().await?;
+        observe_budget(twitch, &body.budget);
 
         out.extend(body.data);
         cursor = body.pagination.cursor;
@@ -343,17 +1607,18 @@ async fn helix_list_eventsub_subscriptions_by_type(
 }
 
 async fn helix_delete_eventsub_subscription(
-    state: &AppState,
+    twitch: &TwitchClient,
     access_token: &str,
     id: &str,
 ) -> anyhow::Result<()> {
-    let mut url = Url::parse(&format!("{HELIX_ENDPOINT}/eventsub/subscriptions"))?;
+    let mut url = Url::parse(&twitch.helix("/eventsub/subscriptions"))?;
     url.query_pairs_mut().append_pair("id", id);
 
-    let resp = state
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
         .http
         .delete(url)
-        .header("Client-Id", &state.config.twitch.client_id)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
         .header("Authorization", format!("Bearer {access_token}"))
         .send()
         .await?;
@@ -371,11 +1636,13 @@ async fn helix_delete_eventsub_subscription(
 }
 
 async fn cleanup_stale_websocket_redemption_subscriptions(
-    state: &AppState,
+    twitch: &TwitchClient,
     access_token: &str,
     broadcaster_id: &str,
 ) -> anyhow::Result<u64> {
-    let subs = helix_list_eventsub_subscriptions_by_type(state, access_token, SUB_TYPE_REDEMPTION_ADD).await?;
+    let subs =
+        helix_list_eventsub_subscriptions_by_type(twitch, access_token, SUB_TYPE_REDEMPTION_ADD)
+            .await?;
     let mut deleted = 0u64;
 
     for s in subs {
@@ -401,7 +1668,7 @@ async fn cleanup_stale_websocket_redemption_subscriptions(
         }
 
         debug!(sub_id=%s.id, status=%s.status, session_id=?s.transport.session_id, connected_at=?s.transport.connected_at, disconnected_at=?s.transport.disconnected_at, "deleting stale websocket subscription");
-        helix_delete_eventsub_subscription(state, access_token, &s.id).await?;
+        helix_delete_eventsub_subscription(twitch, access_token, &s.id).await?;
         deleted += 1;
     }
 
@@ -432,22 +1699,75 @@ struct SessionInfo {
     id: String,
     #[serde(default)]
     reconnect_url: Option<String>,
+    /// Twitch sends a message (at least a keepalive) within this many seconds.
+    #[serde(default)]
+    keepalive_timeout_seconds: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 struct NotificationPayload {
-    subscription: serde_json::Value,
     event: RedemptionEvent,
 }
 
 #[derive(Debug, Deserialize)]
 struct RedemptionEvent {
+    /// Redemption ID
+    id: String,
     user_id: String,
     user_login: String,
     user_name: String,
+    /// Text the viewer typed; empty for rewards without input.
+    #[serde(default)]
+    user_input: String,
+    /// RFC 3339 with a fraction (e.g. `2024-05-01T12:00:03.171067Z`).
+    #[serde(default)]
+    redeemed_at: String,
     reward: RewardInfo,
 }
 
+#[derive(Debug, Deserialize)]
+struct StreamOnlineNotificationPayload {
+    event: StreamOnlineEvent,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamOnlineEvent {
+    /// RFC 3339, when the stream actually started (the notification can arrive later).
+    started_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RaidNotificationPayload {
+    event: RaidEvent,
+}
+
+#[derive(Debug, Deserialize)]
+struct RaidEvent {
+    from_broadcaster_user_login: String,
+    viewers: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatNotificationPayload {
+    event: ChatMessageEvent,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessageEvent {
+    broadcaster_user_id: String,
+    broadcaster_user_login: String,
+    message_id: String,
+    chatter_user_id: String,
+    chatter_user_login: String,
+    chatter_user_name: String,
+    message: ChatMessageText,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessageText {
+    text: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct RewardInfo {
     id: String,
@@ -466,9 +1786,16 @@ struct CreateSubRequest<'a> {
 
 #[derive(Debug, Serialize)]
 struct SubCondition<'a> {
-    broadcaster_user_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broadcaster_user_id: Option<&'a str>,
+    /// channel.raid: raids into this channel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_broadcaster_user_id: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     reward_id: Option<&'a str>,
+    /// channel.chat.message: the user reading the chat (the logged-in broadcaster).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<&'a str>,
 }
 
 #[derive(Debug, Serialize)]
@@ -479,13 +1806,24 @@ struct SubTransport<'a> {
 
 #[derive(Debug, Clone)]
 struct RedemptionRoutingConfig {
+    /// `"redemption"` is in `queue.enqueue_sources`; without it no reward is subscribed to.
+    redemptions: bool,
     join_ids: Vec<String>,
     join_id_set: HashSet<String>,
     cancel_id: Option<String>,
+    /// `twitch.away_reward_id`, unless it collides with a join or the cancel reward.
+    away_id: Option<String>,
+    /// Also subscribe to channel.raid (`twitch.raid_pause_secs`).
+    raid: bool,
+    /// `twitch.chat_join_command`, if chat joins are accepted.
+    chat_command: Option<String>,
+    /// Subscribe to channel.chat.message ([`crate::config::Config::reads_chat`]).
+    chat: bool,
 }
 
 impl RedemptionRoutingConfig {
-    fn from_config(cfg: &crate::config::TwitchConfig) -> Self {
+    fn from_config(config: &crate::config::Config) -> Self {
+        let cfg = &config.twitch;
         let mut join_ids = Vec::new();
         let mut join_id_set = HashSet::new();
 
@@ -512,123 +1850,239 @@ impl RedemptionRoutingConfig {
             Some(cancel_id.to_string())
         };
 
+        let away_id = cfg.away_reward_id.trim();
+        let away_id = if away_id.is_empty() {
+            None
+        } else if join_id_set.contains(away_id) || cancel_id.as_deref() == Some(away_id) {
+            warn!(
+                reward_id = %away_id,
+                "twitch.away_reward_id is also a join or cancel reward; away handling is disabled for safety"
+            );
+            None
+        } else {
+            Some(away_id.to_string())
+        };
+
         Self {
+            redemptions: config
+                .queue
+                .accepts(crate::config::EnqueueSource::Redemption),
             join_ids,
             join_id_set,
             cancel_id,
+            away_id,
+            raid: cfg.raid_pause_secs > 0,
+            chat_command: cfg
+                .chat_join_command()
+                .filter(|_| config.queue.accepts(crate::config::EnqueueSource::Chat))
+                .map(str::to_string),
+            chat: config.reads_chat(),
         }
     }
 
-    fn is_disabled(&self) -> bool {
-        self.join_ids.is_empty() && self.cancel_id.is_none()
+    /// No join reward configured: subscribe to every redemption of the channel so
+    /// the admin page can suggest which reward was meant (see [`run_config_reminder_loop`]).
+    fn is_discovery(&self) -> bool {
+        self.redemptions && self.join_ids.is_empty()
+    }
+
+    /// Whether anything comes in over EventSub: redemptions, chat commands or raids.
+    fn needs_eventsub(&self) -> bool {
+        self.redemptions || self.chat || self.raid
+    }
+
+    /// Reward subscriptions plus the stream.online (channel.raid, channel.chat.message) subscriptions.
+    fn subscription_count(&self) -> usize {
+        let extra = 1 + usize::from(self.raid) + usize::from(self.chat);
+        if !self.redemptions {
+            return extra;
+        }
+        if self.is_discovery() {
+            return 1 + extra;
+        }
+        self.join_ids.len()
+            + usize::from(self.cancel_id.is_some())
+            + usize::from(self.away_id.is_some())
+            + extra
     }
 }
 
-pub async fn run_eventsub_loop(state: Arc<AppState>) -> anyhow::Result<()> {
-    if util::is_blank(&state.config.twitch.client_id) || util::is_blank(&state.config.twitch.client_secret) {
+pub async fn run_eventsub_loop(
+    twitch: Arc<TwitchClient>,
+    queue: QueueService,
+) -> anyhow::Result<()> {
+    if util::is_blank(&queue.settings.twitch.client_id)
+        || util::is_blank(&queue.settings.twitch.client_secret)
+    {
         warn!("twitch.client_id / twitch.client_secret are empty. Set them in config.toml.");
     }
 
-    let routing = RedemptionRoutingConfig::from_config(&state.config.twitch);
+    let routing = RedemptionRoutingConfig::from_config(&queue.settings);
+    if !routing.needs_eventsub() {
+        info!("no redemptions, chat commands or raid pause configured; EventSub is not used");
+        return Ok(());
+    }
+    if !routing.redemptions {
+        info!(
+            "queue.enqueue_sources does not include \"redemption\"; not subscribing to redemptions"
+        );
+    }
     let mut ws_url = Url::parse(EVENTSUB_WS_URL)?;
     let mut need_subscribe = true;
     let mut did_startup_cleanup = false;
-    let mut did_warn_eventsub_disabled = false;
+    let mut did_irc_scope_check = !queue.settings.chat.irc_fallback;
 
-    loop {
-        if routing.is_disabled() {
-            if !did_warn_eventsub_disabled {
-                warn!(
-                    "twitch.target_reward_ids と twitch.cancel_reward_id が未設定なので EventSub は無効です。設定して再起動してください。"
-                );
-                did_warn_eventsub_disabled = true;
-            }
-            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-            continue;
-        }
+    // Keep outages (Twitch down, token broken) from flooding the log
+    let throttle_secs = queue.settings.server.log_throttle_secs;
+    let mut refresh_log = util::LogThrottle::new(throttle_secs);
+    let mut resolve_log = util::LogThrottle::new(throttle_secs);
+    let mut cleanup_log = util::LogThrottle::new(throttle_secs);
+    let mut connect_log = util::LogThrottle::new(throttle_secs);
 
+    loop {
         // We cannot do anything without a token.
-        let Some(mut token) = db::get_oauth_token(&state.db).await? else {
+        let Some(mut token) = db::get_oauth_token(queue.db.read()).await? else {
             tokio::time::sleep(std::time::Duration::from_secs(2)).await;
             continue;
         };
 
         // Refresh if close to expiry
         if token.expires_at <= util::now_epoch() + 60 {
-            match refresh_access_token(&state, &token.refresh_token).await {
+            match refresh_and_store_token(&twitch, &token.refresh_token).await {
                 Ok(new_token) => {
-                    db::upsert_oauth_token(&state.db, &new_token).await?;
                     token = new_token;
+                    refresh_log.reset();
                     info!("refreshed twitch access token");
                 }
                 Err(e) => {
-                    warn!(error = ?e, "failed to refresh token; need re-auth");
+                    if let Some(suppressed) = refresh_log.hit(util::now_epoch()) {
+                        warn!(error = ?e, suppressed, "failed to refresh token; need re-auth");
+                    }
                     tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                     continue;
                 }
             }
         }
 
+        // A login as another account must be confirmed before we subscribe with it.
+        if db::get_pending_broadcaster_switch(queue.db.read())
+            .await?
+            .is_some()
+        {
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            continue;
+        }
+
         // Ensure broadcaster id is known (derived from the authorized user)
-        let broadcaster_id = match db::get_broadcaster_id(&state.db).await? {
+        let broadcaster_id = match db::get_broadcaster_id(queue.db.read()).await? {
             Some(id) => id,
-            None => {
-                match helix_get_self(&state, &token.access_token).await {
-                    Ok(me) => {
-                        db::set_broadcaster_id(&state.db, &me.id).await?;
-                        db::set_broadcaster_login(&state.db, &me.login).await?;
-                        info!(broadcaster_id = %me.id, broadcaster_login = %me.login, "resolved broadcaster");
-                        me.id
-                    }
-                    Err(e) => {
-                        warn!(error = ?e, "failed to resolve broadcaster; waiting");
-                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                        continue;
+            None => match helix_get_self(&twitch, &token.access_token).await {
+                Ok(me) => {
+                    db::set_broadcaster_id(queue.db.write(), &me.id).await?;
+                    db::set_broadcaster_login(queue.db.write(), &me.login).await?;
+                    resolve_log.reset();
+                    info!(broadcaster_id = %me.id, broadcaster_login = %me.login, "resolved broadcaster");
+                    me.id
+                }
+                Err(e) => {
+                    if let Some(suppressed) = resolve_log.hit(util::now_epoch()) {
+                        warn!(error = ?e, suppressed, "failed to resolve broadcaster; waiting");
                     }
+                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    continue;
                 }
             }
         };
 
         // 起動時1回だけ：disabled な購読を削除（token が取れるまで待つ）
         if !did_startup_cleanup {
-            match cleanup_disabled_ws_subscriptions(&state, &token.access_token).await {
+            match cleanup_disabled_ws_subscriptions(&twitch, &token.access_token).await {
                 Ok(n) => {
                     info!(deleted = n, "startup cleanup: removed disabled EventSub subscriptions");
                     did_startup_cleanup = true;
+                    cleanup_log.reset();
                 }
                 Err(e) => {
                     // 失敗しても致命ではないので、次ループで再トライさせる
-                    warn!(error=?e, "startup cleanup failed; will retry");
+                    if let Some(suppressed) = cleanup_log.hit(util::now_epoch()) {
+                        warn!(error=?e, suppressed, "startup cleanup failed; will retry");
+                    }
                 }
             }
         }
 
+        if !did_irc_scope_check {
+            match crate::irc::check_chat_edit_scope(&twitch, &token.access_token).await {
+                Ok(_) => did_irc_scope_check = true,
+                Err(e) => debug!(error=?e, "irc scope check failed; will retry"),
+            }
+        }
+
         info!(ws = %ws_url, "connecting to EventSub WebSocket");
         let connect = tokio_tungstenite::connect_async(ws_url.as_str()).await;
         let (ws_stream, _resp) = match connect {
-            Ok(x) => x,
+            Ok(x) => {
+                if connect_log.reset() {
+                    info!("websocket connect recovered");
+                }
+                x
+            }
             Err(e) => {
-                warn!(error = ?e, "failed to connect websocket; retrying");
+                if let Some(suppressed) = connect_log.hit(util::now_epoch()) {
+                    warn!(error = ?e, suppressed, "failed to connect websocket; retrying");
+                }
                 tokio::time::sleep(std::time::Duration::from_secs(3)).await;
                 continue;
             }
         };
 
         let (mut write, mut read) = ws_stream.split();
+        record_eventsub_event(&twitch, EventSubEventKind::Connected, None).await;
 
         // If we receive a session_reconnect message, we should connect to the given URL.
         // In that case, subscriptions are migrated automatically and we must NOT recreate them.
         let mut received_reconnect = false;
+        let mut disconnect_detail = "stream ended".to_string();
+        // Set from the welcome; any message resets the deadline.
+        let mut keepalive: Option<std::time::Duration> = None;
+        let mut keepalive_deadline: Option<tokio::time::Instant> = None;
 
         // Read loop
-        while let Some(msg) = read.next().await {
+        loop {
+            let keepalive_expired = async {
+                match keepalive_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let next = tokio::select! {
+                next = read.next() => next,
+                _ = keepalive_expired => {
+                    warn!(timeout = ?keepalive, "no EventSub message within the keepalive timeout; reconnecting");
+                    record_eventsub_event(&twitch, EventSubEventKind::KeepaliveTimeout, None).await;
+                    disconnect_detail = "keepalive timeout".to_string();
+                    break;
+                }
+                _ = twitch.eventsub.restart.notified() => {
+                    info!("EventSub restart requested; dropping session");
+                    disconnect_detail = "restart requested".to_string();
+                    break;
+                }
+            };
+            let Some(msg) = next else {
+                break;
+            };
             let msg = match msg {
                 Ok(m) => m,
                 Err(e) => {
                     warn!(error = ?e, "websocket read error");
+                    disconnect_detail = format!("read error: {e}");
                     break;
                 }
             };
+            // Twitch allows a little slack on top of the advertised timeout.
+            keepalive_deadline = keepalive
+                .map(|k| tokio::time::Instant::now() + k + std::time::Duration::from_secs(5));
 
             match msg {
                 Message::Text(text) => {
@@ -640,34 +2094,62 @@ pub async fn run_eventsub_loop(state: Arc<AppState>) -> anyhow::Result<()> {
                         }
                     };
 
-                    match env.metadata.message_type.as_str() {
-                        "session_welcome" => {
-                            let payload: SessionWelcomePayload = serde_json::from_value(env.payload)?;
-                            info!(session_id = %payload.session.id, "eventsub session welcome");
-
-                            if need_subscribe {
-                                if let Err(e) = create_redemption_subscription(
-                                    &state,
-                                    &token.access_token,
-                                    &payload.session.id,
-                                    &broadcaster_id,
-                                    &routing,
-                                )
-                                .await
-                                {
+                    let message = EventSubMessage::from_envelope(env)?;
+                    if let EventSubMessage::Welcome(session) = &message {
+                        keepalive = session
+                            .keepalive_timeout_seconds
+                            .map(std::time::Duration::from_secs);
+                    }
+
+                    match next_ws_action(message, need_subscribe) {
+                        WsAction::Continue => {}
+                        WsAction::Subscribe { session_id } => {
+                            info!(session_id = %session_id, "eventsub session welcome");
+                            record_eventsub_event(&twitch, EventSubEventKind::Welcome, None).await;
+                            match create_redemption_subscription(
+                                &twitch,
+                                &token.access_token,
+                                &session_id,
+                                &broadcaster_id,
+                                &routing,
+                            )
+                            .await
+                            {
+                                Err(e) => {
                                     warn!(error = ?e, "failed to create subscription");
-                                } else {
-                                    info!("created subscription(s)");
+                                    record_eventsub_event(
+                                        &twitch,
+                                        EventSubEventKind::SubscribeFailed,
+                                        Some(format!("{e:#}")),
+                                    )
+                                    .await;
+                                }
+                                Ok(created) => {
+                                    info!(
+                                        count = created,
+                                        planned = routing.subscription_count(),
+                                        "created subscription(s)"
+                                    );
+                                    record_eventsub_event(
+                                        &twitch,
+                                        EventSubEventKind::SubscribeOk,
+                                        None,
+                                    )
+                                    .await;
+                                    twitch
+                                        .eventsub
+                                        .subscription_count
+                                        .store(created, Ordering::Relaxed);
                                     need_subscribe = false;
 
                                     // Best-effort cleanup of stale/disconnected subscriptions.
                                     // Do this AFTER subscribing so we don't risk missing the 10s subscribe window.
-                                    let state2 = Arc::clone(&state);
+                                    let twitch2 = Arc::clone(&twitch);
                                     let access_token2 = token.access_token.clone();
                                     let broadcaster_id2 = broadcaster_id.clone();
                                     tokio::spawn(async move {
                                         match cleanup_stale_websocket_redemption_subscriptions(
-                                            state2.as_ref(),
+                                            &twitch2,
                                             &access_token2,
                                             &broadcaster_id2,
                                         )
@@ -679,113 +2161,64 @@ pub async fn run_eventsub_loop(state: Arc<AppState>) -> anyhow::Result<()> {
                                         }
                                     });
                                 }
-                            } else {
-                                // On session_reconnect, subscriptions are migrated automatically.
-                                info!("reconnected; keeping existing subscriptions");
                             }
                         }
-                        "session_keepalive" => {
-                            // nothing
+                        WsAction::KeepSubscriptions { session_id } => {
+                            info!(session_id = %session_id, "eventsub session welcome");
+                            // On session_reconnect, subscriptions are migrated automatically.
+                            info!("reconnected; keeping existing subscriptions");
+                            record_eventsub_event(
+                                &twitch,
+                                EventSubEventKind::Welcome,
+                                Some(WELCOME_MIGRATED.to_string()),
+                            )
+                            .await;
                         }
-                        "notification" => {
-                            if env.metadata.subscription_type.as_deref() != Some(SUB_TYPE_REDEMPTION_ADD)
-                            {
-                                continue;
-                            }
-
-                            // Dedup (EventSub can resend a message_id)
-                            let already = db::is_processed_message(&state.db, &env.metadata.message_id).await?;
-                            if already {
-                                debug!(message_id = %env.metadata.message_id, "duplicate notification ignored");
-                                continue;
-                            }
-                            db::mark_processed_message(&state.db, &env.metadata.message_id, util::now_epoch()).await?;
-
-                            let payload: NotificationPayload = match serde_json::from_value(env.payload) {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    warn!(error=?e, "failed to parse notification payload");
-                                    continue;
-                                }
-                            };
-
-                            let reward_id = payload.event.reward.id.as_str();
-
-                            if !routing.join_id_set.contains(reward_id) {
-                                if routing.cancel_id.as_deref() == Some(reward_id) {
-                                    let canceled = queue::cancel_by_user_id(&state.db, &payload.event.user_id).await?;
-                                    if canceled {
-                                        info!(user_id=%payload.event.user_id, reward_id=%payload.event.reward.id, "canceled queued user by redemption");
-                                    } else {
-                                        debug!(user_id=%payload.event.user_id, reward_id=%payload.event.reward.id, "cancel redemption ignored; user not in queue");
-                                    }
-                                } else {
-                                    debug!(reward_id=%payload.event.reward.id, title=%payload.event.reward.title, "non-target reward ignored");
-                                }
-                                continue;
-                            }
-
-                            // If already queued, ignore without hitting Helix.
-                            if queue::is_user_queued(&state.db, &payload.event.user_id).await? {
-                                info!(user_id=%payload.event.user_id, "already queued; ignoring redemption");
-                                continue;
-                            }
-
-                            // Get profile image (cached)
-                            let profile_image_url = match get_profile_image_url_cached(
-                                &state,
+                        WsAction::Dispatch {
+                            message_id,
+                            notification,
+                        } => {
+                            handle_notification(
+                                &twitch,
+                                &queue,
                                 &token.access_token,
-                                &payload.event.user_id,
+                                &routing,
+                                &message_id,
+                                notification,
                             )
-                            .await
-                            {
-                                Ok(url) => url,
-                                Err(e) => {
-                                    warn!(error=?e, user_id=%payload.event.user_id, "failed to resolve user profile_image_url");
-                                    continue;
-                                }
-                            };
-
-                            let new_user = queue::NewQueueUser {
-                                user_id: payload.event.user_id,
-                                user_login: payload.event.user_login,
-                                display_name: payload.event.user_name,
-                                profile_image_url,
-                            };
-
-                            let win = state.config.queue.participation_window_secs as i64;
-                            match queue::enqueue_user(&state.db, win, new_user).await {
-                                Ok(queue::EnqueueOutcome::AlreadyQueued) => {
-                                    info!("already queued; ignoring redemption");
-                                }
-                                Ok(queue::EnqueueOutcome::Added { id, position }) => {
-                                    info!(queue_id=%id, position, "enqueued user");
-                                }
-                                Err(e) => {
-                                    error!(error=?e, "failed to enqueue");
-                                }
-                            }
+                            .await?;
                         }
-                        "session_reconnect" => {
+                        WsAction::SwitchUrl(url) => {
                             // reconnect_url includes existing subscriptions
-                            let payload: SessionWelcomePayload = serde_json::from_value(env.payload)?;
-                            let Some(url) = payload.session.reconnect_url else {
-                                warn!("session_reconnect without reconnect_url");
-                                break;
-                            };
                             info!(reconnect_url=%url, "received session_reconnect");
+                            record_eventsub_event(
+                                &twitch,
+                                EventSubEventKind::ReconnectReceived,
+                                None,
+                            )
+                            .await;
                             ws_url = Url::parse(&url)?;
                             // keep need_subscribe=false (subs are migrated)
                             received_reconnect = true;
                             break;
                         }
-                        "revocation" => {
+                        WsAction::Disconnect => {
+                            warn!("session_reconnect without reconnect_url");
+                            disconnect_detail =
+                                "session_reconnect without reconnect_url".to_string();
+                            break;
+                        }
+                        WsAction::Resubscribe => {
                             warn!("subscription revoked (token revoked or user no longer exists). Re-auth required.");
                             // Force resubscribe after re-auth
                             need_subscribe = true;
+                            twitch
+                                .eventsub
+                                .subscription_count
+                                .store(0, Ordering::Relaxed);
                         }
-                        other => {
-                            debug!(message_type=%other, "unhandled ws message");
+                        WsAction::Unhandled(message_type) => {
+                            debug!(message_type=%message_type, "unhandled ws message");
                         }
                     }
                 }
@@ -795,6 +2228,7 @@ pub async fn run_eventsub_loop(state: Arc<AppState>) -> anyhow::Result<()> {
                 }
                 Message::Close(frame) => {
                     info!(?frame, "websocket closed");
+                    disconnect_detail = close_detail(frame.as_ref());
                     break;
                 }
                 _ => {}
@@ -805,132 +2239,2742 @@ pub async fn run_eventsub_loop(state: Arc<AppState>) -> anyhow::Result<()> {
         // it's a disconnect -> subscriptions need to be recreated in a NEW session.
         // (If we *did* receive session_reconnect, Twitch migrates subscriptions automatically.)
         if !received_reconnect {
+            record_eventsub_event(
+                &twitch,
+                EventSubEventKind::Disconnected,
+                Some(disconnect_detail),
+            )
+            .await;
             need_subscribe = true;
+            twitch
+                .eventsub
+                .subscription_count
+                .store(0, Ordering::Relaxed);
             ws_url = Url::parse(EVENTSUB_WS_URL)?;
         }
         tokio::time::sleep(std::time::Duration::from_secs(2)).await;
     }
 }
 
-async fn create_redemption_subscription(
-    state: &AppState,
+/// Disconnect detail recorded for a close frame, e.g. `close 4003: connection unused`.
+fn close_detail(
+    frame: Option<&tokio_tungstenite::tungstenite::protocol::CloseFrame<'_>>,
+) -> String {
+    match frame {
+        Some(f) => format!("close {}: {}", u16::from(f.code), f.reason),
+        None => "close".to_string(),
+    }
+}
+
+/// A parsed EventSub WebSocket message. Payload parsing happens here so the
+/// decision and the side effects below never touch raw JSON.
+#[derive(Debug)]
+enum EventSubMessage {
+    Welcome(SessionInfo),
+    Keepalive,
+    Notification {
+        message_id: String,
+        notification: Notification,
+    },
+    Reconnect(SessionInfo),
+    Revocation,
+    Unknown(String),
+}
+
+#[derive(Debug)]
+enum Notification {
+    /// `started_at` as epoch seconds; `None` when it did not parse.
+    StreamOnline {
+        started_at: Option<i64>,
+    },
+    Raid(RaidEvent),
+    RedemptionAdd(RedemptionEvent),
+    ChatMessage(ChatMessageEvent),
+    /// A redemption whose payload could not be parsed; still deduplicated.
+    MalformedRedemption(serde_json::Error),
+    Other,
+}
+
+impl EventSubMessage {
+    fn from_envelope(env: WsEnvelope) -> anyhow::Result<Self> {
+        let msg = match env.metadata.message_type.as_str() {
+            "session_welcome" => {
+                let payload: SessionWelcomePayload = serde_json::from_value(env.payload)?;
+                EventSubMessage::Welcome(payload.session)
+            }
+            "session_keepalive" => EventSubMessage::Keepalive,
+            "notification" => {
+                let notification = match env.metadata.subscription_type.as_deref() {
+                    Some(SUB_TYPE_STREAM_ONLINE) => Notification::StreamOnline {
+                        started_at: serde_json::from_value::<StreamOnlineNotificationPayload>(
+                            env.payload,
+                        )
+                        .ok()
+                        .and_then(|p| util::parse_utc_datetime(&p.event.started_at)),
+                    },
+                    Some(SUB_TYPE_CHANNEL_RAID) => {
+                        match serde_json::from_value::<RaidNotificationPayload>(env.payload) {
+                            Ok(p) => Notification::Raid(p.event),
+                            Err(_) => Notification::Other,
+                        }
+                    }
+                    Some(SUB_TYPE_CHAT_MESSAGE) => {
+                        match serde_json::from_value::<ChatNotificationPayload>(env.payload) {
+                            Ok(p) => Notification::ChatMessage(p.event),
+                            Err(_) => Notification::Other,
+                        }
+                    }
+                    Some(SUB_TYPE_REDEMPTION_ADD) => {
+                        match serde_json::from_value::<NotificationPayload>(env.payload) {
+                            Ok(p) => Notification::RedemptionAdd(p.event),
+                            Err(e) => Notification::MalformedRedemption(e),
+                        }
+                    }
+                    _ => Notification::Other,
+                };
+                EventSubMessage::Notification {
+                    message_id: env.metadata.message_id,
+                    notification,
+                }
+            }
+            "session_reconnect" => {
+                let payload: SessionWelcomePayload = serde_json::from_value(env.payload)?;
+                EventSubMessage::Reconnect(payload.session)
+            }
+            "revocation" => EventSubMessage::Revocation,
+            other => EventSubMessage::Unknown(other.to_string()),
+        };
+        Ok(msg)
+    }
+}
+
+/// What the read loop should do with a message.
+#[derive(Debug)]
+enum WsAction {
+    Continue,
+    Subscribe {
+        session_id: String,
+    },
+    KeepSubscriptions {
+        session_id: String,
+    },
+    Dispatch {
+        message_id: String,
+        notification: Notification,
+    },
+    SwitchUrl(String),
+    Disconnect,
+    Resubscribe,
+    Unhandled(String),
+}
+
+/// Connection-control decision. Pure: all side effects live in the read loop and
+/// [`handle_notification`].
+fn next_ws_action(message: EventSubMessage, need_subscribe: bool) -> WsAction {
+    match message {
+        EventSubMessage::Welcome(session) if need_subscribe => WsAction::Subscribe {
+            session_id: session.id,
+        },
+        EventSubMessage::Welcome(session) => WsAction::KeepSubscriptions {
+            session_id: session.id,
+        },
+        EventSubMessage::Keepalive => WsAction::Continue,
+        EventSubMessage::Notification {
+            message_id,
+            notification,
+        } => WsAction::Dispatch {
+            message_id,
+            notification,
+        },
+        EventSubMessage::Reconnect(session) => match session.reconnect_url {
+            Some(url) => WsAction::SwitchUrl(url),
+            None => WsAction::Disconnect,
+        },
+        EventSubMessage::Revocation => WsAction::Resubscribe,
+        EventSubMessage::Unknown(message_type) => WsAction::Unhandled(message_type),
+    }
+}
+
+async fn handle_notification(
+    twitch: &TwitchClient,
+    queue: &QueueService,
     access_token: &str,
-    session_id: &str,
-    broadcaster_id: &str,
     routing: &RedemptionRoutingConfig,
+    message_id: &str,
+    notification: Notification,
 ) -> anyhow::Result<()> {
-    for reward_id in &routing.join_ids {
-        create_redemption_subscription_with_reward(
-            state,
-            access_token,
-            session_id,
-            broadcaster_id,
-            reward_id,
-        )
-        .await?;
+    let event = match notification {
+        Notification::StreamOnline { started_at } => {
+            // Boundary for "entered during a previous session"
+            let at = started_at.unwrap_or_else(|| {
+                warn!("stream.online without a usable started_at; using the arrival time");
+                util::now_epoch()
+            });
+            db::set_stream_online_at(queue.db.write(), at).await?;
+            info!(started_at = at, "stream went online");
+            return Ok(());
+        }
+        Notification::Raid(raid) => {
+            let secs = queue.settings.twitch.raid_pause_secs as i64;
+            twitch
+                .eventsub
+                .raid_paused_until
+                .store(util::now_epoch() + secs, Ordering::Relaxed);
+            info!(
+                from = %raid.from_broadcaster_user_login,
+                viewers = raid.viewers,
+                pause_secs = secs,
+                "channel raided; enqueue paused"
+            );
+            return Ok(());
+        }
+        Notification::Other => return Ok(()),
+        Notification::ChatMessage(msg) => {
+            if let Some(args) = parse_chat_command(&msg.message.text, CHAT_PREFS_COMMAND) {
+                if !claim_message(queue, message_id).await? {
+                    return Ok(());
+                }
+                return handle_chat_prefs(queue, &msg, &args).await;
+            }
+            let text = &msg.message.text;
+            let chat = &queue.settings.chat;
+            if chat
+                .leave_command()
+                .is_some_and(|leave| parse_chat_command(text, leave).is_some())
+            {
+                if !claim_message(queue, message_id).await? {
+                    return Ok(());
+                }
+                return handle_chat_leave(twitch, queue, &msg).await;
+            }
+            if chat
+                .position_command()
+                .is_some_and(|position| parse_chat_command(text, position).is_some())
+            {
+                if !claim_message(queue, message_id).await? {
+                    return Ok(());
+                }
+                return handle_chat_position(twitch, queue, access_token, &msg).await;
+            }
+            let Some(user_input) = routing
+                .chat_command
+                .as_deref()
+                .and_then(|join| parse_chat_command(text, join))
+            else {
+                return Ok(());
+            };
+            if !claim_message(queue, message_id).await? {
+                return Ok(());
+            }
+            return handle_chat_join(twitch, queue, access_token, msg, user_input).await;
+        }
+        Notification::RedemptionAdd(event) => Ok(event),
+        Notification::MalformedRedemption(e) => Err(e),
+    };
+
+    if !claim_message(queue, message_id).await? {
+        return Ok(());
     }
 
-    if let Some(cancel_reward_id) = routing.cancel_id.as_deref() {
-        create_redemption_subscription_with_reward(
-            state,
-            access_token,
-            session_id,
-            broadcaster_id,
-            cancel_reward_id,
-        )
-        .await?;
+    match event {
+        Ok(event) => handle_redemption(twitch, queue, access_token, routing, event).await,
+        Err(e) => {
+            warn!(error=?e, "failed to parse notification payload");
+            Ok(())
+        }
+    }
+}
+
+/// Records `message_id` as processed; false if it was already (EventSub can resend a message_id).
+async fn claim_message(queue: &QueueService, message_id: &str) -> anyhow::Result<bool> {
+    // On the writer, behind the mark of a resend that is still queued there.
+    let already = db::is_processed_message(queue.db.write(), message_id).await?;
+    if already {
+        debug!(message_id = %message_id, "duplicate notification ignored");
+        return Ok(false);
     }
+    db::mark_processed_message(queue.db.write(), message_id, util::now_epoch()).await?;
+    queue.processed_sweeper.note_insert(&queue.settings.queue);
+    Ok(true)
+}
 
-    Ok(())
+/// Text after `command` if the message starts with it (case-insensitive, as a whole word).
+fn parse_chat_command(text: &str, command: &str) -> Option<String> {
+    let text = text.trim_start();
+    let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    word.eq_ignore_ascii_case(command)
+        .then(|| rest.trim().to_string())
 }
 
-async fn create_redemption_subscription_with_reward(
-    state: &AppState,
-    access_token: &str,
-    session_id: &str,
-    broadcaster_id: &str,
-    reward_id: &str,
+/// `!queue optout` / `!queue optin`; anything else after `!queue` is ignored.
+async fn handle_chat_prefs(
+    queue: &QueueService,
+    msg: &ChatMessageEvent,
+    args: &str,
 ) -> anyhow::Result<()> {
-    let req = CreateSubRequest {
-        typ: SUB_TYPE_REDEMPTION_ADD,
-        version: "1",
-        condition: SubCondition {
-            broadcaster_user_id: broadcaster_id,
-            reward_id: Some(reward_id),
-        },
-        transport: SubTransport {
-            method: "websocket",
-            session_id,
-        },
+    let on = match args
+        .split_whitespace()
+        .next()
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+    {
+        Some("optout") => false,
+        Some("optin") => true,
+        _ => return Ok(()),
     };
+    prefs::set_all(
+        queue.db.write(),
+        &msg.chatter_user_id,
+        on,
+        util::now_epoch(),
+    )
+    .await?;
+    info!(user_id=%msg.chatter_user_id, notify = on, "viewer updated notification preferences");
+    Ok(())
+}
 
-    let url = format!("{HELIX_ENDPOINT}/eventsub/subscriptions");
-    let resp = state
-        .http
-        .post(url)
-        .header("Client-Id", &state.config.twitch.client_id)
-        .header("Authorization", format!("Bearer {access_token}"))
-        .json(&req)
-        .send()
-        .await?;
+/// `chat.leave_command`: cancels the sender's item (or held entry), refunding its redemption.
+async fn handle_chat_leave(
+    twitch: &TwitchClient,
+    queue: &QueueService,
+    msg: &ChatMessageEvent,
+) -> anyhow::Result<()> {
+    if !chat_cooldown_allows(
+        twitch,
+        "leave",
+        &msg.chatter_user_id,
+        queue.settings.chat.leave_cooldown_secs,
+    ) {
+        debug!(user_id=%msg.chatter_user_id, "repeated chat leave ignored");
+        return Ok(());
+    }
 
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let body = resp.text().await.unwrap_or_default();
-        anyhow::bail!("create subscription failed: {status} {body}");
+    if queue::cancel_by_user_id(queue.db.write(), &queue.timings, &msg.chatter_user_id).await? {
+        info!(user_id=%msg.chatter_user_id, "left the queue from chat");
+    } else {
+        debug!(user_id=%msg.chatter_user_id, "chat leave from a user who is not queued");
+    }
+    Ok(())
+}
+
+/// `chat.position_command`: replies to the sender with their place in the queue.
+async fn handle_chat_position(
+    twitch: &TwitchClient,
+    queue: &QueueService,
+    access_token: &str,
+    msg: &ChatMessageEvent,
+) -> anyhow::Result<()> {
+    let cfg = &queue.settings.chat;
+    if !chat_cooldown_allows(
+        twitch,
+        "position",
+        &msg.chatter_user_id,
+        cfg.position_cooldown_secs,
+    ) {
+        debug!(user_id=%msg.chatter_user_id, "repeated chat position ignored");
+        return Ok(());
     }
 
+    let pos = queue::user_position(queue.db.read(), &msg.chatter_user_id).await?;
+    let reply = match pos.position {
+        Some(position) => cfg
+            .position_reply
+            .replace("{position}", &position.to_string())
+            .replace("{ahead}", &(position - 1).to_string()),
+        None => cfg.position_not_queued_reply.clone(),
+    };
+    let reply = reply
+        .replace("{user}", &msg.chatter_user_name)
+        .replace("{total}", &pos.total.to_string());
+
+    let channel = ChatChannel {
+        broadcaster_id: &msg.broadcaster_user_id,
+        broadcaster_login: &msg.broadcaster_user_login,
+    };
+    match send_chat_message(twitch, access_token, channel, &reply, Some(&msg.message_id)).await {
+        Ok(transport) => {
+            debug!(user_id=%msg.chatter_user_id, ?transport, "replied to chat position")
+        }
+        Err(e) => warn!(error=?e, user_id=%msg.chatter_user_id, "failed to reply to chat position"),
+    }
     Ok(())
 }
 
-// event subscription の掃除
-async fn cleanup_disabled_ws_subscriptions(
-    state: &AppState,
+/// True (and starts the cooldown) unless `user_id` used `command` less than `cooldown_secs` ago.
+fn chat_cooldown_allows(
+    twitch: &TwitchClient,
+    command: &str,
+    user_id: &str,
+    cooldown_secs: u64,
+) -> bool {
+    let now = util::now_epoch();
+    let mut seen = twitch.chat_command_seen.lock().unwrap();
+    seen.retain(|_, at| now - *at < cooldown_secs as i64);
+    let key = (command.to_string(), user_id.to_string());
+    if seen.contains_key(&key) {
+        return false;
+    }
+    seen.insert(key, now);
+    true
+}
+
+/// The chat counterpart of a join redemption: same pause checks and queue rules,
+/// with the global policy and no redemption to update.
+async fn handle_chat_join(
+    twitch: &TwitchClient,
+    queue: &QueueService,
     access_token: &str,
-) -> anyhow::Result<u32> {
-    let mut deleted: u32 = 0;
-    let mut after: Option<String> = None;
+    msg: ChatMessageEvent,
+    user_input: String,
+) -> anyhow::Result<()> {
+    if !queue
+        .settings
+        .queue
+        .accepts(crate::config::EnqueueSource::Chat)
+    {
+        debug!(user_id=%msg.chatter_user_id, "queue.enqueue_sources does not include \"chat\"; ignoring chat join");
+        return Ok(());
+    }
+    if queue.is_overlay_heartbeat_lost() {
+        warn!(user_id=%msg.chatter_user_id, "overlay is not polling; enqueue paused, ignoring chat join");
+        return Ok(());
+    }
+    if let Some(until) = twitch.eventsub.raid_pause_until(util::now_epoch()) {
+        warn!(user_id=%msg.chatter_user_id, until, "enqueue paused after a raid, ignoring chat join");
+        return Ok(());
+    }
+    // On the writer, behind a pause that is still queued there.
+    if queue::is_paused(queue.db.write()).await? {
+        info!(user_id=%msg.chatter_user_id, "queue paused, ignoring chat join");
+        return Ok(());
+    }
 
-    loop {
-        let mut url = Url::parse(&format!("{HELIX_ENDPOINT}/eventsub/subscriptions"))?;
-        {
-            let mut qp = url.query_pairs_mut();
-            // 事故防止：このアプリが使う type だけ対象にする
-            qp.append_pair("type", "channel.channel_points_custom_reward_redemption.add");
-            qp.append_pair("first", "100");
-            if let Some(a) = &after {
-                qp.append_pair("after", a);
-            }
+    if queue::is_user_queued(queue.db.read(), &msg.chatter_user_id).await? {
+        info!(user_id=%msg.chatter_user_id, "already queued; ignoring chat join");
+        return Ok(());
+    }
+
+    let profile_image_url = match get_profile_image_url_cached(
+        twitch,
+        access_token,
+        &msg.chatter_user_id,
+    )
+    .await
+    {
+        Ok(url) => url,
+        Err(e) => {
+            warn!(error=?e, user_id=%msg.chatter_user_id, "failed to resolve user profile_image_url");
+            return Ok(());
         }
+    };
 
-        let resp = state
-            .http
-            .get(url)
-            .header("Client-Id", &state.config.twitch.client_id)
-            .header("Authorization", format!("Bearer {access_token}"))
-            .send()
-            .await?
-            .error_for_status()?;
+    let new_user = queue::NewQueueUser {
+        user_id: msg.chatter_user_id,
+        user_login: msg.chatter_user_login,
+        display_name: msg.chatter_user_name,
+        profile_image_url,
+        reward_id: None,
+        redemption_id: None,
+        user_input: Some(user_input).filter(|s| !s.is_empty()),
+        redeemed_at_ms: None,
+    };
 
-        let list: EventSubListResponse = resp.json().await?;
+    let notices = join_notices(queue, &new_user.user_id).await;
+    match queue.enqueue(new_user, &notices).await {
+        Ok(queue::EnqueueOutcome::AlreadyQueued) => info!("already queued; ignoring chat join"),
+        Ok(queue::EnqueueOutcome::Rejected(reason)) => {
+            info!(?reason, "chat join rejected by queue policy")
+        }
+        Ok(queue::EnqueueOutcome::QueueFull { max_queue_size }) => {
+            info!(max_queue_size, "queue is full; ignoring chat join")
+        }
+        Ok(queue::EnqueueOutcome::Pending { frozen_at }) => {
+            info!(frozen_at, "queue is frozen; chat join held until thaw")
+        }
+        Ok(queue::EnqueueOutcome::Added(r)) => {
+            info!(queue_id=%r.id, position=r.position, queue_len=r.queue_len, source="chat", "enqueued user");
+        }
+        Err(e) => error!(error=?e, "failed to enqueue"),
+    }
+    Ok(())
+}
 
-        for sub in &list.data {
-            // “disabled”扱い：enabled 以外を消す（websocket だけ）
-            if sub.transport.method == "websocket" && sub.status != "enabled" {
-                if let Err(e) = helix_delete_eventsub_subscription(state, access_token, &sub.id).await {
-                    warn!(error=?e, sub_id=%sub.id, status=%sub.status, "failed to delete stale subscription");
-                } else {
-                    deleted += 1;
-                    info!(sub_id=%sub.id, status=%sub.status, "deleted stale subscription");
-                }
+async fn handle_cancel_redemption(
+    queue: &QueueService,
+    event: &RedemptionEvent,
+) -> anyhow::Result<()> {
+    let cfg = &queue.settings.twitch;
+    let matched = match cfg.cancel_reward_behavior {
+        crate::config::CancelRewardBehavior::Remove => {
+            if queue::cancel_by_user_id(queue.db.write(), &queue.timings, &event.user_id).await? {
+                info!(user_id=%event.user_id, reward_id=%event.reward.id, "canceled queued user by redemption");
+                true
+            } else if interest::withdraw(queue.db.write(), &event.user_id).await? {
+                // Dropped while paused and not admitted yet: they no longer want in.
+                info!(user_id=%event.user_id, reward_id=%event.reward.id, "withdrew pending interest by cancel redemption");
+                true
+            } else {
+                false
+            }
+        }
+        crate::config::CancelRewardBehavior::MoveToBack => {
+            let moved = queue::move_to_back_by_user_id(queue.db.write(), &event.user_id).await?;
+            if moved {
+                info!(user_id=%event.user_id, reward_id=%event.reward.id, "moved queued user to the back by cancel redemption");
             }
+            moved
         }
+    };
+    if matched {
+        return Ok(());
+    }
 
-        after = list.pagination.cursor;
-        if after.is_none() {
-            break;
+    debug!(user_id=%event.user_id, reward_id=%event.reward.id, "cancel redemption ignored; user not in queue");
+    if cfg.refund_unmatched_cancel && cfg.update_redemption_status {
+        let refund = outbox::OutboxEvent::RedemptionStatus {
+            reward_id: event.reward.id.clone(),
+            redemption_id: event.id.clone(),
+            status: outbox::RedemptionStatus::Canceled,
+        };
+        outbox::insert(queue.db.write(), &refund, util::now_epoch()).await?;
+    }
+    Ok(())
+}
+
+/// Toggles away / back. A redemption from someone who is not queued is left alone.
+async fn handle_away_redemption(
+    queue: &QueueService,
+    event: &RedemptionEvent,
+) -> anyhow::Result<()> {
+    match queue::toggle_away(queue.db.write(), &event.user_id).await? {
+        Some(change) => info!(user_id=%event.user_id, ?change, "away toggled by redemption"),
+        None => {
+            debug!(user_id=%event.user_id, reward_id=%event.reward.id, "away redemption ignored; user not in queue")
         }
     }
+    Ok(())
+}
 
-    Ok(deleted)
+async fn handle_redemption(
+    twitch: &TwitchClient,
+    queue: &QueueService,
+    access_token: &str,
+    routing: &RedemptionRoutingConfig,
+    event: RedemptionEvent,
+) -> anyhow::Result<()> {
+    let reward_id = event.reward.id.as_str();
+
+    if let Err(e) = stats::record_reward_cost(
+        queue.db.write(),
+        reward_id,
+        event.reward.cost,
+        util::now_epoch(),
+    )
+    .await
+    {
+        warn!(error=?e, reward_id=%reward_id, "failed to record reward cost");
+    }
+
+    if !routing.join_id_set.contains(reward_id) {
+        if routing.cancel_id.as_deref() == Some(reward_id) {
+            handle_cancel_redemption(queue, &event).await?;
+        } else if routing.away_id.as_deref() == Some(reward_id) {
+            handle_away_redemption(queue, &event).await?;
+        } else {
+            let title = util::truncate_with_ellipsis(
+                &event.reward.title,
+                queue.settings.twitch.max_reward_title_len,
+            );
+            debug!(reward_id=%event.reward.id, title=%title, "non-target reward ignored");
+            if routing.is_discovery() {
+                db::record_unconfigured_redemption(
+                    queue.db.write(),
+                    reward_id,
+                    &title,
+                    util::now_epoch(),
+                )
+                .await?;
+            }
+        }
+        return Ok(());
+    }
+
+    if queue.is_overlay_heartbeat_lost() {
+        warn!(user_id=%event.user_id, "overlay is not polling; enqueue paused, ignoring redemption");
+        record_interest(queue, &event, interest::DropReason::QueuePaused).await;
+        return Ok(());
+    }
+    if let Some(until) = twitch.eventsub.raid_pause_until(util::now_epoch()) {
+        warn!(user_id=%event.user_id, until, "enqueue paused after a raid, ignoring redemption");
+        record_interest(queue, &event, interest::DropReason::QueuePaused).await;
+        return Ok(());
+    }
+    // Left unfulfilled on Twitch, so the points can still be refunded (or the viewer admitted later).
+    // On the writer, behind a pause that is still queued there.
+    if queue::is_paused(queue.db.write()).await? {
+        info!(user_id=%event.user_id, "queue paused, ignoring redemption");
+        record_interest(queue, &event, interest::DropReason::QueuePaused).await;
+        return Ok(());
+    }
+
+    // If already queued, ignore without hitting Helix.
+    if queue::is_user_queued(queue.db.read(), &event.user_id).await? {
+        info!(user_id=%event.user_id, "already queued; ignoring redemption");
+        return Ok(());
+    }
+
+    // Get profile image (cached)
+    let profile_image_url =
+        match get_profile_image_url_cached(twitch, access_token, &event.user_id).await {
+            Ok(url) => url,
+            Err(e) => {
+                warn!(error=?e, user_id=%event.user_id, "failed to resolve user profile_image_url");
+                return Ok(());
+            }
+        };
+
+    let redemption_id = queue
+        .settings
+        .twitch
+        .update_redemption_status
+        .then(|| event.id.clone());
+    let user_id = event.user_id.clone();
+    let refund_id = redemption_id.clone();
+
+    let new_user = queue::NewQueueUser {
+        user_id: event.user_id,
+        user_login: event.user_login,
+        display_name: event.user_name,
+        profile_image_url,
+        reward_id: Some(event.reward.id.clone()),
+        redemption_id,
+        user_input: Some(event.user_input),
+        redeemed_at_ms: util::parse_utc_datetime_millis(&event.redeemed_at),
+    };
+
+    let notices = join_notices(queue, &user_id).await;
+    match queue.enqueue(new_user, &notices).await {
+        Ok(queue::EnqueueOutcome::AlreadyQueued) => {
+            info!("already queued; ignoring redemption");
+        }
+        Ok(queue::EnqueueOutcome::Rejected(queue::RejectReason::MaxParticipations {
+            limit,
+            count,
+        })) => {
+            info!(limit, count, user_id=%user_id, reward_id=%event.reward.id, "participation limit reached; redemption rejected");
+        }
+        Ok(queue::EnqueueOutcome::Rejected(reason)) => {
+            info!(?reason, reward_id=%event.reward.id, "redemption rejected by queue policy");
+        }
+        Ok(queue::EnqueueOutcome::QueueFull { max_queue_size }) => {
+            warn!(max_queue_size, user_id=%user_id, reward_id=%event.reward.id, "queue is full; refunding redemption");
+            if let Some(redemption_id) = refund_id {
+                let refund = outbox::OutboxEvent::RedemptionStatus {
+                    reward_id: event.reward.id.clone(),
+                    redemption_id,
+                    status: outbox::RedemptionStatus::Canceled,
+                };
+                outbox::insert(queue.db.write(), &refund, util::now_epoch()).await?;
+            }
+        }
+        Ok(queue::EnqueueOutcome::Pending { frozen_at }) => {
+            info!(frozen_at, "queue is frozen; redemption held until thaw");
+        }
+        Ok(queue::EnqueueOutcome::Added(r)) => {
+            info!(
+                queue_id=%r.id,
+                position=r.position,
+                queue_len=r.queue_len,
+                estimated_wait_secs=?r.estimated_wait_secs,
+                priority=r.priority,
+                priority_placement=r.priority_placement,
+                manual_order_applied=r.manual_order_applied,
+                tiebreak_applied=r.tiebreak_applied,
+                "enqueued user"
+            );
+        }
+        Err(e) => {
+            error!(error=?e, "failed to enqueue");
+        }
+    }
+    Ok(())
+}
+
+/// `chat.join_announce`, `chat.join_whisper` and `alerts.join_message` for a new entry,
+/// queued in the outbox by the enqueue transaction so they are retried and survive a
+/// restart. The chat and whisper follow the viewer's preferences (see `prefs`); when
+/// those cannot be read the join goes ahead unannounced.
+async fn join_notices(queue: &QueueService, user_id: &str) -> queue::JoinNotices {
+    let template = |t: &str| Some(t.trim().to_string()).filter(|t| !t.is_empty());
+    let mut notices = queue::JoinNotices {
+        chat: template(&queue.settings.chat.join_announce),
+        whisper: template(&queue.settings.chat.join_whisper),
+        webhook: template(&queue.settings.alerts.join_message)
+            .filter(|_| !queue.settings.alerts.webhook_url.trim().is_empty()),
+    };
+    if notices.chat.is_some() || notices.whisper.is_some() {
+        match prefs::effective(
+            queue.db.read(),
+            &queue.settings.queue,
+            queue.settings.prefs_overridden(),
+            user_id,
+        )
+        .await
+        {
+            Ok(prefs) => {
+                notices.chat = notices.chat.filter(|_| prefs.chat_mention);
+                notices.whisper = notices.whisper.filter(|_| prefs.whisper);
+            }
+            Err(e) => {
+                error!(error=?e, user_id=%user_id, "failed to read notification preferences; join not announced");
+                return queue::JoinNotices::default();
+            }
+        }
+    }
+    notices
+}
+
+/// Lists a dropped join redemption for later admission; see `interest`.
+async fn record_interest(
+    queue: &QueueService,
+    event: &RedemptionEvent,
+    reason: interest::DropReason,
+) {
+    if queue.settings.queue.pending_interest_ttl_secs == 0 {
+        return;
+    }
+    let entry = interest::Interest {
+        user_id: event.user_id.clone(),
+        user_login: event.user_login.clone(),
+        display_name: event.user_name.clone(),
+        reward_id: event.reward.id.clone(),
+        redemption_id: queue
+            .settings
+            .twitch
+            .update_redemption_status
+            .then(|| event.id.clone()),
+        user_input: Some(event.user_input.clone()),
+        reason,
+    };
+    if let Err(e) = interest::record(queue.db.write(), &entry, util::now_epoch()).await {
+        warn!(error=?e, user_id=%event.user_id, "failed to record pending interest");
+    }
+}
+
+const CONFIG_REMINDER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Warns at startup and every 10 minutes while logged in without any join reward,
+/// the usual reason for "redemptions arrive but the queue stays empty".
+pub async fn run_config_reminder_loop(twitch: Arc<TwitchClient>) {
+    if !twitch
+        .settings
+        .queue
+        .accepts(crate::config::EnqueueSource::Redemption)
+        || !RedemptionRoutingConfig::from_config(&twitch.settings).is_discovery()
+    {
+        return;
+    }
+    loop {
+        match db::has_validish_token(twitch.db.read()).await {
+            Ok(true) => {
+                let seen = db::get_unconfigured_redemptions(twitch.db.read())
+                    .await
+                    .ok()
+                    .flatten();
+                warn!(
+                    dropped_redemptions = seen.as_ref().map_or(0, |s| s.count),
+                    last_reward_id = seen.as_ref().map(|s| s.last_reward_id.as_str()),
+                    last_reward_title = seen.as_ref().map(|s| s.last_reward_title.as_str()),
+                    "twitch.target_reward_ids が未設定なので、報酬が使われてもキューに入りません。管理画面の提案を確認して設定してください。"
+                );
+            }
+            Ok(false) => {}
+            Err(e) => debug!(error = ?e, "config reminder skipped"),
+        }
+        tokio::time::sleep(CONFIG_REMINDER_INTERVAL).await;
+    }
+}
+
+async fn create_redemption_subscription(
+    twitch: &TwitchClient,
+    access_token: &str,
+    session_id: &str,
+    broadcaster_id: &str,
+    routing: &RedemptionRoutingConfig,
+) -> anyhow::Result<usize> {
+    twitch.eventsub.budget.lock().unwrap().warnings.clear();
+    let mut created = 0;
+
+    if routing.is_discovery() {
+        // Every reward, including the cancel and away rewards (routed in handle_redemption).
+        create_redemption_subscription_with_reward(
+            twitch,
+            access_token,
+            session_id,
+            broadcaster_id,
+            None,
+        )
+        .await?;
+        created += 1;
+    }
+
+    for reward_id in routing.join_ids.iter().filter(|_| routing.redemptions) {
+        create_redemption_subscription_with_reward(
+            twitch,
+            access_token,
+            session_id,
+            broadcaster_id,
+            Some(reward_id),
+        )
+        .await?;
+        created += 1;
+    }
+
+    // Join rewards are required; these are skipped if they do not fit.
+    let extras = [
+        ("twitch.cancel_reward_id", routing.cancel_id.as_deref()),
+        ("twitch.away_reward_id", routing.away_id.as_deref()),
+    ];
+    for (feature, reward_id) in extras {
+        let Some(reward_id) = reward_id.filter(|_| routing.redemptions && !routing.is_discovery())
+        else {
+            continue;
+        };
+        let req = redemption_subscription_request(session_id, broadcaster_id, Some(reward_id));
+        created +=
+            usize::from(create_optional_subscription(twitch, access_token, feature, req).await?);
+    }
+
+    // Only marks the previous-session boundary; redemptions work without it.
+    let online = CreateSubRequest {
+        typ: SUB_TYPE_STREAM_ONLINE,
+        version: "1",
+        condition: SubCondition {
+            broadcaster_user_id: Some(broadcaster_id),
+            to_broadcaster_user_id: None,
+            reward_id: None,
+            user_id: None,
+        },
+        transport: SubTransport {
+            method: "websocket",
+            session_id,
+        },
+    };
+    match create_subscription(twitch, access_token, online).await {
+        Ok(()) => created += 1,
+        Err(e) => {
+            warn!(error = ?e, "stream.online subscription failed; previous-session boundary falls back to queue.previous_session_fallback_hours");
+            twitch.eventsub.budget.lock().unwrap().warnings.push(format!(
+                "stream.online の購読に失敗したため、配信開始時刻の代わりに queue.previous_session_fallback_hours を使っています（{e:#}）。"
+            ));
+        }
+    }
+
+    if routing.raid {
+        let req = CreateSubRequest {
+            typ: SUB_TYPE_CHANNEL_RAID,
+            version: "1",
+            condition: SubCondition {
+                broadcaster_user_id: None,
+                to_broadcaster_user_id: Some(broadcaster_id),
+                reward_id: None,
+                user_id: None,
+            },
+            transport: SubTransport {
+                method: "websocket",
+                session_id,
+            },
+        };
+        created += usize::from(
+            create_optional_subscription(twitch, access_token, "twitch.raid_pause_secs", req)
+                .await?,
+        );
+    }
+
+    if routing.chat {
+        let req = CreateSubRequest {
+            typ: SUB_TYPE_CHAT_MESSAGE,
+            version: "1",
+            condition: SubCondition {
+                broadcaster_user_id: Some(broadcaster_id),
+                to_broadcaster_user_id: None,
+                reward_id: None,
+                user_id: Some(broadcaster_id),
+            },
+            transport: SubTransport {
+                method: "websocket",
+                session_id,
+            },
+        };
+        created += usize::from(
+            create_optional_subscription(twitch, access_token, "chat commands", req).await?,
+        );
+    }
+
+    Ok(created)
+}
+
+fn redemption_subscription_request<'a>(
+    session_id: &'a str,
+    broadcaster_id: &'a str,
+    reward_id: Option<&'a str>,
+) -> CreateSubRequest<'a> {
+    CreateSubRequest {
+        typ: SUB_TYPE_REDEMPTION_ADD,
+        version: "1",
+        condition: SubCondition {
+            broadcaster_user_id: Some(broadcaster_id),
+            to_broadcaster_user_id: None,
+            reward_id,
+            user_id: None,
+        },
+        transport: SubTransport {
+            method: "websocket",
+            session_id,
+        },
+    }
+}
+
+async fn create_redemption_subscription_with_reward(
+    twitch: &TwitchClient,
+    access_token: &str,
+    session_id: &str,
+    broadcaster_id: &str,
+    reward_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let req = redemption_subscription_request(session_id, broadcaster_id, reward_id);
+    create_subscription(twitch, access_token, req).await
+}
+
+async fn create_subscription(
+    twitch: &TwitchClient,
+    access_token: &str,
+    req: CreateSubRequest<'_>,
+) -> anyhow::Result<()> {
+    let url = twitch.helix("/eventsub/subscriptions");
+    let _permit = helix_permit(twitch).await?;
+    let resp = twitch
+        .http
+        .post(url)
+        .header("Client-Id", &twitch.settings.twitch.client_id)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .json(&req)
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        if is_limit_error(status, &body) {
+            return Err(SubscriptionLimitExceeded { status, body }.into());
+        }
+        anyhow::bail!("create subscription failed: {status} {body}");
+    }
+
+    // Budget fields are best-effort; a body that does not parse is not a failure.
+    if let Ok(created) = resp.json::<CreateSubResponse>().await {
+        let mut budget = twitch.eventsub.budget.lock().unwrap();
+        budget.observe(&created.budget, util::now_epoch());
+        if let Some(cost) = created.data.first().and_then(|s| s.cost) {
+            budget.last_subscription_cost = Some(cost);
+        }
+    }
+    Ok(())
+}
+
+/// Creates a subscription for an optional feature (`feature` is its config key), or
+/// skips it with a status warning when the budget is exhausted. True if created.
+async fn create_optional_subscription(
+    twitch: &TwitchClient,
+    access_token: &str,
+    feature: &str,
+    req: CreateSubRequest<'_>,
+) -> anyhow::Result<bool> {
+    let skip = |twitch: &TwitchClient, why: String| {
+        warn!(feature, reason = %why, "EventSub budget exhausted; feature disabled for this session");
+        twitch.eventsub.budget.lock().unwrap().warnings.push(format!(
+            "EventSub の購読上限に達したため {feature} を無効にしています（{why}）。使っていない機能を無効にしてください。"
+        ));
+    };
+    let projected = {
+        let budget = twitch.eventsub.budget.lock().unwrap();
+        (!budget.fits(1)).then(|| {
+            format!(
+                "cost {}/{}",
+                budget.total_cost.unwrap_or_default(),
+                budget.max_total_cost.unwrap_or_default()
+            )
+        })
+    };
+    if let Some(why) = projected {
+        skip(twitch, why);
+        return Ok(false);
+    }
+    match create_subscription(twitch, access_token, req).await {
+        Ok(()) => Ok(true),
+        Err(e) => match e.downcast::<SubscriptionLimitExceeded>() {
+            Ok(limit) => {
+                skip(twitch, format!("Helix {}", limit.status));
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        },
+    }
+}
+
+// event subscription の掃除
+async fn cleanup_disabled_ws_subscriptions(
+    twitch: &TwitchClient,
+    access_token: &str,
+) -> anyhow::Result<u32> {
+    let mut deleted: u32 = 0;
+    let mut after: Option<String> = None;
+
+    loop {
+        let mut url = Url::parse(&twitch.helix("/eventsub/subscriptions"))?;
+        {
+            let mut qp = url.query_pairs_mut();
+            // 事故防止：このアプリが使う type だけ対象にする
+            qp.append_pair(
+                "type",
+                "channel.channel_points_custom_reward_redemption.add",
+            );
+            qp.append_pair("first", "100");
+            if let Some(a) = &after {
+                qp.append_pair("after", a);
+            }
+        }
+
+        let permit = helix_permit(twitch).await?;
+        let resp = twitch
+            .http
+            .get(url)
+            .header("Client-Id", &twitch.settings.twitch.client_id)
+            .header("Authorization", format!("Bearer {access_token}"))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let list: EventSubListResponse = resp.json().await?;
+        observe_budget(twitch, &list.budget);
+        // Deleting below takes its own permits.
+        drop(permit);
+
+        for sub in &list.data {
+            // “disabled”扱い：enabled 以外を消す（websocket だけ）
+            if sub.transport.method == "websocket" && sub.status != "enabled" {
+                if let Err(e) =
+                    helix_delete_eventsub_subscription(twitch, access_token, &sub.id).await
+                {
+                    warn!(error=?e, sub_id=%sub.id, status=%sub.status, "failed to delete stale subscription");
+                } else {
+                    deleted += 1;
+                    info!(sub_id=%sub.id, status=%sub.status, "deleted stale subscription");
+                }
+            }
+        }
+
+        after = list.pagination.cursor;
+        if after.is_none() {
+            break;
+        }
+    }
+
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{http::StatusCode, response::IntoResponse, routing::post, Json, Router};
+
+    use super::*;
+    use crate::testing::{self, TestApp};
+
+    fn notification(subscription_type: &str, payload: serde_json::Value) -> Notification {
+        let env: WsEnvelope = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "message_id": "m1",
+                "message_type": "notification",
+                "subscription_type": subscription_type,
+            },
+            "payload": payload,
+        }))
+        .unwrap();
+        match EventSubMessage::from_envelope(env).unwrap() {
+            EventSubMessage::Notification { notification, .. } => notification,
+            other => panic!("not a notification: {other:?}"),
+        }
+    }
+
+    /// Parses `tests/fixtures/eventsub/<name>.json`, a message as Twitch sends it.
+    fn fixture(name: &str) -> EventSubMessage {
+        let path = format!(
+            "{}/tests/fixtures/eventsub/{name}.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let text = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{path}: {e}"));
+        EventSubMessage::from_envelope(serde_json::from_str(&text).unwrap()).unwrap()
+    }
+
+    fn fixture_notification(name: &str) -> (String, Notification) {
+        match fixture(name) {
+            EventSubMessage::Notification {
+                message_id,
+                notification,
+            } => (message_id, notification),
+            other => panic!("{name}: not a notification: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn welcome_subscribes_only_when_subscriptions_are_needed() {
+        let EventSubMessage::Welcome(session) = fixture("welcome") else {
+            panic!("not a welcome")
+        };
+        assert_eq!(session.id, "AQoQILE98gtqShGmLD7AM6yJThAB");
+        assert_eq!(session.keepalive_timeout_seconds, Some(10));
+        assert_eq!(session.reconnect_url, None);
+
+        match next_ws_action(fixture("welcome"), true) {
+            WsAction::Subscribe { session_id } => {
+                assert_eq!(session_id, "AQoQILE98gtqShGmLD7AM6yJThAB")
+            }
+            other => panic!("{other:?}"),
+        }
+        match next_ws_action(fixture("welcome"), false) {
+            WsAction::KeepSubscriptions { session_id } => {
+                assert_eq!(session_id, "AQoQILE98gtqShGmLD7AM6yJThAB")
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn keepalive_continues_either_way() {
+        for need_subscribe in [true, false] {
+            assert!(matches!(
+                next_ws_action(fixture("keepalive"), need_subscribe),
+                WsAction::Continue
+            ));
+        }
+    }
+
+    fn ev(occurred_at: i64, kind: EventSubEventKind, detail: Option<&str>) -> db::EventSubEvent {
+        db::EventSubEvent {
+            occurred_at,
+            kind: kind.as_str().to_string(),
+            detail: detail.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn downtime_runs_from_the_first_failure_to_working_subscriptions() {
+        use EventSubEventKind::*;
+        let down = |start, end: Option<i64>, duration_secs| DowntimeInterval {
+            start,
+            end,
+            duration_secs,
+        };
+        let events = vec![
+            // Before the range: clamped to `from`.
+            ev(50, Disconnected, None),
+            ev(120, Connected, None),
+            ev(121, Welcome, None),
+            ev(125, SubscribeOk, None),
+            // A timeout followed by the disconnect it causes is one interval.
+            ev(200, KeepaliveTimeout, None),
+            ev(201, Disconnected, None),
+            // A fresh welcome is not enough; only a migrated one keeps the subscriptions.
+            ev(210, Welcome, None),
+            ev(215, Welcome, Some(WELCOME_MIGRATED)),
+            ev(300, SubscribeFailed, None),
+            ev(300, SubscribeOk, None),
+            // Unterminated: down until the end of the range.
+            ev(400, Disconnected, None),
+            ev(410, Connected, None),
+        ];
+        assert_eq!(
+            downtime_intervals(&events, 100, 500),
+            vec![
+                down(100, Some(125), 25),
+                down(200, Some(215), 15),
+                down(400, None, 100)
+            ],
+            "a failure recovered within the same second leaves no interval"
+        );
+
+        assert_eq!(downtime_intervals(&[], 100, 500), vec![]);
+        assert_eq!(
+            downtime_intervals(&[ev(50, Disconnected, None)], 100, 500),
+            vec![down(100, None, 400)],
+            "down for the whole range"
+        );
+    }
+
+    #[test]
+    fn reconnect_switches_to_the_given_url_or_disconnects_without_one() {
+        match next_ws_action(fixture("reconnect"), false) {
+            WsAction::SwitchUrl(url) => assert_eq!(
+                url,
+                "wss://eventsub.wss.twitch.tv?id=AQoQexAWVYKSTIu4ec_2VAxyuhAB"
+            ),
+            other => panic!("{other:?}"),
+        }
+
+        let without_url = EventSubMessage::Reconnect(SessionInfo {
+            id: "s".to_string(),
+            reconnect_url: None,
+            keepalive_timeout_seconds: None,
+        });
+        assert!(matches!(
+            next_ws_action(without_url, false),
+            WsAction::Disconnect
+        ));
+    }
+
+    #[test]
+    fn revocation_forces_a_resubscribe() {
+        for need_subscribe in [true, false] {
+            assert!(matches!(
+                next_ws_action(fixture("revocation"), need_subscribe),
+                WsAction::Resubscribe
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_message_type_is_left_unhandled() {
+        match next_ws_action(fixture("unknown_type"), false) {
+            WsAction::Unhandled(message_type) => assert_eq!(message_type, "session_migrated"),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn redemption_add_fixture_parses_every_field_we_use() {
+        let (message_id, n) = fixture_notification("redemption_add");
+        assert_eq!(message_id, "befa7b53-d79d-478f-86b9-120f112b044e");
+        let Notification::RedemptionAdd(ev) = n else {
+            panic!("{n:?}")
+        };
+        assert_eq!(ev.id, "17fa2df1-ad76-4804-bfa5-a40ef63efe63");
+        assert_eq!(
+            (
+                ev.user_id.as_str(),
+                ev.user_login.as_str(),
+                ev.user_name.as_str()
+            ),
+            ("9001", "cooler_user", "Cooler_User")
+        );
+        assert_eq!(ev.user_input, "pogchamp");
+        assert_eq!(ev.redeemed_at, "2024-05-01T12:00:03.171067Z");
+        assert_eq!(ev.reward.id, "92af127c-7326-4483-a52b-b0da0be61c01");
+        assert_eq!((ev.reward.title.as_str(), ev.reward.cost), ("title", 100));
+    }
+
+    #[tokio::test]
+    async fn a_redemption_dropped_while_paused_can_be_admitted_once() {
+        let app = TestApp::new(
+            "[twitch]\ntarget_reward_ids = [\"92af127c-7326-4483-a52b-b0da0be61c01\"]\nupdate_redemption_status = true\n",
+        )
+        .await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+        queue::set_paused(app.queue.db.write(), false, true)
+            .await
+            .unwrap();
+        let (message_id, n) = fixture_notification("redemption_add");
+        handle_notification(&app.twitch, &app.queue, "token", &routing, &message_id, n)
+            .await
+            .unwrap();
+
+        assert!(!queue::is_user_queued(app.queue.db.read(), "9001")
+            .await
+            .unwrap());
+        let pending = interest::list(app.queue.db.read()).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        let row = &pending[0];
+        assert_eq!(
+            (row.user_login.as_str(), row.reason.as_str()),
+            ("cooler_user", "queue_paused")
+        );
+        assert_eq!(
+            row.redemption_id.as_deref(),
+            Some("17fa2df1-ad76-4804-bfa5-a40ef63efe63")
+        );
+
+        queue::set_paused(app.queue.db.write(), false, false)
+            .await
+            .unwrap();
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let admit = format!("{base}/api/pending_interest/{}/admit", row.id);
+        let res = reqwest::Client::new().post(&admit).send().await.unwrap();
+        assert_eq!(res.status().as_u16(), 200, "{}", res.text().await.unwrap());
+
+        let items = queue::list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            (items[0].user_id.as_str(), items[0].reward_id.as_deref()),
+            ("9001", Some("92af127c-7326-4483-a52b-b0da0be61c01"))
+        );
+        let redemption: Option<String> =
+            sqlx::query_scalar("SELECT redemption_id FROM queue_items WHERE user_id = '9001'")
+                .fetch_one(app.queue.db.read())
+                .await
+                .unwrap();
+        assert_eq!(
+            redemption.as_deref(),
+            Some("17fa2df1-ad76-4804-bfa5-a40ef63efe63"),
+            "completing fulfills the original redemption"
+        );
+        assert!(interest::list(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            reqwest::Client::new()
+                .post(&admit)
+                .send()
+                .await
+                .unwrap()
+                .status()
+                .as_u16(),
+            404
+        );
+    }
+
+    #[test]
+    fn malformed_redemption_is_still_dispatched_for_deduplication() {
+        assert!(matches!(
+            fixture_notification("redemption_add_malformed").1,
+            Notification::MalformedRedemption(_)
+        ));
+        match next_ws_action(fixture("redemption_add_malformed"), false) {
+            WsAction::Dispatch {
+                message_id,
+                notification: Notification::MalformedRedemption(_),
+            } => {
+                assert_eq!(message_id, "c7a7c2e6-3b0d-4bb4-8d4c-1d9f4f7f1a50");
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn chat_commands_parse_with_extra_whitespace_and_any_case() {
+        assert_eq!(parse_chat_command("!leave", "!leave"), Some(String::new()));
+        assert_eq!(
+            parse_chat_command("   !LeAvE   ", "!leave"),
+            Some(String::new())
+        );
+        assert_eq!(
+            parse_chat_command("\t!LEAVE  now  please ", "!leave"),
+            Some("now  please".to_string())
+        );
+        assert_eq!(parse_chat_command("!leave", "!Leave"), Some(String::new()));
+        // A whole word only, and only at the start.
+        assert_eq!(parse_chat_command("!leaver", "!leave"), None);
+        assert_eq!(parse_chat_command("please !leave", "!leave"), None);
+        assert_eq!(parse_chat_command("", "!leave"), None);
+    }
+
+    fn chat_notification(user_id: &str, text: &str) -> Notification {
+        notification(
+            SUB_TYPE_CHAT_MESSAGE,
+            serde_json::json!({ "event": {
+                "broadcaster_user_id": "b1",
+                "broadcaster_user_login": "streamer",
+                "chatter_user_id": user_id,
+                "chatter_user_login": user_id,
+                "chatter_user_name": user_id,
+                "message_id": format!("chat-{user_id}"),
+                "message": { "text": text },
+            } }),
+        )
+    }
+
+    #[tokio::test]
+    async fn leave_command_works_without_chat_joins() {
+        let app = TestApp::new("[chat]\nleave_command = \"!leave\"\n").await;
+        assert!(app.settings.twitch.chat_join_command().is_none());
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+        assert!(routing.chat);
+        testing::enqueue(&app.queue, testing::new_user("u1")).await;
+
+        handle_notification(
+            &app.twitch,
+            &app.queue,
+            "token",
+            &routing,
+            "m1",
+            chat_notification("u1", "  !LEAVE "),
+        )
+        .await
+        .unwrap();
+        assert!(!queue::is_user_queued(app.queue.db.read(), "u1")
+            .await
+            .unwrap());
+
+        // Joining is still off: nothing enqueues the sender.
+        handle_notification(
+            &app.twitch,
+            &app.queue,
+            "token",
+            &routing,
+            "m2",
+            chat_notification("u2", "!join"),
+        )
+        .await
+        .unwrap();
+        assert!(!queue::is_user_queued(app.queue.db.read(), "u2")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn chat_join_works_without_the_redemption_source() {
+        let (helix, seen) = mock_subscriptions("none").await;
+        let app = TestApp::with_helix(
+            "[twitch]\nchat_join_command = \"!join\"\n[queue]\nenqueue_sources = [\"chat\", \"manual\"]\n",
+            &helix,
+        )
+        .await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+        assert!(routing.needs_eventsub() && !routing.is_discovery());
+
+        // No reward subscription, not even the catch-all one used without join rewards.
+        let created =
+            create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+                .await
+                .unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![SUB_TYPE_STREAM_ONLINE, SUB_TYPE_CHAT_MESSAGE]
+        );
+        assert_eq!(created, 2);
+        assert_eq!(
+            routing.subscription_count(),
+            app.settings.required_subscription_count()
+        );
+        assert_eq!(routing.subscription_count(), 2);
+
+        let profile = db::CachedUserProfile {
+            user_id: "u1".to_string(),
+            user_login: "u1".to_string(),
+            display_name: "u1".to_string(),
+            profile_image_url: String::new(),
+            updated_at: util::now_epoch(),
+        };
+        db::upsert_cached_user_profile(app.queue.db.write(), &profile)
+            .await
+            .unwrap();
+        handle_notification(
+            &app.twitch,
+            &app.queue,
+            "token",
+            &routing,
+            "m1",
+            chat_notification("u1", "!join"),
+        )
+        .await
+        .unwrap();
+        assert!(queue::is_user_queued(app.queue.db.read(), "u1")
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn eventsub_is_not_used_without_redemptions_chat_commands_or_raids() {
+        let config =
+            crate::config::Config::parse("[queue]\nenqueue_sources = [\"manual\"]\n").unwrap();
+        assert!(!RedemptionRoutingConfig::from_config(&config).needs_eventsub());
+        let config = crate::config::Config::parse(
+            "[twitch]\nraid_pause_secs = 60\n[queue]\nenqueue_sources = [\"manual\"]\n",
+        )
+        .unwrap();
+        assert!(RedemptionRoutingConfig::from_config(&config).needs_eventsub());
+    }
+
+    #[tokio::test]
+    async fn chat_messages_are_subscribed_for_any_chat_command() {
+        let (helix, seen) = mock_subscriptions("none").await;
+        let app = TestApp::with_helix(
+            "[twitch]\ntarget_reward_ids = [\"r1\"]\n[chat]\nleave_command = \"!leave\"\n",
+            &helix,
+        )
+        .await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+        create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+            .await
+            .unwrap();
+        assert!(seen
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|t| t == SUB_TYPE_CHAT_MESSAGE));
+        assert_eq!(
+            routing.subscription_count(),
+            app.settings.required_subscription_count()
+        );
+
+        let (helix, seen) = mock_subscriptions("none").await;
+        let app = TestApp::with_helix("[twitch]\ntarget_reward_ids = [\"r1\"]\n", &helix).await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+        create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+            .await
+            .unwrap();
+        assert!(!seen
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|t| t == SUB_TYPE_CHAT_MESSAGE));
+    }
+
+    #[test]
+    fn chat_raid_and_stream_online_fixtures_parse() {
+        let Notification::ChatMessage(ev) = fixture_notification("chat_message").1 else {
+            panic!("not chat")
+        };
+        assert_eq!(
+            (
+                ev.broadcaster_user_id.as_str(),
+                ev.broadcaster_user_login.as_str()
+            ),
+            ("1337", "cool_user")
+        );
+        assert_eq!(ev.message_id, "cc106a89-1814-919d-454c-f4f2f970aae7");
+        assert_eq!(
+            (
+                ev.chatter_user_id.as_str(),
+                ev.chatter_user_login.as_str(),
+                ev.chatter_user_name.as_str()
+            ),
+            ("4145994", "viptest", "viptest")
+        );
+        assert_eq!(ev.message.text, "!join");
+
+        let Notification::Raid(ev) = fixture_notification("channel_raid").1 else {
+            panic!("not a raid")
+        };
+        assert_eq!(
+            (ev.from_broadcaster_user_login.as_str(), ev.viewers),
+            ("cool_user", 9001)
+        );
+
+        assert!(matches!(
+            fixture_notification("stream_online").1,
+            Notification::StreamOnline {
+                started_at: Some(1_714_564_803)
+            }
+        ));
+    }
+
+    #[test]
+    fn unsubscribed_notification_type_is_dispatched_as_other() {
+        match next_ws_action(fixture("channel_follow"), false) {
+            WsAction::Dispatch {
+                message_id,
+                notification: Notification::Other,
+            } => {
+                assert_eq!(message_id, "7e2b1c4d-3a5f-4e6b-8c9d-0a1b2c3d4e5f");
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn welcome_without_a_session_is_an_error() {
+        let env: WsEnvelope = serde_json::from_value(serde_json::json!({
+            "metadata": { "message_id": "m1", "message_type": "session_welcome" },
+            "payload": {},
+        }))
+        .unwrap();
+        assert!(EventSubMessage::from_envelope(env).is_err());
+    }
+
+    #[test]
+    fn close_frames_are_recorded_with_their_code() {
+        use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+        let frame = |code: u16, reason: &'static str| CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        };
+        let cases = [
+            (
+                Some(frame(4003, "connection unused")),
+                "close 4003: connection unused",
+            ),
+            (
+                Some(frame(4005, "network timeout")),
+                "close 4005: network timeout",
+            ),
+            (
+                Some(frame(4007, "invalid reconnect")),
+                "close 4007: invalid reconnect",
+            ),
+            (Some(frame(1000, "")), "close 1000: "),
+            (None, "close"),
+        ];
+        for (frame, expected) in cases {
+            assert_eq!(close_detail(frame.as_ref()), expected);
+        }
+    }
+
+    #[test]
+    fn stream_online_carries_the_events_started_at() {
+        let n = notification(
+            SUB_TYPE_STREAM_ONLINE,
+            serde_json::json!({ "event": { "id": "1", "type": "live", "started_at": "2024-05-01T12:00:03Z" } }),
+        );
+        assert!(matches!(
+            n,
+            Notification::StreamOnline {
+                started_at: Some(1_714_564_803)
+            }
+        ));
+
+        let n = notification(
+            SUB_TYPE_STREAM_ONLINE,
+            serde_json::json!({ "event": { "type": "live" } }),
+        );
+        assert!(matches!(n, Notification::StreamOnline { started_at: None }));
+    }
+
+    #[tokio::test]
+    async fn stream_online_boundary_is_stored_from_started_at() {
+        let app = TestApp::new("").await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+        let late = Notification::StreamOnline {
+            started_at: Some(1_714_564_803),
+        };
+        handle_notification(&app.twitch, &app.queue, "token", &routing, "m1", late)
+            .await
+            .unwrap();
+        assert_eq!(
+            db::get_stream_online_at(app.queue.db.read()).await.unwrap(),
+            Some(1_714_564_803)
+        );
+    }
+
+    /// Accepts every subscription except the `failing` type; records the types asked for.
+    async fn mock_subscriptions(failing: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let router = Router::new().route(
+            "/eventsub/subscriptions",
+            post(move |Json(req): Json<serde_json::Value>| {
+                let sink = Arc::clone(&sink);
+                async move {
+                    let typ = req["type"].as_str().unwrap_or_default().to_string();
+                    sink.lock().unwrap().push(typ.clone());
+                    if typ == failing {
+                        return (StatusCode::INTERNAL_SERVER_ERROR, "boom").into_response();
+                    }
+                    let body = serde_json::json!({ "data": [{ "cost": 0 }], "total": 1, "total_cost": 0, "max_total_cost": 10 });
+                    (StatusCode::ACCEPTED, Json(body)).into_response()
+                }
+            }),
+        );
+        (testing::serve(router).await, seen)
+    }
+
+    #[tokio::test]
+    async fn failed_stream_online_subscription_keeps_the_redemption_subscriptions() {
+        let (helix, seen) = mock_subscriptions(SUB_TYPE_STREAM_ONLINE).await;
+        let app =
+            TestApp::with_helix("[twitch]\ntarget_reward_ids = [\"r1\", \"r2\"]\n", &helix).await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+
+        let created =
+            create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+                .await
+                .unwrap();
+
+        assert_eq!(created, 2);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                SUB_TYPE_REDEMPTION_ADD,
+                SUB_TYPE_REDEMPTION_ADD,
+                SUB_TYPE_STREAM_ONLINE
+            ]
+        );
+        let warnings = app.twitch.eventsub.budget.lock().unwrap().warnings.clone();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("stream.online"));
+    }
+
+    /// Records each subscription as "type" or "type:reward_id" and answers with `answer(record)`.
+    async fn mock_subscription_answers(
+        answer: fn(&str) -> (StatusCode, serde_json::Value),
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let router = Router::new().route(
+            "/eventsub/subscriptions",
+            post(move |Json(req): Json<serde_json::Value>| {
+                let sink = Arc::clone(&sink);
+                async move {
+                    let mut record = req["type"].as_str().unwrap_or_default().to_string();
+                    if let Some(reward_id) = req["condition"]["reward_id"].as_str() {
+                        record = format!("{record}:{reward_id}");
+                    }
+                    sink.lock().unwrap().push(record.clone());
+                    let (status, body) = answer(&record);
+                    (status, Json(body)).into_response()
+                }
+            }),
+        );
+        (testing::serve(router).await, seen)
+    }
+
+    const OPTIONAL_SUBSCRIPTIONS: &str =
+        "[twitch]\ntarget_reward_ids = [\"r1\"]\ncancel_reward_id = \"c1\"\nraid_pause_secs = 60\n";
+
+    #[tokio::test]
+    async fn optional_subscriptions_are_skipped_once_the_cost_reaches_the_maximum() {
+        let (helix, seen) = mock_subscription_answers(|_| {
+            let full = serde_json::json!({ "data": [{ "cost": 1 }], "total": 10, "total_cost": 10, "max_total_cost": 10 });
+            (StatusCode::ACCEPTED, full)
+        })
+        .await;
+        let app = TestApp::with_helix(OPTIONAL_SUBSCRIPTIONS, &helix).await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+
+        let created =
+            create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+                .await
+                .unwrap();
+
+        // The join reward and stream.online are required; the rest are never requested.
+        assert_eq!(created, 2);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                format!("{SUB_TYPE_REDEMPTION_ADD}:r1"),
+                SUB_TYPE_STREAM_ONLINE.to_string()
+            ]
+        );
+        let warnings = app.twitch.eventsub.budget.lock().unwrap().warnings.clone();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(
+            warnings[0].contains("twitch.cancel_reward_id") && warnings[0].contains("cost 10/10")
+        );
+        assert!(
+            warnings[1].contains("twitch.raid_pause_secs") && warnings[1].contains("cost 10/10")
+        );
+
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let status: serde_json::Value = reqwest::get(format!("{base}/api/status"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let eventsub = &status["eventsub"];
+        assert_eq!(
+            (
+                eventsub["total_cost"].as_i64(),
+                eventsub["max_total_cost"].as_i64()
+            ),
+            (Some(10), Some(10))
+        );
+        assert_eq!(eventsub["last_subscription_cost"].as_i64(), Some(1));
+        assert_eq!(eventsub["warnings"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn optional_subscriptions_refused_for_cost_are_skipped_and_other_errors_are_not() {
+        let (helix, seen) = mock_subscription_answers(|record| match record {
+            "channel.channel_points_custom_reward_redemption.add:c1" => {
+                (StatusCode::BAD_REQUEST, serde_json::json!({ "message": "subscription cost exceeded" }))
+            }
+            SUB_TYPE_CHANNEL_RAID => (StatusCode::TOO_MANY_REQUESTS, serde_json::json!({ "message": "too many" })),
+            _ => (StatusCode::ACCEPTED, serde_json::json!({ "data": [{ "cost": 1 }], "total_cost": 3, "max_total_cost": 10 })),
+        })
+        .await;
+        let app = TestApp::with_helix(OPTIONAL_SUBSCRIPTIONS, &helix).await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+
+        let created =
+            create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+                .await
+                .unwrap();
+
+        assert_eq!(created, 2);
+        assert_eq!(
+            seen.lock().unwrap().len(),
+            4,
+            "every subscription was attempted"
+        );
+        let warnings = app.twitch.eventsub.budget.lock().unwrap().warnings.clone();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(
+            warnings[0].contains("twitch.cancel_reward_id") && warnings[0].contains("Helix 400")
+        );
+        assert!(
+            warnings[1].contains("twitch.raid_pause_secs") && warnings[1].contains("Helix 429")
+        );
+
+        // A 400 that is not about cost is a real failure.
+        let (helix, _) = mock_subscription_answers(|record| match record {
+            SUB_TYPE_CHANNEL_RAID => (
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({ "message": "invalid condition" }),
+            ),
+            _ => (StatusCode::ACCEPTED, serde_json::json!({ "data": [] })),
+        })
+        .await;
+        let app = TestApp::with_helix(OPTIONAL_SUBSCRIPTIONS, &helix).await;
+        let err = create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("invalid condition"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn join_notices_are_queued_in_the_enqueue_transaction() {
+        let app = TestApp::new(
+            "[alerts]\nwebhook_url = \"http://127.0.0.1:9/hook\"\njoin_message = \"{user} joined at #{position}\"\n[chat]\njoin_announce = \"{user}\"\n",
+        )
+        .await;
+        let notices = join_notices(&app.queue, "u1").await;
+        let viewer = queue::NewQueueUser {
+            display_name: "Viewer".to_string(),
+            ..testing::new_user("u1")
+        };
+        let cfg = &app.settings.queue;
+        // The second attempt is rolled back as already queued, and records nothing.
+        for _ in 0..2 {
+            queue::enqueue_user(
+                app.queue.db.write(),
+                &app.queue.timings,
+                cfg,
+                &cfg.default_policy(),
+                viewer.clone(),
+                &notices,
+            )
+            .await
+            .unwrap();
+        }
+
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT event_type, payload FROM outbox ORDER BY id")
+                .fetch_all(app.queue.db.read())
+                .await
+                .unwrap();
+        let rows: Vec<(&str, &str)> = rows.iter().map(|(t, p)| (t.as_str(), p.as_str())).collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("chat_message", r#"{"message":"Viewer"}"#),
+                ("alert_webhook", r#"{"content":"Viewer joined at #1"}"#)
+            ]
+        );
+    }
+
+    fn chat_send_error(status: u16) -> anyhow::Error {
+        let status = reqwest::StatusCode::from_u16(status).unwrap();
+        ChatSendError {
+            status,
+            body: String::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn only_helix_server_errors_fall_back_to_irc() {
+        let cases = [
+            (chat_send_error(500), true),
+            (chat_send_error(502), true),
+            (chat_send_error(503), true),
+            (chat_send_error(504), true),
+            (chat_send_error(400), false),
+            (chat_send_error(404), false),
+            (chat_send_error(422), false),
+            (chat_send_error(429), false),
+            (chat_send_error(401).context("not permitted"), false),
+            (chat_send_error(403).context("not permitted"), false),
+            (anyhow::anyhow!("connection refused"), false),
+            (anyhow::anyhow!("chat message was dropped"), false),
+        ];
+        for (err, expected) in &cases {
+            assert_eq!(falls_back_to_irc(true, err), *expected, "{err:#}");
+            assert!(!falls_back_to_irc(false, err), "fallback is off: {err:#}");
+        }
+    }
+
+    #[tokio::test]
+    async fn helix_chat_failures_keep_their_status_for_the_fallback_decision() {
+        // The message text is the status the mock answers with.
+        let router = Router::new().route(
+            "/chat/messages",
+            post(|Json(req): Json<serde_json::Value>| async move {
+                let status = req["message"].as_str().unwrap().parse().unwrap();
+                (StatusCode::from_u16(status).unwrap(), "nope")
+            }),
+        );
+        let app = TestApp::with_helix(
+            "[chat]\nirc_fallback = true\n",
+            &testing::serve(router).await,
+        )
+        .await;
+        for (status, expected) in [
+            ("500", true),
+            ("503", true),
+            ("400", false),
+            ("403", false),
+            ("429", false),
+        ] {
+            let err = helix_send_chat_message(&app.twitch, "token", "b1", status, None)
+                .await
+                .unwrap_err();
+            assert_eq!(falls_back_to_irc(true, &err), expected, "{status}: {err:#}");
+        }
+    }
+
+    /// Twitch IRC over a local websocket: welcomes any login and records every PRIVMSG line.
+    async fn mock_irc() -> (String, Arc<Mutex<Vec<String>>>) {
+        use futures_util::{SinkExt, StreamExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&received);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sink = Arc::clone(&sink);
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(Ok(Message::Text(text))) = ws.next().await {
+                        for line in text.lines() {
+                            if line.starts_with("NICK ") {
+                                ws.send(Message::Text(
+                                    ":tmi.twitch.tv 001 streamer :Welcome, GLHF!".to_string(),
+                                ))
+                                .await
+                                .unwrap();
+                            } else if line.starts_with("PRIVMSG ") {
+                                sink.lock().unwrap().push(line.to_string());
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn chat_messages_fall_back_to_irc_on_helix_5xx_only() {
+        // The message text is the status Helix answers with.
+        let router = Router::new().route(
+            "/chat/messages",
+            post(|Json(req): Json<serde_json::Value>| async move {
+                match req["message"].as_str().unwrap() {
+                    "200" => Json(
+                        serde_json::json!({ "data": [{ "message_id": "m", "is_sent": true }] }),
+                    )
+                    .into_response(),
+                    status => (
+                        StatusCode::from_u16(status.parse().unwrap()).unwrap(),
+                        "nope",
+                    )
+                        .into_response(),
+                }
+            }),
+        );
+        let (irc, irc_received) = mock_irc().await;
+        let app = TestApp::with_chat(
+            "[chat]\nirc_fallback = true\n",
+            &testing::serve(router).await,
+            &irc,
+        )
+        .await;
+        log_in(&app).await;
+
+        for (status, transport, over_irc) in [
+            ("200", Some("helix"), false),
+            ("500", Some("irc"), true),
+            ("503", Some("irc"), true),
+            ("400", None, false),
+            ("403", None, false),
+            ("429", None, false),
+        ] {
+            irc_received.lock().unwrap().clear();
+            let event = outbox::OutboxEvent::ChatMessage {
+                message: status.to_string(),
+            };
+            outbox::insert(app.queue.db.write(), &event, util::now_epoch())
+                .await
+                .unwrap();
+            assert_eq!(
+                outbox::dispatch_due(&app, util::now_epoch(), None, 5)
+                    .await
+                    .unwrap(),
+                1,
+                "{status}"
+            );
+
+            let (row_status, row_transport): (String, Option<String>) =
+                sqlx::query_as("SELECT status, transport FROM outbox ORDER BY id DESC LIMIT 1")
+                    .fetch_one(app.queue.db.read())
+                    .await
+                    .unwrap();
+            assert_eq!(row_transport.as_deref(), transport, "{status}");
+            assert_eq!(
+                row_status,
+                if transport.is_some() {
+                    "done"
+                } else {
+                    "pending"
+                },
+                "{status}"
+            );
+            let expected_irc = if over_irc {
+                vec![format!("PRIVMSG #streamer :{status}")]
+            } else {
+                vec![]
+            };
+            // The stub reads the line after the send has returned.
+            for _ in 0..50 {
+                if irc_received.lock().unwrap().len() >= expected_irc.len() {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            assert_eq!(*irc_received.lock().unwrap(), expected_irc, "{status}");
+        }
+    }
+
+    /// Accepts chat posts and whispers, recording "chat" or "whisper:<to_user_id>" for each.
+    async fn mock_join_notifications() -> (String, Arc<Mutex<Vec<String>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let chat_sink = Arc::clone(&sent);
+        let whisper_sink = Arc::clone(&sent);
+        let router = Router::new()
+            .route(
+                "/chat/messages",
+                post(move || {
+                    chat_sink.lock().unwrap().push("chat".to_string());
+                    async {
+                        Json(
+                            serde_json::json!({ "data": [{ "message_id": "m", "is_sent": true }] }),
+                        )
+                    }
+                }),
+            )
+            .route(
+                "/whispers",
+                post(
+                    move |axum::extract::RawQuery(query): axum::extract::RawQuery| {
+                        let to = query
+                            .unwrap_or_default()
+                            .split('&')
+                            .find_map(|p| p.strip_prefix("to_user_id="))
+                            .unwrap_or_default()
+                            .to_string();
+                        whisper_sink.lock().unwrap().push(format!("whisper:{to}"));
+                        async { StatusCode::NO_CONTENT }
+                    },
+                ),
+            );
+        (testing::serve(router).await, sent)
+    }
+
+    /// Enqueues "u1" (opted out or in per `opted_in`), runs the outbox, returns what
+    /// was sent and takes "u1" out again.
+    async fn notifications_for(
+        app: &TestApp,
+        sent: &Mutex<Vec<String>>,
+        opted_in: Option<bool>,
+    ) -> Vec<String> {
+        if let Some(on) = opted_in {
+            prefs::set_all(app.queue.db.write(), "u1", on, util::now_epoch())
+                .await
+                .unwrap();
+        }
+        sent.lock().unwrap().clear();
+        let notices = join_notices(&app.queue, "u1").await;
+        let cfg = &app.settings.queue;
+        queue::enqueue_user(
+            app.queue.db.write(),
+            &app.queue.timings,
+            cfg,
+            &cfg.default_policy(),
+            testing::new_user("u1"),
+            &notices,
+        )
+        .await
+        .unwrap();
+        outbox::dispatch_due(app, util::now_epoch(), None, 5)
+            .await
+            .unwrap();
+        queue::cancel_by_user_id(app.queue.db.write(), &app.queue.timings, "u1")
+            .await
+            .unwrap();
+        sent.lock().unwrap().clone()
+    }
+
+    /// Logged in as "streamer" (id b1) with a token that does not need a refresh.
+    async fn log_in(app: &TestApp) {
+        let token = db::OAuthToken {
+            access_token: "token".to_string(),
+            refresh_token: "refresh".to_string(),
+            expires_at: util::now_epoch() + 3600,
+            scopes: None,
+        };
+        db::upsert_oauth_token(app.queue.db.write(), &token)
+            .await
+            .unwrap();
+        db::set_broadcaster_id(app.queue.db.write(), "b1")
+            .await
+            .unwrap();
+        db::set_broadcaster_login(app.queue.db.write(), "streamer")
+            .await
+            .unwrap();
+    }
+
+    async fn join_notification_app(chat_toml: &str) -> (TestApp, Arc<Mutex<Vec<String>>>) {
+        let (helix, sent) = mock_join_notifications().await;
+        let app = TestApp::with_helix(&format!("[chat]\n{chat_toml}"), &helix).await;
+        log_in(&app).await;
+        (app, sent)
+    }
+
+    #[tokio::test]
+    async fn chat_mention_gates_the_join_announcement() {
+        let (app, sent) = join_notification_app("join_announce = \"{user} joined\"\n").await;
+        assert_eq!(notifications_for(&app, &sent, None).await, vec!["chat"]);
+        assert!(notifications_for(&app, &sent, Some(false)).await.is_empty());
+        assert_eq!(
+            notifications_for(&app, &sent, Some(true)).await,
+            vec!["chat"]
+        );
+
+        // The override ignores the opt-out and goes by queue.default_notify.
+        prefs::set_all(app.queue.db.write(), "u1", false, util::now_epoch())
+            .await
+            .unwrap();
+        app.settings.set_prefs_overridden(true);
+        assert_eq!(notifications_for(&app, &sent, None).await, vec!["chat"]);
+    }
+
+    #[tokio::test]
+    async fn whisper_gates_the_join_whisper() {
+        let (app, sent) = join_notification_app("join_whisper = \"you are #{position}\"\n").await;
+        assert_eq!(
+            notifications_for(&app, &sent, None).await,
+            vec!["whisper:u1"]
+        );
+        assert!(notifications_for(&app, &sent, Some(false)).await.is_empty());
+        assert_eq!(
+            notifications_for(&app, &sent, Some(true)).await,
+            vec!["whisper:u1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn opted_out_viewers_get_nothing_by_default() {
+        let (app, sent) =
+            join_notification_app("join_announce = \"{user}\"\njoin_whisper = \"{position}\"\n[queue]\ndefault_notify = false\n").await;
+        assert!(notifications_for(&app, &sent, None).await.is_empty());
+        assert_eq!(
+            notifications_for(&app, &sent, Some(true)).await,
+            vec!["chat", "whisper:u1"]
+        );
+    }
+
+    /// Answers `GET /users?id=..` for every id except those starting with "gone"; records
+    /// the ids asked for per request and waits `delay` before each answer.
+    async fn mock_users(delay: std::time::Duration) -> (String, Arc<Mutex<Vec<Vec<String>>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&calls);
+        let router = Router::new().route(
+            "/users",
+            axum::routing::get(move |axum::extract::RawQuery(query): axum::extract::RawQuery| {
+                let sink = Arc::clone(&sink);
+                async move {
+                    let ids: Vec<String> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+                        .filter(|(k, _)| k == "id")
+                        .map(|(_, v)| v.into_owned())
+                        .collect();
+                    sink.lock().unwrap().push(ids.clone());
+                    tokio::time::sleep(delay).await;
+                    let data: Vec<_> = ids
+                        .iter()
+                        .filter(|id| !id.starts_with("gone"))
+                        .map(|id| {
+                            serde_json::json!({
+                                "id": id,
+                                "login": format!("login_{id}"),
+                                "display_name": format!("Name_{id}"),
+                                "profile_image_url": format!("https://static-cdn.jtvnw.net/u/{id}.png"),
+                            })
+                        })
+                        .collect();
+                    Json(serde_json::json!({ "data": data }))
+                }
+            }),
+        );
+        (testing::serve(router).await, calls)
+    }
+
+    fn ids(prefix: &str, n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{prefix}{i:03}")).collect()
+    }
+
+    #[tokio::test]
+    async fn prewarm_looks_stale_ids_up_in_chunks_of_one_hundred() {
+        let (helix, calls) = mock_users(std::time::Duration::ZERO).await;
+        let app = TestApp::with_helix("", &helix).await;
+        let fresh = db::CachedUserProfile {
+            user_id: "fresh".to_string(),
+            user_login: "fresh".to_string(),
+            display_name: "Fresh".to_string(),
+            profile_image_url: String::new(),
+            updated_at: util::now_epoch(),
+        };
+        db::upsert_cached_user_profile(app.queue.db.write(), &fresh)
+            .await
+            .unwrap();
+        let mut user_ids = ids("u", 245);
+        user_ids.extend(ids("gone", 5));
+        user_ids.push("fresh".to_string());
+
+        let result = prewarm_user_cache(
+            &app.twitch,
+            "token",
+            &user_ids,
+            std::time::Duration::from_secs(30),
+        )
+        .await
+        .unwrap();
+
+        let sizes: Vec<usize> = calls.lock().unwrap().iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![100, 100, 50]);
+        assert!(!calls
+            .lock()
+            .unwrap()
+            .concat()
+            .contains(&"fresh".to_string()));
+        assert_eq!(
+            (
+                result.fresh,
+                result.prewarmed,
+                result.deferred,
+                result.failed
+            ),
+            (1, 245, 0, 5)
+        );
+        let cached = db::get_cached_user_profile(app.queue.db.read(), "u244")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached.display_name, "Name_u244");
+        assert_eq!(
+            cached.profile_image_url,
+            "https://static-cdn.jtvnw.net/u/u244.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn prewarm_defers_the_chunks_left_when_the_budget_runs_out() {
+        let (helix, calls) = mock_users(std::time::Duration::from_millis(200)).await;
+        let app = TestApp::with_helix("", &helix).await;
+
+        let user_ids = ids("u", 350);
+        let result = prewarm_user_cache(
+            &app.twitch,
+            "token",
+            &user_ids,
+            std::time::Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+
+        // The first batch starts inside the budget and finishes; nothing new starts after it.
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        assert_eq!(
+            (
+                result.fresh,
+                result.prewarmed,
+                result.deferred,
+                result.failed
+            ),
+            (0, 100, 250, 0)
+        );
+        assert!(db::get_cached_user_profile(app.queue.db.read(), "u100")
+            .await
+            .unwrap()
+            .is_none());
+
+        // A second run picks up where the first stopped.
+        let again = prewarm_user_cache(
+            &app.twitch,
+            "token",
+            &user_ids,
+            std::time::Duration::from_secs(30),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (again.fresh, again.prewarmed, again.deferred),
+            (100, 250, 0)
+        );
+    }
+
+    const GRANTED: [&str; 3] = ["channel:read:redemptions", "user:write:chat", "chat:edit"];
+
+    /// id.twitch.tv stand-in. The code exchange lists the granted scopes; the first refresh
+    /// leaves `scope` out and later ones send it empty. Each token is `a<n>`, numbered
+    /// across exchange and refreshes. /validate answers with `validated` and records the
+    /// token it was asked about.
+    struct MockOAuth {
+        url: String,
+        validated: Arc<Mutex<Vec<String>>>,
+        validated_tokens: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn mock_oauth() -> MockOAuth {
+        let issued = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let validated = Arc::new(Mutex::new(GRANTED.map(str::to_string).to_vec()));
+        let validated_tokens = Arc::new(Mutex::new(Vec::new()));
+        let (scopes, seen) = (Arc::clone(&validated), Arc::clone(&validated_tokens));
+        let router = Router::new()
+            .route(
+                "/token",
+                post(
+                    move |axum::Form(form): axum::Form<HashMap<String, String>>| {
+                        let n = issued.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                        let mut body = serde_json::json!({
+                            "access_token": format!("a{n}"),
+                            "refresh_token": format!("r{n}"),
+                            "expires_in": 3600,
+                        });
+                        match form["grant_type"].as_str() {
+                            "authorization_code" => body["scope"] = serde_json::json!(GRANTED),
+                            _ if n > 2 => body["scope"] = serde_json::json!([]),
+                            _ => {}
+                        }
+                        async move { Json(body) }
+                    },
+                ),
+            )
+            .route(
+                "/validate",
+                axum::routing::get(move |headers: axum::http::HeaderMap| {
+                    let token = headers["authorization"]
+                        .to_str()
+                        .unwrap()
+                        .trim_start_matches("OAuth ")
+                        .to_string();
+                    seen.lock().unwrap().push(token);
+                    let scopes = scopes.lock().unwrap().clone();
+                    async move { Json(serde_json::json!({ "scopes": scopes })) }
+                }),
+            );
+        MockOAuth {
+            url: testing::serve(router).await,
+            validated,
+            validated_tokens,
+        }
+    }
+
+    async fn stored_scopes(app: &TestApp) -> Option<Vec<String>> {
+        db::get_oauth_token(app.queue.db.read())
+            .await
+            .unwrap()
+            .and_then(|t| t.scopes)
+    }
+
+    #[tokio::test]
+    async fn granted_scopes_survive_refreshes_and_validate_has_the_last_word() {
+        let oauth = mock_oauth().await;
+        let app = TestApp::with_oauth("[chat]\nirc_fallback = true\n", &oauth.url).await;
+        let granted = Some(GRANTED.map(str::to_string).to_vec());
+
+        let token = exchange_code_for_token(&app.twitch, "code").await.unwrap();
+        db::upsert_oauth_token(app.queue.db.write(), &token)
+            .await
+            .unwrap();
+        assert_eq!(stored_scopes(&app).await, granted);
+
+        // Neither a refresh without `scope` nor one with an empty list loses the grant.
+        let refreshed = refresh_and_store_token(&app.twitch, &token.refresh_token)
+            .await
+            .unwrap();
+        assert_eq!(
+            (refreshed.access_token.as_str(), stored_scopes(&app).await),
+            ("a2", granted.clone())
+        );
+        let refreshed = refresh_and_store_token(&app.twitch, &refreshed.refresh_token)
+            .await
+            .unwrap();
+        assert_eq!(
+            (refreshed.access_token.as_str(), stored_scopes(&app).await),
+            ("a3", granted.clone())
+        );
+
+        // The scope check after the refreshes asks about the refreshed token and still passes.
+        assert!(
+            crate::irc::check_chat_edit_scope(&app.twitch, &refreshed.access_token)
+                .await
+                .unwrap()
+        );
+        assert_eq!(*oauth.validated_tokens.lock().unwrap(), ["a3"]);
+        assert_eq!(stored_scopes(&app).await, granted);
+
+        // /validate is trusted either way: a scope it no longer lists is gone.
+        oauth
+            .validated
+            .lock()
+            .unwrap()
+            .retain(|s| s != crate::irc::CHAT_EDIT_SCOPE);
+        assert!(
+            !crate::irc::check_chat_edit_scope(&app.twitch, &refreshed.access_token)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            stored_scopes(&app).await,
+            Some(GRANTED[..2].iter().map(|s| s.to_string()).collect())
+        );
+    }
+
+    fn token(access_token: &str) -> db::OAuthToken {
+        db::OAuthToken {
+            access_token: access_token.to_string(),
+            refresh_token: format!("{access_token}-refresh"),
+            expires_at: util::now_epoch() + 3600,
+            scopes: None,
+        }
+    }
+
+    fn user(id: &str) -> HelixUser {
+        HelixUser {
+            id: id.to_string(),
+            login: format!("login_{id}"),
+            display_name: format!("Name_{id}"),
+            profile_image_url: String::new(),
+        }
+    }
+
+    /// Broadcaster b1 logged in with "old" and one viewer queued; then b2 logs in with "new".
+    async fn switch_pending(config_toml: &str) -> TestApp {
+        let app = TestApp::new(config_toml).await;
+        assert!(
+            !record_authorized_broadcaster(&app.twitch, &user("b1"), &token("old"))
+                .await
+                .unwrap()
+        );
+        testing::enqueue(&app.queue, testing::new_user("viewer")).await;
+
+        assert!(
+            record_authorized_broadcaster(&app.twitch, &user("b2"), &token("new"))
+                .await
+                .unwrap()
+        );
+        // The session is dropped even though the loop was not waiting at that moment.
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            app.twitch.eventsub.restart.notified(),
+        )
+        .await
+        .expect("restart signal kept for the loop");
+        app
+    }
+
+    async fn stored_login(app: &TestApp) -> (String, Option<String>) {
+        let token = db::get_oauth_token(app.queue.db.read())
+            .await
+            .unwrap()
+            .unwrap();
+        (
+            token.access_token,
+            db::get_broadcaster_id(app.queue.db.read()).await.unwrap(),
+        )
+    }
+
+    async fn queued(app: &TestApp) -> usize {
+        queue::list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn login_as_another_account_keeps_the_current_one_until_confirmed() {
+        let app = switch_pending("").await;
+        assert_eq!(
+            stored_login(&app).await,
+            ("old".to_string(), Some("b1".to_string()))
+        );
+        let pending = db::get_pending_broadcaster_switch(app.queue.db.read())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (
+                pending.broadcaster_id.as_str(),
+                pending.broadcaster_login.as_str()
+            ),
+            ("b2", "login_b2")
+        );
+        assert_eq!(queued(&app).await, 1);
+    }
+
+    #[tokio::test]
+    async fn denied_switch_keeps_the_current_login_and_data() {
+        let app = switch_pending("").await;
+        assert!(cancel_broadcaster_switch(&app.twitch).await.unwrap());
+
+        assert_eq!(
+            stored_login(&app).await,
+            ("old".to_string(), Some("b1".to_string()))
+        );
+        assert!(db::get_pending_broadcaster_switch(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db::get_pending_oauth_token(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db::get_broadcaster_switch_notice(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(queued(&app).await, 1);
+        assert!(!cancel_broadcaster_switch(&app.twitch).await.unwrap());
+        assert!(confirm_broadcaster_switch(&app.twitch)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn confirmed_switch_wipes_the_previous_channel_and_takes_the_new_login() {
+        let app = switch_pending("[twitch]\non_broadcaster_switch = \"wipe\"\n").await;
+        let notice = confirm_broadcaster_switch(&app.twitch)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(notice.previous_broadcaster_id, "b1");
+        assert_eq!(
+            notice.previous_broadcaster_login.as_deref(),
+            Some("login_b1")
+        );
+        assert_eq!(
+            (notice.data.as_str(), notice.archive_path.as_deref()),
+            ("wiped", None)
+        );
+        assert_eq!(
+            stored_login(&app).await,
+            ("new".to_string(), Some("b2".to_string()))
+        );
+        assert_eq!(
+            db::get_broadcaster_login(app.queue.db.read())
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("login_b2")
+        );
+        assert!(db::get_pending_broadcaster_switch(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db::get_pending_oauth_token(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(queued(&app).await, 0);
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            app.twitch.eventsub.restart.notified(),
+        )
+        .await
+        .expect("EventSub restarts under the new broadcaster");
+    }
+
+    #[tokio::test]
+    async fn confirmed_switch_can_archive_or_keep_the_previous_data() {
+        let archive_base = testing::TempPath::new();
+        let app = switch_pending(&format!(
+            "[server]\ndb_path = '{}'\n",
+            archive_base.as_str()
+        ))
+        .await;
+        let notice = confirm_broadcaster_switch(&app.twitch)
+            .await
+            .unwrap()
+            .unwrap();
+        let path = notice.archive_path.unwrap();
+        assert_eq!(notice.data, "archived");
+        assert!(path.starts_with(&format!("{}.broadcaster-b1-", archive_base.as_str())));
+        let archived = crate::db::Db::open(&path, 0, 1).await.unwrap();
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM queue_items")
+            .fetch_one(archived.read())
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+        drop(archived);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+        assert_eq!(queued(&app).await, 0);
+
+        let app = switch_pending("[twitch]\non_broadcaster_switch = \"keep\"\n").await;
+        let notice = confirm_broadcaster_switch(&app.twitch)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(notice.data, "kept");
+        assert_eq!(queued(&app).await, 1);
+        assert_eq!(
+            stored_login(&app).await,
+            ("new".to_string(), Some("b2".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_join_reward_subscription_still_fails() {
+        let (helix, _) = mock_subscriptions(SUB_TYPE_REDEMPTION_ADD).await;
+        let app = TestApp::with_helix("[twitch]\ntarget_reward_ids = [\"r1\"]\n", &helix).await;
+        let routing = RedemptionRoutingConfig::from_config(&app.settings);
+        assert!(
+            create_redemption_subscription(&app.twitch, "token", "session", "b1", &routing)
+                .await
+                .is_err()
+        );
+    }
+
+    fn cancel_redemption(user_id: &str) -> RedemptionEvent {
+        serde_json::from_value(serde_json::json!({
+            "id": format!("cancel-{user_id}"),
+            "user_id": user_id,
+            "user_login": user_id,
+            "user_name": user_id,
+            "reward": { "id": "cancel", "title": "Cancel", "cost": 1 },
+        }))
+        .unwrap()
+    }
+
+    async fn queue_order(app: &TestApp) -> Vec<String> {
+        queue::list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|i| i.user_id)
+            .collect()
+    }
+
+    async fn refunds(app: &TestApp) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM outbox WHERE event_type = 'redemption_status'")
+            .fetch_one(app.queue.db.read())
+            .await
+            .unwrap()
+    }
+
+    const CANCEL: &str = "[twitch]\ncancel_reward_id = \"cancel\"\nupdate_redemption_status = true\nrefund_unmatched_cancel = true\n";
+
+    #[tokio::test]
+    async fn cancel_reward_removes_the_item_by_default() {
+        let app = TestApp::new(CANCEL).await;
+        for user in ["a", "b"] {
+            testing::enqueue(&app.queue, testing::new_user(user)).await;
+        }
+
+        handle_cancel_redemption(&app.queue, &cancel_redemption("a"))
+            .await
+            .unwrap();
+
+        assert_eq!(queue_order(&app).await, ["b"]);
+        assert_eq!(refunds(&app).await, 0);
+    }
+
+    #[tokio::test]
+    async fn cancel_reward_can_move_the_item_to_the_back_instead() {
+        let app = TestApp::new(&format!(
+            "{CANCEL}cancel_reward_behavior = \"move_to_back\"\n"
+        ))
+        .await;
+        for user in ["a", "b", "c"] {
+            testing::enqueue(&app.queue, testing::new_user(user)).await;
+        }
+
+        handle_cancel_redemption(&app.queue, &cancel_redemption("a"))
+            .await
+            .unwrap();
+
+        assert_eq!(queue_order(&app).await, ["b", "c", "a"]);
+        assert_eq!(refunds(&app).await, 0);
+    }
+
+    #[tokio::test]
+    async fn cancel_reward_from_someone_not_queued_changes_nothing_and_is_refunded() {
+        for behavior in ["remove", "move_to_back"] {
+            let app = TestApp::new(&format!(
+                "{CANCEL}cancel_reward_behavior = \"{behavior}\"\n"
+            ))
+            .await;
+            testing::enqueue(&app.queue, testing::new_user("a")).await;
+
+            handle_cancel_redemption(&app.queue, &cancel_redemption("stranger"))
+                .await
+                .unwrap();
+
+            assert_eq!(queue_order(&app).await, ["a"], "{behavior}");
+            assert_eq!(refunds(&app).await, 1, "{behavior}");
+        }
+
+        let app = TestApp::new(
+            "[twitch]\ncancel_reward_id = \"cancel\"\nupdate_redemption_status = true\n",
+        )
+        .await;
+        handle_cancel_redemption(&app.queue, &cancel_redemption("stranger"))
+            .await
+            .unwrap();
+        assert_eq!(refunds(&app).await, 0);
+    }
+
+    #[tokio::test]
+    async fn slow_profile_lookups_time_out_and_open_the_breaker() {
+        let (helix, calls) = mock_users(std::time::Duration::from_millis(500)).await;
+        let app = TestApp::with_helix(
+            "[twitch]\nprofile_lookup_timeout_ms = 50\nprofile_breaker_failures = 2\nprofile_breaker_cooldown_secs = 600\n",
+            &helix,
+        )
+        .await;
+
+        for _ in 0..2 {
+            let started = std::time::Instant::now();
+            assert!(get_profile_image_url_cached(&app.twitch, "token", "u1")
+                .await
+                .is_err());
+            assert!(started.elapsed() < std::time::Duration::from_millis(400));
+        }
+        assert!(
+            app.twitch
+                .profile_breaker
+                .lock()
+                .unwrap()
+                .state(util::now_epoch())
+                .open
+        );
+
+        // Open: no further request reaches Helix.
+        assert_eq!(
+            get_profile_image_url_cached(&app.twitch, "token", "u1")
+                .await
+                .unwrap(),
+            ""
+        );
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
 }
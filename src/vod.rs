@@ -0,0 +1,197 @@
+use std::sync::{Arc, Mutex};
+
+use sqlx::{FromRow, SqlitePool};
+use tracing::{info, warn};
+
+use crate::{db, twitch, util, AppState};
+
+/// Participations linked per pass; the rest wait for the next one.
+const LINK_BATCH_ROWS: i64 = 50;
+const LINK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// The archive being recorded starts at about `stream.online`; one created earlier
+/// than this belongs to a previous stream (VODs off or not yet created).
+const START_TOLERANCE_SECS: i64 = 10 * 60;
+/// A session with no archive is asked again after this long, in case Twitch was slow to list it.
+const MISS_RETRY_SECS: i64 = 10 * 60;
+
+#[derive(Debug, Clone)]
+struct SessionVod {
+    url: String,
+    started_at: i64,
+}
+
+#[derive(Debug)]
+struct CachedLookup {
+    session_id: String,
+    vod: Option<SessionVod>,
+    fetched_at: i64,
+}
+
+/// The archive of the current stream session, looked up once per session.
+#[derive(Debug, Default)]
+pub struct VodCache {
+    last: Mutex<Option<CachedLookup>>,
+}
+
+/// Twitch's `?t=` value: `1h02m03s`.
+pub fn timestamp_param(offset_secs: i64) -> String {
+    let s = offset_secs.max(0);
+    format!("{}h{:02}m{:02}s", s / 3600, s % 3600 / 60, s % 60)
+}
+
+/// `vod_url` opened at `offset_secs`.
+pub fn timestamped_link(vod_url: &str, offset_secs: i64) -> String {
+    let sep = if vod_url.contains('?') { '&' } else { '?' };
+    format!("{vod_url}{sep}t={}", timestamp_param(offset_secs))
+}
+
+/// The archive for `session_id` (the current session), from the cache when possible.
+async fn session_vod(
+    state: &AppState,
+    session_id: &str,
+    now: i64,
+) -> anyhow::Result<Option<SessionVod>> {
+    {
+        let last = state.vod_cache.last.lock().unwrap();
+        if let Some(c) = last.as_ref().filter(|c| c.session_id == session_id) {
+            if c.vod.is_some() || now - c.fetched_at < MISS_RETRY_SECS {
+                return Ok(c.vod.clone());
+            }
+        }
+    }
+
+    let Some(broadcaster_id) = db::get_broadcaster_id(state.queue.db.read()).await? else {
+        anyhow::bail!("no broadcaster yet");
+    };
+    let access_token = twitch::get_fresh_access_token(&state.twitch).await?;
+    let video =
+        twitch::helix_get_latest_archive(&state.twitch, &access_token, &broadcaster_id).await?;
+    let session_start = session_id.parse::<i64>().unwrap_or(now);
+    let vod = video.and_then(|v| {
+        let started_at = util::parse_utc_datetime(&v.created_at)?;
+        (started_at >= session_start - START_TOLERANCE_SECS).then_some(SessionVod {
+            url: v.url,
+            started_at,
+        })
+    });
+    if vod.is_none() {
+        info!(
+            session_id,
+            "no archive for this stream; VOD links stay empty"
+        );
+    }
+    *state.vod_cache.last.lock().unwrap() = Some(CachedLookup {
+        session_id: session_id.to_string(),
+        vod: vod.clone(),
+        fetched_at: now,
+    });
+    Ok(vod)
+}
+
+#[derive(Debug, FromRow)]
+struct PendingRow {
+    id: i64,
+    session_id: Option<String>,
+    turn_started_at: i64,
+}
+
+async fn store_link(
+    pool: &SqlitePool,
+    id: i64,
+    link: Option<(&str, i64)>,
+    now: i64,
+) -> anyhow::Result<()> {
+    sqlx::query("UPDATE participations SET vod_url = ?2, vod_offset_secs = ?3, vod_checked_at = ?4 WHERE id = ?1")
+        .bind(id)
+        .bind(link.map(|(url, _)| url))
+        .bind(link.map(|(_, offset)| offset))
+        .bind(now)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Attaches the VOD and offset to turns completed since the last pass. Turns from an
+/// earlier session than the current one can no longer be placed and are stored empty.
+/// Returns how many rows got a link.
+pub async fn link_pending(state: &AppState) -> anyhow::Result<usize> {
+    let pool = state.queue.db.write();
+    let rows = sqlx::query_as::<_, PendingRow>(
+        r#"SELECT id, session_id, turn_started_at FROM participations
+           WHERE vod_checked_at IS NULL AND turn_started_at IS NOT NULL
+           ORDER BY id
+           LIMIT ?1"#,
+    )
+    .bind(LINK_BATCH_ROWS)
+    .fetch_all(pool)
+    .await?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let now = util::now_epoch();
+    let current = db::current_session_id(pool).await?;
+    let mut linked = 0;
+    for row in rows {
+        let vod = match row.session_id.as_deref() {
+            Some(session_id) if current.as_deref() == Some(session_id) => {
+                session_vod(state, session_id, now).await?
+            }
+            _ => None,
+        };
+        let offset = vod
+            .as_ref()
+            .map(|v| row.turn_started_at - v.started_at)
+            .filter(|o| *o >= 0);
+        let link = vod.as_ref().zip(offset).map(|(v, o)| (v.url.as_str(), o));
+        store_link(pool, row.id, link, now).await?;
+        linked += usize::from(link.is_some());
+    }
+    Ok(linked)
+}
+
+/// Background: `link_pending` every `LINK_INTERVAL`.
+pub async fn run_linker(state: Arc<AppState>) {
+    loop {
+        match link_pending(&state).await {
+            Ok(n) if n > 0 => info!(linked = n, "attached VOD links to completed turns"),
+            Ok(_) => {}
+            Err(e) => warn!(error = ?e, "VOD lookup failed; retrying later"),
+        }
+        tokio::time::sleep(LINK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_use_twitchs_hms_form() {
+        for (secs, expected) in [
+            (0, "0h00m00s"),
+            (-5, "0h00m00s"),
+            (7, "0h00m07s"),
+            (59, "0h00m59s"),
+            (60, "0h01m00s"),
+            (3600, "1h00m00s"),
+            (7200, "2h00m00s"),
+            (3723, "1h02m03s"),
+            (36_000 + 59 * 60 + 59, "10h59m59s"),
+        ] {
+            assert_eq!(timestamp_param(secs), expected, "{secs}");
+        }
+    }
+
+    #[test]
+    fn links_keep_an_existing_query() {
+        assert_eq!(
+            timestamped_link("https://www.twitch.tv/videos/1", 3723),
+            "https://www.twitch.tv/videos/1?t=1h02m03s"
+        );
+        assert_eq!(
+            timestamped_link("https://www.twitch.tv/videos/1?filter=all", 59),
+            "https://www.twitch.tv/videos/1?filter=all&t=0h00m59s"
+        );
+    }
+}
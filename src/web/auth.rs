@@ -0,0 +1,480 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query, State},
+    http::{request::Parts, StatusCode},
+    response::Redirect,
+    Json,
+};
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use super::{ApiError, ApiJson, ApiResult};
+use crate::{access, admin_users, db, twitch, util, AppState};
+
+/// Admin-only handlers take this instead of checking the role themselves. It reads the
+/// role that [`access::require_role`] resolved and the route group of the request
+/// ([`access::permission_for`]), so a route that is public by mistake still rejects with
+/// 401 (no credentials) / 403 (role without that permission) before the handler runs.
+#[derive(Debug, Clone)]
+pub struct AdminContext {
+    /// Logged with every change as `name (role)`.
+    pub actor: Actor,
+}
+
+#[derive(Debug, Clone)]
+pub struct Actor {
+    /// See [`access::actor_from_headers`].
+    pub name: String,
+    pub role: access::Role,
+}
+
+impl std::fmt::Display for Actor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.role.as_str())
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminContext {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(&role) = parts.extensions.get::<access::Role>() else {
+            return Err(ApiError::Unauthorized("login required".to_string()));
+        };
+        let actor = Actor {
+            name: access::actor_from_headers(&parts.headers),
+            role,
+        };
+        let permission = access::permission_for(parts.uri.path());
+        if !role.allows(permission) {
+            info!(actor = %actor, permission = permission.as_str(), path = %parts.uri.path(), "permission denied");
+            return Err(ApiError::MissingPermission { permission, role });
+        }
+        Ok(AdminContext { actor })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AuthCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Broadcaster only: whoever logs in here replaces the Twitch token.
+pub(super) async fn auth_start(
+    State(app): State<Arc<AppState>>,
+    _admin: AdminContext,
+) -> ApiResult<Redirect> {
+    if util::is_blank(&app.settings.twitch.client_id)
+        || util::is_blank(&app.settings.twitch.client_secret)
+    {
+        return Err(ApiError::BadRequest(
+            "config.toml の twitch.client_id / twitch.client_secret を設定してください".to_string(),
+        ));
+    }
+
+    let state = uuid::Uuid::new_v4().to_string();
+    {
+        let mut w = app.twitch.oauth_state.write().await;
+        *w = Some(state.clone());
+    }
+
+    let url = twitch::build_authorize_url(&app.settings, &state)?;
+    Ok(Redirect::temporary(&url))
+}
+
+pub(super) async fn auth_callback(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<AuthCallbackQuery>,
+) -> ApiResult<Redirect> {
+    if let Some(err) = q.error {
+        let desc = q.error_description.unwrap_or_default();
+        return Err(ApiError::BadRequest(format!("oauth error: {err} {desc}")));
+    }
+
+    let code = q
+        .code
+        .ok_or_else(|| ApiError::BadRequest("missing code".to_string()))?;
+    let returned_state = q
+        .state
+        .ok_or_else(|| ApiError::BadRequest("missing state".to_string()))?;
+
+    let expected_state = { app.twitch.oauth_state.read().await.clone() };
+    if expected_state.as_deref() != Some(returned_state.as_str()) {
+        return Err(ApiError::BadRequest("state mismatch".to_string()));
+    }
+
+    let token = twitch::exchange_code_for_token(&app.twitch, &code).await?;
+
+    // Resolve the account before storing anything: a different one must be confirmed first.
+    let mut redirect_to = "/admin";
+    match twitch::helix_get_self(&app.twitch, &token.access_token).await {
+        Ok(me) => {
+            if twitch::record_authorized_broadcaster(&app.twitch, &me, &token).await? {
+                redirect_to = "/admin?broadcaster_switch=pending";
+            }
+        }
+        Err(e) => {
+            error!(error=?e, "authorized but failed to resolve broadcaster via helix");
+            // Without the account id we cannot tell whether this is still the same channel.
+            if db::get_broadcaster_id(app.queue.db.write())
+                .await?
+                .is_some()
+            {
+                warn!("could not check the new login against the stored broadcaster; keeping the current login");
+                return Err(ApiError::BadRequest(
+                    "ログインしたアカウントを確認できませんでした。現在のログインはそのままです。もう一度ログインしてください".to_string(),
+                ));
+            }
+            db::upsert_oauth_token(app.queue.db.write(), &token).await?;
+        }
+    }
+
+    {
+        let mut w = app.twitch.oauth_state.write().await;
+        *w = None;
+    }
+
+    Ok(Redirect::temporary(redirect_to))
+}
+
+pub(super) async fn auth_logout(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<StatusCode> {
+    db::delete_oauth_token(app.queue.db.write()).await?;
+    info!(actor = %admin.actor, "logged out");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) async fn api_auth_confirm_switch(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<Json<db::BroadcasterSwitchNotice>> {
+    let Some(notice) = twitch::confirm_broadcaster_switch(&app.twitch).await? else {
+        return Err(ApiError::NotFound(
+            "no broadcaster switch is pending".to_string(),
+        ));
+    };
+    info!(actor = %admin.actor, previous_broadcaster_id=%notice.previous_broadcaster_id, data=%notice.data, "broadcaster switch confirmed");
+    Ok(Json(notice))
+}
+
+/// Rejects the pending switch and forgets the new login; the current one stays in use.
+pub(super) async fn api_auth_cancel_switch(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<StatusCode> {
+    if !twitch::cancel_broadcaster_switch(&app.twitch).await? {
+        return Err(ApiError::NotFound(
+            "no broadcaster switch is pending".to_string(),
+        ));
+    }
+    info!(actor = %admin.actor, "broadcaster switch canceled; keeping the current login");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Helper / manager logins (broadcaster only, like the rest of `/api/admins`).
+pub(super) async fn api_admins(
+    State(app): State<Arc<AppState>>,
+    _admin: AdminContext,
+) -> ApiResult<Json<Vec<admin_users::AdminUser>>> {
+    Ok(Json(admin_users::list(app.queue.db.read()).await?))
+}
+
+/// Replaces the helper / manager logins. Each signs in with its name as the Basic user
+/// name and its own password; leaving `password` out keeps an existing login's password.
+pub(super) async fn api_admins_put(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(entries): ApiJson<Vec<admin_users::AdminUserInput>>,
+) -> ApiResult<Json<Vec<admin_users::AdminUser>>> {
+    if app.settings.server.admin_password.is_empty() {
+        // Without a broadcaster password every request is already a broadcaster one.
+        return Err(ApiError::Conflict(
+            "set server.admin_password before adding admin logins".to_string(),
+        ));
+    }
+    let entries = admin_users::validate(entries).map_err(ApiError::BadRequest)?;
+    if let Err(name) =
+        admin_users::replace(app.queue.db.write(), &entries, util::now_epoch()).await?
+    {
+        return Err(ApiError::BadRequest(format!(
+            "{name}: a new login needs a password"
+        )));
+    }
+    let users = admin_users::list(app.queue.db.read()).await?;
+    info!(
+        actor = %admin.actor,
+        managers = users.iter().filter(|u| u.role == access::Role::Manager).count(),
+        helpers = users.iter().filter(|u| u.role == access::Role::Helper).count(),
+        "admin logins replaced"
+    );
+    Ok(Json(users))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Router};
+    use base64::Engine;
+
+    use super::*;
+    use crate::{
+        queue,
+        testing::{self, TestApp},
+    };
+
+    const PASSWORDS: &str =
+        "[server]\nadmin_password = \"admin-pw\"\nviewer_password = \"viewer-pw\"\n";
+
+    /// Serves a read route in the queue group that answers with the resolved actor.
+    async fn probe(app: &TestApp) -> String {
+        let router = Router::new()
+            .route(
+                "/api/queue/probe",
+                get(|admin: AdminContext| async move { admin.actor.to_string() }),
+            )
+            .layer(middleware::from_fn_with_state(
+                app.state.clone(),
+                access::require_role,
+            ))
+            .with_state(app.state.clone());
+        testing::serve(router).await
+    }
+
+    async fn send(req: reqwest::RequestBuilder) -> (u16, String) {
+        let res = req.send().await.unwrap();
+        (res.status().as_u16(), res.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn each_credential_resolves_to_its_role() {
+        let app = TestApp::new(PASSWORDS).await;
+        let logins = vec![
+            admin_users::AdminUserInput {
+                name: "mod1".into(),
+                role: access::Role::Manager,
+                password: Some("mod-pw".into()),
+            },
+            admin_users::AdminUserInput {
+                name: "help1".into(),
+                role: access::Role::Helper,
+                password: Some("help-pw".into()),
+            },
+        ];
+        admin_users::replace(app.queue.db.write(), &logins, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let base = probe(&app).await;
+        let client = reqwest::Client::new();
+        let url = format!("{base}/api/queue/probe");
+
+        for (user, password, expected) in [
+            // The shared passwords ignore the user name, which only names the actor.
+            ("alice", "admin-pw", (200, "alice (broadcaster)")),
+            ("", "admin-pw", (200, "admin (broadcaster)")),
+            ("mod1", "mod-pw", (200, "mod1 (manager)")),
+            ("help1", "help-pw", (200, "help1 (helper)")),
+            // Viewers hold no permission, not even for a read behind `AdminContext`.
+            ("bob", "viewer-pw", (403, "")),
+            // An admin_users password only works with its own name.
+            ("mod1", "help-pw", (401, "login required")),
+            ("someone", "mod-pw", (401, "login required")),
+            ("alice", "wrong", (401, "login required")),
+        ] {
+            let (status, body) = send(client.get(&url).basic_auth(user, Some(password))).await;
+            assert_eq!(status, expected.0, "{user}:{password}: {body}");
+            if status != 403 {
+                assert_eq!(body, expected.1, "{user}:{password}");
+            }
+        }
+
+        // Only Basic credentials count.
+        assert_eq!(send(client.get(&url)).await.0, 401);
+        assert_eq!(send(client.get(&url).bearer_auth("admin-pw")).await.0, 401);
+        let header = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode("admin-pw")
+        );
+        assert_eq!(
+            send(client.get(&url).header("Authorization", header))
+                .await
+                .0,
+            401,
+            "no user:password pair"
+        );
+    }
+
+    #[tokio::test]
+    async fn without_an_admin_password_every_request_is_the_broadcaster() {
+        let app = TestApp::new("").await;
+        let base = probe(&app).await;
+        let (status, body) =
+            send(reqwest::Client::new().get(format!("{base}/api/queue/probe"))).await;
+        assert_eq!((status, body.as_str()), (200, "admin (broadcaster)"));
+    }
+
+    #[tokio::test]
+    async fn each_role_reaches_exactly_its_permission_groups() {
+        let app = TestApp::new(PASSWORDS).await;
+        let logins = vec![
+            admin_users::AdminUserInput {
+                name: "mod1".into(),
+                role: access::Role::Manager,
+                password: Some("mod-pw".into()),
+            },
+            admin_users::AdminUserInput {
+                name: "help1".into(),
+                role: access::Role::Helper,
+                password: Some("help-pw".into()),
+            },
+        ];
+        admin_users::replace(app.queue.db.write(), &logins, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let client = reqwest::Client::new();
+
+        // One mutating route per group, each answering 2xx when let through.
+        let groups = [
+            (
+                access::Permission::Queue,
+                reqwest::Method::POST,
+                "/api/queue/pause",
+            ),
+            (
+                access::Permission::Settings,
+                reqwest::Method::DELETE,
+                "/api/cache/users",
+            ),
+            (
+                access::Permission::Auth,
+                reqwest::Method::POST,
+                "/api/overlay_token/rotate",
+            ),
+        ];
+        // Allowed per group, in the order of `groups`.
+        for (user, password, role, allowed) in [
+            (
+                "alice",
+                "admin-pw",
+                access::Role::Broadcaster,
+                [true, true, true],
+            ),
+            ("mod1", "mod-pw", access::Role::Manager, [true, true, false]),
+            (
+                "help1",
+                "help-pw",
+                access::Role::Helper,
+                [true, false, false],
+            ),
+            (
+                "bob",
+                "viewer-pw",
+                access::Role::Viewer,
+                [false, false, false],
+            ),
+        ] {
+            for ((permission, method, path), allowed) in groups.iter().zip(allowed) {
+                assert_eq!(access::permission_for(path), *permission, "{path}");
+                let (status, body) = send(
+                    client
+                        .request(method.clone(), format!("{base}{path}"))
+                        .basic_auth(user, Some(password)),
+                )
+                .await;
+                if allowed {
+                    assert!(
+                        (200..300).contains(&status),
+                        "{role:?} {method} {path}: {status} {body}"
+                    );
+                } else {
+                    assert_eq!(status, 403, "{role:?} {method} {path}: {body}");
+                    if role != access::Role::Viewer {
+                        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        assert_eq!(body["permission"], permission.as_str(), "{role:?} {path}");
+                        assert_eq!(body["role"], role.as_str(), "{role:?} {path}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// `(method, path)` of every mutating route registered in `web::router`, read from its
+    /// source so that a new route is covered without touching this test.
+    fn mutating_routes() -> Vec<(reqwest::Method, String)> {
+        let source = include_str!("mod.rs");
+        let router_fn = &source[source.find("pub fn router(").unwrap()..];
+        let router_fn = &router_fn[..router_fn.find("\n}\n").unwrap()];
+        let mut out = Vec::new();
+        for route in router_fn.split(".route(").skip(1) {
+            let Some(path) = route.split('"').nth(1) else {
+                continue;
+            };
+            let path = path
+                .replace(":id", "x")
+                .replace(":name", "x")
+                .replace(":user_id", "x")
+                .replace(":break_id", "x");
+            for (token, method) in [
+                ("post(", reqwest::Method::POST),
+                ("put(", reqwest::Method::PUT),
+                ("patch(", reqwest::Method::PATCH),
+                ("delete(", reqwest::Method::DELETE),
+            ] {
+                let handler = route
+                    .match_indices(token)
+                    .any(|(i, _)| !route[..i].ends_with(|c: char| c.is_alphanumeric() || c == '_'));
+                if handler {
+                    out.push((method, path.clone()));
+                }
+            }
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn every_mutating_route_requires_credentials() {
+        let routes = mutating_routes();
+        assert!(routes.len() > 40, "the route list was not read: {routes:?}");
+        assert!(routes.contains(&(reqwest::Method::PATCH, "/api/queue/x".to_string())));
+        assert!(routes.contains(&(reqwest::Method::DELETE, "/api/cache/users/x".to_string())));
+
+        let app = TestApp::new(&format!("{PASSWORDS}[ingest]\nsecret = \"s3cret\"\n")).await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let client = reqwest::Client::new();
+        for (method, path) in routes {
+            let url = format!("{base}{path}");
+            // The ingest endpoint is open to the bot and checks its request signature instead.
+            let (anonymous, viewer) = if path == "/api/ingest/enqueue" {
+                (401, 401)
+            } else {
+                (401, 403)
+            };
+            let (status, body) = send(client.request(method.clone(), &url).body("{}")).await;
+            assert_eq!(
+                status, anonymous,
+                "{method} {path} without credentials: {body}"
+            );
+            let (status, body) = send(
+                client
+                    .request(method.clone(), &url)
+                    .basic_auth("v", Some("viewer-pw"))
+                    .body("{}"),
+            )
+            .await;
+            assert_eq!(status, viewer, "{method} {path} as a viewer: {body}");
+        }
+        assert!(queue::queued_user_ids(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    response::Response,
+    Json,
+};
+use serde::Deserialize;
+use tracing::info;
+
+use super::{get_valid_access_token, queue_api::RedactQuery, AdminContext, ApiError, ApiResult};
+use crate::{
+    history,
+    history_import::{self, HistoryImportReport, ImportOptions, ParseError, ResolveReport},
+    pagination::PageRequest,
+    AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CursorQuery {
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+impl CursorQuery {
+    fn page(&self) -> ApiResult<PageRequest> {
+        PageRequest::parse(self.limit, self.cursor.as_deref())
+            .map_err(|e| ApiError::BadRequest(e.to_string()))
+    }
+}
+
+pub(super) async fn api_history(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<CursorQuery>,
+    Query(r): Query<RedactQuery>,
+) -> ApiResult<Response> {
+    let page = history::list_history(app.queue.db.read(), None, q.page()?).await?;
+    r.apply(page)
+}
+
+pub(super) async fn api_user_history(
+    State(app): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+    Query(q): Query<CursorQuery>,
+    Query(r): Query<RedactQuery>,
+) -> ApiResult<Response> {
+    let page = history::list_history(app.queue.db.read(), Some(&user_id), q.page()?).await?;
+    r.apply(page)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct HistoryImportQuery {
+    format: String,
+    // Not `#[serde(flatten)] ImportOptions`: flattened query values arrive as strings.
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    offline: bool,
+}
+
+/// Turn history exported from another queue tool; the body is the file as is.
+pub(super) async fn api_import_history(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Query(q): Query<HistoryImportQuery>,
+    body: Bytes,
+) -> ApiResult<Json<HistoryImportReport>> {
+    let Some(adapter) = history_import::adapter(&q.format) else {
+        return Err(ApiError::BadRequest(format!(
+            "unknown format {:?} (one of: {})",
+            q.format,
+            history_import::format_names().join(", ")
+        )));
+    };
+    let input = std::str::from_utf8(&body)
+        .map_err(|_| ApiError::BadRequest("the file must be UTF-8".to_string()))?;
+    let opts = ImportOptions {
+        dry_run: q.dry_run,
+        offline: q.offline,
+    };
+    if !opts.offline {
+        // Logins are looked up right away; say so before parsing instead of failing after.
+        get_valid_access_token(&app).await?;
+    }
+    let report = history_import::import_history(&app, adapter, input, opts)
+        .await
+        .map_err(|e| match e.downcast::<ParseError>() {
+            Ok(p) => ApiError::BadRequest(p.to_string()),
+            Err(e) => ApiError::Internal(e),
+        })?;
+    info!(
+        actor = %admin.actor,
+        format = report.format,
+        dry_run = report.dry_run,
+        parsed = report.parsed,
+        inserted = report.inserted,
+        unresolved = report.unresolved.len(),
+        unknown_logins = report.unknown_logins.len(),
+        "imported turn history"
+    );
+    Ok(Json(report))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct DryRunQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Looks up the logins of offline imports (`offline=true`).
+pub(super) async fn api_import_resolve(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Query(q): Query<DryRunQuery>,
+) -> ApiResult<Json<ResolveReport>> {
+    get_valid_access_token(&app).await?;
+    let report = history_import::resolve_pending(&app, q.dry_run).await?;
+    info!(
+        actor = %admin.actor,
+        dry_run = report.dry_run,
+        resolved = report.resolved.len(),
+        unknown_logins = report.unknown_logins.len(),
+        "resolved imported logins"
+    );
+    Ok(Json(report))
+}
+
+pub(super) async fn api_redemptions_recent(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<CursorQuery>,
+    Query(r): Query<RedactQuery>,
+) -> ApiResult<Response> {
+    let page = history::list_recent_redemptions(app.queue.db.read(), q.page()?).await?;
+    r.apply(page)
+}
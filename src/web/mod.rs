@@ -0,0 +1,523 @@
+mod auth;
+mod history;
+mod overlay;
+mod profiles;
+mod queue_api;
+mod rewards;
+mod status;
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use axum::routing::get_service;
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{DefaultBodyLimit, FromRef, FromRequest, Request},
+    http::{HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use tower_http::services::{ServeDir, ServeFile};
+use tracing::error;
+
+use auth::AdminContext;
+
+use crate::{access, audit, config::Settings, db, queue::QueueService, twitch, util, AppState};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("request body too large")]
+    PayloadTooLarge,
+    #[error("invalid json: {message}")]
+    InvalidJson {
+        message: String,
+        line: usize,
+        column: usize,
+    },
+    #[error("missing permission: {permission:?}")]
+    MissingPermission {
+        permission: access::Permission,
+        role: access::Role,
+    },
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, msg) = match &self {
+            ApiError::BadRequest(s) => (StatusCode::BAD_REQUEST, s.clone()),
+            ApiError::Unauthorized(s) => (StatusCode::UNAUTHORIZED, s.clone()),
+            ApiError::NotFound(s) => (StatusCode::NOT_FOUND, s.clone()),
+            ApiError::Forbidden(s) => (StatusCode::FORBIDDEN, s.clone()),
+            ApiError::Conflict(s) => (StatusCode::CONFLICT, s.clone()),
+            ApiError::Internal(e) => {
+                error!(error=?e, "internal error");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal error".to_string(),
+                )
+            }
+            // Body rejections answer with JSON so clients can tell them apart from handler errors.
+            ApiError::PayloadTooLarge => {
+                let body = serde_json::json!({
+                    "error": "payload_too_large",
+                    "message": "request body exceeds the size limit",
+                });
+                return (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response();
+            }
+            ApiError::MissingPermission { permission, role } => {
+                return access::missing_permission_response(*permission, *role);
+            }
+            ApiError::InvalidJson {
+                message,
+                line,
+                column,
+            } => {
+                let body = serde_json::json!({
+                    "error": "invalid_json",
+                    "message": message,
+                    "line": line,
+                    "column": column,
+                });
+                return (StatusCode::BAD_REQUEST, Json(body)).into_response();
+            }
+        };
+        (status, msg).into_response()
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+static REJECTED_PAYLOAD_TOO_LARGE: AtomicU64 = AtomicU64::new(0);
+static REJECTED_INVALID_JSON: AtomicU64 = AtomicU64::new(0);
+
+/// `Json` with the body rejections mapped to `ApiError` (structured 413 / `invalid_json`).
+/// The body is parsed regardless of Content-Type.
+struct ApiJson<T>(T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: serde::de::DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let bytes = body_bytes(req, state).await?;
+        parse_json(&bytes).map(ApiJson)
+    }
+}
+
+async fn body_bytes<S: Send + Sync>(req: Request, state: &S) -> ApiResult<Bytes> {
+    Bytes::from_request(req, state).await.map_err(|e| {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            REJECTED_PAYLOAD_TOO_LARGE.fetch_add(1, Ordering::Relaxed);
+            ApiError::PayloadTooLarge
+        } else {
+            ApiError::BadRequest(e.body_text())
+        }
+    })
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> ApiResult<T> {
+    serde_json::from_slice(bytes).map_err(|e| {
+        // Empty bodies are expected where the body is optional (`Option<ApiJson<_>>`).
+        if !bytes.is_empty() {
+            REJECTED_INVALID_JSON.fetch_add(1, Ordering::Relaxed);
+        }
+        ApiError::InvalidJson {
+            message: e.to_string(),
+            line: e.line(),
+            column: e.column(),
+        }
+    })
+}
+
+// Handlers that need a single capability extract it (`State<QueueService>`) instead of
+// the whole `AppState`.
+impl FromRef<Arc<AppState>> for QueueService {
+    fn from_ref(app: &Arc<AppState>) -> Self {
+        app.queue.clone()
+    }
+}
+
+impl FromRef<Arc<AppState>> for Settings {
+    fn from_ref(app: &Arc<AppState>) -> Self {
+        app.settings.clone()
+    }
+}
+
+pub fn router(state: Arc<AppState>) -> Router {
+    let static_dir = state.settings.server.static_dir.clone();
+    let obs_file = format!("{static_dir}/obs.html");
+    let admin_file = format!("{static_dir}/admin.html");
+    let rewards_file = format!("{static_dir}/rewards.html");
+    let css_creator_file = format!("{static_dir}/css_creator.html");
+    let assets_dir = format!("{static_dir}/assets");
+    let body_limit = state.settings.server.max_body_bytes;
+    let import_body_limit = state.settings.server.max_import_body_bytes.max(body_limit);
+
+    let api = Router::new()
+        .route("/api/status", get(status::api_status))
+        .route(
+            "/api/auth/confirm_switch",
+            post(auth::api_auth_confirm_switch),
+        )
+        .route(
+            "/api/auth/cancel_switch",
+            post(auth::api_auth_cancel_switch),
+        )
+        .route(
+            "/api/queue",
+            get(queue_api::api_queue).post(queue_api::api_queue_enqueue_login),
+        )
+        .route("/api/queue/admin", get(queue_api::api_queue_admin))
+        .route("/api/queue/explain", get(queue_api::api_queue_explain))
+        .route(
+            "/api/queue/:id",
+            axum::routing::patch(queue_api::api_queue_patch),
+        )
+        .route(
+            "/api/queue/clear_previous",
+            post(queue_api::api_queue_clear_previous),
+        )
+        .route("/api/queue/clear", post(queue_api::api_queue_clear))
+        .route(
+            "/api/queue/freeze",
+            get(queue_api::api_queue_freeze_state).post(queue_api::api_queue_freeze),
+        )
+        .route("/api/queue/thaw", post(queue_api::api_queue_thaw))
+        .route("/api/queue/ffa", post(queue_api::api_queue_ffa))
+        .route("/api/queue/pause", post(queue_api::api_queue_pause))
+        .route("/api/queue/resume", post(queue_api::api_queue_resume))
+        .route("/api/queue/ffa/stop", post(queue_api::api_queue_ffa_stop))
+        .route("/api/queue/add", post(queue_api::api_queue_add))
+        .route("/api/ingest/enqueue", post(queue_api::api_ingest_enqueue))
+        .route(
+            "/api/queue/import",
+            post(queue_api::api_queue_import).layer(DefaultBodyLimit::max(import_body_limit)),
+        )
+        .route(
+            "/api/queue/slots",
+            get(queue_api::api_slots_get)
+                .put(queue_api::api_slots_put)
+                .delete(queue_api::api_slots_delete),
+        )
+        .route("/api/sessions/:id/agenda", get(queue_api::api_agenda))
+        .route(
+            "/api/sessions/:id/agenda.ics",
+            get(queue_api::api_agenda_ics),
+        )
+        .route(
+            "/api/sessions/:id/breaks",
+            post(queue_api::api_session_break_add),
+        )
+        .route(
+            "/api/sessions/:id/breaks/:break_id",
+            axum::routing::delete(queue_api::api_session_break_delete),
+        )
+        .route("/api/queue/dedupe", post(queue_api::api_queue_dedupe))
+        .route("/api/queue/:id/delete", post(queue_api::api_queue_delete))
+        .route(
+            "/api/queue/:id/abort_complete",
+            post(queue_api::api_queue_abort_complete),
+        )
+        .route("/api/queue/:id/away", post(queue_api::api_queue_away))
+        .route("/api/queue/:id/back", post(queue_api::api_queue_back))
+        .route("/api/queue/:id/move_up", post(queue_api::api_queue_move_up))
+        .route(
+            "/api/queue/:id/move_down",
+            post(queue_api::api_queue_move_down),
+        )
+        .route("/api/queue/:id/move", post(queue_api::api_queue_move))
+        .route(
+            "/api/queue/:id/move_top",
+            post(queue_api::api_queue_move_top),
+        )
+        .route(
+            "/api/queue/:id/move_bottom",
+            post(queue_api::api_queue_move_bottom),
+        )
+        .route("/api/cues", get(overlay::api_cues))
+        .route(
+            "/api/overlay/bootstrap",
+            get(overlay::api_overlay_bootstrap),
+        )
+        .route(
+            "/api/overlay_token/status",
+            get(overlay::api_overlay_token_status),
+        )
+        .route(
+            "/api/overlay_token/rotate",
+            post(overlay::api_overlay_token_rotate),
+        )
+        .route("/api/events/export.ndjson", get(status::api_events_export))
+        .route(
+            "/api/pending_interest",
+            get(queue_api::api_pending_interest),
+        )
+        .route(
+            "/api/pending_interest/:id/admit",
+            post(queue_api::api_pending_interest_admit),
+        )
+        .route(
+            "/api/roster",
+            get(queue_api::api_roster_get).put(queue_api::api_roster_put),
+        )
+        .route(
+            "/api/roster/import",
+            post(queue_api::api_roster_import).layer(DefaultBodyLimit::max(import_body_limit)),
+        )
+        .route("/api/rewards", get(rewards::api_rewards))
+        .route(
+            "/api/stats/reward_pricing",
+            get(rewards::api_stats_reward_pricing),
+        )
+        .route(
+            "/api/stats/unique_participants",
+            get(status::api_stats_unique_participants),
+        )
+        .route("/api/stats/public", get(status::api_stats_public))
+        .route(
+            "/api/stats/import",
+            post(status::api_stats_import).layer(DefaultBodyLimit::max(import_body_limit)),
+        )
+        .route("/api/digest", get(status::api_digest))
+        .route("/api/diagnostics/token", get(status::api_diagnostics_token))
+        .route(
+            "/api/diagnostics/eventsub/timeline",
+            get(status::api_diagnostics_eventsub_timeline),
+        )
+        .route(
+            "/api/cache/users",
+            get(queue_api::api_cache_users).delete(queue_api::api_cache_users_clear),
+        )
+        .route(
+            "/api/cache/users/:user_id",
+            delete(queue_api::api_cache_user_delete),
+        )
+        .route(
+            "/api/user_cache/prewarm",
+            post(queue_api::api_user_cache_prewarm),
+        )
+        .route("/api/metrics/timings", get(status::api_metrics_timings))
+        .route(
+            "/api/metrics/rejected_requests",
+            get(status::api_metrics_rejected_requests),
+        )
+        .route(
+            "/api/metrics/processed_messages",
+            get(status::api_metrics_processed_messages),
+        )
+        .route(
+            "/api/diagnostics/config",
+            get(status::api_diagnostics_config),
+        )
+        .route("/api/outbox/failed", get(rewards::api_outbox_failed))
+        .route("/api/history", get(history::api_history))
+        .route(
+            "/api/users/:user_id/history",
+            get(history::api_user_history),
+        )
+        .route("/api/users/:user_id/prefs", get(queue_api::api_user_prefs))
+        .route("/api/prefs/override", post(queue_api::api_prefs_override))
+        .route(
+            "/api/redemptions/recent",
+            get(history::api_redemptions_recent),
+        )
+        .route(
+            "/api/import/history",
+            post(history::api_import_history).layer(DefaultBodyLimit::max(import_body_limit)),
+        )
+        .route("/api/import/resolve", post(history::api_import_resolve))
+        .route("/api/profiles", get(profiles::api_profiles))
+        .route(
+            "/api/profiles/:name",
+            get(profiles::api_profile_get).put(profiles::api_profile_put),
+        )
+        .route(
+            "/api/profiles/:name/activate",
+            post(profiles::api_profile_activate),
+        )
+        .route(
+            "/api/redemptions/flush",
+            post(rewards::api_redemptions_flush),
+        )
+        .route("/api/outbox/:id/retry", post(rewards::api_outbox_retry))
+        .layer(DefaultBodyLimit::max(body_limit))
+        .layer(middleware::map_response(add_server_time_header));
+
+    Router::new()
+        .route("/", get(|| async { Redirect::temporary("/admin") }))
+        .route("/obs", get_service(ServeFile::new(obs_file)))
+        .route("/admin", get_service(ServeFile::new(admin_file)))
+        .route("/admin/rewards", get_service(ServeFile::new(rewards_file)))
+        .route("/admin/css", get_service(ServeFile::new(css_creator_file)))
+        .nest_service("/assets", ServeDir::new(assets_dir))
+        // Auth
+        .route("/auth/start", get(auth::auth_start))
+        .route(crate::config::AUTH_CALLBACK_PATH, get(auth::auth_callback))
+        .route("/auth/logout", post(auth::auth_logout))
+        .route(
+            "/api/admins",
+            get(auth::api_admins).put(auth::api_admins_put),
+        )
+        // API
+        .merge(api)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            audit::record_mutations,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            access::require_role,
+        ))
+        .with_state(state)
+}
+
+/// Every API response carries the server clock (epoch millis) so clients can
+/// compute their offset once per request instead of trusting the local clock.
+async fn add_server_time_header(mut res: Response) -> Response {
+    if let Ok(v) = HeaderValue::from_str(&util::now_epoch_millis().to_string()) {
+        res.headers_mut().insert("Server-Time", v);
+    }
+    res
+}
+
+async fn get_valid_access_token(app: &Arc<AppState>) -> ApiResult<String> {
+    let Some(mut t) = db::get_oauth_token(app.queue.db.read()).await? else {
+        return Err(ApiError::Unauthorized("not authenticated".to_string()));
+    };
+
+    if t.expires_at <= util::now_epoch() + 60 {
+        t = twitch::refresh_and_store_token(&app.twitch, &t.refresh_token).await?;
+    }
+
+    Ok(t.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        queue,
+        testing::{self, TestApp},
+    };
+
+    const SMALL_LIMITS: &str = "[server]\nmax_body_bytes = 256\nmax_import_body_bytes = 8192\n";
+
+    async fn post(base: &str, path: &str, body: String) -> (StatusCode, String) {
+        let res = reqwest::Client::new()
+            .post(format!("{base}{path}"))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        (
+            StatusCode::from_u16(res.status().as_u16()).unwrap(),
+            res.text().await.unwrap(),
+        )
+    }
+
+    fn import_body(n: usize) -> String {
+        let items: Vec<_> = (0..n)
+            .map(|i| serde_json::json!({ "user_id": format!("{i}"), "user_login": format!("u{i}"), "display_name": format!("U{i}") }))
+            .collect();
+        serde_json::json!({ "items": items }).to_string()
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_get_a_structured_413_and_are_counted() {
+        let app = TestApp::new(SMALL_LIMITS).await;
+        let base = testing::serve(router(app.state.clone())).await;
+        let before = REJECTED_PAYLOAD_TOO_LARGE.load(Ordering::Relaxed);
+
+        let padding = "x".repeat(512);
+        let body =
+            serde_json::json!({ "user_id": "1", "user_login": "a", "display_name": padding })
+                .to_string();
+        let (status, text) = post(&base, "/api/queue/add", body).await;
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json["error"], "payload_too_large");
+        assert!(REJECTED_PAYLOAD_TOO_LARGE.load(Ordering::Relaxed) > before);
+    }
+
+    #[tokio::test]
+    async fn malformed_json_reports_where_it_broke() {
+        let app = TestApp::new(SMALL_LIMITS).await;
+        let base = testing::serve(router(app.state.clone())).await;
+        let before = REJECTED_INVALID_JSON.load(Ordering::Relaxed);
+
+        let (status, text) = post(&base, "/api/queue/add", "{\n  \"user_id\": }".to_string()).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json["error"], "invalid_json");
+        assert_eq!(
+            (json["line"].as_u64(), json["column"].as_u64()),
+            (Some(2), Some(14))
+        );
+        assert!(REJECTED_INVALID_JSON.load(Ordering::Relaxed) > before);
+    }
+
+    #[tokio::test]
+    async fn import_endpoints_take_bodies_over_the_api_limit() {
+        let app = TestApp::new(SMALL_LIMITS).await;
+        let base = testing::serve(router(app.state.clone())).await;
+        let body = import_body(20);
+        assert!(body.len() > 256 && body.len() < 8192);
+
+        let (status, text) = post(&base, "/api/queue/import", body.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{text}");
+        assert_eq!(
+            queue::list_queue(app.queue.db.read(), &app.queue.timings, &app.settings)
+                .await
+                .unwrap()
+                .len(),
+            20
+        );
+
+        // The same body on a regular route is still cut off, and imports have their own cap.
+        assert_eq!(
+            post(&base, "/api/queue/add", body).await.0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            post(&base, "/api/queue/import", import_body(200)).await.0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_carry_the_server_clock_in_millis() {
+        let before = util::now_epoch_millis();
+        let res = add_server_time_header(StatusCode::NO_CONTENT.into_response()).await;
+        let after = util::now_epoch_millis();
+        let sent: i64 = res.headers()["Server-Time"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((before..=after).contains(&sent));
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::header,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{AdminContext, ApiResult};
+use crate::{
+    cues, overlay_token, profiles,
+    queue::{self, QueueService},
+    util, AppState,
+};
+
+#[derive(Debug, Serialize)]
+pub(super) struct OverlayTokenDto {
+    #[serde(flatten)]
+    status: overlay_token::OverlayTokenStatusDto,
+    /// Overlay URLs signed with the current key, ready to paste into OBS.
+    urls: Vec<String>,
+}
+
+fn overlay_token_dto(app: &AppState, headers: &axum::http::HeaderMap) -> OverlayTokenDto {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or(&app.settings.server.bind);
+    let base = format!("http://{host}");
+    let keys = app.overlay_keys.read().unwrap();
+    OverlayTokenDto {
+        status: keys.status(util::now_epoch()),
+        urls: overlay_token::overlay_urls(&base, &keys),
+    }
+}
+
+pub(super) async fn api_overlay_token_status(
+    State(app): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+) -> Json<OverlayTokenDto> {
+    Json(overlay_token_dto(&app, &headers))
+}
+
+/// New signing key; URLs with the old key keep working for `server.overlay_token_overlap_secs`.
+pub(super) async fn api_overlay_token_rotate(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    headers: axum::http::HeaderMap,
+) -> ApiResult<Json<OverlayTokenDto>> {
+    let rotated = app.overlay_keys.read().unwrap().rotate(
+        util::now_epoch(),
+        app.settings.server.overlay_token_overlap_secs,
+    );
+    overlay_token::save(app.queue.db.write(), &rotated).await?;
+    *app.overlay_keys.write().unwrap() = rotated;
+    info!(actor = %admin.actor, "overlay token key rotated");
+    Ok(Json(overlay_token_dto(&app, &headers)))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CuesQuery {
+    /// Last cue id the client handled; omit on first load.
+    after: Option<i64>,
+}
+
+pub(super) async fn api_cues(
+    State(queue): State<QueueService>,
+    Query(q): Query<CuesQuery>,
+) -> ApiResult<Json<cues::CuesDto>> {
+    Ok(Json(
+        cues::list_after(queue.db.read(), &queue.settings.overlay, q.after).await?,
+    ))
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct OverlayBootstrapDto {
+    /// Same items as `/api/queue`.
+    queue: Vec<queue::QueueItemDto>,
+    /// Newest cue id; poll `/api/cues?after=` from here so old cues are not replayed.
+    cue_last_id: i64,
+    server_time: i64,
+    /// Set by the active settings profile; `null` is the default look.
+    theme: Option<String>,
+}
+
+/// Everything the overlay needs for its first render in one request (instead of
+/// `/api/queue` followed by `/api/cues`). Counts as an overlay heartbeat.
+pub(super) async fn api_overlay_bootstrap(
+    State(queue): State<QueueService>,
+) -> ApiResult<Json<OverlayBootstrapDto>> {
+    let now = queue.now();
+    queue.note_overlay_poll();
+    let cues = cues::list_after(queue.db.read(), &queue.settings.overlay, None).await?;
+    Ok(Json(OverlayBootstrapDto {
+        queue: queue.list().await?,
+        cue_last_id: cues.last_id,
+        server_time: now,
+        theme: profiles::overlay_theme(queue.db.read()).await?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, TestApp};
+
+    async fn get_json(base: &str, path: &str) -> serde_json::Value {
+        let res = reqwest::get(format!("{base}{path}")).await.unwrap();
+        assert_eq!(res.status().as_u16(), 200, "{path}");
+        res.json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn bootstrap_carries_the_cue_id_its_snapshot_was_taken_at() {
+        let app = TestApp::new("").await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        testing::enqueue(&app.queue, testing::new_user("u1")).await;
+
+        let boot = get_json(&base, "/api/overlay/bootstrap").await;
+        let version = boot["cue_last_id"].as_i64().unwrap();
+        assert!(version > 0, "joining emitted a cue: {boot}");
+        assert_eq!(
+            get_json(&base, "/api/cues").await["last_id"],
+            version,
+            "same id as the cue feed"
+        );
+        assert_eq!(boot["queue"].as_array().unwrap().len(), 1);
+        assert!(boot["server_time"].as_i64().unwrap() > 0);
+
+        // What changed after the snapshot arrives through the cue feed from its id, once.
+        testing::enqueue(&app.queue, testing::new_user("u2")).await;
+        let feed = get_json(&base, &format!("/api/cues?after={version}")).await;
+        let ids: Vec<i64> = feed["cues"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["id"].as_i64().unwrap())
+            .collect();
+        assert!(
+            !ids.is_empty() && ids.iter().all(|&id| id > version),
+            "{feed}"
+        );
+        assert_eq!(feed["last_id"], *ids.last().unwrap());
+        let caught_up = get_json(&base, &format!("/api/cues?after={}", ids.last().unwrap())).await;
+        assert_eq!(caught_up["cues"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn a_client_ahead_of_the_server_gets_the_lower_id_to_resume_from() {
+        let app = TestApp::new("").await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        testing::enqueue(&app.queue, testing::new_user("u1")).await;
+        let version = get_json(&base, "/api/overlay/bootstrap").await["cue_last_id"]
+            .as_i64()
+            .unwrap();
+
+        // An id from before a database restore: nothing matches, and `last_id` is lower.
+        let stale = version + 100;
+        let feed = get_json(&base, &format!("/api/cues?after={stale}")).await;
+        assert_eq!(feed["cues"], serde_json::json!([]));
+        assert_eq!(feed["last_id"], version);
+    }
+}
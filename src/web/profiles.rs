@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    Json,
+};
+use tracing::info;
+
+use super::{parse_json, AdminContext, ApiError, ApiResult};
+use crate::{
+    profiles::{self, ActivateOutcome, ProfileSettings, SettingsProfile},
+    util, AppState,
+};
+
+fn checked_name(name: &str) -> ApiResult<()> {
+    if profiles::is_valid_name(name) {
+        Ok(())
+    } else {
+        Err(ApiError::BadRequest(
+            "profile name must be 1-64 characters of A-Z, a-z, 0-9, - and _".to_string(),
+        ))
+    }
+}
+
+pub(super) async fn api_profiles(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<Vec<SettingsProfile>>> {
+    Ok(Json(profiles::list(app.queue.db.read()).await?))
+}
+
+pub(super) async fn api_profile_get(
+    State(app): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<SettingsProfile>> {
+    profiles::get(app.queue.db.read(), &name)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound("no profile with this name".to_string()))
+}
+
+/// Saves the given settings as `name`; with an empty body, the settings in effect now.
+/// Not `Option<ApiJson<_>>`: a body that fails to parse must not turn into a capture.
+pub(super) async fn api_profile_put(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> ApiResult<Json<SettingsProfile>> {
+    checked_name(&name)?;
+    let settings = if body.is_empty() {
+        profiles::capture(app.queue.db.read()).await?
+    } else {
+        parse_json::<ProfileSettings>(&body)?
+    };
+    settings.validate().map_err(ApiError::BadRequest)?;
+    let profile = profiles::put(app.queue.db.write(), &name, &settings, util::now_epoch()).await?;
+    info!(actor = %admin.actor, %name, "settings profile saved");
+    Ok(Json(profile))
+}
+
+pub(super) async fn api_profile_activate(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(name): Path<String>,
+) -> ApiResult<Json<ProfileSettings>> {
+    match profiles::activate(
+        app.queue.db.write(),
+        &app.settings,
+        &name,
+        util::now_epoch(),
+    )
+    .await?
+    {
+        ActivateOutcome::Applied(settings) => {
+            info!(actor = %admin.actor, %name, ?settings, "settings profile activated");
+            Ok(Json(settings))
+        }
+        ActivateOutcome::NotFound => {
+            Err(ApiError::NotFound("no profile with this name".to_string()))
+        }
+        ActivateOutcome::Invalid(msg) => Err(ApiError::BadRequest(msg)),
+    }
+}
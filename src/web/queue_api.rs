@@ -0,0 +1,1473 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{
+    body_bytes, get_valid_access_token, parse_json, AdminContext, ApiError, ApiJson, ApiResult,
+};
+use crate::{
+    agenda,
+    config::EnqueueSource,
+    db, ingest, interest, prefs, profiles,
+    queue::{self, QueueService},
+    redact, roster, twitch, util, AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub(super) struct QueueQuery {
+    /// "obs" when polled by the overlay (counts as its heartbeat).
+    source: Option<String>,
+}
+
+/// `?redact=true` swaps user ids and logins for per-response pseudonyms before
+/// sharing output outside the stream (see `redact::Redactor`).
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct RedactQuery {
+    #[serde(default)]
+    redact: bool,
+    /// With `redact`, leave display names readable.
+    #[serde(default)]
+    keep_display_names: bool,
+}
+
+impl RedactQuery {
+    pub(super) fn apply<T: Serialize>(&self, data: T) -> ApiResult<Response> {
+        if !self.redact {
+            return Ok(Json(data).into_response());
+        }
+        let value = redact::Redactor::new(self.keep_display_names)
+            .redact(&data)
+            .map_err(anyhow::Error::from)?;
+        Ok(Json(value).into_response())
+    }
+}
+
+pub(super) async fn api_queue(
+    State(queue): State<QueueService>,
+    Query(q): Query<QueueQuery>,
+    Query(r): Query<RedactQuery>,
+) -> ApiResult<Response> {
+    if q.source.as_deref() == Some("obs") {
+        queue.note_overlay_poll();
+    }
+    r.apply(queue.list().await?)
+}
+
+/// Admin page listing: `/api/queue` plus admin-only fields such as `private_note`.
+/// Viewers get 403 (the note is for the broadcaster only).
+pub(super) async fn api_queue_admin(
+    State(queue): State<QueueService>,
+    _admin: AdminContext,
+    Query(r): Query<RedactQuery>,
+) -> ApiResult<Response> {
+    let items = queue::list_queue_admin(queue.db.read(), &queue.timings, &queue.settings).await?;
+    r.apply(items)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct PatchItemBody {
+    /// `null` or blank clears the note.
+    private_note: Option<String>,
+}
+
+pub(super) async fn api_queue_patch(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+    ApiJson(body): ApiJson<PatchItemBody>,
+) -> ApiResult<StatusCode> {
+    if body
+        .private_note
+        .as_deref()
+        .is_some_and(|n| n.chars().count() > queue::MAX_PRIVATE_NOTE_CHARS)
+    {
+        return Err(ApiError::BadRequest(format!(
+            "private_note is longer than {} characters",
+            queue::MAX_PRIVATE_NOTE_CHARS
+        )));
+    }
+    if !queue::set_private_note(app.queue.db.write(), &id, body.private_note.as_deref()).await? {
+        return Err(ApiError::NotFound("queue item not found".to_string()));
+    }
+    info!(actor = %admin.actor, %id, "private note updated");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct ClearedDto {
+    removed: u64,
+}
+
+pub(super) async fn api_queue_clear_previous(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<Json<ClearedDto>> {
+    let now = util::now_epoch();
+    let hours = app.settings.queue.previous_session_fallback_hours;
+    let Some(started_at) =
+        queue::current_session_started_at(app.queue.db.write(), hours, now).await?
+    else {
+        return Ok(Json(ClearedDto { removed: 0 }));
+    };
+    let removed =
+        queue::clear_previous_session(app.queue.db.write(), &app.queue.timings, started_at).await?;
+    info!(actor = %admin.actor, removed, started_at, "cleared items from previous session");
+    Ok(Json(ClearedDto { removed }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct ClearBody {
+    /// `completed` counts everyone removed as having played; `canceled` (the default)
+    /// refunds them.
+    #[serde(default)]
+    mode: Option<queue::DeleteMode>,
+    /// The original form of `mode`: `true` is `completed`, `false` is `canceled`.
+    #[serde(default)]
+    record_participation: Option<bool>,
+}
+
+/// Empties the whole queue, e.g. between streams.
+pub(super) async fn api_queue_clear(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    body: Bytes,
+) -> ApiResult<Json<ClearedDto>> {
+    // Not `Option<ApiJson<_>>`: a mistyped body must not fall back to canceling everyone.
+    let body = if body.is_empty() {
+        ClearBody::default()
+    } else {
+        parse_json::<ClearBody>(&body)?
+    };
+    let mode = match (body.mode, body.record_participation) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::BadRequest(
+                "give either mode or record_participation, not both".to_string(),
+            ))
+        }
+        (Some(mode), None) => mode,
+        (None, Some(true)) => queue::DeleteMode::Completed,
+        (None, Some(false) | None) => queue::DeleteMode::Canceled,
+    };
+    let removed = queue::clear_all(app.queue.db.write(), mode).await?;
+    info!(actor = %admin.actor, removed, ?mode, "cleared queue");
+    Ok(Json(ClearedDto { removed }))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct FfaBody {
+    #[serde(default)]
+    duration_secs: Option<u64>,
+    #[serde(default)]
+    entries: Option<u32>,
+}
+
+/// Starts first-come-first-served mode: new entries go to the end until the time or
+/// entry budget runs out. Announced in chat when `chat.ffa_announce` is set.
+pub(super) async fn api_queue_ffa(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(body): ApiJson<FfaBody>,
+) -> ApiResult<Json<queue::FfaState>> {
+    let duration_secs = body.duration_secs.filter(|&d| d > 0);
+    let entries = body.entries.filter(|&n| n > 0);
+    if duration_secs.is_none() && entries.is_none() {
+        return Err(ApiError::BadRequest(
+            "give duration_secs or entries (greater than 0)".to_string(),
+        ));
+    }
+    let state = queue::start_ffa(app.queue.db.write(), duration_secs, entries).await?;
+    info!(actor = %admin.actor, ?duration_secs, ?entries, "first-come-first-served mode started");
+    announce_ffa(&app, duration_secs, entries).await;
+    Ok(Json(state))
+}
+
+async fn announce_ffa(app: &Arc<AppState>, duration_secs: Option<u64>, entries: Option<u32>) {
+    let template = app.settings.chat.ffa_announce.trim();
+    if template.is_empty() {
+        return;
+    }
+    let limit = [
+        entries.map(|n| format!("{n}人")),
+        duration_secs.map(|d| format!("{}分間", d.div_ceil(60))),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join("・");
+    let message = template.replace("{limit}", &limit);
+
+    let result = async {
+        let access_token = get_valid_access_token(app).await?;
+        let (Some(broadcaster_id), Some(broadcaster_login)) = (
+            db::get_broadcaster_id(app.queue.db.read()).await?,
+            db::get_broadcaster_login(app.queue.db.read()).await?,
+        ) else {
+            return Err(ApiError::Unauthorized("not authenticated".to_string()));
+        };
+        let channel = twitch::ChatChannel {
+            broadcaster_id: &broadcaster_id,
+            broadcaster_login: &broadcaster_login,
+        };
+        Ok(twitch::send_chat_message(&app.twitch, &access_token, channel, &message, None).await?)
+    }
+    .await;
+    match result {
+        Ok(transport) => info!(?transport, "announced first-come-first-served mode"),
+        Err(e) => warn!(error = ?e, "failed to announce first-come-first-served mode"),
+    }
+}
+
+pub(super) async fn api_queue_ffa_stop(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<StatusCode> {
+    if queue::stop_ffa(app.queue.db.write()).await? {
+        info!(actor = %admin.actor, "first-come-first-served mode stopped");
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stops redemptions and chat joins from entering the queue; queued items stay.
+pub(super) async fn api_queue_pause(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<StatusCode> {
+    queue::set_paused(
+        app.queue.db.write(),
+        app.settings.twitch.pause_rewards_with_queue,
+        true,
+    )
+    .await?;
+    info!(actor = %admin.actor, "queue paused");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) async fn api_queue_resume(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<StatusCode> {
+    queue::set_paused(
+        app.queue.db.write(),
+        app.settings.twitch.pause_rewards_with_queue,
+        false,
+    )
+    .await?;
+    info!(actor = %admin.actor, "queue resumed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) async fn api_queue_freeze_state(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<queue::FreezeStateDto>> {
+    Ok(Json(queue::freeze_state(app.queue.db.read()).await?))
+}
+
+pub(super) async fn api_queue_freeze(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<Json<queue::FreezeStateDto>> {
+    let state = queue::freeze(app.queue.db.write()).await?;
+    info!(actor = %admin.actor, members = state.members.len(), "queue frozen");
+    Ok(Json(state))
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct ThawedDto {
+    merged: u64,
+}
+
+pub(super) async fn api_queue_thaw(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<Json<ThawedDto>> {
+    let config = profiles::effective_config(app.queue.db.read(), &app.settings).await?;
+    let merged = queue::thaw(app.queue.db.write(), &config.queue).await?;
+    info!(actor = %admin.actor, merged, "queue thawed");
+    Ok(Json(ThawedDto { merged }))
+}
+
+pub(super) async fn api_slots_get(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<Option<agenda::SlotSchedule>>> {
+    Ok(Json(agenda::get_schedule(app.queue.db.read()).await?))
+}
+
+pub(super) async fn api_slots_put(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(times): ApiJson<agenda::SlotTimes>,
+) -> ApiResult<Json<agenda::SlotSchedule>> {
+    if times.slot_secs <= 0 {
+        return Err(ApiError::BadRequest(
+            "slot_secs must be positive".to_string(),
+        ));
+    }
+    let mut tx = app
+        .queue
+        .db
+        .write()
+        .begin()
+        .await
+        .map_err(anyhow::Error::from)?;
+    let schedule = agenda::set_times(&mut tx, times).await?;
+    tx.commit().await.map_err(anyhow::Error::from)?;
+    info!(actor = %admin.actor, session_id = %schedule.id, slot_secs = schedule.slot_secs, "slot schedule set");
+    Ok(Json(schedule))
+}
+
+pub(super) async fn api_slots_delete(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<StatusCode> {
+    agenda::clear_schedule(app.queue.db.write()).await?;
+    info!(actor = %admin.actor, "slot schedule cleared");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct BreakBody {
+    /// Slot index from the session start; by default the first free slot after the queue.
+    #[serde(default)]
+    slot: Option<i64>,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+pub(super) async fn api_session_break_add(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(session_id): Path<String>,
+    body: Option<ApiJson<BreakBody>>,
+) -> ApiResult<Json<agenda::SlotBreak>> {
+    let (slot, label) = match body {
+        Some(ApiJson(b)) => (b.slot, b.label),
+        None => (None, None),
+    };
+    if slot.is_some_and(|s| s < 0) {
+        return Err(ApiError::BadRequest(
+            "slot must not be negative".to_string(),
+        ));
+    }
+    let label = label
+        .filter(|n| !util::is_blank(n))
+        .unwrap_or_else(|| "休憩".to_string());
+
+    let mut tx = app
+        .queue
+        .db
+        .write()
+        .begin()
+        .await
+        .map_err(anyhow::Error::from)?;
+    let queue_len = queue::present_len_tx(&mut tx).await?;
+    let added = match agenda::add_break(&mut tx, &session_id, slot, queue_len, &label).await? {
+        agenda::AddBreakOutcome::Added(b) => b,
+        agenda::AddBreakOutcome::NoSession => return Err(no_session(&session_id)),
+        agenda::AddBreakOutcome::SlotTaken => {
+            return Err(ApiError::Conflict(
+                "that slot is already a break".to_string(),
+            ))
+        }
+    };
+    tx.commit().await.map_err(anyhow::Error::from)?;
+    info!(actor = %admin.actor, %session_id, slot = added.slot, "break inserted");
+    Ok(Json(added))
+}
+
+pub(super) async fn api_session_break_delete(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path((session_id, break_id)): Path<(String, String)>,
+) -> ApiResult<StatusCode> {
+    let mut tx = app
+        .queue
+        .db
+        .write()
+        .begin()
+        .await
+        .map_err(anyhow::Error::from)?;
+    if !agenda::remove_break(&mut tx, &session_id, &break_id).await? {
+        return Err(ApiError::NotFound(format!(
+            "no break {break_id} in session {session_id}"
+        )));
+    }
+    tx.commit().await.map_err(anyhow::Error::from)?;
+    info!(actor = %admin.actor, %session_id, %break_id, "break removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn no_session(session_id: &str) -> ApiError {
+    ApiError::NotFound(format!(
+        "session {session_id} is not the current slot schedule"
+    ))
+}
+
+async fn load_agenda(app: &AppState, session_id: &str) -> ApiResult<Vec<agenda::AgendaSlot>> {
+    let Some(schedule) = agenda::get_schedule(app.queue.db.read())
+        .await?
+        .filter(|s| s.id == session_id)
+    else {
+        return Err(no_session(session_id));
+    };
+    let items = queue::list_queue(app.queue.db.read(), &app.queue.timings, &app.settings).await?;
+    Ok(agenda::agenda(&items, &schedule))
+}
+
+pub(super) async fn api_agenda(
+    State(app): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> ApiResult<Json<Vec<agenda::AgendaSlot>>> {
+    Ok(Json(load_agenda(&app, &session_id).await?))
+}
+
+pub(super) async fn api_agenda_ics(
+    State(app): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> ApiResult<Response> {
+    let slots = load_agenda(&app, &session_id).await?;
+    let body = agenda::render_ics(&slots, util::now_epoch());
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct DeleteBody {
+    mode: queue::DeleteMode,
+}
+
+pub(super) async fn api_queue_delete(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+    ApiJson(body): ApiJson<DeleteBody>,
+) -> ApiResult<StatusCode> {
+    let graceful = matches!(body.mode, queue::DeleteMode::Completed)
+        && app.settings.queue.complete_grace_secs > 0;
+    let result = if graceful {
+        queue::begin_complete(app.queue.db.write(), &id).await
+    } else {
+        queue::delete_item(app.queue.db.write(), &app.queue.timings, &id, body.mode).await
+    };
+    result.map_err(|e| {
+        if e.to_string().contains("not found") {
+            ApiError::NotFound("queue item not found".to_string())
+        } else {
+            ApiError::Internal(e)
+        }
+    })?;
+    info!(actor = %admin.actor, %id, mode = ?body.mode, "queue item removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Restores an item still within `queue.complete_grace_secs` of being completed.
+pub(super) async fn api_queue_abort_complete(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    if !queue::abort_complete(app.queue.db.write(), &id).await? {
+        return Err(ApiError::NotFound(
+            "no completing queue item with this id".to_string(),
+        ));
+    }
+    info!(actor = %admin.actor, %id, "completion aborted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Parks the item for a viewer who stepped away (the admin side of `twitch.away_reward_id`).
+pub(super) async fn api_queue_away(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+) -> ApiResult<Json<queue::AwayChange>> {
+    let user_id = queue::user_id_of_item(app.queue.db.read(), &id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("queue item not found".to_string()))?;
+    let change = queue::set_away(app.queue.db.write(), &user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("queue item not found".to_string()))?;
+    info!(actor = %admin.actor, %id, ?change, "marked away");
+    Ok(Json(change))
+}
+
+/// Returns a parked item to `min(remembered position, present count)`.
+pub(super) async fn api_queue_back(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+) -> ApiResult<Json<queue::AwayChange>> {
+    let user_id = queue::user_id_of_item(app.queue.db.read(), &id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("queue item not found".to_string()))?;
+    let change = queue::set_back(app.queue.db.write(), &user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("queue item not found".to_string()))?;
+    info!(actor = %admin.actor, %id, ?change, "marked back");
+    Ok(Json(change))
+}
+
+pub(super) async fn api_queue_move_up(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    queue::move_up(
+        app.queue.db.write(),
+        &app.queue.timings,
+        &id,
+        app.settings.queue.complete_on_advance,
+    )
+    .await?;
+    info!(actor = %admin.actor, %id, "moved up");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) async fn api_queue_move_down(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    queue::move_down(
+        app.queue.db.write(),
+        &app.queue.timings,
+        &id,
+        app.settings.queue.complete_on_advance,
+    )
+    .await?;
+    info!(actor = %admin.actor, %id, "moved down");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) async fn api_queue_move_top(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    queue::move_to_top(
+        app.queue.db.write(),
+        &app.queue.timings,
+        &id,
+        app.settings.queue.complete_on_advance,
+    )
+    .await?;
+    info!(actor = %admin.actor, %id, "moved to top");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) async fn api_queue_move_bottom(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    queue::move_to_bottom(
+        app.queue.db.write(),
+        &app.queue.timings,
+        &id,
+        app.settings.queue.complete_on_advance,
+    )
+    .await?;
+    info!(actor = %admin.actor, %id, "moved to bottom");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct MoveBody {
+    /// Clamped to the queue: negative is the top, past the end is the bottom.
+    position: i64,
+}
+
+pub(super) async fn api_queue_move(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<String>,
+    ApiJson(body): ApiJson<MoveBody>,
+) -> ApiResult<StatusCode> {
+    queue::move_to_position(
+        app.queue.db.write(),
+        &app.queue.timings,
+        &id,
+        body.position,
+        app.settings.queue.complete_on_advance,
+    )
+    .await?;
+    info!(actor = %admin.actor, %id, position = body.position, "moved");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct PageQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct CachedUsersDto {
+    total: i64,
+    items: Vec<db::CachedUserProfile>,
+}
+
+pub(super) async fn api_cache_users(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<PageQuery>,
+    Query(r): Query<RedactQuery>,
+) -> ApiResult<Response> {
+    let limit = q.limit.unwrap_or(50).clamp(1, 500);
+    let offset = q.offset.unwrap_or(0).max(0);
+    let total = db::count_cached_user_profiles(app.queue.db.read()).await?;
+    let items = db::list_cached_user_profiles(app.queue.db.read(), limit, offset).await?;
+    r.apply(CachedUsersDto { total, items })
+}
+
+pub(super) async fn api_cache_users_clear(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<Json<ClearedDto>> {
+    let removed = db::delete_cached_user_profiles(app.queue.db.write(), None).await?;
+    info!(actor = %admin.actor, removed, "cleared user profile cache");
+    Ok(Json(ClearedDto { removed }))
+}
+
+pub(super) async fn api_cache_user_delete(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(user_id): Path<String>,
+) -> ApiResult<StatusCode> {
+    if db::delete_cached_user_profiles(app.queue.db.write(), Some(&user_id)).await? == 0 {
+        return Err(ApiError::NotFound("user is not cached".to_string()));
+    }
+    info!(actor = %admin.actor, %user_id, "cached user profile deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// How long a prewarm request may spend on Helix lookups before deferring the rest.
+const PREWARM_BUDGET: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+pub(super) struct PrewarmBody {
+    #[serde(default)]
+    user_ids: Vec<String>,
+    #[serde(default)]
+    all_queued: bool,
+}
+
+pub(super) async fn api_user_cache_prewarm(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(body): ApiJson<PrewarmBody>,
+) -> ApiResult<Json<twitch::PrewarmResult>> {
+    let mut user_ids = body.user_ids;
+    if body.all_queued {
+        user_ids.extend(queue::queued_user_ids(app.queue.db.write()).await?);
+    }
+    user_ids.sort();
+    user_ids.dedup();
+    if user_ids.is_empty() {
+        return Ok(Json(twitch::PrewarmResult::default()));
+    }
+
+    let access_token = get_valid_access_token(&app).await?;
+    let result =
+        twitch::prewarm_user_cache(&app.twitch, &access_token, &user_ids, PREWARM_BUDGET).await?;
+    info!(actor = %admin.actor, ?result, "prewarmed user cache");
+    Ok(Json(result))
+}
+
+fn require_manual_source(app: &AppState) -> ApiResult<()> {
+    if app.settings.queue.accepts(EnqueueSource::Manual) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(
+            "queue.enqueue_sources does not include \"manual\"".to_string(),
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AddBody {
+    user_id: String,
+    user_login: String,
+    display_name: String,
+    /// Looked up (and cached) via Helix when omitted and logged in.
+    #[serde(default)]
+    profile_image_url: Option<String>,
+    /// Game name when the roster has no entry for this user.
+    #[serde(default)]
+    user_input: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ExplainQuery {
+    user_id: String,
+    /// Explain a redemption of this reward (its policy) instead of a manual add.
+    #[serde(default)]
+    reward_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct ExplainDto {
+    outcome: queue::EnqueueOutcome,
+    reasons: Vec<String>,
+}
+
+/// Where `user_id` would land if they joined right now, and which rules decided it.
+/// Nothing is stored.
+pub(super) async fn api_queue_explain(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<ExplainQuery>,
+) -> ApiResult<Json<ExplainDto>> {
+    if util::is_blank(&q.user_id) {
+        return Err(ApiError::BadRequest("user_id is empty".to_string()));
+    }
+    let reward_id = q.reward_id.filter(|r| !util::is_blank(r));
+    let config = profiles::effective_config(app.queue.db.read(), &app.settings).await?;
+    let policy = config.policy_for(reward_id.as_deref());
+    let user = queue::NewQueueUser {
+        user_login: q.user_id.clone(),
+        display_name: q.user_id.clone(),
+        user_id: q.user_id,
+        profile_image_url: String::new(),
+        reward_id,
+        redemption_id: None,
+        user_input: None,
+        redeemed_at_ms: None,
+    };
+    let outcome = queue::explain_enqueue(
+        app.queue.db.read(),
+        &app.queue.timings,
+        &config.queue,
+        &policy,
+        user,
+    )
+    .await?;
+    let reasons = queue::explain_reasons(&outcome);
+    Ok(Json(ExplainDto { outcome, reasons }))
+}
+
+/// Adds a viewer by hand, placed and checked by the global `[queue]` rules.
+pub(super) async fn api_queue_add(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(body): ApiJson<AddBody>,
+) -> ApiResult<Json<queue::EnqueueOutcome>> {
+    require_manual_source(&app)?;
+    if util::is_blank(&body.user_id) {
+        return Err(ApiError::BadRequest("user_id is empty".to_string()));
+    }
+
+    let profile_image_url = match body.profile_image_url {
+        Some(url) => util::checked_profile_image_url(
+            &url,
+            &app.settings.twitch.profile_image_hosts,
+            "manual_add",
+        ),
+        None => lookup_profile_image_url(&app, &body.user_id).await,
+    };
+
+    let user = queue::NewQueueUser {
+        user_id: body.user_id,
+        user_login: body.user_login,
+        display_name: body.display_name,
+        profile_image_url,
+        reward_id: None,
+        redemption_id: None,
+        user_input: body.user_input,
+        redeemed_at_ms: None,
+    };
+    let outcome = app
+        .queue
+        .enqueue(user, &queue::JoinNotices::default())
+        .await?;
+    info!(actor = %admin.actor, ?outcome, "manual enqueue");
+    reject_full(outcome)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct EnqueueLoginBody {
+    /// Twitch login, with or without a leading `@`.
+    user_login: String,
+}
+
+/// Adds a viewer by login (e.g. after a failed redemption), resolved through Helix and
+/// checked by the global `[queue]` rules. Already queued is reported, not an error.
+pub(super) async fn api_queue_enqueue_login(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(body): ApiJson<EnqueueLoginBody>,
+) -> ApiResult<Json<queue::EnqueueOutcome>> {
+    require_manual_source(&app)?;
+    let login = body.user_login.trim().trim_start_matches('@');
+    if login.is_empty() {
+        return Err(ApiError::BadRequest("user_login is empty".to_string()));
+    }
+
+    let access_token = get_valid_access_token(&app).await?;
+    let Some(found) = twitch::helix_get_user_by_login(&app.twitch, &access_token, login).await?
+    else {
+        return Err(ApiError::NotFound(format!("twitch user {login} not found")));
+    };
+    let profile_image_url =
+        twitch::get_profile_image_url_cached(&app.twitch, &access_token, &found.id)
+            .await
+            .unwrap_or_else(|e| {
+                warn!(error=?e, user_id=%found.id, "failed to resolve user profile_image_url");
+                String::new()
+            });
+
+    let user = queue::NewQueueUser {
+        user_id: found.id,
+        user_login: found.login,
+        display_name: found.display_name,
+        profile_image_url,
+        reward_id: None,
+        redemption_id: None,
+        user_input: None,
+        redeemed_at_ms: None,
+    };
+    let outcome = app
+        .queue
+        .enqueue(user, &queue::JoinNotices::default())
+        .await?;
+    info!(actor = %admin.actor, login = %login, ?outcome, "manual enqueue by login");
+    reject_full(outcome)
+}
+
+/// A full queue is a 409 for entries added by hand or by other tools.
+fn reject_full(outcome: queue::EnqueueOutcome) -> ApiResult<Json<queue::EnqueueOutcome>> {
+    match outcome {
+        queue::EnqueueOutcome::QueueFull { max_queue_size } => Err(ApiError::Conflict(format!(
+            "the queue is full (queue.max_queue_size = {max_queue_size})"
+        ))),
+        outcome => Ok(Json(outcome)),
+    }
+}
+
+/// Cached or Helix avatar; empty (placeholder) when logged out or the lookup fails.
+async fn lookup_profile_image_url(app: &Arc<AppState>, user_id: &str) -> String {
+    match get_valid_access_token(app).await {
+        Ok(access_token) => {
+            twitch::get_profile_image_url_cached(&app.twitch, &access_token, user_id)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!(error=?e, user_id=%user_id, "failed to resolve user profile_image_url");
+                    String::new()
+                })
+        }
+        Err(_) => String::new(),
+    }
+}
+
+/// Join redemptions dropped while enqueueing was paused, oldest first.
+pub(super) async fn api_pending_interest(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<Vec<interest::PendingInterestDto>>> {
+    Ok(Json(interest::list(app.queue.db.read()).await?))
+}
+
+/// Enqueues a dropped redemption through the normal path (its reward's policy, and its
+/// redemption so completing fulfills it). The row is kept when the policy rejects or the queue is full.
+pub(super) async fn api_pending_interest_admit(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<queue::EnqueueOutcome>> {
+    let Some(row) = interest::get(app.queue.db.write(), id).await? else {
+        return Err(ApiError::NotFound("pending interest not found".to_string()));
+    };
+    let profile_image_url = lookup_profile_image_url(&app, &row.user_id).await;
+    let user = queue::NewQueueUser {
+        user_id: row.user_id.clone(),
+        user_login: row.user_login,
+        display_name: row.display_name,
+        profile_image_url,
+        reward_id: Some(row.reward_id),
+        redemption_id: row.redemption_id,
+        user_input: row.user_input,
+        redeemed_at_ms: None,
+    };
+    let outcome = app
+        .queue
+        .enqueue(user, &queue::JoinNotices::default())
+        .await?;
+    if !matches!(
+        outcome,
+        queue::EnqueueOutcome::Rejected(_) | queue::EnqueueOutcome::QueueFull { .. }
+    ) {
+        interest::remove(app.queue.db.write(), id, row.recorded_at).await?;
+    }
+    info!(actor = %admin.actor, source = "admitted", user_id = %row.user_id, ?outcome, "admitted pending interest");
+    reject_full(outcome)
+}
+
+/// Enqueue from another tool. Authenticated by an HMAC of the body (`ingest.secret`)
+/// instead of the admin password; see [`ingest`].
+pub(super) async fn api_ingest_enqueue(
+    State(app): State<Arc<AppState>>,
+    req: Request,
+) -> ApiResult<Json<queue::EnqueueOutcome>> {
+    let cfg = &app.settings.ingest;
+    if cfg.secret.is_empty() {
+        return Err(ApiError::NotFound("ingest.secret is not set".to_string()));
+    }
+    let signature = req
+        .headers()
+        .get(ingest::SIGNATURE_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+    let body = body_bytes(req, &()).await?;
+    let now = util::now_epoch();
+    let rejected = |r: ingest::IngestRejection| {
+        warn!(rejection=?r, "ingest request rejected");
+        ApiError::Unauthorized(match r {
+            ingest::IngestRejection::BadSignature => "missing or invalid signature".to_string(),
+            ingest::IngestRejection::Stale { skew_secs } => {
+                format!("timestamp is {skew_secs}s off the server clock")
+            }
+            ingest::IngestRejection::BlankNonce => "nonce is empty".to_string(),
+        })
+    };
+    ingest::verify_signature(&cfg.secret, &body, signature.as_deref()).map_err(rejected)?;
+    let body: ingest::IngestEnqueueRequest = parse_json(&body)?;
+    ingest::check_freshness(&body, now, cfg.max_clock_skew_secs).map_err(rejected)?;
+
+    // Everything that can reject the request comes before the nonce is claimed, so a
+    // corrected retry may reuse it.
+    if body.source.is_some_and(|s| s != EnqueueSource::External) {
+        return Err(ApiError::BadRequest(
+            "source must be \"external\"".to_string(),
+        ));
+    }
+    if !app.settings.queue.accepts(EnqueueSource::External) {
+        return Err(ApiError::Forbidden(
+            "queue.enqueue_sources does not include \"external\"".to_string(),
+        ));
+    }
+    let max_priority = app.settings.max_reward_priority();
+    if body.priority > max_priority {
+        return Err(ApiError::BadRequest(format!(
+            "priority must be at most {max_priority} (the highest reward priority)"
+        )));
+    }
+    let user_ref = match (body.user_id.as_deref(), body.login.as_deref()) {
+        (Some(id), None) if !util::is_blank(id) => twitch::UserRef::Id(id.trim()),
+        (None, Some(login)) if !util::is_blank(login) => {
+            twitch::UserRef::Login(login.trim().trim_start_matches('@'))
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "give exactly one of login / user_id".to_string(),
+            ))
+        }
+    };
+    let access_token = get_valid_access_token(&app).await.ok();
+    let profile = twitch::resolve_user_cached(&app.twitch, access_token.as_deref(), user_ref)
+        .await
+        .map_err(|e| ApiError::NotFound(format!("could not resolve user: {e}")))?;
+
+    if !db::claim_processed_message(app.queue.db.write(), &ingest::nonce_key(&body.nonce), now)
+        .await?
+    {
+        warn!(nonce=%body.nonce, "ingest nonce replayed");
+        return Err(ApiError::Unauthorized("nonce was already used".to_string()));
+    }
+    app.queue.processed_sweeper.note_insert(&app.settings.queue);
+
+    let user = queue::NewQueueUser {
+        user_id: profile.user_id,
+        user_login: profile.user_login,
+        display_name: profile.display_name,
+        profile_image_url: profile.profile_image_url,
+        reward_id: None,
+        redemption_id: None,
+        user_input: None,
+        redeemed_at_ms: None,
+    };
+    let outcome = app
+        .queue
+        .enqueue_adjusted(user, &queue::JoinNotices::default(), |policy| {
+            policy.priority = body.priority;
+            policy.tags.push("external".to_string());
+        })
+        .await?;
+    if let (queue::EnqueueOutcome::Added(receipt), Some(note)) = (&outcome, body.note.as_deref()) {
+        let note: String = note
+            .trim()
+            .chars()
+            .take(queue::MAX_PRIVATE_NOTE_CHARS)
+            .collect();
+        queue::set_private_note(app.queue.db.write(), &receipt.id, Some(&note)).await?;
+    }
+    info!(?outcome, "external enqueue");
+    reject_full(outcome)
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ImportBody {
+    items: Vec<queue::ImportItem>,
+    /// Keep the earliest entry per user_id instead of rejecting the import.
+    #[serde(default)]
+    merge_duplicates: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct ImportDto {
+    #[serde(flatten)]
+    result: queue::ImportResult,
+    /// Avatar lookups for imported users; `None` when not logged in.
+    prewarm: Option<twitch::PrewarmResult>,
+}
+
+pub(super) async fn api_queue_import(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(body): ApiJson<ImportBody>,
+) -> ApiResult<Json<ImportDto>> {
+    require_manual_source(&app)?;
+    let (mut items, merged) =
+        queue::dedup_import(body.items, body.merge_duplicates).map_err(|e| match e {
+            queue::ImportError::BlankUserId { index } => {
+                ApiError::BadRequest(format!("items[{index}].user_id is empty"))
+            }
+            queue::ImportError::Duplicate { user_id } => ApiError::BadRequest(format!(
+                "duplicate user_id {user_id}; set merge_duplicates to keep the first entry"
+            )),
+        })?;
+    for item in &mut items {
+        item.profile_image_url = util::checked_profile_image_url(
+            &item.profile_image_url,
+            &app.settings.twitch.profile_image_hosts,
+            "import",
+        );
+    }
+
+    let mut result = queue::import_items(app.queue.db.write(), &app.settings.queue, &items).await?;
+    result.merged_duplicates = merged;
+    info!(actor = %admin.actor, ?result, "imported queue items");
+
+    // Resolve avatars now so the overlay does not fetch them one by one on stream.
+    let ids: Vec<String> = items.into_iter().map(|i| i.user_id).collect();
+    let prewarm = match get_valid_access_token(&app).await {
+        Ok(access_token) if !ids.is_empty() => {
+            let r = twitch::prewarm_user_cache(&app.twitch, &access_token, &ids, PREWARM_BUDGET)
+                .await?;
+            queue::fill_missing_profile_images(app.queue.db.write()).await?;
+            Some(r)
+        }
+        _ => None,
+    };
+
+    Ok(Json(ImportDto { result, prewarm }))
+}
+
+/// Merges queue items that share a user_id; normally a no-op (see `queue::dedupe_users`).
+pub(super) async fn api_queue_dedupe(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<Json<queue::DedupeReport>> {
+    let report = queue::dedupe_users(app.queue.db.write()).await?;
+    info!(actor = %admin.actor, ?report, "deduped queue users");
+    Ok(Json(report))
+}
+
+pub(super) async fn api_roster_get(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<Vec<roster::RosterEntry>>> {
+    Ok(Json(roster::list(app.queue.db.read()).await?))
+}
+
+/// Replaces the roster and re-resolves the game names of queued items.
+pub(super) async fn api_roster_put(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(entries): ApiJson<Vec<roster::RosterEntry>>,
+) -> ApiResult<Json<roster::RosterUpdateDto>> {
+    let entries = roster::validate(entries).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let result = roster::save(app.queue.db.write(), entries, true).await?;
+    info!(actor = %admin.actor, entries = result.entries, refreshed = result.refreshed_items, "roster replaced");
+    Ok(Json(result))
+}
+
+/// Merges `user,game_name[,game]` CSV lines into the roster (same user replaces its entry).
+pub(super) async fn api_roster_import(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    body: String,
+) -> ApiResult<Json<roster::RosterUpdateDto>> {
+    let entries = roster::parse_csv(&body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let result = roster::save(app.queue.db.write(), entries, false).await?;
+    info!(actor = %admin.actor, entries = result.entries, refreshed = result.refreshed_items, "roster imported");
+    Ok(Json(result))
+}
+
+/// A viewer's notification preferences; only the viewer can change them (from chat).
+pub(super) async fn api_user_prefs(
+    State(app): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> ApiResult<Json<prefs::UserPrefsDto>> {
+    let overridden = app.settings.prefs_overridden();
+    Ok(Json(
+        prefs::describe(
+            app.queue.db.read(),
+            &app.settings.queue,
+            overridden,
+            &user_id,
+        )
+        .await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct PrefsOverrideBody {
+    /// Ignore viewers' preferences (use `queue.default_notify`) until restart or until cleared.
+    overridden: bool,
+}
+
+/// Emergency switch between respecting and overriding every viewer's preferences.
+pub(super) async fn api_prefs_override(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(body): ApiJson<PrefsOverrideBody>,
+) -> StatusCode {
+    app.settings.set_prefs_overridden(body.overridden);
+    warn!(actor = %admin.actor, overridden = body.overridden, "viewer notification preferences override changed");
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestApp};
+
+    const PASSWORDS: &str =
+        "[server]\nadmin_password = \"admin-pw\"\nviewer_password = \"viewer-pw\"\n";
+    const NOTE: &str = "watch chat, was rude last time";
+
+    async fn get(base: &str, path: &str, password: Option<&str>) -> (u16, String) {
+        let mut req = reqwest::Client::new().get(format!("{base}{path}"));
+        if let Some(password) = password {
+            req = req.basic_auth("someone", Some(password));
+        }
+        let res = req.send().await.unwrap();
+        (res.status().as_u16(), res.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn private_note_reaches_only_the_admin_listing() {
+        let app = TestApp::new(PASSWORDS).await;
+        let id = testing::enqueue(&app.queue, testing::new_user("u1")).await;
+        assert!(
+            queue::set_private_note(app.queue.db.write(), &id, Some(NOTE))
+                .await
+                .unwrap()
+        );
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+
+        let (status, admin) = get(&base, "/api/queue/admin", Some("admin-pw")).await;
+        assert_eq!(status, 200);
+        assert!(admin.contains(NOTE));
+
+        for (path, password) in [
+            ("/api/queue", None),
+            ("/api/queue?source=obs", None),
+            ("/api/queue", Some("viewer-pw")),
+            ("/api/queue", Some("admin-pw")),
+            ("/api/overlay/bootstrap", None),
+            ("/api/cues?after=0", None),
+            ("/api/queue/admin?redact=true", Some("admin-pw")),
+        ] {
+            let (status, body) = get(&base, path, password).await;
+            assert_eq!(status, 200, "{path}");
+            assert!(
+                !body.contains(NOTE) && !body.contains("private_note"),
+                "{path} leaked the note: {body}"
+            );
+        }
+
+        let (status, body) = get(&base, "/api/queue/admin", Some("viewer-pw")).await;
+        assert_eq!(status, 403);
+        assert!(!body.contains(NOTE));
+    }
+
+    #[tokio::test]
+    async fn public_listing_gates_names_on_public_views() {
+        let app = TestApp::new(PASSWORDS).await;
+        let mut user = testing::new_user("secretive_user");
+        user.user_input = Some("MyIGN".to_string());
+        testing::enqueue(&app.queue, user).await;
+        testing::enqueue(&app.queue, testing::new_user("open_user")).await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let public_paths = [
+            ("/api/queue", None),
+            ("/api/queue", Some("viewer-pw")),
+            ("/api/overlay/bootstrap", None),
+        ];
+
+        prefs::set_all(
+            app.queue.db.write(),
+            "secretive_user",
+            false,
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        for (path, password) in public_paths {
+            let (status, body) = get(&base, path, password).await;
+            assert_eq!(status, 200, "{path}");
+            assert!(
+                !body.contains("secretive_user") && !body.contains("MyIGN"),
+                "{path} named the viewer: {body}"
+            );
+            assert!(
+                body.contains(queue::ANONYMOUS_DISPLAY_NAME) && body.contains("open_user"),
+                "{path}"
+            );
+        }
+        let (_, admin) = get(&base, "/api/queue/admin", Some("admin-pw")).await;
+        assert!(admin.contains("secretive_user") && admin.contains("MyIGN"));
+
+        prefs::set_all(
+            app.queue.db.write(),
+            "secretive_user",
+            true,
+            util::now_epoch(),
+        )
+        .await
+        .unwrap();
+        for (path, password) in public_paths {
+            let (_, body) = get(&base, path, password).await;
+            assert!(
+                body.contains("secretive_user") && body.contains("MyIGN"),
+                "{path}"
+            );
+            assert!(!body.contains(queue::ANONYMOUS_DISPLAY_NAME), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn clearing_records_participations_only_in_completed_mode() {
+        let app = TestApp::new("").await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let client = reqwest::Client::new();
+        let participations = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM participations")
+                .fetch_one(app.queue.db.read())
+                .await
+                .unwrap()
+        };
+
+        for (body, status, recorded) in [
+            ("", 200, 0),
+            (r#"{"mode":"canceled"}"#, 200, 0),
+            (r#"{"mode":"completed"}"#, 200, 2),
+            (r#"{"record_participation":false}"#, 200, 0),
+            (r#"{"record_participation":true}"#, 200, 2),
+            // Neither a mistyped mode, an unknown field nor both forms together falls back to canceling.
+            (r#"{"mode":"complete"}"#, 400, 0),
+            (r#"{"record_participations":true}"#, 400, 0),
+            (r#"{"mode":"canceled","record_participation":true}"#, 400, 0),
+        ] {
+            sqlx::query("DELETE FROM participations")
+                .execute(app.queue.db.write())
+                .await
+                .unwrap();
+            for login in ["u1", "u2"] {
+                if !queue::is_user_queued(app.queue.db.read(), login)
+                    .await
+                    .unwrap()
+                {
+                    testing::enqueue(&app.queue, testing::new_user(login)).await;
+                }
+            }
+            let res = client
+                .post(format!("{base}/api/queue/clear"))
+                .body(body)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(
+                res.status().as_u16(),
+                status,
+                "{body}: {}",
+                res.text().await.unwrap()
+            );
+            let left = queue::queued_user_ids(app.queue.db.read())
+                .await
+                .unwrap()
+                .len();
+            assert_eq!(left, if status == 200 { 0 } else { 2 }, "{body}");
+            assert_eq!(participations().await, recorded, "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn dedupe_drops_frozen_entries_of_queued_users() {
+        let app = TestApp::new("").await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        testing::enqueue(&app.queue, testing::new_user("u1")).await;
+        sqlx::query(
+            "INSERT INTO pending_queue_items (user_id, user_login, display_name, profile_image_url, enqueued_at)
+             VALUES ('u1', 'u1', 'u1', '', 0)",
+        )
+        .execute(app.queue.db.write())
+        .await
+        .unwrap();
+
+        let res = reqwest::Client::new()
+            .post(format!("{base}/api/queue/dedupe"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+        let report: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({ "merged": [], "removed_pending": 1 })
+        );
+        assert_eq!(
+            queue::queued_user_ids(app.queue.db.read()).await.unwrap(),
+            ["u1"]
+        );
+    }
+
+    const INGEST: &str = "[ingest]\nsecret = \"s3cret\"\nmax_clock_skew_secs = 60\n";
+
+    async fn ingest(base: &str, body: &serde_json::Value, secret: &str) -> (u16, String) {
+        let body = body.to_string();
+        let res = reqwest::Client::new()
+            .post(format!("{base}/api/ingest/enqueue"))
+            .header(
+                ingest::SIGNATURE_HEADER,
+                testing::ingest_signature(secret, body.as_bytes()),
+            )
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        (res.status().as_u16(), res.text().await.unwrap())
+    }
+
+    async fn ingest_app() -> (TestApp, String) {
+        let app = TestApp::new(INGEST).await;
+        for user_id in ["1", "2"] {
+            let profile = db::CachedUserProfile {
+                user_id: user_id.to_string(),
+                user_login: format!("viewer{user_id}"),
+                display_name: format!("Viewer{user_id}"),
+                profile_image_url: String::new(),
+                updated_at: util::now_epoch(),
+            };
+            db::upsert_cached_user_profile(app.queue.db.write(), &profile)
+                .await
+                .unwrap();
+        }
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        (app, base)
+    }
+
+    #[tokio::test]
+    async fn ingest_rejects_bad_signatures_skewed_clocks_and_replays() {
+        let (app, base) = ingest_app().await;
+        let now = util::now_epoch();
+        let body = |nonce: &str, timestamp: i64| serde_json::json!({ "user_id": "1", "timestamp": timestamp, "nonce": nonce });
+
+        assert_eq!(ingest(&base, &body("a", now), "wrong").await.0, 401);
+        assert_eq!(ingest(&base, &body("a", now - 61), "s3cret").await.0, 401);
+        assert_eq!(ingest(&base, &body("a", now + 61), "s3cret").await.0, 401);
+        assert_eq!(ingest(&base, &body(" ", now), "s3cret").await.0, 401);
+        assert!(!queue::is_user_queued(app.queue.db.read(), "1")
+            .await
+            .unwrap());
+
+        // None of those used up the nonce.
+        let (status, text) = ingest(&base, &body("a", now - 30), "s3cret").await;
+        assert_eq!(status, 200, "{text}");
+        assert!(queue::is_user_queued(app.queue.db.read(), "1")
+            .await
+            .unwrap());
+
+        // The same signed request again, and a new request reusing the nonce.
+        let (status, text) = ingest(&base, &body("a", now - 30), "s3cret").await;
+        assert_eq!((status, text.as_str()), (401, "nonce was already used"));
+        let other = serde_json::json!({ "user_id": "2", "timestamp": now, "nonce": "a" });
+        assert_eq!(ingest(&base, &other, "s3cret").await.0, 401);
+        assert!(!queue::is_user_queued(app.queue.db.read(), "2")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn invalid_ingest_requests_leave_the_nonce_for_a_corrected_retry() {
+        let (app, base) = ingest_app().await;
+        let now = util::now_epoch();
+        let request = |extra: serde_json::Value| {
+            let mut body = serde_json::json!({ "timestamp": now, "nonce": "retry-me" });
+            body.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            body
+        };
+
+        for (extra, status) in [
+            (
+                serde_json::json!({ "user_id": "1", "source": "manual" }),
+                400,
+            ),
+            (serde_json::json!({ "user_id": "1", "priority": 99 }), 400),
+            (
+                serde_json::json!({ "user_id": "1", "login": "viewer1" }),
+                400,
+            ),
+            (serde_json::json!({}), 400),
+            (serde_json::json!({ "user_id": "404" }), 404),
+        ] {
+            let (got, text) = ingest(&base, &request(extra.clone()), "s3cret").await;
+            assert_eq!(got, status, "{extra}: {text}");
+        }
+        assert!(queue::queued_user_ids(app.queue.db.read())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let (status, text) = ingest(
+            &base,
+            &request(serde_json::json!({ "login": "@Viewer1", "note": "from the bot" })),
+            "s3cret",
+        )
+        .await;
+        assert_eq!(status, 200, "{text}");
+        assert_eq!(
+            queue::queued_user_ids(app.queue.db.read()).await.unwrap(),
+            ["1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn ingest_source_disabled_is_refused_without_using_the_nonce() {
+        let app = TestApp::new(&format!(
+            "{INGEST}[queue]\nenqueue_sources = [\"manual\"]\n"
+        ))
+        .await;
+        let base = testing::serve(crate::web::router(app.state.clone())).await;
+        let body =
+            serde_json::json!({ "user_id": "1", "timestamp": util::now_epoch(), "nonce": "n" });
+        assert_eq!(ingest(&base, &body, "s3cret").await.0, 403);
+        assert!(db::claim_processed_message(
+            app.queue.db.write(),
+            &ingest::nonce_key("n"),
+            util::now_epoch()
+        )
+        .await
+        .unwrap());
+    }
+}
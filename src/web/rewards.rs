@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use tracing::info;
+
+use super::{get_valid_access_token, AdminContext, ApiError, ApiResult};
+use crate::{db, outbox, reward_prompt, stats, twitch, util, AppState};
+
+pub(super) async fn api_outbox_failed(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<Vec<outbox::OutboxEntryDto>>> {
+    let entries = outbox::list_failed(app.queue.db.read()).await?;
+    Ok(Json(entries))
+}
+
+pub(super) async fn api_outbox_retry(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    Path(id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    if !outbox::retry(app.queue.db.write(), id).await? {
+        return Err(ApiError::NotFound(
+            "failed outbox entry not found".to_string(),
+        ));
+    }
+    info!(actor = %admin.actor, id, "outbox entry queued for retry");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) async fn api_redemptions_flush(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+) -> ApiResult<Json<outbox::FlushResult>> {
+    let result = outbox::flush_redemptions(app.as_ref()).await?;
+    info!(actor = %admin.actor, updated = result.updated, failed = result.failed, "flushed redemption status updates");
+    Ok(Json(result))
+}
+
+pub(super) async fn api_rewards(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<Vec<twitch::HelixReward>>> {
+    let access_token = get_valid_access_token(&app).await?;
+
+    let broadcaster_id = match db::get_broadcaster_id(app.queue.db.read()).await? {
+        Some(id) => id,
+        None => {
+            let me = twitch::helix_get_self(&app.twitch, &access_token).await?;
+            db::set_broadcaster_id(app.queue.db.write(), &me.id).await?;
+            db::set_broadcaster_login(app.queue.db.write(), &me.login).await?;
+            me.id
+        }
+    };
+
+    let mut rewards =
+        twitch::helix_get_custom_rewards(&app.twitch, &access_token, &broadcaster_id).await?;
+
+    let now = util::now_epoch();
+    let max_title_len = app.settings.twitch.max_reward_title_len;
+    for r in &mut rewards {
+        stats::record_reward_cost(app.queue.db.write(), &r.id, r.cost, now).await?;
+        r.prompt_sync = reward_prompt::get_state(app.queue.db.read(), &r.id).await?;
+        r.title = util::truncate_with_ellipsis(&r.title, max_title_len).into_owned();
+    }
+
+    Ok(Json(rewards))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct RewardPricingQuery {
+    reward_id: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    interval_secs: Option<i64>,
+}
+
+pub(super) async fn api_stats_reward_pricing(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<RewardPricingQuery>,
+) -> ApiResult<Json<stats::RewardPricingDto>> {
+    let to = q.to.unwrap_or_else(util::now_epoch);
+    let from = q.from.unwrap_or(to - 30 * 24 * 60 * 60);
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to".to_string()));
+    }
+    let interval_secs = q.interval_secs.unwrap_or(60 * 60);
+    if interval_secs <= 0 {
+        return Err(ApiError::BadRequest(
+            "interval_secs must be positive".to_string(),
+        ));
+    }
+
+    let dto =
+        stats::reward_pricing(app.queue.db.read(), &q.reward_id, from, to, interval_secs).await?;
+    Ok(Json(dto))
+}
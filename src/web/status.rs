@@ -0,0 +1,329 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{
+    AdminContext, ApiError, ApiJson, ApiResult, REJECTED_INVALID_JSON, REJECTED_PAYLOAD_TOO_LARGE,
+};
+use crate::{
+    access, audit, config::EnqueueSource, db, diagnostics, digest, profiles, queue, reward_prompt,
+    stats, sweep, timing, twitch, util, AppState,
+};
+
+#[derive(Debug, Serialize)]
+pub(super) struct StatusDto {
+    authenticated: bool,
+    broadcaster_id: Option<String>,
+    broadcaster_login: Option<String>,
+    target_reward_ids: Vec<String>,
+    eventsub_subscription_count: usize,
+    max_eventsub_subscriptions: usize,
+    /// Subscription cost budget from Helix and the optional features skipped to stay within it.
+    eventsub: twitch::EventSubBudget,
+    participation_window_secs: u64,
+    /// Participations completed at or after this epoch second count for fairness.
+    participation_window_start: i64,
+    /// `queue.max_participations_per_window`; 0 = no limit. Per-reward policies may override it.
+    max_participations_per_window: u32,
+    overlay_last_seen_at: i64,
+    /// Enqueueing is paused because the overlay stopped polling.
+    paused_by_overlay_heartbeat: bool,
+    /// Enqueueing is paused after a raid until this epoch second.
+    raid_paused_until: Option<i64>,
+    /// Paused by the broadcaster (`POST /api/queue/pause`).
+    queue_paused: bool,
+    /// New redemptions are being accepted: none of the pauses above is in effect.
+    queue_open: bool,
+    /// First-come-first-served mode and what is left of it (`POST /api/queue/ffa`).
+    ffa: Option<queue::FfaState>,
+    /// Last activated settings profile (`POST /api/profiles/:name/activate`); settings
+    /// changed one by one since then are not reflected.
+    active_profile: Option<String>,
+    /// Logged in as a different account; EventSub is paused until confirmed or canceled.
+    broadcaster_switch_pending: Option<db::PendingBroadcasterSwitch>,
+    broadcaster_switch_notice: Option<db::BroadcasterSwitchNotice>,
+    /// Why the last reward prompt sync failed (e.g. missing scope).
+    reward_prompt_sync_warning: Option<String>,
+    unique_participants: stats::UniqueParticipantsDto,
+    /// Logged in but no join reward configured; redemptions cannot reach the queue.
+    configuration_incomplete: Option<ConfigurationIncompleteDto>,
+    /// User at position 1, for pre-rendering the next avatar on the overlay.
+    next_up_user_id: Option<String>,
+    /// Helix profile lookups are being skipped after repeated failures.
+    profile_breaker: util::CircuitBreakerState,
+    /// Access tier of the caller; `viewer` gets a read-only admin page.
+    role: Option<access::Role>,
+    server_time: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigurationIncompleteDto {
+    message: String,
+    /// Redemptions seen and dropped since no join reward was configured, with the latest reward.
+    unconfigured_redemptions: Option<db::UnconfiguredRedemptions>,
+}
+
+async fn load_configuration_incomplete(
+    app: &AppState,
+    authenticated: bool,
+) -> ApiResult<Option<ConfigurationIncompleteDto>> {
+    let has_target = app
+        .settings
+        .twitch
+        .target_reward_ids
+        .iter()
+        .any(|id| !util::is_blank(id));
+    if has_target || !authenticated || !app.settings.queue.accepts(EnqueueSource::Redemption) {
+        return Ok(None);
+    }
+    Ok(Some(ConfigurationIncompleteDto {
+        message: "twitch.target_reward_ids が未設定です。参加券にする報酬のIDを config.toml に設定して再起動してください。"
+            .to_string(),
+        unconfigured_redemptions: db::get_unconfigured_redemptions(app.queue.db.read()).await?,
+    }))
+}
+
+pub(super) async fn api_status(
+    State(app): State<Arc<AppState>>,
+    role: Option<axum::Extension<access::Role>>,
+) -> ApiResult<Json<StatusDto>> {
+    let authenticated = db::has_validish_token(app.queue.db.read()).await?;
+    let broadcaster_id = db::get_broadcaster_id(app.queue.db.read()).await?;
+    let broadcaster_login = db::get_broadcaster_login(app.queue.db.read()).await?;
+    let now = util::now_epoch();
+    let queue_paused = queue::is_paused(app.queue.db.read()).await?;
+    let eventsub = app.twitch.eventsub.budget.lock().unwrap().clone();
+
+    Ok(Json(StatusDto {
+        authenticated,
+        broadcaster_id,
+        broadcaster_login,
+        target_reward_ids: app.settings.twitch.target_reward_ids.clone(),
+        eventsub_subscription_count: app
+            .twitch
+            .eventsub
+            .subscription_count
+            .load(Ordering::Relaxed),
+        max_eventsub_subscriptions: app.settings.twitch.max_eventsub_subscriptions,
+        eventsub,
+        participation_window_secs: app.settings.queue.participation_window_secs,
+        participation_window_start: queue::participation_window_start(
+            now,
+            app.settings.queue.participation_window_secs as i64,
+        ),
+        max_participations_per_window: app.settings.queue.max_participations_per_window,
+        overlay_last_seen_at: app.queue.overlay_last_seen_at.load(Ordering::Relaxed),
+        paused_by_overlay_heartbeat: app.queue.is_overlay_heartbeat_lost(),
+        raid_paused_until: app.twitch.eventsub.raid_pause_until(now),
+        queue_paused,
+        queue_open: !queue_paused
+            && !app.queue.is_overlay_heartbeat_lost()
+            && app.twitch.eventsub.raid_pause_until(now).is_none(),
+        ffa: queue::ffa_state(app.queue.db.read()).await?,
+        active_profile: profiles::active_profile(app.queue.db.read()).await?,
+        broadcaster_switch_pending: db::get_pending_broadcaster_switch(app.queue.db.read()).await?,
+        broadcaster_switch_notice: db::get_broadcaster_switch_notice(app.queue.db.read()).await?,
+        reward_prompt_sync_warning: reward_prompt::get_warning(app.queue.db.read()).await?,
+        unique_participants: load_unique_participants(&app, now).await?,
+        configuration_incomplete: load_configuration_incomplete(&app, authenticated).await?,
+        next_up_user_id: queue::next_up_user_id(app.queue.db.read()).await?,
+        profile_breaker: app.twitch.profile_breaker.lock().unwrap().state(now),
+        role: role.map(|axum::Extension(r)| r),
+        server_time: now,
+    }))
+}
+
+/// Effective settings for bug reports, secrets redacted. Admin-only.
+pub(super) async fn api_diagnostics_config(
+    State(app): State<Arc<AppState>>,
+    _admin: AdminContext,
+) -> ApiResult<Json<diagnostics::ConfigDiagnosticsDto>> {
+    Ok(Json(
+        diagnostics::config_report(app.queue.db.read(), &app.settings).await?,
+    ))
+}
+
+pub(super) async fn api_diagnostics_token(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<twitch::TokenDiagnostics>> {
+    let diag = twitch::token_diagnostics(app.queue.db.read(), util::now_epoch()).await?;
+    Ok(Json(diag))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct TimelineQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// EventSub connection events and downtime; defaults to the last 24 hours.
+pub(super) async fn api_diagnostics_eventsub_timeline(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<TimelineQuery>,
+) -> ApiResult<Json<twitch::EventSubTimeline>> {
+    let now = util::now_epoch();
+    let to = q.to.unwrap_or(now);
+    let from = q.from.unwrap_or(to - 24 * 60 * 60);
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to".to_string()));
+    }
+    Ok(Json(
+        twitch::eventsub_timeline(app.queue.db.read(), from, to, now).await?,
+    ))
+}
+
+async fn load_unique_participants(
+    app: &AppState,
+    now: i64,
+) -> ApiResult<stats::UniqueParticipantsDto> {
+    let since = queue::current_session_started_at(
+        app.queue.db.read(),
+        app.settings.queue.previous_session_fallback_hours,
+        now,
+    )
+    .await?;
+    let session_id = db::current_session_id(app.queue.db.read()).await?;
+    let count = stats::unique_participants(
+        app.queue.db.read(),
+        session_id.as_deref(),
+        since.unwrap_or(0),
+    )
+    .await?;
+    let goal = app.settings.queue.unique_participants_goal;
+    Ok(stats::UniqueParticipantsDto {
+        since,
+        count,
+        goal: (goal > 0).then_some(goal),
+    })
+}
+
+pub(super) async fn api_stats_unique_participants(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<stats::UniqueParticipantsDto>> {
+    Ok(Json(
+        load_unique_participants(&app, util::now_epoch()).await?,
+    ))
+}
+
+/// Aggregate numbers for the channel panel; public when `server.public_stats` is set.
+pub(super) async fn api_stats_public(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<stats::PublicStatsDto>> {
+    if !app.settings.server.public_stats {
+        return Err(ApiError::NotFound("public stats are disabled".to_string()));
+    }
+    Ok(Json(
+        stats::public_stats(
+            app.queue.db.read(),
+            &app.public_stats_cache,
+            util::now_epoch(),
+        )
+        .await?,
+    ))
+}
+
+/// Waits at least this long are listed in the digest unless the caller picks another threshold.
+const DIGEST_DEFAULT_WAIT_THRESHOLD_SECS: i64 = 30 * 60;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct DigestQuery {
+    /// Epoch second; usually `until` of the previous digest.
+    since: i64,
+    wait_threshold_secs: Option<i64>,
+}
+
+/// Summary of what happened since `since`, for an admin tab that reconnects after sleeping.
+pub(super) async fn api_digest(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<DigestQuery>,
+) -> ApiResult<Json<digest::DigestDto>> {
+    let now = util::now_epoch();
+    if q.since > now {
+        return Err(ApiError::BadRequest("since is in the future".to_string()));
+    }
+    let threshold = q
+        .wait_threshold_secs
+        .unwrap_or(DIGEST_DEFAULT_WAIT_THRESHOLD_SECS)
+        .max(0);
+    Ok(Json(
+        digest::digest(app.queue.db.read(), q.since, now, threshold).await?,
+    ))
+}
+
+/// Size of the duplicate-notification table and the latest sweep of it.
+pub(super) async fn api_metrics_processed_messages(
+    State(app): State<Arc<AppState>>,
+) -> ApiResult<Json<sweep::ProcessedMessagesDto>> {
+    Ok(Json(
+        app.queue
+            .processed_sweeper
+            .metrics(app.queue.db.read())
+            .await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct EventsExportQuery {
+    /// Only events at or after this epoch second.
+    since: Option<i64>,
+    /// Only this route group: `queue`, `settings` or `auth`.
+    kind: Option<String>,
+}
+
+/// Streams the audit log (`audit`) as NDJSON for log pipelines.
+pub(super) async fn api_events_export(
+    State(app): State<Arc<AppState>>,
+    Query(q): Query<EventsExportQuery>,
+) -> ApiResult<Response> {
+    if let Some(kind) = q.kind.as_deref() {
+        if !matches!(kind, "queue" | "settings" | "auth") {
+            return Err(ApiError::BadRequest(format!("unknown kind: {kind}")));
+        }
+    }
+    let body = axum::body::Body::from_stream(audit::export_ndjson(
+        app.queue.db.read().clone(),
+        q.since,
+        q.kind,
+    ));
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+/// Per-phase latency histograms of queue operations since startup.
+pub(super) async fn api_metrics_timings(
+    State(app): State<Arc<AppState>>,
+) -> Json<Vec<timing::HistogramDto>> {
+    Json(app.queue.timings.snapshot())
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct RejectedRequestsDto {
+    payload_too_large: u64,
+    invalid_json: u64,
+}
+
+/// Requests rejected for an oversized or malformed JSON body since startup.
+pub(super) async fn api_metrics_rejected_requests() -> Json<RejectedRequestsDto> {
+    Json(RejectedRequestsDto {
+        payload_too_large: REJECTED_PAYLOAD_TOO_LARGE.load(Ordering::Relaxed),
+        invalid_json: REJECTED_INVALID_JSON.load(Ordering::Relaxed),
+    })
+}
+
+pub(super) async fn api_stats_import(
+    State(app): State<Arc<AppState>>,
+    admin: AdminContext,
+    ApiJson(records): ApiJson<Vec<stats::ParticipationRecord>>,
+) -> ApiResult<Json<stats::ParticipationImportDto>> {
+    stats::validate_participation_records(&records, util::now_epoch())
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let result = stats::import_participations(app.queue.db.write(), &records).await?;
+    info!(actor = %admin.actor, inserted = result.inserted, duplicates = result.duplicates, "imported participations");
+    Ok(Json(result))
+}
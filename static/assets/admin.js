@@ -20,6 +20,9 @@ function renderQueue(items) {
   const root = document.getElementById('queue');
   root.innerHTML = '';
 
+  const hasPrevious = items.some(x => x.from_previous_session);
+  document.getElementById('clearPreviousBtn').style.display = hasPrevious ? '' : 'none';
+
   if (!items.length) {
     const empty = document.createElement('div');
     empty.className = 'small';
@@ -32,10 +35,12 @@ function renderQueue(items) {
 
   for (const item of items) {
     const row = document.createElement('div');
-    row.className = 'item';
+    row.className = item.completing_at ? 'item completing'
+      : item.away_since ? 'item away'
+      : item.from_previous_session ? 'item stale' : 'item';
 
     const img = document.createElement('img');
-    img.src = item.profile_image_url;
+    img.src = item.profile_image_url || '/assets/avatar_placeholder.svg';
     img.loading = 'lazy';
 
     const info = document.createElement('div');
@@ -46,6 +51,16 @@ function renderQueue(items) {
     const meta = document.createElement('div');
     meta.className = 'meta';
     meta.textContent = `@${item.user_login} / 最近の参加: ${item.recent_participation_count}`;
+    const targets = lastStatus ? lastStatus.target_reward_ids.filter(x => x.trim() !== '') : [];
+    if (item.reward_id && targets.length > 1) {
+      meta.textContent += ` / 報酬: ${item.reward_id}`;
+    }
+    if (item.away_since) {
+      meta.textContent += ` / 離席中（戻ると${item.away_return_position + 1}番目付近）`;
+    }
+    if (item.private_note) {
+      meta.textContent += ` / 🔒 ${item.private_note}`;
+    }
 
     info.appendChild(name);
     info.appendChild(meta);
@@ -69,6 +84,24 @@ function renderQueue(items) {
       await refresh();
     };
 
+    const top = document.createElement('button');
+    top.className = 'btn';
+    top.textContent = '⤒';
+    top.title = '先頭へ';
+    top.onclick = async () => {
+      await api('POST', `/api/queue/${item.id}/move_top`);
+      await refresh();
+    };
+
+    const bottom = document.createElement('button');
+    bottom.className = 'btn';
+    bottom.textContent = '⤓';
+    bottom.title = '最後尾へ';
+    bottom.onclick = async () => {
+      await api('POST', `/api/queue/${item.id}/move_bottom`);
+      await refresh();
+    };
+
     const complete = document.createElement('button');
     complete.className = 'btn';
     complete.textContent = '✅完了';
@@ -85,26 +118,143 @@ function renderQueue(items) {
       await refresh();
     };
 
+    const note = document.createElement('button');
+    note.className = 'btn';
+    note.textContent = '🔒メモ';
+    note.title = '自分だけが見られるメモ（OBS表示には出ません）';
+    note.onclick = async () => {
+      const text = prompt('メモ（空にすると消します）', item.private_note || '');
+      if (text === null) return;
+      await api('PATCH', `/api/queue/${item.id}`, { private_note: text });
+      await refresh();
+    };
+
+    const away = document.createElement('button');
+    away.className = 'btn';
+    away.textContent = item.away_since ? '🙋復帰' : '💤離席';
+    away.title = '離席中は列の後ろで待機し、順番が来ても飛ばされます';
+    away.onclick = async () => {
+      await api('POST', `/api/queue/${item.id}/${item.away_since ? 'back' : 'away'}`);
+      await refresh();
+    };
+
+    const abort = document.createElement('button');
+    abort.className = 'btn';
+    abort.textContent = '↩完了を取り消す';
+    abort.onclick = async () => {
+      await api('POST', `/api/queue/${item.id}/abort_complete`);
+      await refresh();
+    };
+
     row.appendChild(img);
     row.appendChild(info);
     row.appendChild(spacer);
-    row.appendChild(up);
-    row.appendChild(down);
-    row.appendChild(complete);
-    row.appendChild(cancel);
+    if (item.completing_at) {
+      row.appendChild(abort);
+    } else if (item.away_since) {
+      row.appendChild(note);
+      row.appendChild(away);
+      row.appendChild(cancel);
+    } else {
+      row.appendChild(note);
+      row.appendChild(away);
+      row.appendChild(top);
+      row.appendChild(up);
+      row.appendChild(down);
+      row.appendChild(bottom);
+      row.appendChild(complete);
+      row.appendChild(cancel);
+    }
+
+    root.appendChild(row);
+  }
+}
+
+function renderInterest(rows) {
+  document.getElementById('interestSection').style.display = rows.length ? '' : 'none';
+  const root = document.getElementById('interest');
+  root.innerHTML = '';
+  for (const r of rows) {
+    const row = document.createElement('div');
+    row.className = 'item';
 
+    const info = document.createElement('div');
+    const name = document.createElement('div');
+    name.className = 'name';
+    name.textContent = r.display_name;
+    const meta = document.createElement('div');
+    meta.className = 'meta';
+    meta.textContent = `@${r.user_login} / ${new Date(r.recorded_at * 1000).toLocaleTimeString()}`;
+    info.appendChild(name);
+    info.appendChild(meta);
+
+    const spacer = document.createElement('div');
+    spacer.className = 'spacer';
+
+    const admit = document.createElement('button');
+    admit.className = 'btn';
+    admit.textContent = '➕入れる';
+    admit.onclick = async () => {
+      try {
+        const outcome = await api('POST', `/api/pending_interest/${r.id}/admit`);
+        if (outcome && outcome.Rejected) alert(`参加条件を満たしていません: ${JSON.stringify(outcome.Rejected)}`);
+      } catch (e) {
+        // 409: queue.max_size に達している
+        alert(`入れられませんでした: ${e.message}`);
+      }
+      await refresh();
+    };
+
+    row.appendChild(info);
+    row.appendChild(spacer);
+    row.appendChild(admit);
     root.appendChild(row);
   }
 }
 
 let lastStatus = null;
 
+let lastFreeze = null;
+
+// A gap this long between two successful refreshes means the tab was asleep.
+const DIGEST_GAP_SECS = 60;
+let lastServerTime = null;
+
+function renderDigest(d) {
+  const parts = [`参加 ${d.queue.entered}件`, `完了 ${d.queue.completed}件`];
+  if (d.queue.redemptions_canceled) parts.push(`キャンセル ${d.queue.redemptions_canceled}件`);
+  const eventsub = kind => (d.eventsub.find(x => x.kind === kind) || { count: 0 }).count;
+  if (eventsub('disconnected')) parts.push(`EventSub 再接続 ${eventsub('disconnected')}回`);
+  if (eventsub('subscribe_failed')) parts.push(`EventSub 購読失敗 ${eventsub('subscribe_failed')}回`);
+  if (d.long_waits_total) {
+    const top = d.long_waits[0];
+    const name = top.display_name || top.user_login || top.user_id;
+    parts.push(`${Math.round(d.wait_threshold_secs / 60)}分以上待った人 ${d.long_waits_total}人（最長: ${name} ${Math.round(top.waited_secs / 60)}分）`);
+  }
+  if (d.outbox_failures_total) parts.push(`Twitch への反映失敗 ${d.outbox_failures_total}件`);
+  const since = new Date(d.since * 1000).toLocaleTimeString();
+  setText('digestText', `${since} 以降: ${parts.join(' / ')}`);
+  document.getElementById('digestRow').style.display = '';
+}
+
+async function showDigestIfAsleep(serverTime) {
+  const since = lastServerTime;
+  lastServerTime = serverTime;
+  if (since === null || serverTime - since < DIGEST_GAP_SECS) return;
+  try {
+    renderDigest(await api('GET', `/api/digest?since=${since}`));
+  } catch (e) {}
+}
+
 async function refresh() {
   try {
     lastStatus = await api('GET', '/api/status');
     const auth = lastStatus.authenticated ? 'ログイン済み' : '未ログイン';
     const b = lastStatus.broadcaster_login ? ` / broadcaster: ${lastStatus.broadcaster_login}` : '';
-    const w = ` / window: ${lastStatus.participation_window_secs}s`;
+    const limit = lastStatus.max_participations_per_window > 0
+      ? ` (最大${lastStatus.max_participations_per_window}回)`
+      : '';
+    const w = ` / window: ${lastStatus.participation_window_secs}s${limit}`;
     const targetRewardIds = Array.isArray(lastStatus.target_reward_ids)
       ? lastStatus.target_reward_ids.filter(x => typeof x === 'string' && x.trim() !== '')
       : [];
@@ -112,22 +262,55 @@ async function refresh() {
       ? ` / target_reward_ids: ${targetRewardIds.join(',')}`
       : ' / target_reward_ids: (未設定)';
 
-    setText('statusText', `${auth}${b}${w}${reward}`);
+    const subs = ` / EventSub: ${lastStatus.eventsub_subscription_count}/${lastStatus.max_eventsub_subscriptions}`;
+
+    const readonly = lastStatus.role === 'viewer';
+    document.body.classList.toggle('readonly', readonly);
+    setText('statusText', `${readonly ? '閲覧専用 / ' : ''}${auth}${b}${w}${reward}${subs}`);
 
     const hint = document.getElementById('hint');
-    if (!lastStatus.authenticated) {
+    const pending = lastStatus.broadcaster_switch_pending;
+    document.getElementById('switchRow').style.display = pending ? '' : 'none';
+    if (pending) {
+      hint.textContent = `前回と別のアカウント (${pending.broadcaster_login}) でログインしました。切り替えるまで参加受付を止めています。`;
+    } else if (lastStatus.queue_paused) {
+      hint.textContent = '参加受付を一時停止中です。この間の引き換えは「受付待ち」に残ります。';
+    } else if (lastStatus.raid_paused_until) {
+      const until = new Date(lastStatus.raid_paused_until * 1000).toLocaleTimeString();
+      hint.textContent = `レイドされたので ${until} まで参加受付を止めています。`;
+    } else if (lastStatus.paused_by_overlay_heartbeat) {
+      hint.textContent = 'OBS表示からのアクセスが途絶えているため、参加受付を一時停止しています。';
+    } else if (!lastStatus.authenticated) {
       hint.textContent = 'まず「Twitchでログイン」を押してください。';
+    } else if (lastStatus.eventsub && lastStatus.eventsub.warnings.length) {
+      hint.textContent = lastStatus.eventsub.warnings.join(' / ');
     } else if (targetRewardIds.length === 0) {
-      hint.textContent = 'config.toml の twitch.target_reward_ids が未設定です。右上の「報酬ID一覧」で確認して設定してください。';
+      const seen = lastStatus.configuration_incomplete && lastStatus.configuration_incomplete.unconfigured_redemptions;
+      hint.textContent = seen
+        ? `twitch.target_reward_ids が未設定なので ${seen.count}件の引き換えがキューに入りませんでした。最近使われた「${seen.last_reward_title}」(${seen.last_reward_id}) を設定しますか？`
+        : 'config.toml の twitch.target_reward_ids が未設定です。右上の「報酬ID一覧」で確認して設定してください。';
     } else {
       hint.textContent = '';
     }
 
-    const items = await api('GET', '/api/queue');
+    const items = await api('GET', readonly ? '/api/queue' : '/api/queue/admin');
     renderQueue(items);
+    renderInterest(await api('GET', '/api/pending_interest'));
+
+    const pauseBtn = document.getElementById('pauseBtn');
+    pauseBtn.textContent = lastStatus.queue_paused ? '受付を再開' : '受付を一時停止';
+    pauseBtn.classList.toggle('danger', !lastStatus.queue_paused);
+
+    lastFreeze = await api('GET', '/api/queue/freeze');
+    document.getElementById('freezeBtn').textContent = lastFreeze.frozen ? '凍結を解除' : 'キューを凍結';
+    setText('freezeText', lastFreeze.frozen
+      ? `凍結中: 対象 ${lastFreeze.members.length}人 / 保留 ${lastFreeze.pending_count}件`
+      : '');
 
     document.getElementById('loginBtn').style.display = lastStatus.authenticated ? 'none' : '';
     document.getElementById('logoutBtn').style.display = lastStatus.authenticated ? '' : 'none';
+
+    await showDigestIfAsleep(lastStatus.server_time);
   } catch (e) {
     setText('statusText', `エラー: ${e.message}`);
   }
@@ -146,6 +329,60 @@ document.getElementById('logoutBtn').onclick = async () => {
   await refresh();
 };
 
+document.getElementById('clearPreviousBtn').onclick = async () => {
+  if (!confirm('前回の配信から残っている人をキャンセルしますか？')) return;
+  try {
+    await api('POST', '/api/queue/clear_previous');
+  } catch (e) {}
+  await refresh();
+};
+
+document.getElementById('clearAllBtn').onclick = async () => {
+  if (!confirm('キューを空にしますか？並んでいる人は全員いなくなります。')) return;
+  // OK = 参加済みとして記録（報酬は完了扱い） / キャンセル = 記録せず払い戻し
+  const record = confirm('並んでいた人を「参加済み」として記録しますか？\n（OK: 参加回数に数える / キャンセル: 数えずに払い戻す）');
+  try {
+    await api('POST', '/api/queue/clear', { mode: record ? 'completed' : 'canceled' });
+  } catch (e) {}
+  await refresh();
+};
+
+document.getElementById('confirmSwitchBtn').onclick = async () => {
+  if (!confirm('アカウントを切り替えますか？前のチャンネルのキューや参加履歴は設定に従って保存・削除されます。')) return;
+  try {
+    await api('POST', '/api/auth/confirm_switch');
+  } catch (e) {}
+  await refresh();
+};
+
+document.getElementById('cancelSwitchBtn').onclick = async () => {
+  try {
+    await api('POST', '/api/auth/cancel_switch');
+  } catch (e) {}
+  await refresh();
+};
+
+document.getElementById('digestCloseBtn').onclick = () => {
+  document.getElementById('digestRow').style.display = 'none';
+};
+
+document.getElementById('pauseBtn').onclick = async () => {
+  const paused = lastStatus && lastStatus.queue_paused;
+  try {
+    await api('POST', paused ? '/api/queue/resume' : '/api/queue/pause');
+  } catch (e) {}
+  await refresh();
+};
+
+document.getElementById('freezeBtn').onclick = async () => {
+  const frozen = lastFreeze && lastFreeze.frozen;
+  if (frozen && !confirm('凍結を解除して、保留中の参加をキューに追加しますか？')) return;
+  try {
+    await api('POST', frozen ? '/api/queue/thaw' : '/api/queue/freeze');
+  } catch (e) {}
+  await refresh();
+};
+
 async function loop() {
   await refresh();
   setTimeout(loop, 1500);
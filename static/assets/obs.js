@@ -1,5 +1,9 @@
+// Signed overlay URL (/obs?token=...): pass the token on to the API polls.
+const overlayToken = new URLSearchParams(location.search).get('token');
+const tokenParam = overlayToken ? `token=${encodeURIComponent(overlayToken)}` : '';
+
 async function fetchQueue() {
-  const res = await fetch('/api/queue');
+  const res = await fetch(`/api/queue?source=obs${tokenParam ? `&${tokenParam}` : ''}`);
   if (!res.ok) {
     throw new Error(await res.text());
   }
@@ -12,10 +16,11 @@ function render(items) {
 
   for (const item of items) {
     const el = document.createElement('div');
-    el.className = 'item';
+    el.className = item.completing_at ? 'item completing' : item.away_since ? 'item away' : 'item';
+    if (item.tier) el.classList.add(`tier-${item.tier}`);
 
     const img = document.createElement('img');
-    img.src = item.profile_image_url;
+    img.src = item.profile_image_url || '/assets/avatar_placeholder.svg';
     img.loading = 'lazy';
 
     const name = document.createElement('div');
@@ -25,22 +30,87 @@ function render(items) {
     const meta = document.createElement('div');
     meta.className = 'meta';
     meta.textContent = `最近の参加: ${item.recent_participation_count}`;
+    if (item.game_name) {
+      meta.textContent = `🎮 ${item.game_name}${item.game ? ` (${item.game})` : ''} / ${meta.textContent}`;
+    }
+    if (item.away_since) {
+      meta.textContent += ' / 離席中';
+    } else if (typeof item.scheduled_at === 'number') {
+      const at = new Date(item.scheduled_at * 1000);
+      const hhmm = at.toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' });
+      meta.textContent += ` / 枠: ${hhmm}`;
+    } else if (item.position > 0 && typeof item.estimated_start_at === 'number') {
+      const at = new Date(item.estimated_start_at * 1000);
+      const hhmm = at.toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' });
+      meta.textContent += ` / ${hhmm}頃`;
+    }
 
     el.appendChild(img);
     el.appendChild(name);
+    if (item.label) {
+      const badge = document.createElement('div');
+      badge.className = 'badge';
+      badge.textContent = item.label;
+      el.appendChild(badge);
+    }
     el.appendChild(meta);
     root.appendChild(el);
   }
 }
 
+// Named cues (cue:user_joined, ...) for custom overlay scripts / sounds:
+//   window.addEventListener('queuecue', e => { if (e.detail.name === 'cue:user_joined') ... });
+// Each cue id is dispatched once, even if a poll is retried.
+let lastCueId = null;
+
+async function pollCues() {
+  const params = [lastCueId === null ? '' : `after=${lastCueId}`, tokenParam].filter(Boolean).join('&');
+  const res = await fetch(`/api/cues${params ? `?${params}` : ''}`);
+  if (!res.ok) {
+    return;
+  }
+  const data = await res.json();
+  if (lastCueId !== null && data.last_id < lastCueId) {
+    // The server's cue history restarted (e.g. a restored database); resume from its id
+    lastCueId = data.last_id;
+    return;
+  }
+  for (const cue of data.cues) {
+    if (lastCueId !== null && cue.id <= lastCueId) {
+      continue;
+    }
+    window.dispatchEvent(new CustomEvent('queuecue', { detail: cue }));
+    lastCueId = cue.id;
+  }
+  lastCueId = Math.max(lastCueId ?? 0, data.last_id);
+}
+
+// First render from one request; OBS loads many sources at once on startup.
+async function bootstrap() {
+  try {
+    const res = await fetch(`/api/overlay/bootstrap${tokenParam ? `?${tokenParam}` : ''}`);
+    if (res.ok) {
+      const data = await res.json();
+      render(data.queue);
+      lastCueId = data.cue_last_id;
+      // Theme of the active settings profile, for `body[data-theme=...]` styles
+      if (data.theme) document.body.dataset.theme = data.theme;
+    }
+  } catch (e) {
+    // Fall back to the regular polls
+  }
+  setTimeout(loop, 1000);
+}
+
 async function loop() {
   try {
     const items = await fetchQueue();
     render(items);
+    await pollCues();
   } catch (e) {
     // OBS overlay: silently ignore and retry
   }
   setTimeout(loop, 1000);
 }
 
-loop();
+bootstrap();
@@ -46,6 +46,10 @@ function render(rewards) {
     const meta = document.createElement('div');
     meta.className = 'meta';
     meta.textContent = `cost=${r.cost} / enabled=${r.is_enabled} / id=${r.id}`;
+    if (r.prompt_sync) {
+      const at = new Date(r.prompt_sync.pushed_at * 1000).toLocaleString();
+      meta.textContent += ` / 説明文を自動更新: ${at}`;
+    }
 
     info.appendChild(name);
     info.appendChild(meta);
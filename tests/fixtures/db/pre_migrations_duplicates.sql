@@ -0,0 +1,18 @@
+-- A database from before the migrations existed: queue_items has no UNIQUE on user_id,
+-- and a race queued viewer 100 three times.
+CREATE TABLE queue_items (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  user_login TEXT NOT NULL,
+  display_name TEXT NOT NULL,
+  profile_image_url TEXT NOT NULL,
+  enqueued_at INTEGER NOT NULL,
+  position INTEGER NOT NULL
+);
+
+INSERT INTO queue_items (id, user_id, user_login, display_name, profile_image_url, enqueued_at, position) VALUES
+  ('item-a1', '100', 'alice', 'Alice', '', 1700000050, 0),
+  ('item-b', '200', 'bob', 'Bob', '', 1700000020, 1),
+  ('item-a2', '100', 'alice', 'Alice', '', 1700000010, 2),
+  ('item-c', '300', 'carol', 'Carol', '', 1700000030, 3),
+  ('item-a3', '100', 'alice', 'Alice', '', 1700000040, 4);